@@ -0,0 +1,2750 @@
+// Slackのメッセージからゲームを進める処理
+// チャンネルごとのステージと順番待ちの管理、ターンの計算と結果の投稿、ステージの保存を行う
+
+use chrono::prelude::*;
+use tracing::Instrument;
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, RwLock };
+
+use super::{ canvas, command, metrics, render, slack, stage };
+#[cfg(test)]
+use super::health;
+
+// アプリケーション全体のエラー (各モジュールのエラーをまとめる)
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error(transparent)]
+    Slack(#[from] slack::SlackApiError),
+    #[error(transparent)]
+    Stage(#[from] stage::StageError),
+    #[error(transparent)]
+    Canvas(#[from] canvas::CanvasError),
+    // ステージの保存
+    #[error("failed to write the stage file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to serialize the stage: {0}")]
+    Json(#[from] serde_json::Error),
+    // ローカルモードのタスクがpanicした場合など
+    #[error("the turn task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    // ターンの物理演算か描画がpanicした (ステージはターンの開始前に戻している)
+    #[error("the physics simulation panicked")]
+    PhysicsPanic,
+    // ロックを取得できなかった場合など、その他の内部のエラー
+    #[error("{0}")]
+    Internal(String),
+}
+impl From<&str> for AppError {
+    fn from(message: &str) -> AppError {
+        return AppError::Internal(message.to_string());
+    }
+}
+impl AppError {
+    // チャンネルがアーカイブされたか削除されたことを表すエラーか
+    fn is_channel_unavailable(&self) -> bool {
+        return match self {
+            AppError::Slack(err) => err.is_channel_unavailable(),
+            _ => false,
+        };
+    }
+
+    // ターンを処理できなかったことをユーザーに知らせるメッセージ
+    fn get_user_message(&self) -> &'static str {
+        return match self {
+            AppError::Slack(err) if err.is_rate_limited() => "Slackが混み合っているため、ターンを処理できませんでした。しばらくしてからもう一度お試しください。",
+            AppError::Slack(_) => "Slackとの通信でエラーが発生しました。",
+            AppError::Stage(_) | AppError::Task(_) | AppError::PhysicsPanic => "物理演算でエラーが発生しました。",
+            AppError::Canvas(_) => "画像の作成でエラーが発生しました。",
+            _ => "エラーが発生しました。",
+        };
+    }
+}
+
+pub type AppResult<T = ()> = Result<T, AppError>;
+
+// チャンネルの最高記録
+#[derive(Clone, Serialize, Deserialize)]
+struct Record {
+    height: stage::Real,
+    user_id: String,
+    time: DateTime<Local>,
+}
+
+// チャンネルごとの設定
+#[derive(Clone, Serialize, Deserialize)]
+struct ChannelSettings {
+    // 同じユーザーの連続プレイを許可するか
+    solo: bool,
+    // 風を吹かせるか
+    wind: bool,
+    // 地面の両端に壁を置くか
+    walls: bool,
+    // 結果の画像をアニメーションGIFにするか
+    #[serde(default)]
+    gif: bool,
+    // 物理演算の前に落とす位置のガイドの画像を投稿するか
+    #[serde(default)]
+    guide: bool,
+    // 画像のテーマ (ステージをリセットしても引き継ぐ)
+    #[serde(default)]
+    theme: render::Theme,
+    // ゲームごとにスレッドを作り、ゲーム中の投稿をスレッドにまとめるか
+    #[serde(default)]
+    thread: bool,
+}
+
+// 各チャンネルごとに独立したステージを管理
+pub struct ChannelStage {
+    update_time: DateTime<Local>,
+    channel_id: String,
+    stage: Option<stage::Stage>,
+    reset_request_time: Option<DateTime<Local>>,
+    record: Option<Record>,
+    // デイリーチャレンジの記録 (通常の記録とは別に管理)
+    daily_record: Option<Record>,
+    // 現在のステージがデイリーチャレンジか
+    daily: bool,
+    settings: ChannelSettings,
+    // スレッドモードで開始したゲームのスレッドの親メッセージのts
+    thread_ts: Option<String>,
+    // ボタンで選んだ次のターンの左右の位置と回転角度 (保存しない)
+    pending_input: (f64, f64),
+    // DMでの練習用のステージか (結果を記録とリーダーボードに残さない)
+    practice: bool,
+    // スレッドごとのステージの場合はそのスレッドの親メッセージのts (チャンネル全体のステージはNone)
+    scope_thread_ts: Option<String>,
+    // 前回保存してから変更があったか (変更のないチャンネルは保存し直さない)
+    changed: bool,
+}
+impl ChannelStage {
+    // 初めてコマンドを受け取ったチャンネル (またはスレッド) のステージ
+    fn new(channel_id: String, practice: bool, scope_thread_ts: Option<String>) -> ChannelStage {
+        return ChannelStage {
+            update_time: Local::now(),
+            channel_id,
+            stage: None,
+            reset_request_time: None,
+            record: None,
+            daily_record: None,
+            daily: false,
+            settings: ChannelSettings {
+                solo: false,
+                wind: false,
+                walls: false,
+                gif: false,
+                guide: false,
+                theme: render::Theme::Default,
+                thread: false,
+            },
+            thread_ts: None,
+            pending_input: (0.0, 0.0),
+            practice,
+            scope_thread_ts,
+            changed: true,
+        };
+    }
+}
+
+// 計算中に届いたコマンドの順番待ち (チャンネルごと)
+// 計算中はChannelStageがロックされているので、ChannelStageとは別のロックで管理する
+struct QueuedTurn {
+    ticket: u64,
+    message: slack::Message,
+}
+pub struct TurnQueue {
+    turns: std::collections::VecDeque<QueuedTurn>,
+    next_ticket: u64,
+    // 連続でプレイできないユーザー (計算中でもChannelStageのロックを待たずに連続プレイを断るための写し)
+    last_user_id: Option<String>,
+}
+
+// 連続でプレイできないユーザー (ターン順が決まっていない場合に直前のターンを行ったユーザー)
+// 練習モードは1人で遊ぶので連続プレイを許可する
+fn get_blocked_user_id(channel_stage: &ChannelStage) -> Option<String> {
+    let stage = channel_stage.stage.as_ref()?;
+    if channel_stage.settings.solo || channel_stage.practice || stage.get_current_player().is_some() { return None; }
+    return stage.get_last_user_id();
+}
+
+// 順番待ちにある連続でプレイできないユーザーの写しを、ChannelStageの状態に合わせる
+fn sync_blocked_user_id(turn_queue: &Mutex<TurnQueue>, channel_stage: &ChannelStage) {
+    if let Ok(mut turn_queue) = turn_queue.lock() {
+        turn_queue.last_user_id = get_blocked_user_id(channel_stage);
+    }
+}
+
+// ファイルに保存するChannelStage (リセットの確認中の状態は保存しない)
+#[derive(Serialize, Deserialize)]
+struct SavedChannelStage {
+    update_time: DateTime<Local>,
+    channel_id: String,
+    stage: Option<stage::StageSnapshot>,
+    record: Option<Record>,
+    daily_record: Option<Record>,
+    daily: bool,
+    settings: ChannelSettings,
+    #[serde(default)]
+    thread_ts: Option<String>,
+    #[serde(default)]
+    practice: bool,
+    #[serde(default)]
+    scope_thread_ts: Option<String>,
+}
+
+// 振り分けは読み込みのロックで行い、書き込みのロックはチャンネルの追加の時だけ取る
+// キーは (チャンネルID, スレッドのts)。スレッド内のコマンドはスレッドごとのステージで処理し、1つのチャンネルで複数のゲームを遊べるようにする
+// スレッドモードのゲームは、ゲームのスレッドのキーからもチャンネル全体のステージを参照する (別名のキー)
+pub type StageKey = (String, Option<String>);
+pub type SharedChannelStage = Arc<tokio::sync::Mutex<ChannelStage>>;
+pub type ChannelStages = Arc<tokio::sync::RwLock<HashMap<StageKey, SharedChannelStage>>>;
+
+// チャンネル全体のステージを取り出す (まだない場合は追加する)
+// スレッドごとのステージは24時間で削除されるので、記録はチャンネル全体のステージに残す
+async fn get_channel_level_stage(stages: &ChannelStages, channel_id: &str, practice: bool) -> SharedChannelStage {
+    let key: StageKey = (channel_id.to_string(), None);
+    if let Some(channel_stage) = stages.read().await.get(&key) {
+        return Arc::clone(channel_stage);
+    }
+    return Arc::clone(stages.write().await.entry(key).or_insert_with(|| {
+        Arc::new(tokio::sync::Mutex::new(ChannelStage::new(channel_id.to_string(), practice, None)))
+    }));
+}
+
+// 全チャンネル共通のリーダーボード (1ターンで伸ばした高さの自己ベスト)
+struct LeaderboardEntry {
+    best_delta: stage::Real,
+    time: DateTime<Local>,
+}
+pub struct Leaderboard {
+    entries: HashMap<String, LeaderboardEntry>,
+}
+
+// 全チャンネル共通のユーザー情報のキャッシュ
+// ステージがリセットされたり、複数のチャンネルで遊んだりしてもアイコン画像を取得し直さないようにする
+// 取得してから24時間で期限切れになり、上限を超えた場合は最後に使ってから最も時間が経ったユーザーから忘れる
+const USER_CACHE_TTL_HOURS: i64 = 24;
+const USER_CACHE_CAPACITY: usize = 500;
+#[derive(Clone)]
+pub struct CachedUser {
+    name: Option<String>,
+    // 縮小済みのアイコン画像 (アイコン画像がない場合はNone)
+    icon_png: Option<Arc<Vec<u8>>>,
+    fetched_at: DateTime<Local>,
+    used_at: DateTime<Local>,
+}
+pub type UserCache = Arc<RwLock<HashMap<String, CachedUser>>>;
+
+// ユーザー情報をキャッシュから取り出す (期限切れかキャッシュにない場合はslackから取得してキャッシュする)
+async fn get_cached_user(slack_client: &slack::SlackClient, user_cache: &UserCache, user_id: &str) -> slack::SlackResult<CachedUser> {
+    let now = Local::now();
+    if let Ok(mut users) = user_cache.write() {
+        if let Some(user) = users.get_mut(user_id) {
+            if (now - user.fetched_at).num_hours() < USER_CACHE_TTL_HOURS {
+                user.used_at = now;
+                return Ok(user.clone());
+            }
+        }
+    }
+
+    let user_info = slack_client.get_user_info(user_id.to_string()).await?;
+    // 縮小できない画像はそのまま使う (描画できない形式の場合は白で塗られる)
+    let icon_png = user_info.icon_data.map(|icon_data| match canvas::Canvas::resize_icon(&icon_data, ICON_SIZE) {
+        Ok(icon_data) => Arc::new(icon_data),
+        Err(error) => {
+            tracing::warn!("failed to resize the icon of {}: {}", user_id, error);
+            Arc::new(icon_data)
+        },
+    });
+    let user = CachedUser { name: user_info.name, icon_png, fetched_at: now, used_at: now };
+    if let Ok(mut users) = user_cache.write() {
+        if !users.contains_key(user_id) && users.len() >= USER_CACHE_CAPACITY {
+            let least_recently_used = users.iter().min_by_key(|(_, user)| user.used_at).map(|(user_id, _)| user_id.clone());
+            if let Some(least_recently_used) = least_recently_used { users.remove(&least_recently_used); }
+        }
+        users.insert(user_id.to_string(), user.clone());
+    }
+    return Ok(user);
+}
+
+// 風向きと強さを文字列に変換 (例: "風: ←← 中")
+fn wind_to_string(wind: stage::Real) -> String {
+    let level = stage::get_wind_level(wind);
+    let arrow = if wind > 0.0 { "→" } else { "←" };
+    return format!("風: {} {}", arrow.repeat(level), ["弱", "中", "強"][level - 1]);
+}
+
+// ピースセットのSVGファイルを置くディレクトリ
+pub const SHAPE_DIR: &str = "resources";
+// ピースセットの名前ごとの形状 (reloadshapesで全体を差し替える)
+pub type ShapePacks = Arc<RwLock<Arc<HashMap<String, Vec<canvas::ShapeDef>>>>>;
+// ピースセットの指定がない場合に使うピースセット
+pub const DEFAULT_SHAPE_PACK: &str = "shapes";
+
+// SVGから読み込んだ形状の拡大率
+pub const SHAPE_SCALE: f64 = 3.0;
+// ピースに貼るアイコン画像の大きさ (px)
+const ICON_SIZE: u32 = 96;
+// アップロードされるSVGファイルの制限
+const MAX_SHAPE_FILE_SIZE: u64 = 100 * 1024;
+const MAX_SHAPE_VERTICES: usize = 200;
+const MAX_SHAPE_SIZE: f64 = 300.0;
+// 計算中に届いたコマンドを順番待ちにできる数 (超えた場合は断る)
+const MAX_QUEUED_TURNS: usize = 3;
+
+// アップロードされた形状の検証 (問題がある場合は理由を返す)
+fn validate_shapes(shapes: &[canvas::ShapeDef]) -> Result<(), String> {
+    if shapes.is_empty() { return Err("SVGファイルに形状 (path) が含まれていません。".to_string()); }
+    // 読み込んだ形状は左右反転したものと2つずつ並んでいる
+    let vertex_count = shapes.iter().map(|shape| shape.vertices.len()).sum::<usize>() / 2;
+    if vertex_count > MAX_SHAPE_VERTICES {
+        return Err(format!("頂点の数が多すぎます ({}個、最大{}個)。", vertex_count, MAX_SHAPE_VERTICES));
+    }
+    for (index, shape) in shapes.iter().enumerate() {
+        shape.validate().map_err(|reason| format!("{}番目の形状: {}。", index / 2 + 1, reason))?;
+        let width = shape.vertices.iter().map(|(x, _)| x.abs()).fold(0.0, f64::max) * 2.0;
+        let height = shape.vertices.iter().map(|(_, y)| y.abs()).fold(0.0, f64::max) * 2.0;
+        if width > MAX_SHAPE_SIZE || height > MAX_SHAPE_SIZE {
+            return Err(format!("形状が大きすぎます ({:.0}x{:.0} px、最大{} px)。", width, height, MAX_SHAPE_SIZE));
+        }
+    }
+    return Ok(());
+}
+
+// ピースセットを読み込み直す
+// 1つでも読み込めないファイルや使えない形状があればエラーを返す
+fn reload_shape_packs() -> Result<HashMap<String, Vec<canvas::ShapeDef>>, String> {
+    let mut shape_packs = HashMap::new();
+    let entries = std::fs::read_dir(SHAPE_DIR).map_err(|error| format!("{}: {}", SHAPE_DIR, error))?;
+    for entry in entries {
+        let path = entry.map_err(|error| format!("{}: {}", SHAPE_DIR, error))?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("svg") { continue; }
+        let name = match path.file_stem().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => { continue; }
+        };
+        let shapes = canvas::Canvas::load_shaper_from_svg(&path.to_string_lossy(), SHAPE_SCALE)
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        if shapes.is_empty() { return Err(format!("{}: 形状がありません", path.display())); }
+        for (index, shape) in shapes.iter().enumerate() {
+            shape.validate().map_err(|reason| format!("{}: 形状{}: {}", path.display(), index / 2, reason))?;
+        }
+        shape_packs.insert(name, shapes);
+    }
+    if shape_packs.is_empty() { return Err(format!("{} にピースセットがありません", SHAPE_DIR)); }
+    return Ok(shape_packs);
+}
+
+// メンションが送られてきたときに呼ばれる関数
+// ChannelStageのロックを取得する (計算中の場合は順番待ちに加えて、順番が来るまで待つ)
+// tokioのMutexはロックを待っている順に取得できるので、順番待ちの順にターンを処理する
+// 同じユーザーが既に順番待ちしている場合はそのコマンドを置き換え、順番待ちがいっぱいの場合は断る (どちらもNoneを返す)
+// 順番が来た時は、順番待ちの間に置き換えられた最新のコマンドを返す
+// ロックは物理演算の間にspawn_blockingのスレッドへ渡せるように、所有権を持つガードで返す
+async fn wait_for_turn(
+    slack_client: &slack::SlackClient,
+    channel_stage: &SharedChannelStage,
+    turn_queue: &Mutex<TurnQueue>,
+    message: slack::Message
+) -> AppResult<Option<(tokio::sync::OwnedMutexGuard<ChannelStage>, slack::Message)>> {
+    let (channel_id, user_id, thread_ts) = (message.channel_id.clone(), message.user_id.clone(), message.thread_ts.clone());
+    let (ticket, reply) = {
+        let mut turn_queue = match turn_queue.lock() {
+            Ok(turn_queue) => turn_queue,
+            Err(_) => { return Err("failed to lock turn queue".into()); },
+        };
+        if turn_queue.turns.is_empty() {
+            if let Ok(channel_stage) = Arc::clone(channel_stage).try_lock_owned() { return Ok(Some((channel_stage, message))); }
+        }
+        // 置き換えるのは同じ種類の操作だけにする (ボタンの操作で順番待ち中のコマンドが消えないように)
+        let is_button = message.event_type == "block_action";
+        if let Some(index) = turn_queue.turns.iter().position(|turn| turn.message.user_id == user_id && (turn.message.event_type == "block_action") == is_button) {
+            turn_queue.turns[index].message = message;
+            (None, format!("<@{}> :hourglass: 順番待ち {}番目 (前のコマンドを置き換えました)", user_id, index + 2))
+        }
+        else if turn_queue.turns.len() >= MAX_QUEUED_TURNS {
+            (None, format!("<@{}> 現在計算中で、順番待ちもいっぱいです。\n結果が投稿された後に再度お試しください。", user_id))
+        }
+        else {
+            let ticket = turn_queue.next_ticket;
+            turn_queue.next_ticket += 1;
+            turn_queue.turns.push_back(QueuedTurn { ticket, message });
+            (Some(ticket), format!("<@{}> :hourglass: 順番待ち {}番目", user_id, turn_queue.turns.len() + 1))
+        }
+    };
+    // 順番待ちの通知に失敗しても、並んだターンは順番が来たら処理する (ここで戻ると順番待ちに残り続ける)
+    if let Err(err) = slack_client.post_ephemeral(channel_id, thread_ts, user_id, reply).await {
+        tracing::warn!("failed to notify queued turn: {}", err);
+    }
+    let ticket = match ticket {
+        Some(ticket) => ticket,
+        None => { return Ok(None); },
+    };
+    let channel_stage = Arc::clone(channel_stage).lock_owned().await;
+    let mut turn_queue = match turn_queue.lock() {
+        Ok(turn_queue) => turn_queue,
+        Err(_) => { return Err("failed to lock turn queue".into()); },
+    };
+    return match turn_queue.turns.iter().position(|turn| turn.ticket == ticket).and_then(|index| turn_queue.turns.remove(index)) {
+        Some(turn) => Ok(Some((channel_stage, turn.message))),
+        None => Err("queued turn not found".into()),
+    };
+}
+
+// 編集されたコマンドを修正として受け付ける時間 (元のメッセージを送ってから)
+const CORRECTION_WINDOW_SEC: f64 = 30.0;
+
+// 編集されたメッセージで、順番待ち中の同じメッセージのコマンドを置き換える
+// 送ってから時間が経った編集と、既にターンを処理したメッセージの編集は無視する (古いコマンドを再実行しないため)
+fn correct_queued_turn(turn_queue: &Mutex<TurnQueue>, message: slack::Message) {
+    // tsはUNIX時間の秒 (例: "1700000000.123456")
+    let elapsed = match message.ts.parse::<f64>() {
+        Ok(ts) => Local::now().timestamp() as f64 - ts,
+        Err(_) => { return; },
+    };
+    if elapsed > CORRECTION_WINDOW_SEC {
+        tracing::info!("ignored edit of an old message {} ({:.0} s)", message.ts, elapsed);
+        return;
+    }
+    if let Ok(mut turn_queue) = turn_queue.lock() {
+        match turn_queue.turns.iter_mut().find(|turn| turn.message.ts == message.ts && turn.message.user_id == message.user_id) {
+            Some(turn) => {
+                tracing::info!("corrected queued message {} in channel {}", message.ts, message.channel_id);
+                turn.message.text = message.text;
+            },
+            None => { tracing::info!("ignored edit of a message {} that is not queued", message.ts); },
+        };
+    }
+}
+
+// botへのメンション (<@U0123> または <@U0123|name>) に一致する正規表現
+fn get_mention_regex(bot_user_id: &str) -> regex::Regex {
+    return regex::Regex::new(&format!(r"<@{}(\|[^>]*)?>", regex::escape(bot_user_id))).unwrap();
+}
+
+// botへのコマンドか
+// スラッシュコマンド (/tower 0.2 30) とボタンはメンションと同じように扱い、DMではメンションなしで遊べる
+// botがメンションされていないメッセージと、編集や削除などのsubtypeのメッセージはコマンドではない (添付ファイル付きのメッセージは除く)
+fn is_bot_command(mention: &regex::Regex, message: &slack::Message) -> bool {
+    if message.subtype.as_deref().is_some_and(|subtype| subtype != "file_share") { return false; }
+    let slash_command = message.event_type == "slash_command" || message.event_type == "block_action";
+    let direct_message = message.event_type == "message" && message.channel_type.as_deref() == Some("im");
+    if message.event_type != "app_mention" && !slash_command && !direct_message { return false; }
+    return slash_command || direct_message || mention.is_match(&message.text);
+}
+
+// オブジェクトを落とすコマンドか (数値のコマンドと、ボタンの「落とす」)
+fn is_turn_command(mention: &regex::Regex, message: &slack::Message) -> bool {
+    let text = strip_mention(mention, &message.text);
+    if message.event_type == "block_action" { return text == "drop"; }
+    return command::parse_turn_command(&text).is_ok();
+}
+
+// 同じユーザーの連続プレイを断る (本人にだけ見えるメッセージで知らせる)
+async fn reject_consecutive_play(slack_client: &slack::SlackClient, message: &slack::Message, thread_ts: Option<String>) -> slack::SlackResult {
+    return slack_client.post_ephemeral(message.channel_id.clone(), thread_ts, message.user_id.clone(),
+        format!("<@{}> 連続でプレイできません。他の人の後にどうぞ :pray:", message.user_id)
+    ).await;
+}
+
+// メッセージからbotへのメンションを取り除き、空白を1つにまとめたコマンドを返す
+// メモ: メンションは文の途中にあってもよい (例: "<@U0123> 0 0" と "0 0 <@U0123>" は同じ)
+fn strip_mention(mention: &regex::Regex, text: &str) -> String {
+    let text = mention.replace_all(text, " ");
+    return text.split_whitespace().collect::<Vec<&str>>().join(" ");
+}
+
+async fn compute_turn(
+    context: AppContext,
+    channel_stage: SharedChannelStage,
+    turn_queue: Arc<Mutex<TurnQueue>>,
+    message: slack::Message
+) -> AppResult {
+    let AppContext { slack_client, bot_user_id, shape_packs, admin_user_ids, stage_config, leaderboard, user_cache, stages, data_dir, .. } = context;
+    // botへのコマンドかはhandle_messageで確認済み
+    let mention = get_mention_regex(&bot_user_id);
+    let text = strip_mention(&mention, &message.text);
+    // スレッド内でメンションされた場合はスレッドに返信する
+    let thread_ts = message.thread_ts.clone();
+
+    // ヘルプの表示 (ステージの状態には触れない)
+    if text == "help" {
+        slack_client.post_message(message.channel_id, thread_ts.clone(),
+            "【遊び方】\n".to_string() +
+            "みんなでオブジェクトを順番に積み重ねて、タワーの高さを競うゲームです。\n" +
+            "オブジェクトが1つでも地面から落ちるとゲームオーバーになります。\n" +
+            "10ターンごとに地震が発生するので注意してください。\n\n" +
+            "【コマンド】\n" +
+            "`@slack_tower_battle <左右の位置> <回転角度>`\n" +
+            "・左右の位置: -1〜1 (-1が左端、1が右端)\n" +
+            "・回転角度: -180〜180 (時計回りが正の回転)\n" +
+            "・`#候補の番号` (#1〜#3) を付けると右上の候補からオブジェクトを選べます (省略時は1番目)\n" +
+            "・3つ目の数値で落とす高さ (0〜1) を指定すると落とす位置を上下できます (0がタワーの真上)\n" +
+            "・`spin=回転速度` (-5〜5 rad/s) を付けると回転させながら落とせます\n" +
+            "・`flip` を付けるとオブジェクトを左右反転して落とせます\n\n" +
+            "【コマンド例】\n" +
+            ":point_right: `@slack_tower_battle 0 0` (中央にそのまま落とす)\n" +
+            ":point_right: `@slack_tower_battle -0.25 45` (少し左に45度回転させて落とす)\n" +
+            ":point_right: `@slack_tower_battle #2 0 90` (右上の2番目の候補を90度回転させて落とす)\n" +
+            ":point_right: `@slack_tower_battle 0.5 0 0` (右寄りにタワーの真上からそっと落とす)\n" +
+            ":point_right: `@slack_tower_battle 0.3 15 spin=2` (時計回りに回転させながら落とす)\n" +
+            ":point_right: `@slack_tower_battle 0.2 30 flip` (左右反転して落とす)\n\n" +
+            "【その他のコマンド】\n" +
+            "・`@slack_tower_battle start`: ゲームを開始 (進行中の場合は現在のタワーを表示)\n" +
+            "・`@slack_tower_battle start teams`: 赤チームと青チームに分かれたチーム戦を開始\n" +
+            "・`@slack_tower_battle start 20`: 20手で終了する手数制限モードを開始\n" +
+            "・`@slack_tower_battle start hard`: 滑りやすく弾みやすいハードモードを開始\n" +
+            "・`@slack_tower_battle start elimination`: タワーを崩した人が脱落する脱落モードを開始\n" +
+            "・`@slack_tower_battle start goal 3.5`: 3.5 mに到達した人が勝ちの目標モードを開始\n" +
+            "・`@slack_tower_battle start animals`: 指定したピースセット (resources内のSVGファイル名) で開始\n" +
+            "・`@slack_tower_battle daily`: 全チャンネル共通のピース順で遊ぶデイリーチャレンジを開始\n" +
+            "・`@slack_tower_battle join red/blue`: チーム戦のチームを選択\n" +
+            "・`@slack_tower_battle help`: この説明を表示\n" +
+            "・`@slack_tower_battle status`: 現在のタワーを表示\n" +
+            "・`@slack_tower_battle record`: このチャンネルの最高記録を表示\n" +
+            "・`@slack_tower_battle leaderboard`: 全チャンネルのリーダーボードを表示\n" +
+            "・`@slack_tower_battle join`: ターン順に参加 (参加者がいる場合は順番にプレイ)\n" +
+            "・`@slack_tower_battle leave`: ターン順から抜ける\n" +
+            "・`@slack_tower_battle settings solo on/off`: 同じユーザーの連続プレイの許可/禁止\n" +
+            "・`@slack_tower_battle settings wind on/off`: ターンごとに風を吹かせる\n" +
+            "・`@slack_tower_battle settings walls on/off`: 地面の両端に壁を置く\n" +
+            "・`@slack_tower_battle settings gif on/off`: 結果の画像を物理演算の様子のアニメーションGIFにする\n" +
+            "・`@slack_tower_battle settings guide on/off`: 結果の前に落とす位置のガイドの画像を投稿する\n" +
+            "・`@slack_tower_battle settings theme default/dark/sakura`: 画像のテーマを変更する\n" +
+            "・`@slack_tower_battle settings thread on/off`: ゲームをスレッドで進行する\n" +
+            "・`@slack_tower_battle addshape`: 添付したSVGファイルの形状を現在のステージに追加 (100 KB、頂点200個まで)\n" +
+            "・`@slack_tower_battle undo`: 自分が行った直前のターンを取り消す\n" +
+            "・`@slack_tower_battle reset`: ステージをリセット (60秒以内に2回送信すると実行)"
+        ).await?;
+        return Ok(());
+    }
+
+    // ピースセットの再読み込み (管理者のみ、新しく開始するステージから反映)
+    if text == "reloadshapes" {
+        if !admin_user_ids.contains(&message.user_id) {
+            slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                format!("<@{}> このコマンドは管理者のみ実行できます。", message.user_id)
+            ).await?;
+            return Ok(());
+        }
+        let reply = match reload_shape_packs() {
+            Ok(new_shape_packs) => {
+                let mut names: Vec<String> = new_shape_packs.keys().cloned().collect();
+                names.sort();
+                let shape_count: usize = new_shape_packs.values().map(|shapes| shapes.len()).sum();
+                match shape_packs.write() {
+                    Ok(mut shape_packs) => {
+                        *shape_packs = Arc::new(new_shape_packs);
+                        tracing::info!("reload shapes: {:?}", names);
+                        format!("<@{}> ピースセットを読み込み直しました。(ピースセット: {}、形状: {}個)\n進行中のステージには次のゲームから反映されます。",
+                            message.user_id, names.join(", "), shape_count)
+                    },
+                    Err(_) => format!("<@{}> ピースセットを差し替えられませんでした。", message.user_id),
+                }
+            },
+            Err(reason) => {
+                slack_client.report_error(format!("ピースセットを読み込めませんでした (ユーザー: {})", message.user_id), reason.clone()).await;
+                format!("<@{}> ピースセットを読み込めませんでした。現在のピースセットを引き続き使います。\n{}", message.user_id, reason)
+            },
+        };
+        slack_client.post_message(message.channel_id, thread_ts.clone(), reply).await?;
+        return Ok(());
+    }
+
+    // リーダーボードの表示 (ステージのロックは取得しない)
+    if text == "leaderboard" {
+        let mut ranking: Vec<(String, stage::Real, DateTime<Local>)> = match leaderboard.lock() {
+            Ok(leaderboard) => leaderboard.entries.iter()
+                .map(|(user_id, entry)| (user_id.clone(), entry.best_delta, entry.time))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        // 同じ記録の場合は先に達成した人を上位にする
+        ranking.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.2.cmp(&b.2)));
+        ranking.truncate(10);
+
+        let mut lines = Vec::<String>::new();
+        for (rank, (user_id, best_delta, _)) in ranking.iter().enumerate() {
+            // 名前を取得できない場合はユーザーIDで表示する
+            let name = match get_cached_user(&slack_client, &user_cache, user_id).await {
+                Ok(user) => user.name.unwrap_or(user_id.clone()),
+                Err(err) => {
+                    tracing::warn!("failed to get the name of {}: {}", user_id, err);
+                    user_id.clone()
+                },
+            };
+            lines.push(format!("{}. {}: +{} m", rank + 1, name, best_delta));
+        }
+        let leaderboard_message = if lines.is_empty() {
+            "リーダーボードにはまだ記録がありません。".to_string()
+        } else {
+            ":crown: リーダーボード (1ターンで伸ばした高さ)\n".to_string() + &lines.join("\n")
+        };
+        slack_client.post_message(message.channel_id, thread_ts.clone(), leaderboard_message).await?;
+        return Ok(());
+    }
+
+    // 現在のピースセット (処理中に再読み込みされても影響を受けないように複製しておく)
+    let shape_packs = match shape_packs.read() {
+        Ok(shape_packs) => Arc::clone(&shape_packs),
+        Err(_) => { return Err("failed to read shape packs".into()); },
+    };
+
+    // スレッドモードのゲームを開始する時に、ゲームのスレッドのキーを登録するために使う
+    let channel_stage_handle = Arc::clone(&channel_stage);
+    // 物理演算の結果を返す前に他の人のターンが重なるのを防ぐ (計算中の場合は順番待ちにする)
+    let (mut channel_stage, message) = match wait_for_turn(&slack_client, &channel_stage, &turn_queue, message).await? {
+        Some(turn) => turn,
+        None => { return Ok(()); },
+    };
+    // コマンドによってはステージや設定を変えるので、次の定期的な保存で保存し直す
+    channel_stage.changed = true;
+    // 前のコマンドで変わった状態を、連続プレイを断るための写しに反映する
+    sync_blocked_user_id(&turn_queue, &channel_stage);
+
+    // 結果が出るまで時間がかかるので、コマンドを受け付けたことをリアクションで知らせる
+    let (reaction_channel_id, reaction_ts) = (message.channel_id.clone(), message.ts.clone());
+    update_reaction(&slack_client, &reaction_channel_id, &reaction_ts, "hourglass_flowing_sand", true).await;
+    let slack_client_for_reaction = Arc::clone(&slack_client);
+    let stages_for_removal = Arc::clone(&stages);
+    let result: AppResult = async move {
+        // 順番待ちの間にコマンドが置き換えられている場合があるので読み直す
+        let text = strip_mention(&mention, &message.text);
+
+        // 描画するテーマはチャンネルの設定に合わせる (ファイルから復元したステージにも反映する)
+        let theme = channel_stage.settings.theme;
+        if let Some(stage) = &mut channel_stage.stage { stage.set_render_options(theme.get_render_options()); }
+
+        // スレッドモードでゲーム中の場合は、ゲームを開始したメッセージのスレッドに返信する
+        // スレッドごとのステージの場合は常にそのスレッドに返信する
+        let thread_ts = match (&channel_stage.stage, &channel_stage.thread_ts) {
+            (Some(_), Some(root_ts)) => Some(root_ts.clone()),
+            _ => message.thread_ts.clone().or(channel_stage.scope_thread_ts.clone()),
+        };
+
+        // ボタンが押された場合
+        // 回転角度と左右の位置のボタンは次のターンの入力を変えてボタンのメッセージを書き換え、落とすボタンでターンを進める
+        let text = if message.event_type == "block_action" {
+            let max_translation_x = channel_stage.stage.as_ref().map_or(1.0, |stage| stage.get_config().max_translation_x as f64);
+            let (translation_x, rotation) = channel_stage.pending_input;
+            let pending_input = match text.split_once(':') {
+                Some(("rotation", value)) => value.parse::<f64>().ok().map(|rotation| (translation_x, rotation)),
+                Some(("nudge", value)) => value.parse::<f64>().ok().map(|delta| {
+                    (((translation_x + delta) * 10.0).round() / 10.0).clamp(-max_translation_x, max_translation_x)
+                }).map(|translation_x| (translation_x, rotation)),
+                _ => None,
+            };
+            if let Some(pending_input) = pending_input {
+                channel_stage.pending_input = pending_input;
+                if let Some(ts) = &message.action_message_ts {
+                    let (button_text, blocks) = create_turn_buttons(pending_input);
+                    slack_client.update_blocks(message.channel_id.clone(), ts.clone(), button_text, &blocks).await?;
+                }
+                return Ok(());
+            }
+            if text != "drop" { return Ok(()); }
+            format!("{} {}", translation_x, rotation)
+        } else {
+            text
+        };
+
+        // 添付されたSVGファイルから形状を追加 (現在のステージのみ)
+        if text == "addshape" {
+            let stage = match &mut channel_stage.stage {
+                Some(stage) => stage,
+                None => {
+                    slack_client.post_message(message.channel_id, thread_ts.clone(),
+                        "現在ステージはありません。\n`@slack_tower_battle start` でゲームを開始してから追加してください。".to_string()
+                    ).await?;
+                    return Ok(());
+                },
+            };
+            let file = match message.files.first() {
+                Some(file) if file.size <= MAX_SHAPE_FILE_SIZE => file,
+                Some(_) => {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> ファイルが大きすぎます (最大{} KB)。", message.user_id, MAX_SHAPE_FILE_SIZE / 1024)
+                    ).await?;
+                    return Ok(());
+                },
+                None => {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> SVGファイルを添付して `@slack_tower_battle addshape` を送信してください。", message.user_id)
+                    ).await?;
+                    return Ok(());
+                },
+            };
+            let svg_data = slack_client.download_data(&file.url, true).await?;
+            let shapes = if svg_data.len() as u64 > MAX_SHAPE_FILE_SIZE {
+                Err(format!("ファイルが大きすぎます (最大{} KB)。", MAX_SHAPE_FILE_SIZE / 1024))
+            } else {
+                match canvas::Canvas::load_shaper_from_svg_data(&svg_data, SHAPE_SCALE) {
+                    Ok(shapes) => validate_shapes(&shapes).map(|_| shapes),
+                    Err(_) => Err(format!("{} をSVGファイルとして読み込めませんでした。", file.name)),
+                }
+            };
+            match shapes {
+                Ok(shapes) => {
+                    let data = stage::render_shapes_preview(&shapes)?;
+                    let shape_count = shapes.len();
+                    stage.add_shapes(shapes);
+                    slack_client.post_image(message.channel_id, thread_ts.clone(),
+                        format!("<@{}> {}個の形状を追加しました (左右反転したものを含む)。", message.user_id, shape_count),
+                    &data, "shapes.png".to_string()).await?;
+                },
+                Err(reason) => {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> 形状を追加できませんでした。\n{}", message.user_id, reason)
+                    ).await?;
+                },
+            };
+            return Ok(());
+        }
+
+        // 現在のステージの表示 (ターンは消費しない)
+        if text == "status" {
+            if let Some(stage) = &channel_stage.stage {
+                let data = stage.render_frame()?;
+                let mut status_message = format!("現在の高さ: {} m\nオブジェクトの数: {} (物理演算中: {})\n難易度: {}\n地面: {}\nシード: {}\n最後のターン: {}",
+                    stage.get_height(),
+                    stage.get_object_count(),
+                    stage.get_active_object_count(),
+                    stage.get_difficulty().get_name(),
+                    stage.get_ground().get_name(),
+                    stage.get_seed(),
+                    channel_stage.update_time.format("%Y/%m/%d %H:%M:%S"));
+                if let Some(turns_remaining) = stage.get_turns_remaining() {
+                    status_message += &format!("\n残り手数: {}", turns_remaining);
+                }
+                if let Some(target_height) = stage.get_target_height() {
+                    status_message += &format!("\n目標の高さ: {} m", target_height);
+                }
+                if stage.is_elimination_mode() {
+                    let players: Vec<String> = stage.get_players().iter().map(|user_id| format!("<@{}>", user_id)).collect();
+                    let eliminated_players: Vec<String> = stage.get_eliminated_players().iter().map(|user_id| format!("<@{}>", user_id)).collect();
+                    status_message += &format!("\n生存: {}\n脱落: {}", players.join(" "), eliminated_players.join(" "));
+                }
+                slack_client.post_image(message.channel_id, thread_ts.clone(), status_message,
+                &data, "result.png".to_string()).await?;
+            }
+            else {
+                slack_client.post_message(message.channel_id, thread_ts.clone(),
+                    "現在ステージはありません。\n`@slack_tower_battle start` でゲームを開始できます。".to_string()
+                ).await?;
+            }
+            return Ok(());
+        }
+
+        // チャンネルの最高記録の表示 (スレッドごとのステージの記録もチャンネル全体のステージにある)
+        if text == "record" {
+            let channel_level_stage = match channel_stage.scope_thread_ts {
+                Some(_) => Some(get_channel_level_stage(&stages, &channel_stage.channel_id, channel_stage.practice).await.lock_owned().await),
+                None => None,
+            };
+            let records: &ChannelStage = match &channel_level_stage {
+                Some(channel_level_stage) => channel_level_stage,
+                None => &channel_stage,
+            };
+            let record_message = match &records.record {
+                Some(record) => format!(":trophy: このチャンネルの最高記録: {} m\n達成者: <@{}>\n達成日時: {}",
+                    record.height, record.user_id, record.time.format("%Y/%m/%d %H:%M:%S")),
+                None => "このチャンネルの記録はまだありません。".to_string(),
+            };
+            let record_message = match &records.daily_record {
+                Some(record) if record.time.num_days_from_ce() == Local::now().num_days_from_ce() => format!(
+                    "{}\n\n:calendar: 今日のデイリーチャレンジの記録: {} m\n最後のプレイヤー: <@{}>",
+                    record_message, record.height, record.user_id),
+                _ => record_message,
+            };
+            slack_client.post_message(message.channel_id, thread_ts.clone(), record_message).await?;
+            return Ok(());
+        }
+
+        // 直前のターンの取り消し (直前のターンを行ったユーザーのみ1回まで)
+        if text == "undo" {
+            if let Some(stage) = &mut channel_stage.stage {
+                if stage.get_last_user_id() != Some(message.user_id.clone()) {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> 取り消しは直前のターンを行ったユーザーのみ可能です。", message.user_id)
+                    ).await?;
+                    return Ok(());
+                }
+                if !stage.restore_snapshot() {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> 取り消せるターンがありません。", message.user_id)
+                    ).await?;
+                    return Ok(());
+                }
+                let data = stage.render_frame()?;
+                slack_client.post_image(message.channel_id, thread_ts.clone(),
+                    format!("<@{}> 直前のターンを取り消しました。\n現在の高さ: {} m", message.user_id, stage.get_height()),
+                &data, "result.png".to_string()).await?;
+                channel_stage.update_time = Local::now();
+            }
+            else {
+                slack_client.post_message(message.channel_id, thread_ts.clone(),
+                    "現在ステージはありません。".to_string()
+                ).await?;
+            }
+            return Ok(());
+        }
+
+        // ステージのリセット (60秒以内に2回送信された場合のみ実行)
+        if text == "reset" {
+            if channel_stage.stage.is_none() {
+                slack_client.post_message(message.channel_id, thread_ts.clone(),
+                    "リセットするステージがありません。".to_string()
+                ).await?;
+                return Ok(());
+            }
+            let confirmed = match channel_stage.reset_request_time {
+                Some(request_time) => (Local::now() - request_time).num_seconds() < 60,
+                None => false,
+            };
+            if !confirmed {
+                channel_stage.reset_request_time = Some(Local::now());
+                slack_client.post_message(message.channel_id, thread_ts.clone(),
+                    "本当にリセットしますか？\n".to_string() +
+                    "60秒以内にもう一度 `@slack_tower_battle reset` を送信するとリセットされます。"
+                ).await?;
+                return Ok(());
+            }
+            if let Some(stage) = &channel_stage.stage {
+                let data = stage.render_frame()?;
+                slack_client.post_image(message.channel_id, thread_ts.clone(),
+                    format!("<@{}> ステージをリセットしました。\n最終的な高さ: {} m", message.user_id, stage.get_height()),
+                &data, "result.png".to_string()).await?;
+            }
+            channel_stage.stage = None;
+            channel_stage.reset_request_time = None;
+            channel_stage.update_time = Local::now();
+            return Ok(());
+        }
+
+        // チャンネルの設定の変更
+        let args: Vec<&str> = text.split_whitespace().collect();
+        if args.first() == Some(&"settings") {
+            let value = match args.get(2) {
+                Some(&"on") => Some(true),
+                Some(&"off") => Some(false),
+                _ => None,
+            };
+            // 無効な設定の場合は送信した人にだけ表示する
+            let reply: Result<String, String> = match (args.get(1), value) {
+                (Some(&"solo"), Some(value)) => {
+                    channel_stage.settings.solo = value;
+                    Ok(if value { "同じユーザーの連続プレイを許可しました。".to_string() }
+                    else     { "同じユーザーの連続プレイを禁止しました。".to_string() })
+                },
+                (Some(&"wind"), Some(value)) => {
+                    channel_stage.settings.wind = value;
+                    Ok(if value { "次のターンから風が吹くようになります。".to_string() }
+                    else     { "次のターンから風が止みます。".to_string() })
+                },
+                (Some(&"walls"), Some(value)) => {
+                    channel_stage.settings.walls = value;
+                    if let Some(stage) = &mut channel_stage.stage { stage.set_walls_enabled(value); }
+                    Ok(if value { "地面の両端に壁を置きました。".to_string() }
+                    else     { "地面の両端の壁を取り除きました。".to_string() })
+                },
+                (Some(&"gif"), Some(value)) => {
+                    channel_stage.settings.gif = value;
+                    Ok(if value { "次のターンから結果をアニメーションGIFで表示します。".to_string() }
+                    else     { "次のターンから結果を画像で表示します。".to_string() })
+                },
+                (Some(&"guide"), Some(value)) => {
+                    channel_stage.settings.guide = value;
+                    Ok(if value { "次のターンから落とす位置のガイドを投稿します。".to_string() }
+                    else     { "次のターンから落とす位置のガイドを投稿しません。".to_string() })
+                },
+                (Some(&"thread"), Some(value)) => {
+                    channel_stage.settings.thread = value;
+                    Ok(if value { "次のゲームからスレッドで進行します。".to_string() }
+                    else {
+                        channel_stage.thread_ts = None;
+                        "スレッドでの進行をやめました。".to_string()
+                    })
+                },
+                (Some(&"theme"), _) => match args.get(2).and_then(|name| render::Theme::from_name(name)) {
+                    Some(theme) => {
+                        channel_stage.settings.theme = theme;
+                        if let Some(stage) = &mut channel_stage.stage { stage.set_render_options(theme.get_render_options()); }
+                        Ok(format!("画像のテーマを {} にしました。", theme.get_name()))
+                    },
+                    None => {
+                        let names: Vec<&str> = render::Theme::ALL.iter().map(|theme| theme.get_name()).collect();
+                        Err("無効なテーマです。\n選べるテーマ: ".to_string() + &names.join(", "))
+                    },
+                },
+                _ => Err("無効な設定です。\n設定例 :point_right: `@slack_tower_battle settings solo on`".to_string()),
+            };
+            match reply {
+                Ok(reply) => { slack_client.post_message(message.channel_id, thread_ts.clone(), reply).await?; },
+                Err(reply) => { slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(), reply).await?; },
+            };
+            return Ok(());
+        }
+
+        // チーム戦のチームを選択
+        if args.len() == 2 && args[0] == "join" {
+            let team = match args[1] {
+                "red" => Some(stage::Team::Red),
+                "blue" => Some(stage::Team::Blue),
+                _ => None,
+            };
+            let reply: Result<String, String> = match (&mut channel_stage.stage, team) {
+                (Some(stage), Some(team)) if stage.is_team_mode() => {
+                    let assigned_team = stage.assign_team(&message.user_id, Some(team)).unwrap_or(team);
+                    if assigned_team == team {
+                        Ok(format!("<@{}> {}チームに参加しました。", message.user_id, team.get_name()))
+                    } else {
+                        Err(format!("<@{}> 既に{}チームに所属しています。", message.user_id, assigned_team.get_name()))
+                    }
+                },
+                (Some(_), Some(_)) => Err("現在のゲームはチーム戦ではありません。\n`@slack_tower_battle start teams` でチーム戦を開始できます。".to_string()),
+                (None, _) => Err("現在ステージはありません。\n`@slack_tower_battle start` でゲームを開始できます。".to_string()),
+                (_, None) => Err("無効なチームです。\nコマンド例 :point_right: `@slack_tower_battle join red`".to_string()),
+            };
+            match reply {
+                Ok(reply) => { slack_client.post_message(message.channel_id, thread_ts.clone(), reply).await?; },
+                Err(reply) => { slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(), reply).await?; },
+            };
+            return Ok(());
+        }
+
+        // ターン順への参加と離脱
+        if text == "join" || text == "leave" {
+            if let Some(stage) = &mut channel_stage.stage {
+                let mut reply = if text == "join" {
+                    if stage.is_eliminated(&message.user_id) {
+                        format!("<@{}> 脱落したプレイヤーは再参加できません。", message.user_id)
+                    }
+                    else if stage.join(message.user_id.clone()) {
+                        format!("<@{}> ターン順に参加しました。(参加者: {}人)", message.user_id, stage.get_players().len())
+                    } else {
+                        format!("<@{}> 既にターン順に参加しています。", message.user_id)
+                    }
+                } else {
+                    if stage.leave(&message.user_id) {
+                        format!("<@{}> ターン順から抜けました。(参加者: {}人)", message.user_id, stage.get_players().len())
+                    } else {
+                        format!("<@{}> ターン順に参加していません。", message.user_id)
+                    }
+                };
+                match stage.get_current_player() {
+                    Some(current_player) => { reply += &format!("\n現在は <@{}> さんの番です。", current_player); },
+                    None => { reply += "\n参加者がいないため、誰でもプレイできます。"; },
+                };
+                slack_client.post_message(message.channel_id, thread_ts.clone(), reply).await?;
+            }
+            else {
+                slack_client.post_message(message.channel_id, thread_ts.clone(),
+                    "現在ステージはありません。\n`@slack_tower_battle start` でゲームを開始できます。".to_string()
+                ).await?;
+            }
+            return Ok(());
+        }
+
+        // ステージの開始 (既にステージがある場合は現在のタワーを再表示)
+        // デイリーチャレンジは日付から決めたseedでステージを生成する
+        if args.first() == Some(&"start") || text == "daily" {
+            if let Some(stage) = &channel_stage.stage {
+                let data = stage.render_frame()?;
+                slack_client.post_image(message.channel_id, thread_ts.clone(),
+                    format!("既にゲームが進行中です。\n現在の高さ: {} m", stage.get_height()),
+                &data, "result.png".to_string()).await?;
+            }
+            else {
+                // ゲームモードの解析 (例: `start teams 20`, `start hard`, `start goal 3.5`)
+                let mut team_mode = false;
+                let mut turn_limit = None;
+                let mut elimination_mode = false;
+                let mut target_height = None;
+                let mut difficulty = stage::Difficulty::Normal;
+                let mut ground = stage::GroundVariant::Flat;
+                let mut shape_pack_names: Vec<&String> = shape_packs.keys().collect();
+                shape_pack_names.sort();
+                let mut shape_pack = if shape_packs.contains_key(DEFAULT_SHAPE_PACK) { DEFAULT_SHAPE_PACK.to_string() } else { shape_pack_names[0].clone() };
+                let mut options = args[1..].iter();
+                while let Some(arg) = options.next() {
+                    match (*arg, arg.parse::<u32>()) {
+                        ("teams", _) => { team_mode = true; },
+                        ("hard", _) => { difficulty = stage::Difficulty::Hard; },
+                        ("tilt", _) => { ground = stage::GroundVariant::Tilted; },
+                        ("narrow", _) => { ground = stage::GroundVariant::Narrow; },
+                        ("bumpy", _) => { ground = stage::GroundVariant::Steps; },
+                        ("elimination", _) => { elimination_mode = true; },
+                        ("goal", _) => {
+                            match options.next().and_then(|value| value.parse::<stage::Real>().ok()) {
+                                Some(value) if value > 0.0 && value <= 20.0 => { target_height = Some(value); },
+                                _ => {
+                                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                                        "目標の高さは0より大きく20以下の数値 (m) で指定してください。\nコマンド例 :point_right: `@slack_tower_battle start goal 3.5`".to_string()
+                                    ).await?;
+                                    return Ok(());
+                                },
+                            };
+                        },
+                        (_, Ok(turns)) if turns > 0 => { turn_limit = Some(turns); },
+                        (name, _) if shape_packs.contains_key(name) => { shape_pack = name.to_string(); },
+                        _ => {
+                            let pack_list: Vec<String> = shape_pack_names.iter().map(|name| format!("`{}`", name)).collect();
+                            slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                                "無効なゲームモードです。\nコマンド例 :point_right: `@slack_tower_battle start teams` / `@slack_tower_battle start 20` / `@slack_tower_battle start hard` / `@slack_tower_battle start tilt` / `@slack_tower_battle start elimination` / `@slack_tower_battle start goal 3.5`".to_string() +
+                                &format!("\n利用可能なピースセット: {}", pack_list.join(", "))
+                            ).await?;
+                            return Ok(());
+                        },
+                    };
+                }
+
+                let daily = text == "daily";
+                let seed = if daily {
+                    Local::now().format("%Y%m%d").to_string().parse::<u64>().unwrap_or_default()
+                } else {
+                    rand::random::<u64>()
+                };
+                tracing::info!("start: channel {} seed {}{}", message.channel_id, seed, if daily { " (daily)" } else { "" });
+                // ピースセットごとの密度の倍率 (例: TOWER_DENSITY_ANIMALS)
+                let stage_config = stage::StageConfig {
+                    density_multiplier: env_or(&format!("TOWER_DENSITY_{}", shape_pack.to_uppercase()), stage_config.density_multiplier),
+                    ..stage_config
+                };
+                let mut stage = stage::Stage::new(shape_packs[&shape_pack].clone(), difficulty, ground, seed, stage_config);
+                if team_mode { stage.enable_teams(); }
+                if elimination_mode { stage.enable_elimination(); }
+                if let Some(target_height) = target_height { stage.set_target_height(target_height); }
+                if let Some(turns) = turn_limit { stage.set_turn_limit(turns); }
+                stage.set_walls_enabled(channel_stage.settings.walls);
+                stage.set_render_options(channel_stage.settings.theme.get_render_options());
+                let data = stage.next_turn(None, stage::TurnInput::default())?.image_png;
+                channel_stage.stage = Some(stage);
+                channel_stage.daily = daily;
+                channel_stage.update_time = Local::now();
+                let mut welcome_message =
+                    ":sparkles: slack tower battleへようこそ :sparkles:\n".to_string() +
+                    "みんなでオブジェクトを積み重ねて高みを目指しましょう:fire: :fire: :fire:\n" +
+                    &format!("難易度: {}\n地面: {}\nピースセット: {}\n壁: {}\n\n", difficulty.get_name(), ground.get_name(), shape_pack,
+                        if channel_stage.settings.walls { "あり (`settings walls off` で取り除けます)" } else { "なし (`settings walls on` で置けます)" }) +
+                    "【遊び方】\n" +
+                    "左右の位置(-1〜1) と回転角度(-180〜180、時計回りが正の回転) を送信してください。\n" +
+                    "コマンド例 :point_right: `@slack_tower_battle -0.25 45`";
+                if daily {
+                    welcome_message +=
+                        "\n\n【デイリーチャレンジ】\n今日のピースの順番は全チャンネル共通です。ゲーム終了時の高さは通常の記録とは別に記録されます。";
+                }
+                if team_mode {
+                    welcome_message = welcome_message +
+                        "\n\n【チーム戦】\n" +
+                        "最初のターンで赤チームと青チームに交互に振り分けられます。\n" +
+                        "`@slack_tower_battle join red` または `join blue` で先にチームを選ぶこともできます。\n" +
+                        "タワーを崩したチームの負けです。";
+                }
+                if difficulty == stage::Difficulty::Hard {
+                    welcome_message +=
+                        "\n\n【ハードモード】\nオブジェクトが氷のように滑りやすく、弾みやすくなっています。慎重に積み上げましょう。";
+                }
+                if elimination_mode {
+                    welcome_message = welcome_message +
+                        "\n\n【脱落モード】\n" +
+                        "`@slack_tower_battle join` で参加したプレイヤーだけがプレイできます。\n" +
+                        "ピースを落としたプレイヤーは脱落し、残りのタワーでゲームが続きます。最後まで残った1人の優勝です。";
+                }
+                if let Some(target_height) = target_height {
+                    welcome_message += &format!("\n\n【目標モード】\nタワーを {} m (画像の破線) まで積み上げた人の勝利です。", target_height);
+                }
+                if channel_stage.scope_thread_ts.is_some() {
+                    welcome_message +=
+                        "\n\n【スレッドのゲーム】\nこのゲームはこのスレッドの中だけで進行します。チャンネルや他のスレッドのゲームとは別のステージです。";
+                }
+                if channel_stage.practice {
+                    welcome_message +=
+                        "\n\n【練習モード】\nこのDMでのプレイは練習です。結果はチャンネルの記録やリーダーボードに残りません。メンションなしで `-0.25 45` のように送信できます。";
+                }
+                if let Some(turns) = turn_limit {
+                    welcome_message += &format!("\n\n【手数制限】\n{}手でゲームが終了します。最後まで崩さずに積み上げましょう。", turns);
+                }
+                // スレッドモードではようこそメッセージをスレッドの親にして、画像以降はスレッドに投稿する
+                // (スレッドごとのステージは既にスレッドの中なので、新しいスレッドは作らない)
+                if channel_stage.settings.thread && channel_stage.scope_thread_ts.is_none() {
+                    let root_ts = slack_client.post_message(message.channel_id.clone(), None,
+                        welcome_message + "\n\n:thread: このゲームはこのメッセージのスレッドで進行します。").await?;
+                    channel_stage.thread_ts = Some(root_ts.clone());
+                    // スレッド内のコマンドもこのステージで処理されるように、スレッドのキーを登録する
+                    stages.write().await.insert((message.channel_id.clone(), Some(root_ts.clone())), Arc::clone(&channel_stage_handle));
+                    slack_client.post_image(message.channel_id, Some(root_ts),
+                        format!("<@{}> さんがゲームを開始しました。", message.user_id),
+                    &data, "result.png".to_string()).await?;
+                }
+                else {
+                    channel_stage.thread_ts = None;
+                    slack_client.post_image(message.channel_id, thread_ts.clone(), welcome_message,
+                    &data, "result.png".to_string()).await?;
+                }
+            }
+            return Ok(());
+        }
+
+        // 連続プレイは普段はhandle_messageで断っているが、写しが古い間に受け付けたコマンドもここで断る
+        let blocked_user_id = get_blocked_user_id(&channel_stage);
+        let solo = channel_stage.settings.solo || channel_stage.practice;
+        let wind_enabled = channel_stage.settings.wind;
+        let gif_enabled = channel_stage.settings.gif;
+        let guide_enabled = channel_stage.settings.guide;
+        if let Some(stage) = &mut channel_stage.stage {
+            // 脱落モードでは参加者以外のプレイを拒否
+            if stage.is_elimination_mode() && !stage.get_players().contains(&message.user_id) {
+                let reply = if stage.is_eliminated(&message.user_id) {
+                    format!("<@{}> 脱落したためプレイできません。", message.user_id)
+                } else {
+                    format!("<@{}> 脱落モードでは `@slack_tower_battle join` で参加したプレイヤーのみプレイできます。", message.user_id)
+                };
+                slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(), reply).await?;
+                return Ok(());
+            }
+
+            // ターン順が決まっている場合は自分の番でなければ拒否
+            if let Some(current_player) = stage.get_current_player() {
+                if current_player != message.user_id {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> 現在は <@{}> さんの番です。", message.user_id, current_player)
+                    ).await?;
+                    return Ok(());
+                }
+            }
+            // ターン順が決まっていない場合は同じユーザーの連続プレイを拒否
+            else if blocked_user_id.as_ref() == Some(&message.user_id) {
+                reject_consecutive_play(&slack_client, &message, thread_ts.clone()).await?;
+                return Ok(());
+            }
+
+            // アイコン画像の登録 (全チャンネル共通のキャッシュから縮小済みの画像を受け取る)
+            // 一時的に取得できない場合はアイコンなしでターンを進め、次のターンで取得し直す
+            if !stage.user_icons.contains_key(&message.user_id) {
+                match get_cached_user(&slack_client, &user_cache, &message.user_id).await {
+                    Ok(user) => {
+                        let user_icon = match user.icon_png {
+                            Some(icon_png) => stage::UserIcon::Image(icon_png),
+                            // アイコン画像がない場合も登録して、毎ターン問い合わせないようにする
+                            None => stage::UserIcon::Identicon,
+                        };
+                        stage.user_icons.insert(message.user_id.clone(), user_icon);
+                        if let Some(name) = user.name {
+                            stage.user_names.insert(message.user_id.clone(), name);
+                        }
+                    },
+                    Err(err) => {
+                        tracing::warn!("failed to get the icon of {}: {}", message.user_id, err);
+                    },
+                };
+            }
+
+            // メッセージの解析
+            let command = match command::parse_turn_command(&text) {
+                Ok(command) => command,
+                Err(error) => {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("無効な入力です。{}\n`@slack_tower_battle help` で遊び方を確認できます。", error.get_message())
+                    ).await?;
+                    return Ok(());
+                },
+            };
+            // 左右の位置と回転角度が範囲を少しだけ超えた場合は範囲内に収め、大きく超えた場合はターンを消費せずに断る
+            let config = stage.get_config();
+            let (command, range_notes) = match command::fit_to_stage(command, config.max_translation_x as f64, config.max_rotation as f64) {
+                Ok(fitted) => fitted,
+                Err(error) => {
+                    slack_client.post_ephemeral(message.channel_id, thread_ts.clone(), message.user_id.clone(),
+                        format!("<@{}> {}\nターンは消費していません。もう一度送信してください。", message.user_id, error.get_message())
+                    ).await?;
+                    return Ok(());
+                },
+            };
+
+            // 候補の番号が不正な場合は1番目の候補を使い、落とす高さと回転速度は範囲内に収める
+            let (input, notes) = command::to_turn_input(command, stage);
+            let mut input_notes = range_notes;
+            input_notes.extend(notes);
+
+            // チーム戦の場合は初めてのターンでチームに割り当てる
+            let new_team = if stage.is_team_mode() && stage.get_team(&message.user_id).is_none() {
+                stage.assign_team(&message.user_id, None)
+            } else {
+                None
+            };
+
+            // 物理演算
+            let height_before = stage.get_height();
+            stage.set_wind_enabled(wind_enabled);
+            stage.set_animation_enabled(gif_enabled);
+            stage.set_drop_guide_enabled(guide_enabled);
+            // 計算中に同じユーザーが送った次のコマンドは、このターンの結果を待たずに断る
+            if !solo && stage.get_current_player().is_none() {
+                if let Ok(mut turn_queue) = turn_queue.lock() { turn_queue.last_user_id = Some(message.user_id.clone()); }
+            }
+            // 計算に時間がかかっても止まっていないことが分かるように、計算中のメッセージを投稿しておき後で結果に書き換える
+            let progress_ts = match slack_client.post_message(channel_stage.channel_id.clone(), thread_ts.clone(), format!("<@{}> :hourglass_flowing_sand: 計算中…", message.user_id)).await {
+                Ok(progress_ts) => Some(progress_ts),
+                Err(err) => {
+                    tracing::warn!("failed to post progress message: {}", err);
+                    None
+                },
+            };
+            // 物理演算と描画は時間がかかるので、spawn_blockingで実行して受信や他のチャンネルのターンを止めない
+            // ステージはロックしたChannelStageごと渡して計算後に受け取る (終了時に計算中のターンを中断しても、ロックと一緒にステージは残る)
+            let user_id = Some(message.user_id.clone());
+            let (returned_channel_stage, turn_report) = tokio::task::spawn_blocking(move || {
+                let mut channel_stage = channel_stage;
+                let turn_report = match channel_stage.stage.as_mut() {
+                    Some(stage) => std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| stage.next_turn(user_id, input))).ok(),
+                    None => None,
+                };
+                return (channel_stage, turn_report);
+            }).await?;
+            channel_stage = returned_channel_stage;
+            let stage = match channel_stage.stage.as_mut() {
+                Some(stage) => stage,
+                None => { return Ok(()); },
+            };
+            let turn_report = match turn_report {
+                Some(turn_report) => turn_report,
+                None => {
+                    // 計算中にpanicした場合は、ステージをターンの開始前の状態に戻す
+                    let restored = stage.restore_snapshot();
+                    tracing::error!("physics panicked in channel {} (stage restored: {})", message.channel_id, restored);
+                    return Err(AppError::PhysicsPanic);
+                },
+            };
+            if let Ok(report) = turn_report
+            {
+                let turn_result = report.result;
+                let height = report.height;
+                tracing::info!("turn: channel {}, result {:?}, height {} ({:+}), steps {}, elapsed {} ms, render {} ms, next shape {:?}",
+                    message.channel_id, turn_result, height, report.height_delta, report.steps_executed, report.elapsed_ms, report.render_ms, report.pending_shape_index);
+                let metrics = metrics::get();
+                metrics.turns.with_label_values(&[&format!("{:?}", turn_result)]).inc();
+                metrics.physics_duration.observe(report.elapsed_ms as f64 / 1000.0);
+                metrics.render_duration.observe(report.render_ms as f64 / 1000.0);
+                // 最初に落下したオブジェクトの持ち主とチーム
+                let (fallen_user_id, fallen_team) = match report.fallen_object_indices.first().and_then(|index| stage.get_objects().get(*index)) {
+                    Some(object) => (object.user_id.clone(), object.team),
+                    None => (None, None),
+                };
+                // 脱落モードの場合は落下したピースの持ち主を脱落させ、残りが2人以上ならゲームを続ける
+                let elimination_mode = stage.is_elimination_mode();
+                let eliminated_user_ids = if turn_result == stage::TurnResult::Failure { stage.eliminate_fallen_objects() } else { Vec::new() };
+                // 目標モードの場合は目標の高さに到達したらゲーム終了
+                let goal_reached = turn_result == stage::TurnResult::Success && stage.get_target_height().is_some_and(|target_height| height >= target_height);
+                let game_over = match turn_result {
+                    stage::TurnResult::Success => goal_reached,
+                    stage::TurnResult::Unsettled | stage::TurnResult::Aborted => false,
+                    stage::TurnResult::Failure if elimination_mode => stage.get_players().len() <= 1,
+                    _ => true,
+                };
+                let next_player = stage.get_current_player();
+                let earthquake = stage.is_earthquake();
+                let user_team = stage.get_team(&message.user_id);
+                let team_piece_counts = if stage.is_team_mode() { Some(stage.get_team_piece_counts()) } else { None };
+                let turns_remaining = stage.get_turns_remaining();
+                let user_piece_counts = stage.get_user_piece_counts();
+                let mut result_message = match turn_result {
+                    stage::TurnResult::Success => {
+                        format!("{} m\nオーバーハング: {:.2} m / 安定度: {:.0}%", height, report.overhang, report.stability * 100.0)
+                    },
+                    stage::TurnResult::Failure if elimination_mode => {
+                        let mut message = ":boom: ピースが落ちました".to_string();
+                        for eliminated_user_id in eliminated_user_ids.iter() {
+                            message += &format!("\n:skull: <@{}> さんが脱落しました", eliminated_user_id);
+                        }
+                        if game_over {
+                            match stage.get_players().first() {
+                                Some(winner) => { message += &format!("\n:crown: <@{}> さんの優勝です！", winner); },
+                                None => { message += "\n全員が脱落しました"; },
+                            };
+                        }
+                        message
+                    },
+                    stage::TurnResult::Failure => {
+                        match fallen_user_id {
+                            Some(fallen_user_id) => format!("Game Over :angry:\n<@{}> のピースが落ちました (最終的な高さ: {} m)", fallen_user_id, height_before),
+                            None => format!("Game Over :angry:\n(最終的な高さ: {} m)", height_before),
+                        }
+                    },
+                    stage::TurnResult::Unsettled => {
+                        ":hourglass: タワーがまだ揺れています…\n次のコマンドでは、オブジェクトを落とす前に続きから物理演算します。".to_string()
+                    },
+                    stage::TurnResult::Aborted => {
+                        ":hourglass: 計算に時間がかかりすぎたため、途中の状態を表示しています。\n次のコマンドでは、オブジェクトを落とす前に続きから物理演算します。".to_string()
+                    },
+                    stage::TurnResult::Timeout => { "物理演算がタイムアウトしました:confounded:".to_string() },
+                    stage::TurnResult::Finished => { format!(":checkered_flag: ゲーム終了！\n最終的な高さ: {} m", height) },
+                };
+                if let (stage::TurnResult::Success, Some(turns_remaining)) = (&turn_result, turns_remaining) {
+                    result_message += &format!(" (残り {} 手)", turns_remaining);
+                }
+                if goal_reached {
+                    result_message += &format!("\n:tada: 目標の高さ {} m に到達しました！ <@{}> さんの勝利です！",
+                        stage.get_target_height().unwrap_or(height), message.user_id);
+                }
+                if turn_result == stage::TurnResult::Finished {
+                    result_message += "\n【積み上げた数】";
+                    for (user_id, count) in user_piece_counts.iter() {
+                        result_message += &format!("\n<@{}>: {}個", user_id, count);
+                    }
+                }
+                if report.resumed {
+                    result_message += "\n前のターンのタワーが揺れていたため、今回の入力は使わずに続きから物理演算しました。";
+                    if turn_result == stage::TurnResult::Success {
+                        result_message += "\nもう一度コマンドを送ってオブジェクトを落としてください。";
+                    }
+                }
+                else {
+                    for input_note in input_notes.iter() {
+                        result_message += &format!("\n{}", input_note);
+                    }
+                    if let Some(mass) = report.mass {
+                        result_message += &format!("\nこのピース: {:.1} kg", mass);
+                    }
+                    if let Some(spawn_lift) = report.spawn_lift {
+                        result_message += &format!("\nタワーと重なっていたため、{:.2} m 上から落としました。", spawn_lift);
+                    }
+                }
+                if earthquake {
+                    if turn_result == stage::TurnResult::Failure {
+                        result_message += "\n:warning: 地震でタワーが崩れました… (あなたのせいではありません)";
+                    }
+                    else {
+                        result_message += "\n:warning: 地震が発生しました！";
+                    }
+                }
+                if let Some(wind) = report.wind {
+                    result_message += &format!("\n{}", wind_to_string(wind));
+                }
+                if let Some(team) = new_team {
+                    result_message += &format!("\n{}チームに参加しました。", team.get_name());
+                }
+                if report.golden {
+                    result_message += &format!("\n:star2: ゴールデンピース！ (高さの伸びが{}倍でリーダーボードに記録されます)", stage::GOLDEN_BONUS);
+                }
+
+                // チーム戦の結果 (落下したピースのチームの負け)
+                if let Some(team) = fallen_team.or(user_team) {
+                    if turn_result == stage::TurnResult::Failure && game_over {
+                        result_message += &format!(
+                            "\n{}チームのピースでタワーが崩れました。\n:tada: {}チームの勝利！",
+                            team.get_name(), team.get_opponent().get_name());
+                    }
+                    else if let Some((red_count, blue_count)) = team_piece_counts {
+                        result_message += &format!("\n{}チーム: {}個 / {}チーム: {}個",
+                            stage::Team::Red.get_name(), red_count, stage::Team::Blue.get_name(), blue_count);
+                    }
+                }
+
+                // デイリーチャレンジはゲーム終了時の高さを別に記録する
+                // 練習モードの結果は記録しない
+                let daily = channel_stage.daily;
+                let practice = channel_stage.practice;
+                // スレッドごとのステージは24時間で削除されるので、記録はチャンネル全体のステージに残す
+                let recordable = if daily { game_over } else { turn_result == stage::TurnResult::Success || turn_result == stage::TurnResult::Finished };
+                let mut channel_level_stage = match channel_stage.scope_thread_ts {
+                    Some(_) if recordable && !practice => Some(get_channel_level_stage(&stages, &channel_stage.channel_id, practice).await.lock_owned().await),
+                    _ => None,
+                };
+                let records: &mut ChannelStage = match &mut channel_level_stage {
+                    Some(channel_level_stage) => {
+                        channel_level_stage.changed = true;
+                        channel_level_stage
+                    },
+                    None => &mut channel_stage,
+                };
+                if daily && !practice && game_over {
+                    let final_height = if turn_result == stage::TurnResult::Success || turn_result == stage::TurnResult::Finished { height } else { height_before };
+                    let is_new_record = match &records.daily_record {
+                        Some(record) => record.time.num_days_from_ce() != Local::now().num_days_from_ce() || final_height > record.height,
+                        None => true,
+                    };
+                    if is_new_record {
+                        records.daily_record = Some(Record {
+                            height: final_height,
+                            user_id: message.user_id.clone(),
+                            time: Local::now(),
+                        });
+                        result_message += "\n:calendar: 今日のデイリーチャレンジの新記録！";
+                    }
+                }
+
+                // 最高記録の更新
+                if !daily && !practice && (turn_result == stage::TurnResult::Success || turn_result == stage::TurnResult::Finished) {
+                    let is_new_record = match &records.record {
+                        Some(record) => height > record.height,
+                        None => true,
+                    };
+                    if is_new_record {
+                        records.record = Some(Record {
+                            height,
+                            user_id: message.user_id.clone(),
+                            time: Local::now(),
+                        });
+                        result_message += "\n:trophy: 新記録！";
+                    }
+
+                    // リーダーボードの更新 (ゴールデンピースは伸びにボーナス倍率をかける)
+                    // 続きから物理演算しただけの場合は自分で落としたオブジェクトではないので記録しない
+                    let delta = report.height_delta * if report.golden { stage::GOLDEN_BONUS } else { 1.0 };
+                    if let (false, Ok(mut leaderboard)) = (report.resumed, leaderboard.lock()) {
+                        let is_best = match leaderboard.entries.get(&message.user_id) {
+                            Some(entry) => delta > entry.best_delta,
+                            None => true,
+                        };
+                        if is_best {
+                            leaderboard.entries.insert(message.user_id.clone(), LeaderboardEntry {
+                                best_delta: delta,
+                                time: Local::now(),
+                            });
+                        }
+                    }
+                }
+                drop(channel_level_stage);
+
+                // ゲームが終了した場合は次のゲームの開始方法を案内
+                if game_over {
+                    result_message += "\n`@slack_tower_battle start` で新しいゲームを開始できます。";
+                }
+                else if let Some(next_player) = next_player {
+                    result_message += &format!("\n次は <@{}> さんの番です。", next_player);
+                }
+
+                let result_message = if daily {
+                    format!("<@{}> 【デイリーチャレンジ】 {}", message.user_id.clone(), result_message)
+                } else {
+                    format!("<@{}> {}", message.user_id.clone(), result_message)
+                };
+                // ゲームオーバー、タイムアウトまたはゲーム終了の場合は、画像の投稿に失敗してもステージが残らないように先にリセットする
+                let finished_thread_ts = if game_over {
+                    channel_stage.stage = None;
+                    channel_stage.update_time = Local::now();
+                    channel_stage.thread_ts.take()
+                } else {
+                    None
+                };
+                // 計算中のメッセージを結果に書き換えて、画像はそのスレッドに投稿する
+                // (画像の投稿に失敗しても結果の高さは残る。書き換えられなかった場合は今までどおり画像と一緒に投稿する)
+                let (image_thread_ts, image_text) = match progress_ts {
+                    Some(progress_ts) => match slack_client.update_message(channel_stage.channel_id.clone(), progress_ts.clone(), result_message.clone()).await {
+                        Ok(()) => (thread_ts.clone().or(Some(progress_ts)), "結果の画像".to_string()),
+                        Err(err) => {
+                            tracing::warn!("failed to update progress message: {}", err);
+                            (thread_ts.clone(), result_message)
+                        },
+                    },
+                    None => (thread_ts.clone(), result_message),
+                };
+                // 落とす位置のガイドは結果の前に投稿する
+                if let Some(drop_guide_png) = &report.drop_guide_png {
+                    warn_post_failure(slack_client.post_image(channel_stage.channel_id.clone(), image_thread_ts.clone(),
+                        format!("<@{}> さんが落とす位置", message.user_id), drop_guide_png, "guide.png".to_string()).await, "drop guide image")?;
+                }
+                // 失敗した場合はタワーが崩れる様子をスローモーションで表示する
+                let (result_image, result_filename) = match (&report.animation_gif, &report.collapse_gif) {
+                    (Some(animation_gif), _) => (animation_gif, "result.gif"),
+                    (None, Some(collapse_gif)) => (collapse_gif, "collapse.gif"),
+                    (None, None) => (&report.image_png, "result.png"),
+                };
+                warn_post_failure(slack_client.post_image(channel_stage.channel_id.clone(), image_thread_ts, image_text, result_image, result_filename.to_string()).await, "result image")?;
+                let result_emoji = if turn_result == stage::TurnResult::Failure { "x" } else { "white_check_mark" };
+                update_reaction(&slack_client, &channel_stage.channel_id, &message.ts, result_emoji, true).await;
+
+                // 次のプレイヤーが数値を打たずに遊べるように、回転角度と左右の位置のボタンを投稿する
+                if turn_result == stage::TurnResult::Success && !game_over {
+                    channel_stage.pending_input = (0.0, 0.0);
+                    let (button_text, blocks) = create_turn_buttons(channel_stage.pending_input);
+                    warn_post_failure(slack_client.post_blocks(channel_stage.channel_id.clone(), thread_ts.clone(), button_text, &blocks).await, "turn buttons")?;
+                }
+
+                // スレッドモードでは最終結果をチャンネルにも表示する
+                if let Some(root_ts) = finished_thread_ts {
+                    let final_height = if turn_result == stage::TurnResult::Success || turn_result == stage::TurnResult::Finished { height } else { height_before };
+                    warn_post_failure(slack_client.post_broadcast(channel_stage.channel_id.clone(), root_ts,
+                        format!(":checkered_flag: ゲームが終了しました。最終的な高さ: {} m\n`@slack_tower_battle start` で新しいゲームを開始できます。", final_height)
+                    ).await, "final result")?;
+                }
+            }
+            else if let Some(progress_ts) = progress_ts {
+                // 計算中のメッセージが残らないように失敗したことを表示する
+                slack_client.update_message(channel_stage.channel_id.clone(), progress_ts,
+                    format!("<@{}> 物理演算に失敗しました。もう一度お試しください。", message.user_id)).await?;
+            }
+        }
+        else {
+            // ステージが存在しない場合は開始方法を案内
+            slack_client.post_message(message.channel_id, thread_ts.clone(),
+                "現在ステージはありません。\n`@slack_tower_battle start` でゲームを開始できます。".to_string()
+            ).await?;
+            return Ok(());
+        }
+
+        channel_stage.update_time = Local::now();
+        Ok(())
+    }.await;
+
+    // チャンネルがアーカイブされたか削除された場合は、再試行せずにチャンネルのステージを削除する
+    if let Err(err) = &result {
+        if err.is_channel_unavailable() {
+            remove_channel_stages(&stages_for_removal, &data_dir, &reaction_channel_id).await;
+            return result;
+        }
+    }
+
+    // 処理が終わったら受け付けのリアクションを外す (エラーの場合は失敗したことを知らせる)
+    update_reaction(&slack_client_for_reaction, &reaction_channel_id, &reaction_ts, "hourglass_flowing_sand", false).await;
+    if result.is_err() {
+        update_reaction(&slack_client_for_reaction, &reaction_channel_id, &reaction_ts, "x", true).await;
+    }
+    return result;
+}
+
+// 結果の画像などの投稿の失敗は警告にとどめる (結果のメッセージは残るので、ターンは失敗にしない)
+// チャンネルがアーカイブされたか削除された場合だけエラーにして、チャンネルのステージを削除する
+fn warn_post_failure<T>(result: slack::SlackResult<T>, what: &str) -> Result<(), AppError> {
+    if let Err(err) = result {
+        let err = AppError::from(err);
+        if err.is_channel_unavailable() { return Err(err); }
+        tracing::warn!("failed to post {}: {}", what, err);
+    }
+    return Ok(());
+}
+
+// 次のターンの入力を選ぶボタンのメッセージ (通知用のテキストとBlock Kitのブロック)
+// ボタンのvalueは "rotation:<回転角度>"、"nudge:<左右の位置の変化量>"、"drop" のいずれか
+// 参考: https://api.slack.com/reference/block-kit/block-elements#button
+fn create_turn_buttons((translation_x, rotation): (f64, f64)) -> (String, serde_json::Value) {
+    let text = format!("次のターンの入力: 左右の位置 {} / 回転角度 {}°", translation_x, rotation);
+    let button = |label: String, value: String| serde_json::json!({
+        "type": "button",
+        "text": { "type": "plain_text", "text": label },
+        "value": value,
+        "action_id": value,
+    });
+    let rotation_buttons: Vec<serde_json::Value> = [-90, -45, 0, 45, 90].iter()
+        .map(|preset| button(format!("{}°", preset), format!("rotation:{}", preset)))
+        .collect();
+    let mut drop_button = button("落とす".to_string(), "drop".to_string());
+    drop_button["style"] = serde_json::json!("primary");
+    let blocks = serde_json::json!([
+        { "type": "section", "text": { "type": "mrkdwn", "text": text.clone() + "\nボタンで選んで「落とす」を押してください。" } },
+        { "type": "actions", "elements": rotation_buttons },
+        { "type": "actions", "elements": [
+            button("← 0.1".to_string(), "nudge:-0.1".to_string()),
+            button("→ 0.1".to_string(), "nudge:0.1".to_string()),
+            drop_button,
+        ] },
+    ]);
+    return (text, blocks);
+}
+
+// ターンの処理で起きたエラーを管理用のチャンネルに報告する
+// 管理用のチャンネルが設定されていない場合は、ターンが処理されなかったことだけをユーザーに知らせる
+async fn report_turn_error(slack_client: &slack::SlackClient, channel_id: String, thread_ts: Option<String>, user_id: String, err: &AppError) {
+    // チャンネルがアーカイブされた場合などは、ステージを削除済みなので報告しない
+    if err.is_channel_unavailable() { return; }
+    if slack_client.report_error(format!("ターンの処理でエラーが発生しました (チャンネル: <#{}>、ユーザー: {})", channel_id, user_id), err.to_string()).await {
+        return;
+    }
+    if let Err(err) = slack_client.post_ephemeral(channel_id, thread_ts, user_id.clone(), format!("<@{}> {}", user_id, err.get_user_message())).await {
+        tracing::warn!("failed to notify the user of an error: {}", err);
+    }
+}
+
+// コマンドのメッセージにリアクションを付ける (addがfalseの場合は外す)
+// メッセージが削除された場合などに失敗してもターンの処理は続ける
+async fn update_reaction(slack_client: &slack::SlackClient, channel_id: &str, ts: &str, emoji: &str, add: bool) {
+    // スラッシュコマンドなど、リアクションを付けるメッセージがない場合は何もしない
+    if ts.is_empty() { return; }
+    let result = if add {
+        slack_client.add_reaction(channel_id.to_string(), ts.to_string(), emoji.to_string()).await
+    } else {
+        slack_client.remove_reaction(channel_id.to_string(), ts.to_string(), emoji.to_string()).await
+    };
+    if let Err(err) = result {
+        tracing::warn!("failed to update reaction {} on {}: {}", emoji, ts, err);
+    }
+}
+
+// 24時間以上経過したステージを自動削除するタスク
+// メモ: 最高記録を残すためにチャンネル全体のChannelStage自体は削除しない
+// スレッドごとのChannelStageはステージがなくなってから24時間経過したら保存したファイルごと削除する
+pub async fn stage_cleaner(stages: ChannelStages, data_dir: String) {
+    loop {
+        let current_time = Local::now();
+        // 読み込みのロックはチャンネルの一覧を取り出す間だけ取り、削除の判断はロックを外してから行う
+        let channel_stages: Vec<(StageKey, SharedChannelStage)> = stages.read().await.iter()
+            .map(|(key, channel_stage)| (key.clone(), Arc::clone(channel_stage)))
+            .collect();
+        let mut removed_keys = Vec::<StageKey>::new();
+        for (key, channel_stage) in channel_stages {
+            if let Ok(mut channel_stage) = channel_stage.try_lock() {
+                // スレッドモードのゲームが終わった後の別名のキー
+                if key.1 != channel_stage.scope_thread_ts {
+                    if channel_stage.stage.is_none() || channel_stage.thread_ts != key.1 { removed_keys.push(key); }
+                    continue;
+                }
+                let elapsed_time = current_time - channel_stage.update_time;
+                if channel_stage.stage.is_some() && elapsed_time.num_hours() >= 24 {
+                    channel_stage.stage = None;
+                    channel_stage.update_time = current_time;
+                    channel_stage.changed = true;
+                    tracing::info!("delete: channel {} {:?}", key.0, key.1);
+                }
+                else if channel_stage.stage.is_none() && key.1.is_some() && elapsed_time.num_hours() >= 24 {
+                    let _ = std::fs::remove_file(get_channel_stage_path(&data_dir, &channel_stage.channel_id, &channel_stage.scope_thread_ts));
+                    tracing::info!("delete: thread {} {:?}", key.0, key.1);
+                    removed_keys.push(key);
+                }
+            }
+        }
+        if !removed_keys.is_empty() {
+            let mut stages = stages.write().await;
+            for key in removed_keys.iter() { stages.remove(key); }
+        }
+        // 60秒おきに監視
+        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+    }
+}
+
+// チャンネルの全てのステージ (スレッドごとのステージを含む) を保存したファイルごと削除する
+// チャンネルがアーカイブされた場合やbotがチャンネルから外された場合に、24時間待たずに削除する
+async fn remove_channel_stages(stages: &ChannelStages, data_dir: &str, channel_id: &str) {
+    let mut stages = stages.write().await;
+    let keys: Vec<StageKey> = stages.keys().filter(|key| key.0 == channel_id).cloned().collect();
+    for key in keys {
+        stages.remove(&key);
+        let _ = std::fs::remove_file(get_channel_stage_path(data_dir, &key.0, &key.1));
+    }
+    tracing::info!("delete: channel {} (unavailable)", channel_id);
+}
+
+// ChannelStageを保存するファイルのパス
+fn get_channel_stage_path(data_dir: &str, channel_id: &str, scope_thread_ts: &Option<String>) -> std::path::PathBuf {
+    let name = match scope_thread_ts {
+        Some(scope_thread_ts) => format!("{}_{}.json", channel_id, scope_thread_ts),
+        None => format!("{}.json", channel_id),
+    };
+    return std::path::Path::new(data_dir).join(name);
+}
+
+// ChannelStageを1つのファイルに保存する
+// 書き込み途中で終了しても壊れないように、一時ファイルに書いてから置き換える
+fn save_channel_stage(data_dir: &str, channel_stage: &ChannelStage) -> AppResult {
+    let saved = SavedChannelStage {
+        update_time: channel_stage.update_time,
+        channel_id: channel_stage.channel_id.clone(),
+        stage: channel_stage.stage.as_ref().map(|stage| stage.to_snapshot()),
+        record: channel_stage.record.clone(),
+        daily_record: channel_stage.daily_record.clone(),
+        daily: channel_stage.daily,
+        settings: channel_stage.settings.clone(),
+        thread_ts: channel_stage.thread_ts.clone(),
+        practice: channel_stage.practice,
+        scope_thread_ts: channel_stage.scope_thread_ts.clone(),
+    };
+    std::fs::create_dir_all(data_dir)?;
+    let path = get_channel_stage_path(data_dir, &channel_stage.channel_id, &channel_stage.scope_thread_ts);
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, serde_json::to_vec(&saved)?)?;
+    std::fs::rename(&temp_path, &path)?;
+    return Ok(());
+}
+
+// 保存されたChannelStageを全て読み込む
+// 壊れたファイルや形式の違うファイルはログに残して読み飛ばす
+fn load_channel_stages(data_dir: &str) -> HashMap<StageKey, SharedChannelStage> {
+    let mut stages = HashMap::new();
+    let entries = match std::fs::read_dir(data_dir) {
+        Ok(entries) => entries,
+        Err(_) => { return stages; }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") { continue; }
+        let saved: SavedChannelStage = match std::fs::read(&path).map_err(|error| error.to_string())
+            .and_then(|data| serde_json::from_slice(&data).map_err(|error| error.to_string())) {
+            Ok(saved) => saved,
+            Err(error) => { tracing::warn!("failed to load {}: {}", path.display(), error); continue; }
+        };
+        let stage = match saved.stage.map(stage::Stage::from_snapshot).transpose() {
+            Ok(stage) => stage,
+            Err(error) => { tracing::warn!("failed to load {}: {}", path.display(), error); continue; }
+        };
+        tracing::info!("load: channel {} {:?}{}", saved.channel_id, saved.scope_thread_ts, if stage.is_some() { " (stage)" } else { "" });
+        let key = (saved.channel_id.clone(), saved.scope_thread_ts.clone());
+        // スレッドモードで進行中のゲームはスレッドのキーも登録する
+        let alias_key = match (&stage, &saved.thread_ts, &saved.scope_thread_ts) {
+            (Some(_), Some(thread_ts), None) => Some((saved.channel_id.clone(), Some(thread_ts.clone()))),
+            _ => None,
+        };
+        let channel_stage = Arc::new(tokio::sync::Mutex::new(ChannelStage {
+            update_time: saved.update_time,
+            channel_id: saved.channel_id,
+            stage,
+            reset_request_time: None,
+            record: saved.record,
+            daily_record: saved.daily_record,
+            daily: saved.daily,
+            settings: saved.settings,
+            thread_ts: saved.thread_ts,
+            pending_input: (0.0, 0.0),
+            practice: saved.practice,
+            scope_thread_ts: saved.scope_thread_ts,
+            changed: false,
+        }));
+        if let Some(alias_key) = alias_key { stages.insert(alias_key, Arc::clone(&channel_stage)); }
+        stages.insert(key, channel_stage);
+    }
+    return stages;
+}
+
+// 全てのChannelStageを保存する
+// wait_for_turnがfalseの場合は計算中のチャンネルを飛ばす (次の保存で保存される)
+pub async fn save_channel_stages(stages: &ChannelStages, data_dir: &str, wait_for_turn: bool) {
+    // ロック中に待たないように、先にChannelStageを取り出す
+    let channel_stages: Vec<(StageKey, SharedChannelStage)> = stages.read().await.iter()
+        .map(|(key, channel_stage)| (key.clone(), Arc::clone(channel_stage)))
+        .collect();
+    for (key, channel_stage) in channel_stages {
+        let mut channel_stage = if wait_for_turn {
+            channel_stage.lock().await
+        }
+        else {
+            match channel_stage.try_lock() {
+                Ok(channel_stage) => channel_stage,
+                Err(_) => { continue; }
+            }
+        };
+        // 別名のキーは元のキーで保存する
+        if key.1 != channel_stage.scope_thread_ts { continue; }
+        // 前回の保存から変わっていないチャンネルは保存し直さない
+        if !channel_stage.changed { continue; }
+        match save_channel_stage(data_dir, &channel_stage) {
+            Ok(()) => { channel_stage.changed = false; },
+            Err(error) => { tracing::warn!("failed to save channel {}: {}", channel_stage.channel_id, error); },
+        };
+    }
+}
+
+// 定期的にステージを保存するタスク
+pub async fn stage_saver(stages: ChannelStages, data_dir: String) {
+    loop {
+        // 60秒おきに保存
+        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        metrics::get().active_stages.set(stages.read().await.len() as i64);
+        save_channel_stages(&stages, &data_dir, false).await;
+    }
+}
+
+// 終了する時に、計算中のターンが終わるのを最大grace_periodだけ待ってから全てのステージを保存する
+// 時間内に終わらなかったターンは中断し、その数を返す
+pub async fn finish_turns(context: &AppContext, grace_period: std::time::Duration) -> usize {
+    let mut turn_tasks = match context.turn_tasks.lock() {
+        Ok(mut turn_tasks) => std::mem::take(&mut *turn_tasks),
+        Err(_) => tokio::task::JoinSet::new(),
+    };
+    tracing::info!("waiting for {} turns in progress", turn_tasks.len());
+    let mut aborted = 0;
+    if tokio::time::timeout(grace_period, async { while turn_tasks.join_next().await.is_some() {} }).await.is_err() {
+        aborted = turn_tasks.len();
+        tracing::warn!("{} turns did not finish in {} s, aborting them", aborted, grace_period.as_secs_f64());
+        turn_tasks.shutdown().await;
+    }
+    tracing::info!("saving stages");
+    save_channel_stages(&context.stages, &context.data_dir, true).await;
+    return aborted;
+}
+
+// 環境変数の値 (設定されていない場合や不正な場合は既定値)
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    return std::env::var(key).ok().and_then(|value| value.parse::<T>().ok()).unwrap_or(default);
+}
+
+// 順番待ちはステージと同じキーで管理する
+pub type TurnQueues = Arc<Mutex<HashMap<StageKey, Arc<Mutex<TurnQueue>>>>>;
+// 計算中のターンのタスク (終了する時に終わるのを待つ)
+pub type TurnTasks = Arc<Mutex<tokio::task::JoinSet<()>>>;
+
+// メッセージの処理に必要な、全てのチャンネルで共有する状態
+#[derive(Clone)]
+pub struct AppContext {
+    pub slack_client: Arc<slack::SlackClient>,
+    pub bot_user_id: Arc<String>,
+    pub shape_packs: ShapePacks,
+    pub admin_user_ids: Arc<Vec<String>>,
+    pub stage_config: stage::StageConfig,
+    pub leaderboard: Arc<Mutex<Leaderboard>>,
+    pub user_cache: UserCache,
+    pub stages: ChannelStages,
+    pub turn_queues: TurnQueues,
+    pub turn_tasks: TurnTasks,
+    pub data_dir: String,
+}
+impl AppContext {
+    // data_dirに保存されたステージを読み込み、リーダーボードとユーザー情報のキャッシュは空で始める
+    pub fn new(
+        slack_client: Arc<slack::SlackClient>,
+        bot_user_id: Arc<String>,
+        shape_packs: ShapePacks,
+        admin_user_ids: Arc<Vec<String>>,
+        stage_config: stage::StageConfig,
+        data_dir: String
+    ) -> AppContext {
+        return AppContext {
+            slack_client,
+            bot_user_id,
+            shape_packs,
+            admin_user_ids,
+            stage_config,
+            leaderboard: Arc::new(Mutex::new(Leaderboard {
+                entries: HashMap::new(),
+            })),
+            user_cache: Arc::new(RwLock::new(HashMap::new())),
+            stages: Arc::new(tokio::sync::RwLock::new(load_channel_stages(&data_dir))),
+            turn_queues: Arc::new(Mutex::new(HashMap::new())),
+            turn_tasks: Arc::new(Mutex::new(tokio::task::JoinSet::new())),
+            data_dir,
+        };
+    }
+}
+
+// 順番待ちのキー
+// 順番待ちはステージごとに1つにする (スレッドモードのゲームのスレッドのキーは、チャンネル全体のステージの別名なのでチャンネル全体の順番待ちに並ぶ)
+async fn get_turn_queue_key(stages: &ChannelStages, key: StageKey, channel_stage: &SharedChannelStage) -> StageKey {
+    if key.1.is_none() { return key; }
+    let channel_key: StageKey = (key.0.clone(), None);
+    return match stages.read().await.get(&channel_key) {
+        Some(channel_level_stage) if Arc::ptr_eq(channel_level_stage, channel_stage) => channel_key,
+        _ => key,
+    };
+}
+
+// 受信したメッセージを振り分ける
+// メモ: websocket_receiverは処理が終わるまで次のメッセージを渡さないので、ターンの計算は別のタスクで行う
+pub async fn handle_message(context: AppContext, message: slack::Message) {
+    let AppContext { slack_client, bot_user_id, stages, turn_queues, turn_tasks, data_dir, .. } = context.clone();
+    // チャンネルがアーカイブされた場合とbotがチャンネルから外された場合は、チャンネルのステージと順番待ちを削除する
+    // (他のメンバーが退出した場合は何もしない)
+    if message.event_type == "channel_archive" || message.event_type == "member_left_channel" {
+        if message.event_type == "channel_archive" || message.user_id == *bot_user_id {
+            remove_channel_stages(&stages, &data_dir, &message.channel_id).await;
+            if let Ok(mut turn_queues) = turn_queues.lock() { turn_queues.retain(|key, _| key.0 != message.channel_id); }
+        }
+        return;
+    }
+    // スレッド内のコマンドはスレッドごとのステージに振り分ける
+    let key: StageKey = (message.channel_id.clone(), message.thread_ts.clone());
+    // 編集されたメッセージは順番待ち中のコマンドの修正にだけ使う (ステージや順番待ちを新しく作らない)
+    if message.subtype.as_deref() == Some("message_changed") {
+        let channel_stage = stages.read().await.get(&key).map(Arc::clone);
+        if let Some(channel_stage) = channel_stage {
+            let key = get_turn_queue_key(&stages, key, &channel_stage).await;
+            let turn_queue = turn_queues.lock().ok().and_then(|turn_queues| turn_queues.get(&key).map(Arc::clone));
+            if let Some(turn_queue) = turn_queue { correct_queued_turn(&turn_queue, message); }
+        }
+        return;
+    }
+    // botへのコマンド以外のメッセージは、ステージに触れずに無視する (保存するステージを増やさないため)
+    if !is_bot_command(&get_mention_regex(&bot_user_id), &message) { return; }
+    // 既存のチャンネルは読み込みのロックだけで振り分け、初めてのチャンネルの場合だけ書き込みのロックを取る
+    let channel_stage = stages.read().await.get(&key).map(Arc::clone);
+    let channel_stage = match channel_stage {
+        Some(channel_stage) => channel_stage,
+        None => {
+            // DMのチャンネルIDはDから始まる
+            let practice = message.channel_type.as_deref() == Some("im") || message.channel_id.starts_with('D');
+            Arc::clone(stages.write().await.entry(key.clone()).or_insert_with(|| {
+                Arc::new(tokio::sync::Mutex::new(ChannelStage::new(message.channel_id.clone(), practice, message.thread_ts.clone())))
+            }))
+        },
+    };
+
+    let key = get_turn_queue_key(&stages, key, &channel_stage).await;
+    let turn_queue = match turn_queues.lock() {
+        Ok(mut turn_queues) => Arc::clone(turn_queues.entry(key).or_insert_with(|| Arc::new(Mutex::new(TurnQueue {
+            turns: std::collections::VecDeque::new(),
+            next_ticket: 0,
+            // ファイルから復元したステージの場合もあるので、計算中でなければ今の状態から写す
+            last_user_id: channel_stage.try_lock().ok().and_then(|channel_stage| get_blocked_user_id(&channel_stage)),
+        })))),
+        Err(_) => { return; },
+    };
+    // 同じユーザーの連続プレイは、計算中のターンや順番待ちを待たずにすぐ断る
+    let consecutive = is_turn_command(&get_mention_regex(&bot_user_id), &message)
+        && turn_queue.lock().is_ok_and(|turn_queue| turn_queue.last_user_id.as_ref() == Some(&message.user_id));
+    // ターンの計算は時間がかかるので、次のメッセージの受信を待たせないように別のタスクで行う
+    // ターンのログにはチャンネルとユーザーを付け、失敗した場合はエラーをログに残す
+    let span = tracing::info_span!("turn", channel_id = %message.channel_id, user_id = %message.user_id);
+    let turn = async move {
+        let (channel_id, thread_ts, user_id) = (message.channel_id.clone(), message.thread_ts.clone(), message.user_id.clone());
+        let result = if consecutive {
+            reject_consecutive_play(&slack_client, &message, message.thread_ts.clone()).await.map_err(AppError::from)
+        } else {
+            compute_turn(context, Arc::clone(&channel_stage), Arc::clone(&turn_queue), message).await
+        };
+        if let Err(err) = result {
+            tracing::error!("turn failed: {}", err);
+            report_turn_error(&slack_client, channel_id, thread_ts, user_id, &err).await;
+        }
+        // 次のコマンドがまだロックを取っていなければ、このコマンドで変わった状態を写しに反映する
+        if let Ok(channel_stage) = channel_stage.try_lock() { sync_blocked_user_id(&turn_queue, &channel_stage); }
+    }.instrument(span);
+    // 終了したタスクを取り除いてから追加する
+    match turn_tasks.lock() {
+        Ok(mut turn_tasks) => {
+            while turn_tasks.try_join_next().is_some() {}
+            turn_tasks.spawn(turn);
+        },
+        Err(_) => { tokio::spawn(turn); },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 正方形と横長の長方形 (px単位)
+    fn test_shapes() -> Vec<canvas::ShapeDef> {
+        return vec![
+            canvas::ShapeDef::new(vec![(-30.0, -30.0), (30.0, -30.0), (30.0, 30.0), (-30.0, 30.0)]),
+            canvas::ShapeDef::new(vec![(-60.0, -15.0), (60.0, -15.0), (60.0, 15.0), (-60.0, 15.0)]),
+        ];
+    }
+
+    // テストを実行する速さで結果が変わらないように、実時間での打ち切りは無効にする
+    fn test_config() -> stage::StageConfig {
+        return stage::StageConfig { wall_clock_budget_sec: 1.0e6, ..stage::StageConfig::default() };
+    }
+
+    // テストごとに空の保存先のディレクトリを作る
+    fn temp_data_dir(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("slack_tower_battle_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        return path.to_string_lossy().into_owned();
+    }
+
+    // テスト用のSlack APIのサーバーに接続するコンテキスト
+    fn test_context(data_dir: &str, slack: &slack::MockSlack) -> AppContext {
+        let slack_client = slack.client();
+        let shape_packs = HashMap::from([(DEFAULT_SHAPE_PACK.to_string(), test_shapes())]);
+        return AppContext::new(Arc::new(slack_client), Arc::new("U_BOT".to_string()), Arc::new(RwLock::new(Arc::new(shape_packs))),
+            Arc::new(Vec::new()), test_config(), data_dir.to_string());
+    }
+
+    fn test_message(event_type: &str, channel_id: &str, text: &str) -> slack::Message {
+        return slack::Message {
+            event_type: event_type.to_string(),
+            channel_id: channel_id.to_string(),
+            user_id: "U_TEST".to_string(),
+            text: text.to_string(),
+            files: Vec::new(),
+            ts: format!("{}.000100", Local::now().timestamp()),
+            thread_ts: None,
+            event_id: None,
+            event_time: None,
+            action_message_ts: None,
+            channel_type: None,
+            subtype: None,
+        };
+    }
+
+    #[test]
+    fn strips_only_the_bot_mention() {
+        // 他のユーザーへのメンションは残し、botへのメンションは文の途中や複数あっても全て取り除く
+        let mention = get_mention_regex("U_BOT");
+        assert_eq!(strip_mention(&mention, "<@U_BOT> 0.1 20"), "0.1 20");
+        assert_eq!(strip_mention(&mention, "<@U_OTHER> look <@U_BOT> 0.1 20"), "<@U_OTHER> look 0.1 20");
+        assert_eq!(strip_mention(&mention, "0.1  <@U_BOT|tower>\t20 <@U_BOT>"), "0.1 20");
+        assert_eq!(strip_mention(&mention, "<@U_BOTTOM> 0 0"), "<@U_BOTTOM> 0 0");
+
+        let is_command = |event_type: &str, text: &str| is_bot_command(&mention, &test_message(event_type, "C_TEST", text));
+        assert!(is_command("app_mention", "<@U_OTHER> look <@U_BOT> 0.1 20"));
+        assert!(is_command("app_mention", "0.1 20 <@U_BOT>"));
+        assert!(!is_command("app_mention", "<@U_OTHER> <@U_BOTTOM> 0.1 20"));
+        // スラッシュコマンドはメンションなしでコマンドになり、チャンネルのメッセージはメンションがあってもコマンドではない
+        assert!(is_command("slash_command", "0.1 20"));
+        assert!(!is_command("message", "<@U_BOT> 0.1 20"));
+    }
+
+    #[tokio::test]
+    async fn user_cache_expires_and_evicts_the_least_recently_used() {
+        let slack = slack::MockSlack::start().await;
+        let slack_client = slack.client();
+        let user_cache: UserCache = Arc::new(RwLock::new(HashMap::new()));
+        let get_name = |user_id: &'static str| {
+            let (slack_client, user_cache) = (&slack_client, &user_cache);
+            async move { get_cached_user(slack_client, user_cache, user_id).await.unwrap().name }
+        };
+
+        // 期限内はキャッシュを使い、slackに問い合わせない
+        slack.push_json("users.profile.get", serde_json::json!({"ok": true, "profile": {"display_name": "first"}}));
+        slack.push_json("users.profile.get", serde_json::json!({"ok": true, "profile": {"display_name": "second"}}));
+        assert_eq!(get_name("U_TEST").await.as_deref(), Some("first"));
+        assert_eq!(get_name("U_TEST").await.as_deref(), Some("first"));
+        assert_eq!(slack.get_requests("users.profile.get").len(), 1);
+
+        // 取得してからUSER_CACHE_TTL_HOURS経つと取得し直す
+        user_cache.write().unwrap().get_mut("U_TEST").unwrap().fetched_at = Local::now() - chrono::Duration::hours(USER_CACHE_TTL_HOURS);
+        assert_eq!(get_name("U_TEST").await.as_deref(), Some("second"));
+        assert_eq!(slack.get_requests("users.profile.get").len(), 2);
+
+        // 上限に達している場合は、最後に使ってから最も時間が経ったユーザーから忘れる
+        // (U001が最近使ったユーザーで、番号が大きいほど前に使ったユーザー)
+        {
+            let now = Local::now();
+            let mut users = user_cache.write().unwrap();
+            for index in 1..USER_CACHE_CAPACITY {
+                let used_at = now - chrono::Duration::minutes(index as i64);
+                users.insert(format!("U{:03}", index), CachedUser { name: None, icon_png: None, fetched_at: now, used_at });
+            }
+            assert_eq!(users.len(), USER_CACHE_CAPACITY);
+        }
+        let oldest = format!("U{:03}", USER_CACHE_CAPACITY - 1);
+        let second_oldest = format!("U{:03}", USER_CACHE_CAPACITY - 2);
+        let third_oldest = format!("U{:03}", USER_CACHE_CAPACITY - 3);
+        // 使ったユーザーは最近使ったユーザーになる
+        assert_eq!(get_cached_user(&slack_client, &user_cache, &oldest).await.unwrap().name, None);
+        assert_eq!(slack.get_requests("users.profile.get").len(), 2);
+        get_name("U_NEW").await;
+        {
+            let users = user_cache.read().unwrap();
+            assert_eq!(users.len(), USER_CACHE_CAPACITY);
+            assert!(users.contains_key(&oldest) && users.contains_key("U_NEW"));
+            assert!(!users.contains_key(&second_oldest));
+        }
+        get_name("U_NEWER").await;
+        let users = user_cache.read().unwrap();
+        assert_eq!(users.len(), USER_CACHE_CAPACITY);
+        assert!(!users.contains_key(&third_oldest));
+        assert!(users.contains_key("U_TEST") && users.contains_key("U_NEW") && users.contains_key("U_NEWER"));
+    }
+
+    #[test]
+    fn user_messages_follow_the_error_variants() {
+        let api = |error: &str| AppError::from(slack::SlackApiError::Api { method: "chat.postMessage".to_string(), error: error.to_string() });
+        let busy = "Slackが混み合っているため、ターンを処理できませんでした。しばらくしてからもう一度お試しください。";
+        assert_eq!(api("ratelimited").get_user_message(), busy);
+        let throttled = AppError::from(slack::SlackApiError::Transport { method: "chat.postMessage".to_string(), reason: "HTTP 429".to_string() });
+        assert_eq!(throttled.get_user_message(), busy);
+        assert_eq!(api("not_in_channel").get_user_message(), "Slackとの通信でエラーが発生しました。");
+        assert!(api("channel_not_found").is_channel_unavailable() && api("is_archived").is_channel_unavailable());
+        assert!(!api("not_in_channel").is_channel_unavailable());
+
+        assert_eq!(AppError::from(stage::StageError::NotYourTurn(Some("U_OTHER".to_string()))).get_user_message(), "物理演算でエラーが発生しました。");
+        assert_eq!(AppError::PhysicsPanic.get_user_message(), "物理演算でエラーが発生しました。");
+        assert_eq!(AppError::from(canvas::CanvasError::Png("test".to_string())).get_user_message(), "画像の作成でエラーが発生しました。");
+        assert_eq!(AppError::from("lock failed").get_user_message(), "エラーが発生しました。");
+    }
+
+    #[tokio::test]
+    async fn non_command_messages_do_not_create_stages() {
+        // 編集されたスレッドの返信、削除などのsubtypeのDM、botがメンションされていないメッセージではステージを作らない
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("non_command");
+        let context = test_context(&data_dir, &slack);
+        let edited = slack::Message {
+            thread_ts: Some("1700000000.000100".to_string()),
+            subtype: Some("message_changed".to_string()),
+            ..test_message("message", "C_TEST", "<@U_BOT> 0 0")
+        };
+        let deleted = slack::Message {
+            channel_type: Some("im".to_string()),
+            subtype: Some("message_deleted".to_string()),
+            ..test_message("message", "D_TEST", "")
+        };
+        let other_mention = test_message("app_mention", "C_TEST", "<@U_OTHER> 0 0");
+        for message in [edited, deleted, other_mention] {
+            handle_message(context.clone(), message).await;
+        }
+        assert!(context.stages.read().await.is_empty());
+        assert!(context.turn_queues.lock().unwrap().is_empty());
+        save_channel_stages(&context.stages, &data_dir, true).await;
+        assert!(std::fs::read_dir(&data_dir).is_err());
+        assert!(slack.get_requests("chat.postMessage").is_empty());
+    }
+
+    #[tokio::test]
+    async fn slash_commands_play_like_mentions() {
+        // スラッシュコマンド (/tower start、/tower 0 0) はメンションなしでメンションと同じように遊べる
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("slash_command");
+        let context = test_context(&data_dir, &slack);
+        for text in ["start", "0 0"] {
+            let message = slack::Message { ts: String::new(), ..test_message("slash_command", "C_TEST", text) };
+            handle_message(context.clone(), message).await;
+            wait_for_turns(&context).await;
+        }
+        let channel_stage = Arc::clone(&context.stages.read().await[&("C_TEST".to_string(), None)]);
+        assert_eq!(channel_stage.lock().await.stage.as_ref().map(|stage| stage.get_object_count()), Some(1));
+        assert!(!slack.get_requests("files.completeUploadExternal").is_empty());
+        // tsがないのでリアクションは付けない
+        assert!(slack.get_requests("reactions.add").is_empty());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn invalid_input_is_answered_only_to_the_user() {
+        // 無効な入力は本人にだけ見えるメッセージで知らせ、チャンネルには投稿しない
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("ephemeral");
+        let context = test_context(&data_dir, &slack);
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> start")).await;
+        wait_for_turns(&context).await;
+        let posts = slack.get_requests("chat.postMessage").len() + slack.get_requests("files.completeUploadExternal").len();
+
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> left please")).await;
+        wait_for_turns(&context).await;
+        let requests = slack.get_requests("chat.postEphemeral");
+        assert_eq!(requests.len(), 1);
+        let form = requests[0].get_form();
+        assert_eq!((form["channel"].as_str(), form["user"].as_str()), ("C_TEST", "U_TEST"));
+        assert!(form["text"].starts_with("無効な入力です。"), "{}", form["text"]);
+        assert_eq!(slack.get_requests("chat.postMessage").len() + slack.get_requests("files.completeUploadExternal").len(), posts);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    // userからC_TESTにメンションでコマンドを送り、ターンが終わるまで待つ
+    async fn send(context: &AppContext, user_id: &str, text: &str) {
+        let message = slack::Message { user_id: user_id.to_string(), ..test_message("app_mention", "C_TEST", &format!("<@U_BOT> {}", text)) };
+        handle_message(context.clone(), message).await;
+        wait_for_turns(context).await;
+    }
+
+    // methodで投稿したテキスト (画像はinitial_comment) を投稿した順に返す
+    fn get_texts(slack: &slack::MockSlack, method: &str) -> Vec<String> {
+        return slack.get_requests(method).iter().map(|request| {
+            let mut form = request.get_form();
+            form.remove("text").or_else(|| form.remove("initial_comment")).unwrap_or_default()
+        }).collect();
+    }
+
+    fn last_text(slack: &slack::MockSlack, method: &str) -> String {
+        return get_texts(slack, method).pop().unwrap_or_default();
+    }
+
+    async fn get_test_channel_stage(context: &AppContext) -> SharedChannelStage {
+        return Arc::clone(&context.stages.read().await[&("C_TEST".to_string(), None)]);
+    }
+
+    async fn get_object_count(context: &AppContext) -> Option<usize> {
+        return get_test_channel_stage(context).await.lock().await.stage.as_ref().map(|stage| stage.get_object_count());
+    }
+
+    #[tokio::test]
+    async fn help_works_with_or_without_a_stage() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("help");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "help").await;
+        assert!(last_text(&slack, "chat.postMessage").starts_with("【遊び方】"));
+        assert_eq!(get_object_count(&context).await, None);
+
+        // ステージも最後のターンの時刻も変えない (放置されたステージが削除されなくならないように)
+        send(&context, "U_TEST", "start").await;
+        send(&context, "U_TEST", "0 0").await;
+        let channel_stage = get_test_channel_stage(&context).await;
+        let (object_count, update_time) = (get_object_count(&context).await, channel_stage.lock().await.update_time);
+        send(&context, "U_OTHER", "help").await;
+        assert!(last_text(&slack, "chat.postMessage").starts_with("【遊び方】"));
+        assert_eq!(get_object_count(&context).await, object_count);
+        assert_eq!(channel_stage.lock().await.update_time, update_time);
+
+        // 無効な入力ではヘルプのコマンドを案内する
+        send(&context, "U_OTHER", "left please").await;
+        assert!(last_text(&slack, "chat.postEphemeral").contains("`@slack_tower_battle help`"));
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn reset_needs_a_confirmation_within_60_seconds() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("reset");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "reset").await;
+        assert_eq!(last_text(&slack, "chat.postMessage"), "リセットするステージがありません。");
+
+        send(&context, "U_TEST", "start").await;
+        send(&context, "U_TEST", "reset").await;
+        assert!(last_text(&slack, "chat.postMessage").starts_with("本当にリセットしますか？"));
+        assert!(get_object_count(&context).await.is_some());
+        // 確認から60秒経った場合はもう一度確認する
+        let channel_stage = get_test_channel_stage(&context).await;
+        channel_stage.lock().await.reset_request_time = Some(Local::now() - chrono::Duration::seconds(61));
+        send(&context, "U_OTHER", "reset").await;
+        assert!(last_text(&slack, "chat.postMessage").starts_with("本当にリセットしますか？"));
+        assert!(get_object_count(&context).await.is_some());
+
+        // 他のユーザーが確認してもリセットし、最後のタワーの画像を投稿する
+        send(&context, "U_TEST", "reset").await;
+        assert!(last_text(&slack, "files.completeUploadExternal").starts_with("<@U_TEST> ステージをリセットしました。"));
+        assert_eq!(get_object_count(&context).await, None);
+        assert!(channel_stage.lock().await.reset_request_time.is_none());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn status_shows_the_tower_without_taking_a_turn() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("status");
+        let context = test_context(&data_dir, &slack);
+        // ステージがない場合はステージを作らずに開始方法を案内する
+        send(&context, "U_TEST", "status").await;
+        assert!(last_text(&slack, "chat.postMessage").starts_with("現在ステージはありません。"));
+        assert_eq!(get_object_count(&context).await, None);
+
+        send(&context, "U_TEST", "start").await;
+        send(&context, "U_TEST", "0 0").await;
+        let object_count = get_object_count(&context).await;
+        send(&context, "U_OTHER", "status").await;
+        let status = last_text(&slack, "files.completeUploadExternal");
+        assert!(status.starts_with("現在の高さ: "), "{}", status);
+        assert!(status.contains(&format!("オブジェクトの数: {} ", object_count.unwrap())), "{}", status);
+        assert!(status.contains("最後のターン: "), "{}", status);
+        assert_eq!(get_object_count(&context).await, object_count);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn records_outlive_the_stage() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("record");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "record").await;
+        assert_eq!(last_text(&slack, "chat.postMessage"), "このチャンネルの記録はまだありません。");
+
+        send(&context, "U_TEST", "start").await;
+        send(&context, "U_TEST", "0 0").await;
+        assert!(last_text(&slack, "chat.update").contains(":trophy: 新記録！"));
+        // リセットした後も記録は残る
+        send(&context, "U_TEST", "reset").await;
+        send(&context, "U_TEST", "reset").await;
+        assert_eq!(get_object_count(&context).await, None);
+        send(&context, "U_OTHER", "record").await;
+        let record = last_text(&slack, "chat.postMessage");
+        assert!(record.starts_with(":trophy: このチャンネルの最高記録: ") && record.contains("達成者: <@U_TEST>"), "{}", record);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn leaderboard_lists_the_best_delta_of_each_user() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("leaderboard");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "leaderboard").await;
+        assert_eq!(last_text(&slack, "chat.postMessage"), "リーダーボードにはまだ記録がありません。");
+
+        send(&context, "U_TEST", "start").await;
+        send(&context, "U_TEST", "0 0").await;
+        // 名前を取得できないユーザーはユーザーIDで表示する
+        slack.push_json("users.profile.get", serde_json::json!({"ok": false, "error": "user_not_found"}));
+        send(&context, "U_OTHER", "leaderboard").await;
+        let leaderboard = last_text(&slack, "chat.postMessage");
+        assert!(leaderboard.starts_with(":crown: リーダーボード") && leaderboard.contains("\n1. U_TEST: +"), "{}", leaderboard);
+        assert_eq!(leaderboard.lines().count(), 2);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn undo_is_only_for_the_last_player_once() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("undo");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "start").await;
+        let start_count = get_object_count(&context).await;
+        send(&context, "U_TEST", "0 0").await;
+        assert_ne!(get_object_count(&context).await, start_count);
+
+        send(&context, "U_OTHER", "undo").await;
+        assert_eq!(last_text(&slack, "chat.postEphemeral"), "<@U_OTHER> 取り消しは直前のターンを行ったユーザーのみ可能です。");
+        send(&context, "U_TEST", "undo").await;
+        assert!(last_text(&slack, "files.completeUploadExternal").starts_with("<@U_TEST> 直前のターンを取り消しました。"));
+        assert_eq!(get_object_count(&context).await, start_count);
+        // 取り消したターンをもう一度取り消すことはできない
+        let uploads = slack.get_requests("files.completeUploadExternal").len();
+        send(&context, "U_TEST", "undo").await;
+        assert_eq!(slack.get_requests("chat.postEphemeral").len(), 2);
+        assert_eq!(slack.get_requests("files.completeUploadExternal").len(), uploads);
+        assert_eq!(get_object_count(&context).await, start_count);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn games_start_only_with_start() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("start");
+        let context = test_context(&data_dir, &slack);
+        // ステージがない場合は、数値のコマンドでもステージを作らずに開始方法を案内する
+        send(&context, "U_TEST", "0 0").await;
+        assert!(last_text(&slack, "chat.postMessage").starts_with("現在ステージはありません。"));
+        assert_eq!(get_object_count(&context).await, None);
+        assert!(slack.get_requests("files.completeUploadExternal").is_empty());
+
+        send(&context, "U_TEST", "start").await;
+        assert!(get_object_count(&context).await.is_some());
+        send(&context, "U_TEST", "0 0").await;
+        let object_count = get_object_count(&context).await;
+        // 進行中のstartは現在のタワーを表示するだけでターンを進めない
+        send(&context, "U_OTHER", "start").await;
+        assert!(last_text(&slack, "files.completeUploadExternal").starts_with("既にゲームが進行中です。"));
+        assert_eq!(get_object_count(&context).await, object_count);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn joined_players_take_turns_in_order() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("turn_order");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_A", "start").await;
+        send(&context, "U_A", "join").await;
+        send(&context, "U_B", "join").await;
+        assert_eq!(last_text(&slack, "chat.postMessage"), "<@U_B> ターン順に参加しました。(参加者: 2人)\n現在は <@U_A> さんの番です。");
+
+        // 自分の番でなければ断り、ターンを進めると成功しても失敗しても次の人の番になる
+        send(&context, "U_B", "0 0").await;
+        assert_eq!(last_text(&slack, "chat.postEphemeral"), "<@U_B> 現在は <@U_A> さんの番です。");
+        send(&context, "U_A", "0 0").await;
+        assert!(last_text(&slack, "chat.update").contains("次は <@U_B> さんの番です。"));
+        send(&context, "U_A", "0 0").await;
+        assert_eq!(last_text(&slack, "chat.postEphemeral"), "<@U_A> 現在は <@U_B> さんの番です。");
+        assert_eq!(slack.get_requests("chat.update").len(), 1);
+
+        // 番の人が抜けても次の人の番になり、全員抜けると誰でもプレイできる
+        send(&context, "U_B", "leave").await;
+        assert_eq!(last_text(&slack, "chat.postMessage"), "<@U_B> ターン順から抜けました。(参加者: 1人)\n現在は <@U_A> さんの番です。");
+        send(&context, "U_A", "leave").await;
+        assert!(last_text(&slack, "chat.postMessage").ends_with("参加者がいないため、誰でもプレイできます。"));
+        send(&context, "U_C", "0 0").await;
+        assert_eq!(slack.get_requests("chat.update").len(), 2);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn consecutive_play_follows_the_solo_setting() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("solo");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "start").await;
+        // 最初のピースは誰でも落とせるが、同じユーザーは続けて落とせない
+        send(&context, "U_TEST", "0 0").await;
+        send(&context, "U_TEST", "0.1 0").await;
+        assert_eq!(last_text(&slack, "chat.postEphemeral"), "<@U_TEST> 連続でプレイできません。他の人の後にどうぞ :pray:");
+        assert_eq!(slack.get_requests("chat.update").len(), 1);
+
+        // 連続プレイを許可すると続けて落とせ、禁止し直すと再び断る
+        send(&context, "U_TEST", "settings solo on").await;
+        assert_eq!(last_text(&slack, "chat.postMessage"), "同じユーザーの連続プレイを許可しました。");
+        send(&context, "U_TEST", "0.1 0").await;
+        assert_eq!(slack.get_requests("chat.update").len(), 2);
+        send(&context, "U_TEST", "settings solo off").await;
+        send(&context, "U_TEST", "-0.1 0").await;
+        assert_eq!(slack.get_requests("chat.postEphemeral").len(), 2);
+        assert_eq!(slack.get_requests("chat.update").len(), 2);
+        // 他のユーザーの後は再び落とせる
+        send(&context, "U_OTHER", "-0.1 0").await;
+        send(&context, "U_TEST", "-0.2 0").await;
+        assert_eq!(slack.get_requests("chat.update").len(), 4);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn queued_turns_are_replaced_per_user_and_bounded() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("queue");
+        let context = test_context(&data_dir, &slack);
+        send(&context, "U_TEST", "start").await;
+
+        // 計算中のターンの代わりにステージのロックを持ったまま、順番待ちのコマンドを送る
+        let channel_stage = get_test_channel_stage(&context).await;
+        let guard = channel_stage.lock().await;
+        let mut replies = Vec::new();
+        for (user_id, text) in [("U_A", "0 0"), ("U_A", "0.3 0"), ("U_B", "0.1 0"), ("U_C", "-0.1 0"), ("U_D", "0 0")] {
+            let message = slack::Message { user_id: user_id.to_string(), ..test_message("app_mention", "C_TEST", &format!("<@U_BOT> {}", text)) };
+            handle_message(context.clone(), message).await;
+            while slack.get_requests("chat.postEphemeral").len() <= replies.len() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            replies.push(last_text(&slack, "chat.postEphemeral"));
+        }
+        assert_eq!(replies[..4], [
+            "<@U_A> :hourglass: 順番待ち 2番目",
+            "<@U_A> :hourglass: 順番待ち 2番目 (前のコマンドを置き換えました)",
+            "<@U_B> :hourglass: 順番待ち 3番目",
+            "<@U_C> :hourglass: 順番待ち 4番目",
+        ]);
+        assert!(replies[4].starts_with("<@U_D> 現在計算中で、順番待ちもいっぱいです。"), "{}", replies[4]);
+
+        // ロックを離すと順番待ちの順に処理し、置き換えたコマンドは1回だけ処理する
+        drop(guard);
+        wait_for_turns(&context).await;
+        assert_eq!(slack.get_requests("chat.update").len(), 3);
+        let channel_stage = channel_stage.lock().await;
+        let user_ids: Vec<String> = channel_stage.stage.as_ref().unwrap().get_objects().iter().filter_map(|object| object.user_id.clone()).collect();
+        assert_eq!(user_ids, vec!["U_A", "U_B", "U_C"]);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    // 計算中のターンが全て終わるまで待つ
+    async fn wait_for_turns(context: &AppContext) {
+        loop {
+            let mut turn_tasks = std::mem::take(&mut *context.turn_tasks.lock().unwrap());
+            if turn_tasks.is_empty() { return; }
+            while let Some(result) = turn_tasks.join_next().await { result.unwrap(); }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn many_messages_across_channels_are_all_handled() {
+        // 8つのタスクから40チャンネルに合計400件のコマンドを同時に送っても、詰まらずに全て処理される
+        // (順番待ちがいっぱいで断られたコマンドは、断りの通知で処理済みとみなす)
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("stress");
+        let context = test_context(&data_dir, &slack);
+        let (channel_count, message_count) = (40, 400);
+        let producers: Vec<_> = (0..8).map(|producer| {
+            let context = context.clone();
+            tokio::spawn(async move {
+                for index in (producer..message_count).step_by(8) {
+                    let message = slack::Message {
+                        user_id: format!("U{:03}", index),
+                        ..test_message("app_mention", &format!("C{:02}", index % channel_count), "<@U_BOT> status")
+                    };
+                    handle_message(context.clone(), message).await;
+                }
+            })
+        }).collect();
+        // 定期的な保存も同時に行う
+        let saver = {
+            let (stages, data_dir) = (Arc::clone(&context.stages), data_dir.clone());
+            tokio::spawn(async move {
+                loop {
+                    save_channel_stages(&stages, &data_dir, false).await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let all_handled = async {
+            for producer in producers { producer.await.unwrap(); }
+            wait_for_turns(&context).await;
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(60), all_handled).await.expect("the messages were not handled in time (deadlock?)");
+        saver.abort();
+
+        assert_eq!(context.stages.read().await.len(), channel_count);
+        let replies = slack.get_requests("chat.postMessage").len();
+        let rejected = slack.get_requests("chat.postEphemeral").iter().filter(|request| request.get_form()["text"].contains("いっぱい")).count();
+        assert_eq!(replies + rejected, message_count);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn unavailable_channel_removes_the_stage() {
+        // 投稿がchannel_not_foundで失敗した場合は、再試行せずにチャンネルのステージを保存したファイルごと削除する
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("channel_not_found");
+        let context = test_context(&data_dir, &slack);
+        for channel_id in ["C_GONE", "C_TEST"] {
+            handle_message(context.clone(), test_message("app_mention", channel_id, "<@U_BOT> start")).await;
+            wait_for_turns(&context).await;
+        }
+        save_channel_stages(&context.stages, &data_dir, true).await;
+        assert!(get_channel_stage_path(&data_dir, "C_GONE", &None).exists());
+
+        let posts = slack.get_requests("chat.postMessage").len();
+        for method in ["chat.postMessage", "chat.update", "files.completeUploadExternal"] {
+            slack.push_json(method, serde_json::json!({"ok": false, "error": "channel_not_found"}));
+        }
+        handle_message(context.clone(), test_message("app_mention", "C_GONE", "<@U_BOT> 0 0")).await;
+        wait_for_turns(&context).await;
+        assert_eq!(slack.get_requests("chat.postMessage").len(), posts + 1);
+        let stages = context.stages.read().await;
+        assert!(!stages.contains_key(&("C_GONE".to_string(), None)));
+        assert!(stages.contains_key(&("C_TEST".to_string(), None)));
+        assert!(!get_channel_stage_path(&data_dir, "C_GONE", &None).exists());
+        assert!(get_channel_stage_path(&data_dir, "C_TEST", &None).exists());
+        // 失敗したことを知らせるメッセージも送らない
+        assert!(slack.get_requests("chat.postEphemeral").is_empty());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_at_game_over_still_end_the_game() {
+        // ゲーム終了の画像を投稿できなくても、ステージはリセットされて次のゲームを開始できる
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("game_over_upload");
+        let context = test_context(&data_dir, &slack);
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> start 1")).await;
+        wait_for_turns(&context).await;
+
+        slack.push_json("files.completeUploadExternal", serde_json::json!({"ok": false, "error": "not_in_channel"}));
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> 0 0")).await;
+        wait_for_turns(&context).await;
+        let channel_stage = Arc::clone(&context.stages.read().await[&("C_TEST".to_string(), None)]);
+        assert!(channel_stage.lock().await.stage.is_none());
+        let updates = slack.get_requests("chat.update");
+        assert!(updates.last().unwrap().get_form()["text"].contains("ゲーム終了"));
+        // 失敗のリアクションも付けない
+        assert!(slack.get_requests("reactions.add").iter().all(|request| request.get_form()["name"] != "x"));
+
+        let posts = slack.get_requests("chat.postMessage").len();
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> 0 0")).await;
+        wait_for_turns(&context).await;
+        let requests = slack.get_requests("chat.postMessage");
+        assert_eq!(requests.len(), posts + 1);
+        assert!(requests[posts].get_form()["text"].starts_with("現在ステージはありません。"));
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn archived_or_left_channels_remove_the_stages() {
+        // アーカイブされたチャンネルと、botが外されたチャンネルのステージ (スレッドごとのステージを含む) を削除する
+        // 他のメンバーが退出した場合は残す
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("archive");
+        let context = test_context(&data_dir, &slack);
+        for (channel_id, thread_ts) in [("C_ARCHIVED", None), ("C_ARCHIVED", Some("1700000000.000100")), ("C_KICKED", None), ("C_TEST", None)] {
+            let message = slack::Message { thread_ts: thread_ts.map(str::to_string), ..test_message("app_mention", channel_id, "<@U_BOT> start") };
+            handle_message(context.clone(), message).await;
+            wait_for_turns(&context).await;
+        }
+        assert_eq!(context.stages.read().await.len(), 4);
+
+        let events = [
+            test_message("channel_archive", "C_ARCHIVED", ""),
+            slack::Message { user_id: "U_BOT".to_string(), ..test_message("member_left_channel", "C_KICKED", "") },
+            test_message("member_left_channel", "C_TEST", ""),
+        ];
+        for message in events {
+            handle_message(context.clone(), message).await;
+        }
+        let keys: Vec<StageKey> = context.stages.read().await.keys().cloned().collect();
+        assert_eq!(keys, vec![("C_TEST".to_string(), None)]);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    // 出力されたログを記録する
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+    impl CapturedLogs {
+        fn get_lines(&self) -> Vec<String> {
+            return String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect();
+        }
+    }
+
+    #[tokio::test]
+    async fn turns_log_the_channel_the_user_and_the_failure() {
+        // このスレッドで出力されたこのクレートのログを記録する (ターンのタスクも同じスレッドで動く)
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({ let logs = logs.clone(); move || logs.clone() })
+            .with_ansi(false)
+            .with_env_filter(tracing_subscriber::EnvFilter::new("slack_tower_battle=debug"))
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("logging");
+        let context = test_context(&data_dir, &slack);
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> start")).await;
+        wait_for_turns(&context).await;
+        slack.push_json("files.completeUploadExternal", serde_json::json!({"ok": false, "error": "not_in_channel"}));
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> 0 0")).await;
+        wait_for_turns(&context).await;
+        for method in ["chat.postEphemeral", "chat.postMessage"] {
+            slack.push_json(method, serde_json::json!({"ok": false, "error": "not_in_channel"}));
+        }
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> left please")).await;
+        wait_for_turns(&context).await;
+
+        // ターンのログにはチャンネルとユーザーが付く
+        let lines = logs.get_lines();
+        let find = |parts: &[&str]| lines.iter().find(|line| parts.iter().all(|part| line.contains(part))).cloned()
+            .unwrap_or_else(|| panic!("no log line with {:?} in {:#?}", parts, lines));
+        let span = "turn{channel_id=C_TEST user_id=U_TEST}";
+        find(&["INFO", span, "turn: channel C_TEST, result Success"]);
+        // Slack APIの呼び出しの結果 (失敗した場合はレスポンスの本文も残す)
+        find(&["DEBUG", span, "api call succeeded", "method=\"files.getUploadURLExternal\"", "elapsed_ms="]);
+        find(&["WARN", span, "api call failed: not_in_channel", "method=\"files.completeUploadExternal\"", "body="]);
+        // 画像の投稿に失敗しても結果は残るので警告にとどめる
+        find(&["WARN", span, "failed to post result image: files.completeUploadExternal failed: not_in_channel"]);
+        // 別のタスクで失敗したターンのエラー
+        find(&["ERROR", span, "turn failed: chat.postMessage failed: not_in_channel"]);
+
+        // 物理演算の結果 (実行したステップ数と結果)
+        let mut stage = stage::Stage::new(test_shapes(), stage::Difficulty::Normal, stage::GroundVariant::Flat, 1, test_config());
+        stage.next_turn(None, stage::TurnInput::default()).unwrap();
+        stage.next_turn(Some("U_TEST".to_string()), stage::TurnInput::default()).unwrap();
+        let lines = logs.get_lines();
+        assert!(lines.iter().any(|line| line.contains("physics finished") && line.contains("steps_executed=") && line.contains("result=Success")), "{:#?}", lines);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_counts_synthetic_turns() {
+        // ゲームを開始して2人が1手ずつ進めると、/metricsのターンの数と物理演算の時間、Slack APIの呼び出しが増える
+        // (他のテストも同じメトリクスに記録するので、増えた量が少なくともこのテストの分あるかを確認する)
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("metrics");
+        let context = test_context(&data_dir, &slack);
+        let health_state = health::HealthState { shapes_loaded: Arc::new(std::sync::atomic::AtomicBool::new(true)), websocket: slack::WebsocketHealth::new() };
+        let base_url = health::start_test_server(health_state);
+        let scrape = || async { reqwest::get(format!("{}/metrics", base_url)).await.unwrap().text().await.unwrap() };
+
+        let before = scrape().await;
+        for (user_id, text) in [("U_TEST", "<@U_BOT> start"), ("U_TEST", "<@U_BOT> 0 0"), ("U_OTHER", "<@U_BOT> 0.1 0")] {
+            let message = slack::Message { user_id: user_id.to_string(), ..test_message("app_mention", "C_TEST", text) };
+            handle_message(context.clone(), message).await;
+            wait_for_turns(&context).await;
+        }
+        let after = scrape().await;
+        let moved = |series: &str| metrics::read_value(&after, series) - metrics::read_value(&before, series);
+        assert!(moved("tower_turns_total{result=\"Success\"}") >= 2.0, "{}", after);
+        assert!(moved("tower_physics_duration_seconds_count") >= 2.0);
+        assert!(moved("tower_render_duration_seconds_count") >= 2.0);
+        assert!(moved("slack_api_duration_seconds_count{method=\"chat.postMessage\"}") >= 1.0);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn acks_stay_fast_during_a_long_turn() {
+        // 1つのスレッドで動くランタイムでも、物理演算の間に他のタスク (受信の確認の送信など) が待たされない
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("long_turn");
+        let mut context = test_context(&data_dir, &slack);
+        // 重力がなく、回転させて落としたピースが静止しないので、実時間の上限 (1秒) まで物理演算を続けるステージ
+        context.stage_config = stage::StageConfig { gravity: 0.0, timeout_sec: 1.0e6, wall_clock_budget_sec: 1.0, ..test_config() };
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> start")).await;
+        wait_for_turns(&context).await;
+
+        let turn_start = std::time::Instant::now();
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> 0 0 spin=5")).await;
+        let turn = wait_for_turns(&context);
+        tokio::pin!(turn);
+        let tick = std::time::Duration::from_millis(10);
+        let mut max_delay = std::time::Duration::ZERO;
+        loop {
+            let tick_start = std::time::Instant::now();
+            tokio::select! {
+                _ = &mut turn => { break; },
+                _ = tokio::time::sleep(tick) => { max_delay = max_delay.max(tick_start.elapsed() - tick); },
+            };
+        }
+        assert!(turn_start.elapsed() >= std::time::Duration::from_secs(1));
+        assert!(max_delay < std::time::Duration::from_millis(200), "a task waited {} ms during the turn", max_delay.as_millis());
+        // 計算中のメッセージを結果に書き換えている
+        assert!(!slack.get_requests("chat.update").is_empty());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn consecutive_moves_are_rejected_during_the_turn() {
+        // 計算中に同じユーザーが次のコマンドを送った場合は、順番待ちにせず計算が終わる前に断る
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("consecutive");
+        let mut context = test_context(&data_dir, &slack);
+        // 重力がなく、回転させて落としたピースが静止しないので、実時間の上限 (1秒) まで物理演算を続けるステージ
+        context.stage_config = stage::StageConfig { gravity: 0.0, timeout_sec: 1.0e6, wall_clock_budget_sec: 1.0, ..test_config() };
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> start")).await;
+        wait_for_turns(&context).await;
+
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> 0 0 spin=5")).await;
+        while !slack.get_requests("chat.postMessage").iter().any(|request| request.get_form()["text"].contains("計算中")) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> 0.5 0")).await;
+        let rejected = async {
+            while !slack.get_requests("chat.postEphemeral").iter().any(|request| request.get_form()["text"].contains("連続でプレイできません")) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_millis(500), rejected).await.expect("the consecutive move waited for the turn");
+        // 断ったコマンドは順番待ちに入れない
+        let channel_stage = Arc::clone(&context.stages.read().await[&("C_TEST".to_string(), None)]);
+        assert!(channel_stage.try_lock().is_err());
+        assert!(context.turn_queues.lock().unwrap().values().all(|turn_queue| turn_queue.lock().unwrap().turns.is_empty()));
+        wait_for_turns(&context).await;
+        assert_eq!(slack.get_requests("chat.update").len(), 1);
+        assert_eq!(slack.get_requests("chat.postEphemeral").len(), 1);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_the_turn_in_progress_and_saves() {
+        let slack = slack::MockSlack::start().await;
+        let data_dir = temp_data_dir("shutdown");
+        let context = test_context(&data_dir, &slack);
+        handle_message(context.clone(), test_message("app_mention", "C_TEST", "<@U_BOT> start")).await;
+        wait_for_turns(&context).await;
+
+        // ステージのロックを持ったまま時間のかかるターン
+        let channel_stage = Arc::clone(&context.stages.read().await[&("C_TEST".to_string(), None)]);
+        let finished = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        {
+            let finished = Arc::clone(&finished);
+            context.turn_tasks.lock().unwrap().spawn(async move {
+                let _channel_stage = channel_stage.lock().await;
+                tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                finished.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+        }
+        // 猶予の間に終わったターンは中断せず、ターンが終わってから保存する
+        assert_eq!(finish_turns(&context, std::time::Duration::from_secs(10)).await, 0);
+        assert!(finished.load(std::sync::atomic::Ordering::SeqCst));
+        assert!(get_channel_stage_path(&data_dir, "C_TEST", &None).exists());
+
+        // 猶予の間に終わらないターンは中断する
+        context.turn_tasks.lock().unwrap().spawn(futures::future::pending::<()>());
+        let shutdown_start = std::time::Instant::now();
+        assert_eq!(finish_turns(&context, std::time::Duration::from_millis(100)).await, 1);
+        assert!(shutdown_start.elapsed() < std::time::Duration::from_secs(5));
+        assert!(context.turn_tasks.lock().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[test]
+    fn saved_stage_is_restored_and_continues() {
+        // 1ターン進めたステージを保存して読み込むと、同じ状態から同じ結果でゲームを続けられる
+        let data_dir = temp_data_dir("save_load");
+        let mut channel_stage = ChannelStage::new("C_TEST".to_string(), false, None);
+        let mut original = stage::Stage::new(test_shapes(), stage::Difficulty::Normal, stage::GroundVariant::Flat, 1, test_config());
+        original.next_turn(None, stage::TurnInput::default()).unwrap();
+        let report = original.next_turn(Some("U_TEST".to_string()), stage::TurnInput::default()).unwrap();
+        assert_eq!(report.result, stage::TurnResult::Success);
+        channel_stage.stage = Some(original);
+        channel_stage.record = Some(Record { height: report.height, user_id: "U_TEST".to_string(), time: Local::now() });
+        save_channel_stage(&data_dir, &channel_stage).unwrap();
+
+        let stages = load_channel_stages(&data_dir);
+        let key: StageKey = ("C_TEST".to_string(), None);
+        let mut restored = stages[&key].try_lock().unwrap();
+        assert!(!restored.changed);
+        assert_eq!(restored.record.as_ref().map(|record| record.height), Some(report.height));
+        let original = channel_stage.stage.as_mut().unwrap();
+        let restored = restored.stage.as_mut().unwrap();
+        assert_eq!(restored.get_object_count(), original.get_object_count());
+        assert_eq!(restored.get_height().to_bits(), original.get_height().to_bits());
+
+        let input = stage::TurnInput { translation_x: 0.1, rotation: 30.0, ..stage::TurnInput::default() };
+        let expected = original.next_turn(Some("U_TEST".to_string()), input.clone()).unwrap();
+        let actual = restored.next_turn(Some("U_TEST".to_string()), input).unwrap();
+        assert_eq!(actual.result, expected.result);
+        assert_eq!(actual.height.to_bits(), expected.height.to_bits());
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+}
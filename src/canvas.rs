@@ -1,14 +1,448 @@
 use std::rc::Rc;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{ Arc, OnceLock };
 use usvg::NodeExt;
+use serde::{Serialize, Deserialize};
+
+// 画像の読み込み、描画、書き出しのエラー
+#[derive(Debug, thiserror::Error)]
+pub enum CanvasError {
+    #[error("failed to read the file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to parse the svg: {0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("failed to parse the svg: {0}")]
+    Svg(#[from] usvg::Error),
+    // アイコンなどの画像のデコードとエンコード
+    #[error("failed to convert the image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("failed to encode the png: {0}")]
+    Png(String),
+    #[error("failed to encode the gif: {0}")]
+    Gif(#[from] gif::EncodingError),
+    // 描画に使う画像やパスを作れなかった (大きさが0の場合など)
+    #[error("failed to render: {0}")]
+    Render(&'static str),
+}
+
+pub type CanvasResult<T> = Result<T, CanvasError>;
+
+// SVGから読み込んだオブジェクトの形状と材質
+// 材質はpathのidで指定する (例: id="rock_f1.2_r0.3_d3" で摩擦係数1.2、反発係数0.3、密度3)
+// 指定がない材質はNone (ステージの既定値を使う)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShapeDef {
+    pub vertices: Vec<(f64, f64)>,
+    pub friction: Option<f64>,
+    pub restitution: Option<f64>,
+    pub density: Option<f64>,
+}
+impl ShapeDef {
+    pub fn new(vertices: Vec<(f64, f64)>) -> Self {
+        return ShapeDef { vertices, friction: None, restitution: None, density: None };
+    }
+    // pathのidから材質を読み取る (数値として解釈できない指定は無視する)
+    pub fn from_id(vertices: Vec<(f64, f64)>, id: &str) -> Self {
+        let mut shape = ShapeDef::new(vertices);
+        for token in id.split('_').skip(1) {
+            let mut chars = token.chars();
+            let key = chars.next();
+            let value = chars.as_str().parse::<f64>().ok().filter(|value| *value >= 0.0);
+            match key {
+                Some('f') => { shape.friction = value.or(shape.friction); },
+                Some('r') => { shape.restitution = value.or(shape.restitution); },
+                Some('d') => { shape.density = value.filter(|value| *value > 0.0).or(shape.density); },
+                _ => {},
+            };
+        }
+        return shape;
+    }
+    // 左右反転した形状 (材質はそのまま、頂点の順番は逆にして回り方を揃える)
+    pub fn mirrored(&self) -> Self {
+        return ShapeDef { vertices: self.vertices.iter().rev().map(|(x, y)| (-x, *y)).collect(), ..self.clone() };
+    }
+    // 頂点の座標をfactor倍した形状 (材質はそのまま)
+    pub fn scaled(&self, factor: f64) -> Self {
+        return ShapeDef { vertices: self.vertices.iter().map(|(x, y)| (x * factor, y * factor)).collect(), ..self.clone() };
+    }
+    // 符号付き面積 (頂点の回り方で符号が変わる)
+    fn get_signed_area(&self) -> f64 {
+        let mut area = 0.0;
+        for (index, vertex) in self.vertices.iter().enumerate() {
+            let next = self.vertices[(index + 1) % self.vertices.len()];
+            area += vertex.0 * next.1 - next.0 * vertex.1;
+        }
+        return area * 0.5;
+    }
+    // 連続する重複した頂点を取り除き、頂点の回り方を揃える
+    pub fn normalized(&self) -> Self {
+        let mut vertices: Vec<(f64, f64)> = Vec::new();
+        for vertex in &self.vertices {
+            if vertices.last() != Some(vertex) { vertices.push(*vertex); }
+        }
+        while vertices.len() > 1 && vertices.first() == vertices.last() { vertices.pop(); }
+        let mut shape = ShapeDef { vertices, ..self.clone() };
+        if shape.get_signed_area() < 0.0 { shape.vertices.reverse(); }
+        return shape;
+    }
+    // オブジェクトとして使えない形状の場合は理由を返す
+    pub fn validate(&self) -> Result<(), String> {
+        let mut distinct_vertices = self.vertices.clone();
+        distinct_vertices.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        distinct_vertices.dedup();
+        if distinct_vertices.len() < 3 { return Err("頂点が3つ未満です".to_string()); }
+        if self.vertices.iter().any(|(x, y)| !x.is_finite() || !y.is_finite()) { return Err("頂点の座標が不正です".to_string()); }
+        if self.get_signed_area().abs() < 1.0 { return Err("面積がありません".to_string()); }
+        return Ok(());
+    }
+    // 辺同士が交差しているか (隣り合う辺は除く)
+    pub fn is_self_intersecting(&self) -> bool {
+        fn cross(o: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+            return (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+        }
+        let count = self.vertices.len();
+        for i in 0..count {
+            let (a1, a2) = (self.vertices[i], self.vertices[(i + 1) % count]);
+            for j in (i + 2)..count {
+                if i == 0 && j == count - 1 { continue; }
+                let (b1, b2) = (self.vertices[j], self.vertices[(j + 1) % count]);
+                let d1 = cross(b1, b2, a1);
+                let d2 = cross(b1, b2, a2);
+                let d3 = cross(a1, a2, b1);
+                let d4 = cross(a1, a2, b2);
+                if d1 * d2 < 0.0 && d3 * d4 < 0.0 { return true; }
+            }
+        }
+        return false;
+    }
+}
+
+// 曲線を折れ線にする時の許容誤差 (拡大後のpx)
+const CURVE_TOLERANCE: f64 = 0.5;
+
+// 3次ベジェ曲線を折れ線に分割してpointsに追加する (始点は追加しない)
+// 制御点と弦の距離が許容誤差未満になるまで半分に分割する
+fn flatten_cubic_bezier(p0: (f64, f64), p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), tolerance: f64, depth: u32, points: &mut Vec<(f64, f64)>) {
+    let chord = (p3.0 - p0.0, p3.1 - p0.1);
+    let chord_length = (chord.0 * chord.0 + chord.1 * chord.1).sqrt();
+    let distance = |p: (f64, f64)| -> f64 {
+        if chord_length == 0.0 { return ((p.0 - p0.0).powi(2) + (p.1 - p0.1).powi(2)).sqrt(); }
+        return ((p.0 - p0.0) * chord.1 - (p.1 - p0.1) * chord.0).abs() / chord_length;
+    };
+    if depth >= 16 || (distance(p1) < tolerance && distance(p2) < tolerance) {
+        points.push(p3);
+        return;
+    }
+    let mid = |a: (f64, f64), b: (f64, f64)| ((a.0 + b.0) * 0.5, (a.1 + b.1) * 0.5);
+    let p01 = mid(p0, p1);
+    let p12 = mid(p1, p2);
+    let p23 = mid(p2, p3);
+    let p012 = mid(p01, p12);
+    let p123 = mid(p12, p23);
+    let p0123 = mid(p012, p123);
+    flatten_cubic_bezier(p0, p01, p012, p0123, tolerance, depth + 1, points);
+    flatten_cubic_bezier(p0123, p123, p23, p3, tolerance, depth + 1, points);
+}
+
+// 楕円 (円) を多角形で近似する時の頂点数
+const ELLIPSE_SEGMENTS: usize = 32;
+
+// 数値の属性を読む (単位はpxのみ対応)
+fn parse_length(value: Option<&str>) -> Option<f64> {
+    let value = value?.trim();
+    let value = value.strip_suffix("px").unwrap_or(value);
+    return value.parse::<f64>().ok().filter(|value| value.is_finite());
+}
+
+// rect, circle, ellipse, polygon, polylineの頂点を求める
+// 属性が不正な場合はNoneを返す
+fn get_primitive_points(node: &roxmltree::Node) -> Option<Vec<(f64, f64)>> {
+    match node.tag_name().name() {
+        "rect" => {
+            let x = parse_length(node.attribute("x")).unwrap_or(0.0);
+            let y = parse_length(node.attribute("y")).unwrap_or(0.0);
+            let width = parse_length(node.attribute("width"))?;
+            let height = parse_length(node.attribute("height"))?;
+            return Some(vec![(x, y), (x + width, y), (x + width, y + height), (x, y + height)]);
+        },
+        "circle" | "ellipse" => {
+            let cx = parse_length(node.attribute("cx")).unwrap_or(0.0);
+            let cy = parse_length(node.attribute("cy")).unwrap_or(0.0);
+            let (rx, ry) = if node.tag_name().name() == "circle" {
+                let r = parse_length(node.attribute("r"))?;
+                (r, r)
+            } else {
+                (parse_length(node.attribute("rx"))?, parse_length(node.attribute("ry"))?)
+            };
+            return Some((0..ELLIPSE_SEGMENTS).map(|i| {
+                let angle = 2.0 * std::f64::consts::PI * (i as f64) / (ELLIPSE_SEGMENTS as f64);
+                (cx + rx * angle.cos(), cy + ry * angle.sin())
+            }).collect());
+        },
+        "polygon" | "polyline" => {
+            // polylineも始点と終点を結んで閉じた形状として扱う
+            let values = node.attribute("points")?
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|value| !value.is_empty())
+                .map(|value| value.parse::<f64>().ok().filter(|value| value.is_finite()))
+                .collect::<Option<Vec<f64>>>()?;
+            if values.len() % 2 != 0 { return None; }
+            return Some(values.chunks(2).map(|pair| (pair[0], pair[1])).collect());
+        },
+        _ => { return None; },
+    }
+}
+
+// 形状を中心に移動して拡大し、左右反転したものと一緒にshapesに追加する
+// 使えない形状は読み飛ばす (SVGを修正できるように番号を出力)
+fn push_shape(shapes: &mut Vec<ShapeDef>, mut shape: Vec<(f64, f64)>, id: &str, scale: f64) {
+    let mut rect = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for point in &shape {
+        if point.0 < rect.0 { rect.0 = point.0; }
+        if point.1 < rect.1 { rect.1 = point.1; }
+        if rect.2 < point.0 { rect.2 = point.0; }
+        if rect.3 < point.1 { rect.3 = point.1; }
+    }
+    let center = ((rect.0 + rect.2) * 0.5, (rect.1 + rect.3) * 0.5);
+    shape.iter_mut().for_each(|point| {
+        point.0 = (point.0 - center.0) * scale;
+        point.1 = (point.1 - center.1) * scale;
+    });
+    let shape = ShapeDef::from_id(shape, id).normalized();
+    if let Err(reason) = shape.validate() {
+        tracing::warn!("shape {} (id: \"{}\") was rejected: {}", shapes.len() / 2, id, reason);
+        return;
+    }
+    if shape.is_self_intersecting() {
+        tracing::warn!("shape {} (id: \"{}\") is self-intersecting, its convex hull is used instead", shapes.len() / 2, id);
+    }
+    let mirrored_shape = shape.mirrored();
+    shapes.push(shape);
+    shapes.push(mirrored_shape);
+}
+
+// 文字列の配置 (指定したx座標が文字列の左端/中央/右端になる)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextAnchor {
+    Start,
+    Middle,
+    End,
+}
+
+// 文字の描画に使うフォントを置くディレクトリ
+const FONT_DIR: &str = "resources/fonts";
+
+// 文字の描画に使うフォント (最初に文字を描画する時に1度だけ読み込む)
+// TOWER_FONT_PATHのフォント、FONT_DIRのフォントの順に優先して使い、
+// それらにない文字 (日本語や絵文字など) はシステムのフォントから探す
+static TEXT_OPTIONS: OnceLock<usvg::Options> = OnceLock::new();
+fn get_text_options() -> &'static usvg::Options {
+    return TEXT_OPTIONS.get_or_init(|| {
+        let mut options = usvg::Options::default();
+        if let Ok(path) = std::env::var("TOWER_FONT_PATH") {
+            if let Err(error) = options.fontdb.load_font_file(&path) {
+                tracing::warn!("failed to load font {}: {}", path, error);
+            }
+        }
+        options.fontdb.load_fonts_dir(FONT_DIR);
+        let bundled_family = options.fontdb.faces().first().map(|face| face.family.clone());
+        options.fontdb.load_system_fonts();
+        // 同梱のフォントがない場合はシステムのゴシック体 (名前にSansを含むフォント) を優先する
+        let system_family = options.fontdb.faces().iter()
+            .find(|face| face.family.contains("Sans"))
+            .or_else(|| options.fontdb.faces().first())
+            .map(|face| face.family.clone());
+        match bundled_family.or(system_family) {
+            Some(family) => {
+                options.fontdb.set_sans_serif_family(family.clone());
+                options.font_family = family;
+            },
+            None => { tracing::warn!("no fonts found, text will not be rendered"); },
+        }
+        options
+    });
+}
+
+// 円のパス (4つの3次ベジェ曲線で近似)
+fn get_circle_path(center_x: f64, center_y: f64, radius: f64) -> usvg::PathData {
+    // 4分の1の円弧を3次ベジェ曲線で近似する時の制御点の距離の比
+    const K: f64 = 0.5522847498;
+    let (x, y, r, k) = (center_x, center_y, radius, radius * K);
+    let mut path = usvg::PathData::new();
+    path.push_move_to(x + r, y);
+    path.push_curve_to(x + r, y + k, x + k, y + r, x, y + r);
+    path.push_curve_to(x - k, y + r, x - r, y + k, x - r, y);
+    path.push_curve_to(x - r, y - k, x - k, y - r, x, y - r);
+    path.push_curve_to(x + k, y - r, x + r, y - k, x + r, y);
+    path.push_close_path();
+    return path;
+}
+
+// 色相 (0〜360度) から彩度と明度が一定の色を作る
+fn hue_to_rgb(hue: f64) -> (u8, u8, u8) {
+    let (saturation, value) = (0.65, 0.85);
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    return (((r + m) * 255.0) as u8, ((g + m) * 255.0) as u8, ((b + m) * 255.0) as u8);
+}
+
+// 描画の方式
+// Usvg: SVGのツリーを組み立てて、encode_pngでresvgにまとめて描画する
+// Skia: ツリーを作らずにtiny-skiaで直接描画する (速いが、細部の描画結果はUsvgと少し異なる)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderBackend {
+    Usvg,
+    Skia,
+}
+
+// 既定の描画の方式 (TOWER_RENDER_BACKENDで選ぶ、最初に使う時に1度だけ読む)
+static DEFAULT_RENDER_BACKEND: OnceLock<RenderBackend> = OnceLock::new();
+impl Default for RenderBackend {
+    fn default() -> Self {
+        return *DEFAULT_RENDER_BACKEND.get_or_init(|| {
+            match std::env::var("TOWER_RENDER_BACKEND").as_deref() {
+                Ok("skia") => RenderBackend::Skia,
+                Ok("usvg") | Err(_) => RenderBackend::Usvg,
+                Ok(backend) => { tracing::warn!("unknown render backend {}, using usvg", backend); RenderBackend::Usvg },
+            }
+        });
+    }
+}
+
+// tiny-skiaで描画する場合の円形のパターンの大きさ (px)
+// パターンの端の色を外側に伸ばしても白になるように、周りに1pxの白い余白を付けて作る
+const SKIA_PATTERN_SIZE: u32 = 96;
+
+// tiny-skiaで描画する場合のパターン (usvgの場合はdefsに登録する)
+enum SkiaPattern {
+    // 円形に切り抜いた画像 (周りは白)
+    Image(tiny_skia::Pixmap),
+    // 上から下への線形グラデーション
+    Gradient((u8, u8, u8), (u8, u8, u8)),
+}
+
+// 白で塗ったパターンの画像と、その中央の円のパス (SKIA_PATTERN_SIZEの正方形に内接する)
+fn create_skia_circle_pattern() -> Option<(tiny_skia::Pixmap, tiny_skia::Path)> {
+    let mut pattern = tiny_skia::Pixmap::new(SKIA_PATTERN_SIZE + 2, SKIA_PATTERN_SIZE + 2)?;
+    pattern.fill(tiny_skia::Color::WHITE);
+    let radius = SKIA_PATTERN_SIZE as f32 * 0.5;
+    let circle = tiny_skia::PathBuilder::from_circle(radius + 1.0, radius + 1.0, radius)?;
+    return Some((pattern, circle));
+}
+
+// 画像を円形に切り抜いたパターン (画像は縦横比を保って円の外接正方形に収める)
+fn create_skia_image_pattern(data: &[u8]) -> CanvasResult<tiny_skia::Pixmap> {
+    let image = image::load_from_memory(data)?.to_rgba8();
+    let mut image_pixmap = tiny_skia::Pixmap::new(image.width(), image.height()).ok_or(CanvasError::Render("empty image"))?;
+    for (pixel, rgba) in image_pixmap.pixels_mut().iter_mut().zip(image.pixels()) {
+        *pixel = tiny_skia::ColorU8::from_rgba(rgba[0], rgba[1], rgba[2], rgba[3]).premultiply();
+    }
+    let (mut pattern, circle) = create_skia_circle_pattern().ok_or(CanvasError::Render("failed to create pattern"))?;
+    let mut clip_mask = tiny_skia::ClipMask::new();
+    clip_mask.set_path(pattern.width(), pattern.height(), &circle, tiny_skia::FillRule::Winding, true).ok_or(CanvasError::Render("failed to create clip mask"))?;
+    let size = SKIA_PATTERN_SIZE as f32;
+    let scale = size / image_pixmap.width().max(image_pixmap.height()) as f32;
+    let offset_x = 1.0 + (size - image_pixmap.width() as f32 * scale) * 0.5;
+    let offset_y = 1.0 + (size - image_pixmap.height() as f32 * scale) * 0.5;
+    let paint = tiny_skia::PixmapPaint { quality: tiny_skia::FilterQuality::Bicubic, ..tiny_skia::PixmapPaint::default() };
+    pattern.draw_pixmap(0, 0, image_pixmap.as_ref(), &paint,
+        tiny_skia::Transform::from_row(scale, 0.0, 0.0, scale, offset_x, offset_y), Some(&clip_mask));
+    return Ok(pattern);
+}
+
+fn to_skia_color(color: usvg::Color, opacity: f64) -> tiny_skia::Color {
+    return tiny_skia::Color::from_rgba8(color.red, color.green, color.blue, (opacity * 255.0).round() as u8);
+}
+
+// points (positionからの相対座標) の多角形をtiny-skiaで直接描画する
+// パターンは図形の外接矩形に合わせる (usvgのObjectBoundingBoxと同じ)
+fn draw_skia_shape(
+    pixmap: &mut tiny_skia::Pixmap,
+    patterns: &HashMap<String, SkiaPattern>,
+    fill: &Option<usvg::Fill>,
+    stroke: &Option<usvg::Stroke>,
+    points: &[(f64, f64)],
+    position: (f64, f64),
+    rotation: f64,
+) -> Option<()> {
+    let mut builder = tiny_skia::PathBuilder::new();
+    for (i, point) in points.iter().enumerate() {
+        if i == 0 { builder.move_to(point.0 as f32, point.1 as f32); }
+        else      { builder.line_to(point.0 as f32, point.1 as f32); }
+    }
+    builder.close();
+    let path = builder.finish()?;
+    let transform = tiny_skia::Transform::from_translate(position.0 as f32, position.1 as f32)
+        .pre_concat(tiny_skia::Transform::from_rotate(rotation as f32));
+
+    if let Some(fill) = fill {
+        let mut paint = tiny_skia::Paint { anti_alias: true, ..tiny_skia::Paint::default() };
+        let bounds = path.bounds();
+        let shader = match &fill.paint {
+            usvg::Paint::Color(color) => Some(tiny_skia::Shader::SolidColor(to_skia_color(*color, fill.opacity.value()))),
+            usvg::Paint::Link(id) => match patterns.get(id) {
+                Some(SkiaPattern::Image(pattern)) => {
+                    // 外接矩形の短い辺を直径とする円を中央に置く
+                    let diameter = bounds.width().min(bounds.height());
+                    let scale = diameter / SKIA_PATTERN_SIZE as f32;
+                    let left = bounds.left() + (bounds.width() - diameter) * 0.5 - scale;
+                    let top = bounds.top() + (bounds.height() - diameter) * 0.5 - scale;
+                    Some(tiny_skia::Pattern::new(pattern.as_ref(), tiny_skia::SpreadMode::Pad, tiny_skia::FilterQuality::Bicubic, 1.0,
+                        tiny_skia::Transform::from_row(scale, 0.0, 0.0, scale, left, top)))
+                },
+                Some(SkiaPattern::Gradient(top, bottom)) => tiny_skia::LinearGradient::new(
+                    tiny_skia::Point::from_xy(0.0, bounds.top()),
+                    tiny_skia::Point::from_xy(0.0, bounds.bottom()),
+                    vec![
+                        tiny_skia::GradientStop::new(0.0, tiny_skia::Color::from_rgba8(top.0, top.1, top.2, 255)),
+                        tiny_skia::GradientStop::new(1.0, tiny_skia::Color::from_rgba8(bottom.0, bottom.1, bottom.2, 255)),
+                    ],
+                    tiny_skia::SpreadMode::Pad,
+                    tiny_skia::Transform::identity(),
+                ),
+                None => None,
+            },
+        };
+        if let Some(shader) = shader {
+            paint.shader = shader;
+            pixmap.fill_path(&path, &paint, tiny_skia::FillRule::Winding, transform, None);
+        }
+    }
+
+    if let Some(stroke) = stroke {
+        if let usvg::Paint::Color(color) = stroke.paint {
+            let mut paint = tiny_skia::Paint { anti_alias: true, ..tiny_skia::Paint::default() };
+            paint.set_color(to_skia_color(color, stroke.opacity.value()));
+            let skia_stroke = tiny_skia::Stroke { width: stroke.width.value() as f32, ..tiny_skia::Stroke::default() };
+            pixmap.stroke_path(&path, &paint, &skia_stroke, transform, None);
+        }
+    }
+    return Some(());
+}
 
 pub struct Canvas {
     rtree: usvg::Tree,
+    // tiny-skiaで直接描画する場合の描画先とパターン (usvgで描画する場合はNoneで、rtreeに追加していく)
+    pixmap: Option<tiny_skia::Pixmap>,
+    patterns: HashMap<String, SkiaPattern>,
     fill: Option<usvg::Fill>,
     stroke: Option<usvg::Stroke>,
 }
 impl Canvas {
+    // 既定の描画の方式 (TOWER_RENDER_BACKEND) で描画するCanvas
     pub fn new(width: f64, height: f64) -> Self {
+        return Canvas::with_backend(width, height, RenderBackend::default());
+    }
+    pub fn with_backend(width: f64, height: f64, backend: RenderBackend) -> Self {
         let canvas_size = usvg::Size::new(width, height).unwrap();
         Canvas{
             rtree: usvg::Tree::create(usvg::Svg {
@@ -18,6 +452,8 @@ impl Canvas {
                     aspect: usvg::AspectRatio::default(),
                 },
             }),
+            pixmap: if backend == RenderBackend::Skia { tiny_skia::Pixmap::new(width.ceil() as u32, height.ceil() as u32) } else { None },
+            patterns: HashMap::new(),
             fill: None,
             stroke: None,
         }
@@ -32,6 +468,42 @@ impl Canvas {
             ..usvg::Fill::default()
         });
     }
+    // 半透明の塗りつぶし (opacityは0〜1)
+    pub fn set_translucent_color_fill(&mut self, red: u8, green: u8, blue: u8, opacity: f64) {
+        self.fill = Some(usvg::Fill {
+            paint: usvg::Paint::Color(usvg::Color::new_rgb(red, green, blue)),
+            opacity: usvg::Opacity::new(opacity),
+            ..usvg::Fill::default()
+        });
+    }
+    // 上から下への線形グラデーションで塗りつぶす (図形の外接矩形の上端がtop、下端がbottomの色)
+    // グラデーションはidでdefsに登録する (同じidは登録済みのものを使う)
+    pub fn set_gradient_fill(&mut self, id: String, top: (u8, u8, u8), bottom: (u8, u8, u8)) {
+        if self.pixmap.is_some() {
+            self.patterns.entry(id.clone()).or_insert(SkiaPattern::Gradient(top, bottom));
+        }
+        else if self.rtree.defs_by_id(&id).is_none() {
+            let stop = |offset: f64, color: (u8, u8, u8)| usvg::Stop {
+                offset: usvg::StopOffset::new(offset),
+                color: usvg::Color::new_rgb(color.0, color.1, color.2),
+                opacity: usvg::Opacity::default(),
+            };
+            self.rtree.append_to_defs(usvg::NodeKind::LinearGradient(usvg::LinearGradient {
+                id: id.clone(),
+                x1: 0.0,
+                y1: 0.0,
+                x2: 0.0,
+                y2: 1.0,
+                base: usvg::BaseGradient {
+                    units: usvg::Units::ObjectBoundingBox,
+                    transform: usvg::Transform::default(),
+                    spread_method: usvg::SpreadMethod::Pad,
+                    stops: vec![stop(0.0, top), stop(1.0, bottom)],
+                },
+            }));
+        }
+        self.fill = Some(usvg::Fill { paint: usvg::Paint::Link(id), ..usvg::Fill::default() });
+    }
     pub fn set_no_stroke(&mut self) { self.stroke = None; }
     pub fn set_color_stroke(&mut self, red: u8, green: u8, blue: u8, width: f64) {
         self.stroke = Some(usvg::Stroke {
@@ -40,7 +512,11 @@ impl Canvas {
             ..usvg::Stroke::default()
         });
     }
-    pub fn add_shape(&mut self, points: &Vec<(f64, f64)>, position: (f64, f64), rotation: f64) {
+    pub fn add_shape(&mut self, points: &[(f64, f64)], position: (f64, f64), rotation: f64) {
+        if let Some(pixmap) = &mut self.pixmap {
+            draw_skia_shape(pixmap, &self.patterns, &self.fill, &self.stroke, points, position, rotation);
+            return;
+        }
         let mut path = usvg::PathData::new();
         for (i, point) in points.iter().enumerate() {
             if i == 0 { path.push_move_to(point.0, point.1); }
@@ -58,7 +534,151 @@ impl Canvas {
             .. usvg::Path::default()
         }));
     }
-    pub fn add_image(&mut self, id: String, data: &Vec<u8>) {
+    // 文字列を描画する (yは文字列のベースライン、sizeは文字の大きさ px)
+    // usvgで文字をパスに変換してから追加する (フォントにない文字は描画されない)
+    pub fn add_text(&mut self, text: &str, x: f64, y: f64, size: f64, color: (u8, u8, u8), anchor: TextAnchor) {
+        if let Some(text_tree) = self.layout_text(text, x, y, size, color, anchor) {
+            if let Some(pixmap) = &mut self.pixmap {
+                resvg::render(&text_tree, usvg::FitTo::Original, tiny_skia::Transform::default(), pixmap.as_mut());
+                return;
+            }
+            for mut node in text_tree.root().children() {
+                self.rtree.root().append(node.make_deep_copy());
+            }
+        }
+    }
+    // 文字列を描画した時の幅 (px、背景の大きさを決める用)
+    pub fn measure_text(&self, text: &str, size: f64) -> f64 {
+        return self.layout_text(text, 0.0, size, size, (0, 0, 0), TextAnchor::Start)
+            .and_then(|text_tree| text_tree.root().calculate_bbox())
+            .map_or(0.0, |bbox| bbox.width());
+    }
+    fn layout_text(&self, text: &str, x: f64, y: f64, size: f64, color: (u8, u8, u8), anchor: TextAnchor) -> Option<usvg::Tree> {
+        let anchor = match anchor {
+            TextAnchor::Start => "start",
+            TextAnchor::Middle => "middle",
+            TextAnchor::End => "end",
+        };
+        let text = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        let canvas_size = self.rtree.svg_node().size;
+        let svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\
+             <text x=\"{}\" y=\"{}\" font-family=\"sans-serif\" font-size=\"{}\" fill=\"rgb({},{},{})\" text-anchor=\"{}\" xml:space=\"preserve\">{}</text>\
+             </svg>",
+            canvas_size.width(), canvas_size.height(), x, y, size, color.0, color.1, color.2, anchor, text);
+        return match usvg::Tree::from_str(&svg, &get_text_options().to_ref()) {
+            Ok(text_tree) => Some(text_tree),
+            Err(error) => { tracing::warn!("failed to render text {:?}: {}", text, error); None }
+        };
+    }
+    // 画像を円形に切り抜いたパターンを登録する (set_image_fillでidを指定して使う)
+    // 画像のデータはコピーせずに参照を共有する (描画のたびに大きなデータを複製しない)
+    pub fn add_image(&mut self, id: String, data: &Arc<Vec<u8>>) {
+        if self.pixmap.is_some() {
+            // 描画できない画像は白で塗る (usvgの場合と同じ)
+            let pattern = create_skia_image_pattern(data)
+                .map_err(|error| tracing::warn!("unsupported image format: {}", error))
+                .ok()
+                .or_else(|| create_skia_circle_pattern().map(|(pattern, _)| pattern));
+            if let Some(pattern) = pattern { self.patterns.insert(id, SkiaPattern::Image(pattern)); }
+            return;
+        }
+        let mut group = self.append_circle_pattern(id);
+        enum ImageFormat { Png, Jpeg, Gif, WebP }
+        let data_type = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            Some(ImageFormat::WebP)
+        } else {
+            None
+        };
+        let kind_node = match data_type {
+            Some(ImageFormat::Jpeg) => Some(usvg::ImageKind::JPEG(Arc::clone(data))),
+            Some(ImageFormat::Png)  => Some(usvg::ImageKind::PNG (Arc::clone(data))),
+            Some(ImageFormat::Gif)  => Some(usvg::ImageKind::GIF (Arc::clone(data))),
+            // usvgが対応していない形式 (WebPなど) はデコードできればPNGに変換する
+            Some(ImageFormat::WebP) | None => match Canvas::transcode_to_png(data) {
+                Ok(png) => Some(usvg::ImageKind::PNG(Arc::new(png))),
+                Err(error) => { tracing::warn!("unsupported image format: {}", error); None },
+            },
+        };
+        if let Some(kind_node) = kind_node {
+            group.append_kind(usvg::NodeKind::Image(usvg::Image{
+                id: "".into(),
+                transform: usvg::Transform::default(),
+                visibility: usvg::Visibility::Visible,
+                view_box: usvg::ViewBox {
+                    rect: usvg::Rect::new(0.0, 0.0, 1.0, 1.0).unwrap(),
+                    aspect: usvg::AspectRatio::default(),
+                },
+                rendering_mode: usvg::ImageRendering::OptimizeQuality,
+                kind: kind_node,
+            }));
+        }
+    }
+    // アイコン画像がないユーザー用に、seed (user_idなど) から決まる模様のパターンを登録する
+    // 左右対称な5x5のマスを2色で塗り分ける (add_imageと同じく円形に切り抜く)
+    pub fn add_identicon(&mut self, id: String, seed: &str) {
+        // FNV-1a (Rustのバージョンによって結果が変わらないハッシュ)
+        let hash = seed.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+        let color = hue_to_rgb((hash % 360) as f64);
+        let pale = |value: u8| ((value as u16 + 255 * 3) / 4) as u8;
+        let background = (pale(color.0), pale(color.1), pale(color.2));
+        // 左側の3列 (15マス) をハッシュの上位のビットで決め、右側の2列は左右反転して塗る
+        // 円に収まるように、マスは中心の正方形 (一辺1/√2) の中に並べる (パターンの座標は0〜1)
+        let cell_size = std::f64::consts::FRAC_1_SQRT_2 / 5.0;
+        let offset = (1.0 - cell_size * 5.0) * 0.5;
+        let mut cells = Vec::<(f64, f64)>::new();
+        for row in 0..5 {
+            for column in 0..3 {
+                if (hash >> (32 + row * 3 + column)) & 1 == 0 { continue; }
+                for x in [column, 4 - column] {
+                    cells.push((offset + x as f64 * cell_size, offset + row as f64 * cell_size));
+                }
+            }
+        }
+
+        if self.pixmap.is_some() {
+            if let Some((mut pattern, circle)) = create_skia_circle_pattern() {
+                let mut paint = tiny_skia::Paint { anti_alias: true, ..tiny_skia::Paint::default() };
+                paint.set_color_rgba8(background.0, background.1, background.2, 255);
+                pattern.fill_path(&circle, &paint, tiny_skia::FillRule::Winding, tiny_skia::Transform::identity(), None);
+                paint.set_color_rgba8(color.0, color.1, color.2, 255);
+                let size = SKIA_PATTERN_SIZE as f64;
+                for (x, y) in cells {
+                    if let Some(rect) = tiny_skia::Rect::from_xywh((1.0 + x * size) as f32, (1.0 + y * size) as f32, (cell_size * size) as f32, (cell_size * size) as f32) {
+                        pattern.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
+                    }
+                }
+                self.patterns.insert(id, SkiaPattern::Image(pattern));
+            }
+            return;
+        }
+
+        let mut group = self.append_circle_pattern(id);
+        let mut add_rect = |rect: usvg::Rect, color: (u8, u8, u8)| {
+            group.append_kind(usvg::NodeKind::Path(usvg::Path {
+                fill: Some(usvg::Fill {
+                    paint: usvg::Paint::Color(usvg::Color::new_rgb(color.0, color.1, color.2)),
+                    ..usvg::Fill::default()
+                }),
+                data: Rc::new(usvg::PathData::from_rect(rect)),
+                .. usvg::Path::default()
+            }));
+        };
+        add_rect(usvg::Rect::new(0.0, 0.0, 1.0, 1.0).unwrap(), background);
+        for (x, y) in cells {
+            add_rect(usvg::Rect::new(x, y, cell_size, cell_size).unwrap(), color);
+        }
+    }
+    // 円形に切り抜いたパターンを追加して、中身を追加するグループを返す
+    // 円は図形の外接矩形の短い辺を直径として中央に置き (細長い図形でも潰れない)、周りは白で塗る
+    fn append_circle_pattern(&mut self, id: String) -> usvg::Node {
+        let clip_path_id = id.clone() + "_clip";
         let mut pattern = self.rtree
             .append_to_defs(usvg::NodeKind::Pattern(usvg::Pattern {
                 id,
@@ -71,7 +691,7 @@ impl Canvas {
                     aspect: usvg::AspectRatio{
                         defer: false,
                         align: usvg::Align::XMidYMid,
-                        slice: true,
+                        slice: false,
                     },
                 }),
             }));
@@ -80,88 +700,434 @@ impl Canvas {
                 paint: usvg::Paint::Color(usvg::Color::new_rgb(255, 255, 255)),
                 ..usvg::Fill::default()
             }),
-            data: Rc::new(usvg::PathData::from_rect(usvg::Rect::new(0.0, 0.0, 1.0, 1.0).unwrap())),
+            // 細長い図形ではview_boxの外側も見えるので、背景は十分大きく塗る
+            data: Rc::new(usvg::PathData::from_rect(usvg::Rect::new(-100.0, -100.0, 201.0, 201.0).unwrap())),
             .. usvg::Path::default()
         }));
-        enum ImageFormat { PNG, JPEG, GIF }
-        let data_type = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
-            Some(ImageFormat::PNG)
-        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
-            Some(ImageFormat::JPEG)
-        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
-            Some(ImageFormat::GIF)
-        } else {
-            None
-        };
-        let kind_node = match data_type {
-            Some(ImageFormat::JPEG) => Some(usvg::ImageKind::JPEG(Arc::new(data.clone()))),
-            Some(ImageFormat::PNG)  => Some(usvg::ImageKind::PNG (Arc::new(data.clone()))),
-            Some(ImageFormat::GIF)  => Some(usvg::ImageKind::GIF (Arc::new(data.clone()))),
-            _ => None,
-        };
-        if let Some(kind_node) = kind_node {
-            pattern.append_kind(usvg::NodeKind::Image(usvg::Image{
-                id: "".into(),
-                transform: usvg::Transform::default(),
-                visibility: usvg::Visibility::Visible,
-                view_box: usvg::ViewBox {
-                    rect: usvg::Rect::new(0.0, 0.0, 1.0, 1.0).unwrap(),
-                    aspect: usvg::AspectRatio::default(),
-                },
-                rendering_mode: usvg::ImageRendering::OptimizeQuality,
-                kind: kind_node,
-            }));
-        }
+        // 円形の切り抜き (パターンの座標で中心(0.5, 0.5)、半径0.5)
+        let mut clip_path = self.rtree.append_to_defs(usvg::NodeKind::ClipPath(usvg::ClipPath {
+            id: clip_path_id.clone(),
+            ..usvg::ClipPath::default()
+        }));
+        clip_path.append_kind(usvg::NodeKind::Path(usvg::Path {
+            fill: Some(usvg::Fill::default()),
+            data: Rc::new(get_circle_path(0.5, 0.5, 0.5)),
+            .. usvg::Path::default()
+        }));
+        return pattern.append_kind(usvg::NodeKind::Group(usvg::Group {
+            clip_path: Some(clip_path_id),
+            ..usvg::Group::default()
+        }));
     }
     //pub fn encode_svg(&self) -> String {
     //    return self.rtree.to_string(&usvg::XmlOptions::default());
     //}
-    pub fn encode_png(&self) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let pixmap_size = self.rtree.svg_node().size.to_screen_size();
+    pub fn encode_png(&self) -> CanvasResult<Vec<u8>> {
+        let pixmap = self.render_pixmap(1.0);
+        pixmap.encode_png().map_err(|err| CanvasError::Png(err.to_string()))
+    }
+    // zoom倍の大きさで描画
+    fn render_pixmap(&self, zoom: f64) -> tiny_skia::Pixmap {
+        if let Some(pixmap) = &self.pixmap {
+            if zoom == 1.0 { return pixmap.clone(); }
+            let mut zoomed = tiny_skia::Pixmap::new((pixmap.width() as f64 * zoom).ceil() as u32, (pixmap.height() as f64 * zoom).ceil() as u32).unwrap();
+            let paint = tiny_skia::PixmapPaint { quality: tiny_skia::FilterQuality::Bicubic, ..tiny_skia::PixmapPaint::default() };
+            zoomed.draw_pixmap(0, 0, pixmap.as_ref(), &paint, tiny_skia::Transform::from_scale(zoom as f32, zoom as f32), None);
+            return zoomed;
+        }
+        let fit_to = usvg::FitTo::Zoom(zoom as f32);
+        let pixmap_size = fit_to.fit_to(self.rtree.svg_node().size.to_screen_size()).unwrap();
         let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
-        resvg::render(&self.rtree, usvg::FitTo::Original, tiny_skia::Transform::default(), pixmap.as_mut()).unwrap();
-        Ok(pixmap.encode_png()?)
+        resvg::render(&self.rtree, fit_to, tiny_skia::Transform::default(), pixmap.as_mut()).unwrap();
+        pixmap
     }
-    //pub fn save_png(&self, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    //pub fn save_png(&self, path: &str) -> CanvasResult<()> {
     //    let data = self.encode_png()?;
     //    std::fs::write(path, data)?;
     //    Ok(())
     //}
-    pub fn load_shaper_from_svg(path: &str, scale: f64) -> Result<Vec<Vec<(f64, f64)>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    // 画像をデコードしてPNGに変換する
+    fn transcode_to_png(data: &[u8]) -> CanvasResult<Vec<u8>> {
+        let mut png = Vec::new();
+        image::load_from_memory(data)?.write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+        Ok(png)
+    }
+    // アイコン画像を中央の正方形で切り抜いてsize x size pxのPNGに縮小する
+    // slackのアイコンは512px以上のことが多く、そのまま埋め込むと描画が遅く投稿する画像も重くなる
+    pub fn resize_icon(data: &[u8], size: u32) -> CanvasResult<Vec<u8>> {
+        let icon = image::load_from_memory(data)?.resize_to_fill(size, size, image::imageops::FilterType::Triangle);
+        let mut png = Vec::new();
+        icon.write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+        Ok(png)
+    }
+    pub fn load_shaper_from_svg(path: &str, scale: f64) -> CanvasResult<Vec<ShapeDef>> {
         let svg_data = std::fs::read(path)?;
+        return Canvas::load_shaper_from_svg_data(&svg_data, scale);
+    }
+    pub fn load_shaper_from_svg_data(svg_data: &[u8], scale: f64) -> CanvasResult<Vec<ShapeDef>> {
+        // rect, circle, ellipse, polygon, polylineは自前で多角形にし、残りのpathをusvgで読む
+        // (usvgに渡すと円が曲線になったり二重に読まれたりするので、元のSVGから取り除いておく)
+        let mut primitives: Vec<(Vec<(f64, f64)>, String)> = Vec::new();
+        let mut svg_data = svg_data.to_vec();
+        if let Ok(text) = std::str::from_utf8(&svg_data) {
+            let document = roxmltree::Document::parse(text)?;
+            let mut ranges = Vec::new();
+            for node in document.descendants() {
+                if !node.is_element() { continue; }
+                if node.ancestors().any(|ancestor| ancestor.tag_name().name() == "defs") { continue; }
+                let id = node.attribute("id").unwrap_or("").to_string();
+                let points = match node.tag_name().name() {
+                    "rect" | "circle" | "ellipse" | "polygon" | "polyline" => get_primitive_points(&node),
+                    _ => { continue; }
+                };
+                match points {
+                    Some(points) => { primitives.push((points, id)); },
+                    None => { tracing::warn!("<{}> (id: \"{}\") has invalid attributes", node.tag_name().name(), id); },
+                }
+                ranges.push(node.range());
+            }
+            let mut stripped = text.to_string();
+            for range in ranges.iter().rev() {
+                stripped.replace_range(range.clone(), "");
+            }
+            svg_data = stripped.into_bytes();
+        }
         let opt = usvg::Options::default();
         let rtree = usvg::Tree::from_data(&svg_data, &opt.to_ref())?;
-        let mut shapes: Vec<Vec<(f64, f64)>> = Vec::new();
+        let mut shapes: Vec<ShapeDef> = Vec::new();
         for node in rtree.root().descendants() {
             if !rtree.is_in_defs(&node) {
                 let node = (*node.borrow()).clone();
                 if let usvg::NodeKind::Path(path) = node {
                     let mut shape: Vec<(f64, f64)> = Vec::new();
-                    let mut rect = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
-                    for point in path.data.iter() {
-                        let point: Option<(f64, f64)> = match point {
-                            usvg::PathSegment::MoveTo{ x, y } => Some((*x, *y)),
-                            usvg::PathSegment::LineTo{ x, y } => Some((*x, *y)),
-                            _ => None
+                    // 曲線は拡大後の誤差がCURVE_TOLERANCE px未満になるまで分割して折れ線にする
+                    // (ClosePathの閉じる線分は直線なので頂点の追加は不要)
+                    let tolerance = CURVE_TOLERANCE / scale;
+                    for segment in path.data.iter() {
+                        match segment {
+                            usvg::PathSegment::MoveTo{ x, y } => { shape.push((*x, *y)); },
+                            usvg::PathSegment::LineTo{ x, y } => { shape.push((*x, *y)); },
+                            usvg::PathSegment::CurveTo{ x1, y1, x2, y2, x, y } => {
+                                let start = shape.last().copied().unwrap_or((*x1, *y1));
+                                flatten_cubic_bezier(start, (*x1, *y1), (*x2, *y2), (*x, *y), tolerance, 0, &mut shape);
+                            },
+                            usvg::PathSegment::ClosePath => {},
                         };
-                        if let Some(point) = point {
-                            if point.0 < rect.0 { rect.0 = point.0; }
-                            if point.1 < rect.1 { rect.1 = point.1; }
-                            if rect.2 < point.0 { rect.2 = point.0; }
-                            if rect.3 < point.1 { rect.3 = point.1; }
-                            shape.push(point);
-                        }
                     }
-                    let center = ((rect.0 + rect.2) * 0.5, (rect.1 + rect.3) * 0.5);
-                    shape.iter_mut().for_each(|point| {
-                        point.0 = (point.0 - center.0) * scale;
-                        point.1 = (point.1 - center.1) * scale;
-                    });
-                    shapes.push(shape.clone());
-                    shapes.push(shape.iter().map(|(x, y)| (-x, *y)).collect());
+                    push_shape(&mut shapes, shape, &path.id, scale);
                 }
             }
         }
+        for (points, id) in primitives {
+            push_shape(&mut shapes, points, &id, scale);
+        }
         Ok(shapes)
     }
+    // ディレクトリ内の全てのSVGファイルを読み込む (ファイル名の拡張子を除いた部分がキー)
+    // 読み込めないファイルは警告を出して読み飛ばす
+    pub fn load_shape_packs_from_dir(dir: &str, scale: f64) -> CanvasResult<HashMap<String, Vec<ShapeDef>>> {
+        let mut shape_packs = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("svg") { continue; }
+            let name = match path.file_stem().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => { continue; }
+            };
+            match Canvas::load_shaper_from_svg(&path.to_string_lossy(), scale) {
+                Ok(shapes) if !shapes.is_empty() => { shape_packs.insert(name, shapes); },
+                Ok(_) => { tracing::warn!("no shapes in {}", path.display()); },
+                Err(error) => { tracing::warn!("failed to load {}: {}", path.display(), error); },
+            }
+        }
+        Ok(shape_packs)
+    }
+}
+
+// Canvasをフレームとして並べたアニメーションGIF
+pub struct GifEncoder {
+    encoder: gif::Encoder<Vec<u8>>,
+    zoom: f64,
+}
+impl GifEncoder {
+    // width x heightのCanvasをzoom倍に縮小して書き出す
+    pub fn new(width: f64, height: f64, zoom: f64) -> CanvasResult<Self> {
+        let size = usvg::Size::new(width * zoom, height * zoom).ok_or(CanvasError::Render("invalid gif size"))?.to_screen_size();
+        let mut encoder = gif::Encoder::new(Vec::new(), size.width() as u16, size.height() as u16, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        Ok(GifEncoder { encoder, zoom })
+    }
+    // delayは次のフレームまでの時間 (1/100秒単位)
+    pub fn add_frame(&mut self, canvas: &Canvas, delay: u16) -> CanvasResult<()> {
+        let mut pixmap = canvas.render_pixmap(self.zoom);
+        let (width, height) = (pixmap.width() as u16, pixmap.height() as u16);
+        let mut frame = gif::Frame::from_rgba_speed(width, height, pixmap.data_mut(), 10);
+        frame.delay = delay;
+        self.encoder.write_frame(&frame)?;
+        Ok(())
+    }
+    pub fn finish(self) -> CanvasResult<Vec<u8>> {
+        Ok(self.encoder.into_inner()?)
+    }
+}
+
+// 描画結果をtests/fixtures/golden/{name}.pngと比較する (テスト用)
+// フォントのヒンティングなどの環境の差は許容するため、色の差がGOLDEN_CHANNEL_TOLERANCEを超える画素が
+// GOLDEN_PIXEL_TOLERANCEの割合以下なら一致とみなす
+// 描画を意図して変えた場合はUPDATE_GOLDEN=1を付けてテストを実行すると画像を作り直す
+#[cfg(test)]
+const GOLDEN_CHANNEL_TOLERANCE: u8 = 8;
+#[cfg(test)]
+const GOLDEN_PIXEL_TOLERANCE: f64 = 0.005;
+#[cfg(test)]
+pub(crate) fn assert_golden_png(name: &str, png: &[u8]) {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(format!("{}/tests/fixtures/golden", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        std::fs::write(get_golden_path(name), png).unwrap();
+        return;
+    }
+    assert_similar_to_golden_png(name, png, GOLDEN_PIXEL_TOLERANCE);
+}
+#[cfg(test)]
+fn get_golden_path(name: &str) -> String {
+    return format!("{}/tests/fixtures/golden/{}.png", env!("CARGO_MANIFEST_DIR"), name);
+}
+// 色の差がGOLDEN_CHANNEL_TOLERANCEを超える画素の割合がpixel_toleranceまでなら一致とみなす
+// (別の描画の方式の結果を同じ画像と比べる用、こちらは画像を作り直さない)
+#[cfg(test)]
+pub(crate) fn assert_similar_to_golden_png(name: &str, png: &[u8], pixel_tolerance: f64) {
+    let path = get_golden_path(name);
+    let expected = match std::fs::read(&path) {
+        Ok(expected) => image::load_from_memory(&expected).unwrap().to_rgba8(),
+        Err(error) => panic!("failed to read {} ({}), run the test with UPDATE_GOLDEN=1 to create it", path, error),
+    };
+    let actual = image::load_from_memory(png).unwrap().to_rgba8();
+    assert_eq!(actual.dimensions(), expected.dimensions(), "{}: the image size changed", name);
+    let different_pixels = actual.pixels().zip(expected.pixels())
+        .filter(|(actual, expected)| actual.0.iter().zip(expected.0.iter()).any(|(a, b)| a.abs_diff(*b) > GOLDEN_CHANNEL_TOLERANCE))
+        .count();
+    let ratio = different_pixels as f64 / (actual.width() * actual.height()) as f64;
+    assert!(ratio <= pixel_tolerance,
+        "{}: {:.2}% of the pixels differ from {} (run the test with UPDATE_GOLDEN=1 if the change is intended)", name, ratio * 100.0, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_svg(svg: &str) -> Vec<ShapeDef> {
+        return Canvas::load_shaper_from_svg_data(svg.as_bytes(), 1.0).unwrap();
+    }
+
+    #[test]
+    fn reads_material_from_the_path_id() {
+        // 材質の指定があるpathとないpath (左右反転した形状も続けて追加される)
+        let shapes = load_svg(r#"<svg xmlns="http://www.w3.org/2000/svg" width="200" height="100">
+            <path id="rock_f1.2_d3" d="M 0 0 L 40 0 L 40 20 L 0 20 Z"/>
+            <path id="plain" d="M 100 0 L 140 0 L 120 30 Z"/>
+        </svg>"#);
+        assert_eq!(shapes.len(), 4);
+        let (rock, plain) = (&shapes[0], &shapes[2]);
+        assert_eq!((rock.friction, rock.restitution, rock.density), (Some(1.2), None, Some(3.0)));
+        assert_eq!((plain.friction, plain.restitution, plain.density), (None, None, None));
+
+        // 左右反転した形状は材質を引き継ぐ
+        for (shape, mirrored) in [(&shapes[0], &shapes[1]), (&shapes[2], &shapes[3])] {
+            assert_eq!(*mirrored, shape.mirrored());
+            assert_eq!((mirrored.friction, mirrored.restitution, mirrored.density), (shape.friction, shape.restitution, shape.density));
+        }
+    }
+
+    #[test]
+    fn ignores_invalid_material_values() {
+        // 数値でない指定、負の値、密度0は無視する
+        let shape = ShapeDef::from_id(vec![], "wood_fx_r-1_d0_q5");
+        assert_eq!((shape.friction, shape.restitution, shape.density), (None, None, None));
+        let shape = ShapeDef::from_id(vec![], "ice_f0.05_r0.8");
+        assert_eq!((shape.friction, shape.restitution, shape.density), (Some(0.05), Some(0.8), None));
+    }
+
+    #[test]
+    fn rejects_degenerate_shapes_without_panicking() {
+        // 重複した頂点しかない形状と一直線の形状は読み飛ばし、残りの形状は読み込む
+        let shapes = load_svg(r#"<svg xmlns="http://www.w3.org/2000/svg" width="200" height="100">
+            <path id="dot" d="M 10 10 L 10 10 L 10 10 Z"/>
+            <path id="line" d="M 0 0 L 20 0 L 40 0 L 20 0 Z"/>
+            <path id="box" d="M 100 0 L 140 0 L 140 20 L 100 20 Z"/>
+        </svg>"#);
+        assert_eq!(shapes.len(), 2);
+        assert_eq!(shapes[0].vertices.len(), 4);
+
+        // 連続する重複した頂点は取り除き、時計回りと反時計回りのどちらでも同じ向きに揃える
+        let clockwise = ShapeDef::new(vec![(0.0, 0.0), (0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0), (0.0, 0.0)]).normalized();
+        let counterclockwise = ShapeDef::new(vec![(0.0, 10.0), (10.0, 10.0), (10.0, 0.0), (0.0, 0.0)]).normalized();
+        assert_eq!(clockwise.vertices.len(), 4);
+        assert!(clockwise.get_signed_area() > 0.0 && counterclockwise.get_signed_area() > 0.0);
+        assert!(ShapeDef::new(vec![(0.0, 0.0), (1.0, f64::NAN), (0.0, 1.0)]).validate().is_err());
+    }
+
+    #[test]
+    fn keeps_self_intersecting_shapes() {
+        // 辺が交差している形状 (蝶ネクタイ型) は読み込んで、凸包で代用できるように判定する
+        let shapes = load_svg(r#"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="100">
+            <path id="bowtie" d="M 0 0 L 40 40 L 40 10 L 0 40 Z"/>
+        </svg>"#);
+        assert_eq!(shapes.len(), 2);
+        assert!(shapes.iter().all(|shape| shape.is_self_intersecting()));
+        assert!(!ShapeDef::new(vec![(0.0, 0.0), (40.0, 0.0), (40.0, 40.0), (0.0, 40.0)]).is_self_intersecting());
+    }
+
+    // 外接矩形 (左, 上, 右, 下)
+    fn get_bbox(shape: &ShapeDef) -> (f64, f64, f64, f64) {
+        return shape.vertices.iter().fold((f64::MAX, f64::MAX, f64::MIN, f64::MIN), |rect, (x, y)| {
+            (rect.0.min(*x), rect.1.min(*y), rect.2.max(*x), rect.3.max(*y))
+        });
+    }
+
+    #[test]
+    fn flattens_a_circle_drawn_with_curves() {
+        // 半径50の円を4つの3次ベジェ曲線で描いたpath (Inkscapeが書き出す形)
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="200" height="200">
+            <path id="disc" d="M 150 100 C 150 127.6 127.6 150 100 150 C 72.4 150 50 127.6 50 100 C 50 72.4 72.4 50 100 50 C 127.6 50 150 72.4 150 100 Z"/>
+        </svg>"#;
+        let shapes = load_svg(svg);
+        assert_eq!(shapes.len(), 2);
+        let circle = &shapes[0];
+        assert!((16..=128).contains(&circle.vertices.len()), "{} vertices", circle.vertices.len());
+        let bbox = get_bbox(circle);
+        for (actual, expected) in [bbox.0, bbox.1, bbox.2, bbox.3].iter().zip([-50.0, -50.0, 50.0, 50.0]) {
+            assert!((actual - expected).abs() < 1.0, "bbox {:?}", bbox);
+        }
+        // 全ての頂点が円周から許容誤差程度しか離れていない
+        for (x, y) in &circle.vertices {
+            assert!(((x * x + y * y).sqrt() - 50.0).abs() < 1.0, "({}, {}) is off the circle", x, y);
+        }
+
+        // 拡大して読み込むと、拡大後の誤差を保つために頂点が増える
+        let scaled = Canvas::load_shaper_from_svg_data(svg.as_bytes(), 4.0).unwrap();
+        assert!(scaled[0].vertices.len() > circle.vertices.len());
+    }
+
+    #[test]
+    fn loads_primitive_elements_from_the_fixture() {
+        // pathを先に、rect, circle, ellipse, polygon, polylineを文書の順に読み込む (defsの中の要素は使わない)
+        let shapes = Canvas::load_shaper_from_svg_data(include_bytes!("../tests/fixtures/primitives.svg"), 1.0).unwrap();
+        let vertex_counts: Vec<usize> = shapes.iter().step_by(2).map(|shape| shape.vertices.len()).collect();
+        assert_eq!(vertex_counts, vec![3, 4, ELLIPSE_SEGMENTS, ELLIPSE_SEGMENTS, 4, 3]);
+        for pair in shapes.chunks(2) {
+            assert_eq!(pair[1], pair[0].mirrored());
+        }
+
+        // 中心に移動した後の大きさと、idの材質
+        let (rect, circle, ellipse, diamond) = (&shapes[2], &shapes[4], &shapes[6], &shapes[8]);
+        assert_eq!(get_bbox(rect), (-15.0, -10.0, 15.0, 10.0));
+        assert_eq!(rect.friction, Some(0.5));
+        let bbox = get_bbox(circle);
+        assert!((bbox.2 - 15.0).abs() < 1.0e-9 && (bbox.3 - 15.0).abs() < 1.0, "circle bbox {:?}", bbox);
+        assert_eq!(circle.restitution, Some(0.8));
+        let bbox = get_bbox(ellipse);
+        assert!((bbox.2 - 20.0).abs() < 1.0e-9 && (bbox.3 - 12.0).abs() < 1.0, "ellipse bbox {:?}", bbox);
+        assert_eq!(diamond.density, Some(2.0));
+    }
+
+    // 白で塗った背景だけのCanvas
+    fn blank_canvas(width: f64, height: f64) -> Canvas {
+        let mut canvas = Canvas::new(width, height);
+        canvas.set_color_fill(255, 255, 255);
+        canvas.add_shape(&[(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)], (0.0, 0.0), 0.0);
+        return canvas;
+    }
+
+    #[test]
+    fn renders_text() {
+        let empty = blank_canvas(240.0, 40.0).encode_png().unwrap();
+        let mut canvas = blank_canvas(240.0, 40.0);
+        canvas.add_text("TURN 3 / 1.25 m", 8.0, 28.0, 20.0, (0, 0, 0), TextAnchor::Start);
+        assert!(canvas.encode_png().unwrap() != empty, "no text was drawn");
+        assert!(canvas.measure_text("TURN 3 / 1.25 m", 20.0) > 0.0);
+
+        // 絵文字や日本語、エスケープが必要な文字を含んでいてもpanicしない (フォントにない文字は描画されないだけ)
+        let mut canvas = blank_canvas(240.0, 40.0);
+        canvas.add_text("🏗️ タワー <&> 😀", 120.0, 28.0, 20.0, (0, 0, 0), TextAnchor::Middle);
+        canvas.encode_png().unwrap();
+        canvas.measure_text("🏗️ タワー <&> 😀", 20.0);
+    }
+
+    // 上半分が赤、下半分が青のアイコン画像
+    fn test_icon(width: u32, height: u32, format: image::ImageOutputFormat) -> Arc<Vec<u8>> {
+        let icon = image::RgbaImage::from_fn(width, height, |_, y| {
+            if y < height / 2 { image::Rgba([220, 30, 30, 255]) } else { image::Rgba([30, 30, 220, 255]) }
+        });
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgba8(icon).write_to(&mut std::io::Cursor::new(&mut data), format).unwrap();
+        return Arc::new(data);
+    }
+
+    // 横長の長方形 (160 x 80 px) を描画した画像
+    fn render_rectangle(icon: Option<&Arc<Vec<u8>>>) -> image::RgbaImage {
+        let mut canvas = blank_canvas(200.0, 120.0);
+        canvas.set_color_fill(240, 240, 240);
+        if let Some(icon) = icon {
+            canvas.add_image("U_ICON".to_string(), icon);
+            canvas.set_image_fill("U_ICON".to_string());
+        }
+        canvas.set_no_stroke();
+        canvas.add_shape(&[(-80.0, -40.0), (80.0, -40.0), (80.0, 40.0), (-80.0, 40.0)], (100.0, 60.0), 0.0);
+        let png = canvas.encode_png().unwrap();
+        let name = if icon.is_some() { "avatar_on_rectangle" } else { "plain_rectangle" };
+        assert_golden_png(name, &png);
+        return image::load_from_memory(&png).unwrap().to_rgba8();
+    }
+
+    #[test]
+    fn crops_the_avatar_to_a_circle() {
+        // アイコンは長方形の短い辺を直径とする円に切り抜いて中央に置き、周りは白で塗る (円は潰れない)
+        let before = render_rectangle(None);
+        let after = render_rectangle(Some(&test_icon(64, 64, image::ImageOutputFormat::Png)));
+        assert_eq!(before.get_pixel(30, 30).0, [240, 240, 240, 255]);
+        assert_eq!(after.get_pixel(100, 35).0, [220, 30, 30, 255]);
+        assert_eq!(after.get_pixel(100, 85).0, [30, 30, 220, 255]);
+        // 円の外側 (長方形の左右の端と、円の外接正方形の角)
+        for (x, y) in [(30, 60), (170, 60), (63, 23), (137, 97)] {
+            assert_eq!(after.get_pixel(x, y).0, [255, 255, 255, 255], "({}, {}) is not white", x, y);
+        }
+        // 円の左右の端は中心から40px
+        assert_eq!(after.get_pixel(58, 60).0, [255, 255, 255, 255]);
+        assert_ne!(after.get_pixel(62, 60).0, [255, 255, 255, 255]);
+    }
+
+    // idのパターンの中にアイコンの画像のノードがあるか (usvgで描画する場合)
+    fn pattern_has_image(canvas: &Canvas, id: &str) -> bool {
+        return canvas.rtree.defs_by_id(id)
+            .is_some_and(|pattern| pattern.descendants().any(|node| matches!(*node.borrow(), usvg::NodeKind::Image(_))));
+    }
+
+    #[test]
+    fn transcodes_webp_avatars() {
+        // 上半分が赤、下半分が青の8x8pxのWebP (可逆圧縮)
+        let webp = Arc::new(std::fs::read(format!("{}/tests/fixtures/avatar.webp", env!("CARGO_MANIFEST_DIR"))).unwrap());
+        let mut canvas = Canvas::with_backend(200.0, 120.0, RenderBackend::Usvg);
+        canvas.add_image("U_WEBP".to_string(), &webp);
+        assert!(pattern_has_image(&canvas, "U_WEBP"), "the webp avatar was not added as an image");
+        // PNGに変換されてusvgに渡される
+        let png = Canvas::transcode_to_png(&webp).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8().get_pixel(0, 0).0, [220, 30, 30, 255]);
+        // デコードできないデータは画像のノードを追加しない (白で塗る)
+        canvas.add_image("U_BROKEN".to_string(), &Arc::new(b"RIFF\0\0\0\0WEBPVP8L".to_vec()));
+        assert!(!pattern_has_image(&canvas, "U_BROKEN"));
+
+        // どちらの描画の方式でもアイコンの色で塗られる
+        for backend in [RenderBackend::Usvg, RenderBackend::Skia] {
+            let mut canvas = Canvas::with_backend(200.0, 120.0, backend);
+            canvas.add_image("U_WEBP".to_string(), &webp);
+            canvas.set_image_fill("U_WEBP".to_string());
+            canvas.set_no_stroke();
+            canvas.add_shape(&[(-80.0, -40.0), (80.0, -40.0), (80.0, 40.0), (-80.0, 40.0)], (100.0, 60.0), 0.0);
+            let image = image::load_from_memory(&canvas.encode_png().unwrap()).unwrap().to_rgba8();
+            let near = |pixel: [u8; 4], expected: [u8; 4]| pixel.iter().zip(expected.iter()).all(|(a, b)| a.abs_diff(*b) <= 8);
+            assert!(near(image.get_pixel(100, 30).0, [220, 30, 30, 255]), "{:?}: {:?}", backend, image.get_pixel(100, 30));
+            assert!(near(image.get_pixel(100, 90).0, [30, 30, 220, 255]), "{:?}: {:?}", backend, image.get_pixel(100, 90));
+        }
+    }
 }
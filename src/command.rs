@@ -0,0 +1,335 @@
+// オブジェクトを落とすコマンドの解析
+// 書式: [#候補の番号] <左右の位置> <回転角度> [落とす高さ] [spin=回転速度] [flip]
+// 候補の番号は#を付けて指定する (数値の並びだけでは `1 45 0.5` のような端への落下と区別できないため)
+// 日本語入力で打った全角の数字や記号、カンマ区切り、角度の単位 (度、deg、°) も受け付ける
+
+use super::stage;
+
+// 解析したコマンド (候補の番号と落とす高さ、回転速度の範囲はステージに合わせて後で確認する)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedCommand {
+    pub selection: Option<f64>,
+    pub translation_x: f64,
+    pub rotation: f64,
+    pub drop_height: Option<f64>,
+    pub spin: Option<f64>,
+    pub flip: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    // 数値の数が2〜3個ではない
+    ArgumentCount(usize),
+    // 数値として読み取れなかった値 (何番目の値か、値)
+    InvalidNumber(usize, String),
+    InvalidSpin(String),
+    InvalidSelection(String),
+    // nanやinfなど、有限の数値ではない値
+    NotFinite(String),
+    // 範囲を大きく超えた値 (値、範囲の上限)
+    TranslationOutOfRange(f64, f64),
+    RotationOutOfRange(f64, f64),
+}
+
+impl CommandError {
+    pub fn get_message(&self) -> String {
+        return match self {
+            CommandError::ArgumentCount(count) => format!("数値が{}個送られました。左右の位置と回転角度を送信してください。", count),
+            CommandError::InvalidNumber(index, value) => format!("{}番目の値 `{}` を数値として読み取れませんでした。", index + 1, value),
+            CommandError::InvalidSpin(value) => format!("回転速度 `{}` を数値として読み取れませんでした。", value),
+            CommandError::InvalidSelection(value) => format!("候補の番号 `#{}` を数値として読み取れませんでした。", value),
+            CommandError::NotFinite(value) => format!("`{}` は使えません。有限の数値を指定してください。", value),
+            CommandError::TranslationOutOfRange(value, limit) => format!("左右の位置は -{}〜{} で指定してください (入力: {})。", limit, limit, value),
+            CommandError::RotationOutOfRange(value, limit) => format!("回転角度は -{}〜{} で指定してください (入力: {})。", limit, limit, value),
+        };
+    }
+}
+
+// 全角の英数字と記号を半角に、カンマと読点を空白に置き換える
+fn normalize(text: &str) -> String {
+    return text.chars().map(|c| match c {
+        // 全角の英数字と記号 (！〜～) は半角と同じ並びになっている
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        '\u{2212}' | 'ー' => '-',
+        '、' => ',',
+        _ => c,
+    }).map(|c| if c == ',' { ' ' } else { c }).collect();
+}
+
+// 数値の後ろに付いた角度の単位を取り除く
+fn strip_unit(arg: &str) -> &str {
+    for unit in ["度", "deg", "°"] {
+        if let Some(value) = arg.strip_suffix(unit) { return value; }
+    }
+    return arg;
+}
+
+pub fn parse_turn_command(text: &str) -> Result<ParsedCommand, CommandError> {
+    let text = normalize(text);
+    let mut selection = None;
+    let mut spin = None;
+    let mut flip = false;
+    let mut args = Vec::<&str>::new();
+    for arg in text.split_whitespace() {
+        // flipは何回指定しても1回だけ左右反転する
+        if arg == "flip" { flip = true; continue; }
+        if let Some(value) = arg.strip_prefix('#') {
+            let parsed = value.parse::<f64>().map_err(|_| CommandError::InvalidSelection(value.to_string()))?;
+            if !parsed.is_finite() { return Err(CommandError::NotFinite(value.to_string())); }
+            selection = Some(parsed);
+            continue;
+        }
+        match arg.strip_prefix("spin=") {
+            Some(value) => {
+                let parsed = value.parse::<f64>().map_err(|_| CommandError::InvalidSpin(value.to_string()))?;
+                if !parsed.is_finite() { return Err(CommandError::NotFinite(value.to_string())); }
+                spin = Some(parsed);
+            },
+            None => { args.push(strip_unit(arg)); },
+        };
+    }
+    if args.len() < 2 || args.len() > 3 {
+        return Err(CommandError::ArgumentCount(args.len()));
+    }
+    let mut values = Vec::<f64>::new();
+    for (index, arg) in args.iter().enumerate() {
+        match arg.parse::<f64>() {
+            // f64のparseはnanやinfも受け付けるので、物理演算に渡す前にここで断る
+            Ok(value) if !value.is_finite() => { return Err(CommandError::NotFinite(arg.to_string())); },
+            Ok(value) => values.push(value),
+            Err(_) => { return Err(CommandError::InvalidNumber(index, arg.to_string())); },
+        };
+    }
+
+    let (translation_x, rotation) = (values[0], values[1]);
+    let drop_height = values.get(2).copied();
+    return Ok(ParsedCommand { selection, translation_x, rotation, drop_height, spin, flip });
+}
+
+// 範囲外の値の許容量 (範囲の上限の10%までは範囲内に収め、それ以上はエラーにする)
+const RANGE_TOLERANCE: f64 = 0.1;
+
+// valueを-limit〜limitに収める
+// 範囲を少しだけ超えた場合は範囲内に収めた値を、大きく超えた場合はNoneを返す
+fn fit_to_range(value: f64, limit: f64) -> Option<f64> {
+    if value.abs() <= limit { return Some(value); }
+    if value.abs() <= limit * (1.0 + RANGE_TOLERANCE) { return Some(value.clamp(-limit, limit)); }
+    return None;
+}
+
+// 左右の位置と回転角度をステージの範囲に収める
+// 範囲内に収めた場合は結果のメッセージに添える注意書きを返す
+pub fn fit_to_stage(command: ParsedCommand, max_translation_x: f64, max_rotation: f64) -> Result<(ParsedCommand, Vec<String>), CommandError> {
+    let mut notes = Vec::<String>::new();
+    let translation_x = fit_to_range(command.translation_x, max_translation_x)
+        .ok_or(CommandError::TranslationOutOfRange(command.translation_x, max_translation_x))?;
+    if translation_x != command.translation_x {
+        notes.push(format!("左右の位置は-{}〜{}で指定してください。{}に修正しました。", max_translation_x, max_translation_x, translation_x));
+    }
+    let rotation = fit_to_range(command.rotation, max_rotation)
+        .ok_or(CommandError::RotationOutOfRange(command.rotation, max_rotation))?;
+    if rotation != command.rotation {
+        notes.push(format!("回転角度は-{}〜{}で指定してください。{}に修正しました。", max_rotation, max_rotation, rotation));
+    }
+    return Ok((ParsedCommand { translation_x, rotation, ..command }, notes));
+}
+
+// 解析したコマンドをステージの操作に変換する (Slackのコマンドとローカルモードで共通)
+// 候補の番号が不正な場合は1番目の候補を使い、落とす高さと回転速度は範囲内に収める (修正した内容はnotesで返す)
+pub fn to_turn_input(command: ParsedCommand, stage: &stage::Stage) -> (stage::TurnInput, Vec<String>) {
+    let ParsedCommand { selection, translation_x, rotation, drop_height, spin, flip } = command;
+    let mut notes = Vec::<String>::new();
+
+    let candidate_count = stage.get_candidate_count();
+    let selection = match selection {
+        None => 0,
+        Some(index) if index.fract() == 0.0 && index >= 1.0 && index <= candidate_count as f64 => index as usize - 1,
+        Some(_) => {
+            notes.push(format!("候補の番号は#1〜#{}で指定してください。1番目の候補を使用しました。", candidate_count));
+            0
+        },
+    };
+
+    let mut input = stage::TurnInput {
+        selection,
+        translation_x: translation_x as stage::Real,
+        rotation: rotation as stage::Real,
+        flip,
+        ..stage::TurnInput::default()
+    };
+    if let Some(drop_height) = drop_height {
+        let clamped_drop_height = drop_height.clamp(0.0, 1.0);
+        if clamped_drop_height != drop_height {
+            notes.push(format!("落とす高さは0〜1で指定してください。{}に修正しました。", clamped_drop_height));
+        }
+        input.drop_height = Some(clamped_drop_height as stage::Real);
+    }
+    if let Some(spin) = spin {
+        let max_spin = stage::MAX_SPIN as f64;
+        let clamped_spin = spin.clamp(-max_spin, max_spin);
+        if clamped_spin != spin {
+            notes.push(format!("回転速度は-{}〜{}で指定してください。{}に修正しました。", max_spin, max_spin, clamped_spin));
+        }
+        input.spin = clamped_spin as stage::Real;
+        notes.push(format!("スピン: {} rad/s", clamped_spin));
+    }
+    if flip { notes.push("左右反転して落としました。".to_string()); }
+    return (input, notes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> ParsedCommand {
+        return parse_turn_command(text).unwrap();
+    }
+
+    #[test]
+    fn parses_two_numbers() {
+        let command = parse("0.25 45");
+        assert_eq!(command.translation_x, 0.25);
+        assert_eq!(command.rotation, 45.0);
+        assert_eq!(command.selection, None);
+        assert_eq!(command.drop_height, None);
+        assert_eq!(command.spin, None);
+        assert!(!command.flip);
+    }
+
+    #[test]
+    fn parses_negative_and_extra_whitespace() {
+        let command = parse("  -0.5    -90  ");
+        assert_eq!((command.translation_x, command.rotation), (-0.5, -90.0));
+    }
+
+    #[test]
+    fn parses_full_width_digits() {
+        let command = parse("－０．２５　４５");
+        assert_eq!((command.translation_x, command.rotation), (-0.25, 45.0));
+        let command = parse("０．５ −３０");
+        assert_eq!((command.translation_x, command.rotation), (0.5, -30.0));
+    }
+
+    #[test]
+    fn parses_commas() {
+        assert_eq!(parse("0.25, 45").rotation, 45.0);
+        assert_eq!(parse("0.25,45").rotation, 45.0);
+        assert_eq!(parse("0.25、45").rotation, 45.0);
+    }
+
+    #[test]
+    fn strips_angle_units() {
+        assert_eq!(parse("0.25 45度").rotation, 45.0);
+        assert_eq!(parse("0.25 45deg").rotation, 45.0);
+        assert_eq!(parse("0.25 45°").rotation, 45.0);
+        assert_eq!(parse("０．２５ ４５度").rotation, 45.0);
+    }
+
+    #[test]
+    fn parses_spin_and_flip() {
+        let command = parse("0.3 15 spin=2 flip");
+        assert_eq!(command.spin, Some(2.0));
+        assert!(command.flip);
+        assert!(parse("flip 0.3 15 flip").flip);
+    }
+
+    #[test]
+    fn rejects_wrong_argument_count() {
+        assert_eq!(parse_turn_command("0.5"), Err(CommandError::ArgumentCount(1)));
+        assert_eq!(parse_turn_command(""), Err(CommandError::ArgumentCount(0)));
+    }
+
+    #[test]
+    fn reports_which_argument_is_invalid() {
+        assert_eq!(parse_turn_command("0.5 abc"), Err(CommandError::InvalidNumber(1, "abc".to_string())));
+        assert_eq!(parse_turn_command("0.5 45 spin=fast"), Err(CommandError::InvalidSpin("fast".to_string())));
+    }
+
+    #[test]
+    fn rejects_nan() {
+        assert_eq!(parse_turn_command("nan 45"), Err(CommandError::NotFinite("nan".to_string())));
+        assert_eq!(parse_turn_command("0 NaN"), Err(CommandError::NotFinite("NaN".to_string())));
+        assert_eq!(parse_turn_command("0 0 nan"), Err(CommandError::NotFinite("nan".to_string())));
+        assert_eq!(parse_turn_command("0 0 spin=nan"), Err(CommandError::NotFinite("nan".to_string())));
+    }
+
+    #[test]
+    fn rejects_infinity() {
+        assert_eq!(parse_turn_command("inf 45"), Err(CommandError::NotFinite("inf".to_string())));
+        assert_eq!(parse_turn_command("0 -infinity"), Err(CommandError::NotFinite("-infinity".to_string())));
+        assert_eq!(parse_turn_command("0 0 spin=-inf"), Err(CommandError::NotFinite("-inf".to_string())));
+        assert_eq!(parse_turn_command("０ ０ ｉｎｆ"), Err(CommandError::NotFinite("inf".to_string())));
+    }
+
+    #[test]
+    fn third_number_is_drop_height() {
+        // 右端に45度で高さ0.5から落とす (以前は候補の番号と誤認していた)
+        let command = parse("1 45 0.5");
+        assert_eq!(command.selection, None);
+        assert_eq!((command.translation_x, command.rotation, command.drop_height), (1.0, 45.0, Some(0.5)));
+    }
+
+    #[test]
+    fn parses_selection_with_hash() {
+        let command = parse("#2 -0.25 45");
+        assert_eq!(command.selection, Some(2.0));
+        assert_eq!((command.translation_x, command.rotation, command.drop_height), (-0.25, 45.0, None));
+        let command = parse("-0.25 45 0 #3");
+        assert_eq!(command.selection, Some(3.0));
+        assert_eq!(command.drop_height, Some(0.0));
+        assert_eq!(parse("＃１ 0 0").selection, Some(1.0));
+    }
+
+    #[test]
+    fn rejects_invalid_selection() {
+        assert_eq!(parse_turn_command("#a 0 0"), Err(CommandError::InvalidSelection("a".to_string())));
+        assert_eq!(parse_turn_command("#nan 0 0"), Err(CommandError::NotFinite("nan".to_string())));
+    }
+
+    #[test]
+    fn rejects_four_numbers() {
+        assert_eq!(parse_turn_command("2 0.5 45 0.5"), Err(CommandError::ArgumentCount(4)));
+    }
+
+    fn fit(translation_x: f64, rotation: f64) -> Result<(ParsedCommand, Vec<String>), CommandError> {
+        let command = ParsedCommand { selection: None, translation_x, rotation, drop_height: None, spin: None, flip: false };
+        return fit_to_stage(command, 1.0, 180.0);
+    }
+
+    #[test]
+    fn keeps_values_on_the_boundary() {
+        let (command, notes) = fit(-1.0, 180.0).unwrap();
+        assert_eq!((command.translation_x, command.rotation), (-1.0, 180.0));
+        assert!(notes.is_empty());
+        let (command, notes) = fit(1.0, -180.0).unwrap();
+        assert_eq!((command.translation_x, command.rotation), (1.0, -180.0));
+        assert!(notes.is_empty());
+    }
+
+    #[test]
+    fn clamps_values_slightly_out_of_range() {
+        let (command, notes) = fit(1.0001, 0.0).unwrap();
+        assert_eq!(command.translation_x, 1.0);
+        assert_eq!(notes.len(), 1);
+        let (command, notes) = fit(-1.1, 198.0).unwrap();
+        assert_eq!((command.translation_x, command.rotation), (-1.0, 180.0));
+        assert_eq!(notes.len(), 2);
+    }
+
+    #[test]
+    fn rejects_values_far_out_of_range() {
+        assert_eq!(fit(-999.0, 0.0), Err(CommandError::TranslationOutOfRange(-999.0, 1.0)));
+        assert_eq!(fit(1.2, 0.0), Err(CommandError::TranslationOutOfRange(1.2, 1.0)));
+        assert_eq!(fit(0.0, 360.0), Err(CommandError::RotationOutOfRange(360.0, 180.0)));
+    }
+
+    #[test]
+    fn range_follows_the_stage_limits() {
+        // 幅の広いステージでは上限も広がる
+        let command = ParsedCommand { selection: None, translation_x: 1.5, rotation: 0.0, drop_height: None, spin: None, flip: false };
+        let (command, notes) = fit_to_stage(command, 2.0, 180.0).unwrap();
+        assert_eq!(command.translation_x, 1.5);
+        assert!(notes.is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+// Kubernetesなどから死活監視するためのHTTPサーバー
+// /healthz: socket modeの接続が1つ以上あり、最近フレームを受信している場合に200
+// /readyz: ピースセットを読み込み、websocketのURLを取得した後に200
+// /metrics: Prometheusのメトリクス
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::server::Builder;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use super::metrics;
+use super::slack::WebsocketHealth;
+
+// ヘルスチェックで確認する状態
+#[derive(Clone)]
+pub struct HealthState {
+    pub shapes_loaded: Arc<AtomicBool>,
+    pub websocket: WebsocketHealth,
+}
+
+// ヘルスチェックのポート (TOWER_HEALTH_PORT、設定しない場合はサーバーを起動しない)
+pub fn get_health_port() -> Option<u16> {
+    return std::env::var("TOWER_HEALTH_PORT").ok().and_then(|value| value.parse::<u16>().ok());
+}
+
+// パスごとのステータスコードと本文
+fn check(state: &HealthState, path: &str) -> (StatusCode, String) {
+    let healthy = match path {
+        "/healthz" => state.websocket.is_alive(),
+        "/readyz" => state.shapes_loaded.load(Ordering::SeqCst) && state.websocket.is_url_obtained(),
+        "/metrics" => {
+            return match metrics::get().encode() {
+                Ok(text) => (StatusCode::OK, text),
+                Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+            };
+        },
+        _ => { return (StatusCode::NOT_FOUND, "not found".to_string()); },
+    };
+    return if healthy { (StatusCode::OK, "ok".to_string()) } else { (StatusCode::SERVICE_UNAVAILABLE, "unavailable".to_string()) };
+}
+
+// 0.0.0.0:portでリクエストを待ち受ける (ポートを開けなかった場合はエラーを返す)
+pub async fn serve(port: u16, state: HealthState) -> Result<(), hyper::Error> {
+    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    return serve_on(Server::try_bind(&address)?, state).await;
+}
+
+// 待ち受けを開始したサーバーでリクエストに応答する (テストでは空いているポートで待ち受ける)
+pub(crate) async fn serve_on(server: Builder<AddrIncoming>, state: HealthState) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let (status, body) = check(&state, request.uri().path());
+                async move {
+                    let mut response = Response::new(Body::from(body));
+                    *response.status_mut() = status;
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    return server.serve(make_service).await;
+}
+
+// 127.0.0.1の空いているポートでヘルスチェックのサーバーを起動して、そのURLを返す (テスト用)
+#[cfg(test)]
+pub(crate) fn start_test_server(state: HealthState) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve_on(Server::from_tcp(listener).unwrap(), state));
+    return base_url;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(base_url: &str, path: &str) -> (u16, String) {
+        let response = reqwest::get(format!("{}{}", base_url, path)).await.unwrap();
+        return (response.status().as_u16(), response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn endpoints_follow_the_connection_state() {
+        let state = HealthState { shapes_loaded: Arc::new(AtomicBool::new(false)), websocket: WebsocketHealth::new() };
+        let base_url = start_test_server(state.clone());
+        // 起動直後は接続もピースセットもない
+        assert_eq!(get(&base_url, "/healthz").await.0, 503);
+        assert_eq!(get(&base_url, "/readyz").await.0, 503);
+        assert_eq!(get(&base_url, "/unknown").await.0, 404);
+
+        // ピースセットを読み込んでURLを取得したら準備完了 (まだ接続していないので/healthzは503)
+        state.shapes_loaded.store(true, Ordering::SeqCst);
+        assert_eq!(get(&base_url, "/readyz").await.0, 503);
+        state.websocket.set_test_state(0, None, true);
+        assert_eq!(get(&base_url, "/readyz").await, (200, "ok".to_string()));
+        assert_eq!(get(&base_url, "/healthz").await.0, 503);
+
+        // 接続していて最近フレームを受信していれば正常、しばらく受信していなければ異常
+        state.websocket.set_test_state(1, Some(std::time::Duration::ZERO), true);
+        assert_eq!(get(&base_url, "/healthz").await, (200, "ok".to_string()));
+        state.websocket.set_test_state(1, Some(std::time::Duration::from_secs(3600)), true);
+        assert_eq!(get(&base_url, "/healthz").await.0, 503);
+        state.websocket.set_test_state(0, Some(std::time::Duration::ZERO), true);
+        assert_eq!(get(&base_url, "/healthz").await.0, 503);
+
+        let (status, body) = get(&base_url, "/metrics").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("tower_active_stages"), "{}", body);
+    }
+}
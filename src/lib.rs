@@ -0,0 +1,15 @@
+// slack上で遊ぶタワーバトルのライブラリ
+// ステージの物理演算と描画はSlackに依存しないので、bot以外からも使える
+
+// 早期リターンと揃えるため、関数の最後も明示的にreturnする
+#![allow(clippy::needless_return)]
+
+pub mod slack;
+pub mod canvas;
+pub mod stage;
+pub mod render;
+pub mod command;
+pub mod health;
+pub mod metrics;
+pub mod app;
+pub mod local;
@@ -0,0 +1,179 @@
+// Slackを使わずに端末で遊ぶローカルモード (cargo run -- --local)
+// 標準入力から1行ずつコマンドを読んでターンを進め、結果の画像をout_dir/turn_N.pngに書き出す
+// 物理演算や描画を変更した時に手元で確かめるためにも使う
+
+use std::io::{ BufRead, Write };
+
+use super::{ canvas, command, stage };
+
+// ローカルモードのエラー (Slackを使わないので、app::AppErrorとは分ける)
+#[derive(Debug, thiserror::Error)]
+pub enum LocalError {
+    // 標準入力の読み込みと画像の書き出し
+    #[error("failed to read or write: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Stage(#[from] stage::StageError),
+    #[error(transparent)]
+    Canvas(#[from] canvas::CanvasError),
+    // 指定されたピースセットが見つからなかった
+    #[error("shape pack {name} not found (available: {available:?})")]
+    ShapePack { name: String, available: Vec<String> },
+}
+
+pub type LocalResult<T = ()> = Result<T, LocalError>;
+
+pub struct LocalOptions {
+    // ピースセットの名前 (Noneの場合はapp::DEFAULT_SHAPE_PACK)
+    pub shape_pack: Option<String>,
+    // Noneの場合はランダム
+    pub seed: Option<u64>,
+    pub out_dir: String,
+    pub difficulty: stage::Difficulty,
+}
+
+impl Default for LocalOptions {
+    fn default() -> Self {
+        return LocalOptions {
+            shape_pack: None,
+            seed: None,
+            out_dir: "out".to_string(),
+            difficulty: stage::Difficulty::Normal,
+        };
+    }
+}
+
+// ターンの画像を書き出す
+fn write_turn_image(out_dir: &str, turn: usize, data: &[u8]) -> LocalResult<String> {
+    let path = format!("{}/turn_{}.png", out_dir, turn);
+    std::fs::write(&path, data)?;
+    return Ok(path);
+}
+
+// 入力の行をステージの操作に変換する (Slackのコマンドと同じ書式)
+fn parse_input(line: &str, stage: &stage::Stage) -> Result<(stage::TurnInput, Vec<String>), String> {
+    let command = command::parse_turn_command(line).map_err(|error| error.get_message())?;
+    let config = stage.get_config();
+    let (command, mut notes) = command::fit_to_stage(command, config.max_translation_x as f64, config.max_rotation as f64)
+        .map_err(|error| error.get_message())?;
+    let (input, input_notes) = command::to_turn_input(command, stage);
+    notes.extend(input_notes);
+    return Ok((input, notes));
+}
+
+// 標準入力が終わるか、ゲームが終了するまでターンを進める
+pub fn run(options: LocalOptions, shape_packs: &std::collections::HashMap<String, Vec<canvas::ShapeDef>>, config: stage::StageConfig) -> LocalResult {
+    play(options, shape_packs, config, &mut std::io::stdin().lock())?;
+    return Ok(());
+}
+
+// 入力が終わるか、ゲームが終了するまでターンを進める (テストでは用意した入力を渡す)
+// 戻り値は進めたターンの数
+fn play(options: LocalOptions, shape_packs: &std::collections::HashMap<String, Vec<canvas::ShapeDef>>, config: stage::StageConfig, input: &mut impl BufRead) -> LocalResult<usize> {
+    let shape_pack = options.shape_pack.unwrap_or_else(|| super::app::DEFAULT_SHAPE_PACK.to_string());
+    let shapes = match shape_packs.get(&shape_pack) {
+        Some(shapes) => shapes.clone(),
+        None => {
+            let mut available: Vec<String> = shape_packs.keys().cloned().collect();
+            available.sort();
+            return Err(LocalError::ShapePack { name: shape_pack, available });
+        },
+    };
+    let seed = options.seed.unwrap_or_else(rand::random::<u64>);
+    std::fs::create_dir_all(&options.out_dir)?;
+
+    // ゲーム開始時の画像 (Slackのstartと同じく、オブジェクトを落とさずに1ターン進める)
+    let mut stage = stage::Stage::new(shapes, options.difficulty, stage::GroundVariant::Flat, seed, config);
+    let report = stage.next_turn(None, stage::TurnInput::default())?;
+    let path = write_turn_image(&options.out_dir, 0, &report.image_png)?;
+    println!("shape pack: {}, seed: {}", shape_pack, seed);
+    println!("turn 0: {}", path);
+    println!("「左右の位置 回転角度」を入力してください (例: 0.5 90、空行かquitで終了)");
+
+    let mut turn = 0;
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 { break; }
+        let line = line.trim();
+        if line.is_empty() || line == "quit" || line == "exit" { break; }
+
+        let (input, notes) = match parse_input(line, &stage) {
+            Ok(parsed) => parsed,
+            Err(message) => {
+                println!("{}", message);
+                continue;
+            },
+        };
+        for note in notes { println!("{}", note); }
+
+        turn += 1;
+        let report = stage.next_turn(None, input)?;
+        let path = write_turn_image(&options.out_dir, turn, &report.image_png)?;
+        println!("turn {}: {:?}, height {:.3} m ({:+.3}), steps {}, physics {} ms, render {} ms, {}",
+            turn, report.result, report.height, report.height_delta, report.steps_executed, report.elapsed_ms, report.render_ms, path);
+        match report.result {
+            stage::TurnResult::Success | stage::TurnResult::Unsettled | stage::TurnResult::Aborted => {},
+            _ => {
+                println!("game over: {} m", stage.get_height());
+                break;
+            },
+        };
+    }
+    return Ok(turn);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 正方形と横長の長方形 (px単位)
+    fn test_shapes() -> Vec<canvas::ShapeDef> {
+        return vec![
+            canvas::ShapeDef::new(vec![(-30.0, -30.0), (30.0, -30.0), (30.0, 30.0), (-30.0, 30.0)]),
+            canvas::ShapeDef::new(vec![(-60.0, -15.0), (60.0, -15.0), (60.0, 15.0), (-60.0, 15.0)]),
+        ];
+    }
+
+    // テストを実行する速さで結果が変わらないように、実時間での打ち切りは無効にする
+    fn test_config() -> stage::StageConfig {
+        return stage::StageConfig { wall_clock_budget_sec: 1.0e6, ..stage::StageConfig::default() };
+    }
+
+    #[test]
+    fn inputs_are_checked_like_slack_commands() {
+        let mut stage = stage::Stage::new(test_shapes(), stage::Difficulty::Normal, stage::GroundVariant::Flat, 1, test_config());
+        stage.next_turn(None, stage::TurnInput::default()).unwrap();
+
+        let (input, notes) = parse_input("#2 0.5 90 0.3 flip", &stage).unwrap();
+        assert_eq!((input.selection, input.translation_x, input.rotation, input.drop_height, input.flip), (1, 0.5, 90.0, Some(0.3), true));
+        assert_eq!(notes, vec!["左右反転して落としました。".to_string()]);
+
+        // 範囲を少し超えた値は収めて、修正したことを知らせる
+        let candidate_count = stage.get_candidate_count();
+        let (input, notes) = parse_input(&format!("#{} 0 0 2", candidate_count + 1), &stage).unwrap();
+        assert_eq!((input.selection, input.drop_height), (0, Some(1.0)));
+        assert_eq!(notes, vec![
+            format!("候補の番号は#1〜#{}で指定してください。1番目の候補を使用しました。", candidate_count),
+            "落とす高さは0〜1で指定してください。1に修正しました。".to_string(),
+        ]);
+        assert!(parse_input("left please", &stage).is_err());
+    }
+
+    #[test]
+    fn scripted_input_plays_turns_and_writes_images() {
+        // 読み取れない行は飛ばし、空行で終了する
+        let out_dir = std::env::temp_dir().join(format!("slack_tower_battle_local_{}", std::process::id()));
+        let options = LocalOptions { seed: Some(1), out_dir: out_dir.to_string_lossy().into_owned(), ..LocalOptions::default() };
+        let shape_packs = std::collections::HashMap::from([(crate::app::DEFAULT_SHAPE_PACK.to_string(), test_shapes())]);
+        let mut input = std::io::Cursor::new("0 0\nleft please\n0.1 0\n\n0 0\n");
+        let turns = play(options, &shape_packs, test_config(), &mut input).unwrap();
+        assert_eq!(turns, 2);
+        for turn in 0..=2 {
+            assert!(out_dir.join(format!("turn_{}.png", turn)).exists());
+        }
+        assert!(!out_dir.join("turn_3.png").exists());
+        let _ = std::fs::remove_dir_all(&out_dir);
+    }
+}
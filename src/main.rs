@@ -1,161 +1,220 @@
-mod slack;
-mod canvas;
-mod stage;
+// 早期リターンと揃えるため、関数の最後も明示的にreturnする
+#![allow(clippy::needless_return)]
 
 use std::env;
 use dotenv::dotenv;
-use chrono::prelude::*;
 use futures::future;
 use futures_util::pin_mut;
 
-use std::collections::HashMap;
-use std::sync::{ Arc, Mutex };
+use std::sync::{ Arc, RwLock };
+
+use slack_tower_battle::{ app, canvas, health, local, slack, stage };
+
+const USAGE: &str = "\
+Usage: slack_tower_battle [OPTIONS]
+
+Slackのsocket modeに接続してタワーバトルのbotを起動します (SLACK_APP_TOKENとSLACK_BOT_TOKENが必要)。
+--localを指定した場合は、Slackに接続せずに端末で遊びます。
+
+Options:
+  --local          標準入力から「左右の位置 回転角度」を1行ずつ読んでターンを進め、画像を書き出す
+  --pack <NAME>    ローカルモードで使うピースセット (既定値: shapes)
+  --seed <SEED>    ローカルモードのステージのseed (既定値: ランダム)
+  --out <DIR>      ローカルモードの画像の書き出し先 (既定値: out、turn_<ターン数>.pngを書き出す)
+  --hard           ローカルモードをハードモードで遊ぶ
+  -h, --help       このヘルプを表示する
+
+物理演算のパラメーターはローカルモードでもTOWER_GRAVITYなどの環境変数 (.envも可) で変更できます。
+";
+
+// 起動の方法
+enum Mode {
+    Bot,
+    Local(local::LocalOptions),
+    Help,
+}
+
+// コマンドライン引数の解析
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Mode, String> {
+    let mut is_local = false;
+    let mut local_only = None;
+    let mut options = local::LocalOptions::default();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => { return Ok(Mode::Help); },
+            "--local" => { is_local = true; },
+            "--pack" => {
+                options.shape_pack = Some(args.next().ok_or("--pack requires a shape pack name")?);
+                local_only = Some(arg);
+            },
+            "--seed" => {
+                let value = args.next().ok_or("--seed requires a number")?;
+                options.seed = Some(value.parse::<u64>().map_err(|_| format!("invalid seed: {}", value))?);
+                local_only = Some(arg);
+            },
+            "--out" => {
+                options.out_dir = args.next().ok_or("--out requires a directory")?;
+                local_only = Some(arg);
+            },
+            "--hard" => {
+                options.difficulty = stage::Difficulty::Hard;
+                local_only = Some(arg);
+            },
+            _ => { return Err(format!("unknown argument: {}", arg)); },
+        };
+    }
+    if !is_local {
+        if let Some(arg) = local_only { return Err(format!("{} can only be used with --local", arg)); }
+        return Ok(Mode::Bot);
+    }
+    return Ok(Mode::Local(options));
+}
+
+// 物理演算のパラメーター (環境変数が設定されていない場合や不正な場合は既定値)
+fn load_stage_config() -> stage::StageConfig {
+    let default_stage_config = stage::StageConfig::default();
+    return stage::StageConfig {
+        gravity: app::env_or("TOWER_GRAVITY", default_stage_config.gravity),
+        timeout_sec: app::env_or("TOWER_TIMEOUT_SEC", default_stage_config.timeout_sec),
+        wall_clock_budget_sec: app::env_or("TOWER_WALL_CLOCK_SEC", default_stage_config.wall_clock_budget_sec),
+        world_scale: app::env_or("TOWER_WORLD_SCALE", default_stage_config.world_scale),
+        ground_y: app::env_or("TOWER_GROUND_Y", default_stage_config.ground_y),
+        fail_y: app::env_or("TOWER_FAIL_Y", default_stage_config.fail_y),
+        ground_half_width: app::env_or("TOWER_GROUND_HALF_WIDTH", default_stage_config.ground_half_width),
+        spawn_margin: app::env_or("TOWER_SPAWN_MARGIN", default_stage_config.spawn_margin),
+        friction: app::env_or("TOWER_FRICTION", default_stage_config.friction),
+        rest_energy: app::env_or("TOWER_REST_ENERGY", default_stage_config.rest_energy),
+        rest_frames: app::env_or("TOWER_REST_FRAMES", default_stage_config.rest_frames),
+        density_multiplier: app::env_or("TOWER_DENSITY", default_stage_config.density_multiplier),
+        overlay: app::env_or("TOWER_OVERLAY", default_stage_config.overlay),
+        ..default_stage_config
+    };
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+async fn main() -> app::AppResult {
     // .envから各種アクセストークンの取得
     dotenv().ok();
+
+    // ログの出力 (RUST_LOGで出力するレベルを変更できる、例: RUST_LOG=debug)
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")))
+        .init();
+
+    let mode = match parse_args(env::args().skip(1)) {
+        Ok(mode) => mode,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            std::process::exit(2);
+        },
+    };
+    match mode {
+        Mode::Bot => {},
+        Mode::Help => {
+            print!("{}", USAGE);
+            return Ok(());
+        },
+        // ローカルモードはトークンを使わず、標準入力を待つのでブロッキング用のスレッドで実行する
+        Mode::Local(options) => {
+            let shape_packs = canvas::Canvas::load_shape_packs_from_dir(app::SHAPE_DIR, app::SHAPE_SCALE)?;
+            let stage_config = load_stage_config();
+            if let Err(err) = tokio::task::spawn_blocking(move || local::run(options, &shape_packs, stage_config)).await? {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return Ok(());
+        },
+    };
+
+    // ヘルスチェックのHTTPサーバー (TOWER_HEALTH_PORTを設定した場合のみ)
+    // 起動に時間がかかっても応答できるように、Slackへの接続やピースセットの読み込みより先に起動する
+    let shapes_loaded = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let websocket_health = slack::WebsocketHealth::new();
+    if let Some(port) = health::get_health_port() {
+        let health_state = health::HealthState { shapes_loaded: Arc::clone(&shapes_loaded), websocket: websocket_health.clone() };
+        tokio::spawn(async move {
+            if let Err(err) = health::serve(port, health_state).await {
+                tracing::error!("health check server failed: {}", err);
+            }
+        });
+        tracing::info!("health check server listening on port {}", port);
+    }
+
     let slack_app_token = env::var("SLACK_APP_TOKEN").expect("SLACK_APP_TOKEN must be set");
     let slack_bot_token = env::var("SLACK_BOT_TOKEN").expect("SLACK_BOT_TOKEN must be set");
+    // Slack APIのクライアントは全てのターンで共有する
+    let slack_client = Arc::new(slack::SlackClient::new(slack_bot_token, slack_app_token)?);
+    // 自分へのメンションを判別するために、botのユーザーIDを取得しておく
+    let bot_user_id = Arc::new(slack_client.auth_test().await?);
+    tracing::info!("bot user: {}", bot_user_id);
 
-    // オブジェクトの形状を読み込み
-    let shapes = canvas::Canvas::load_shaper_from_svg("resources/shapes.svg", 3.0)?;
+    // オブジェクトの形状をピースセットごとに読み込み (resources/<ピースセット名>.svg)
+    // reloadshapesで差し替えられるようにRwLockに入れる (作成済みのステージは古い形状のまま)
+    let shape_packs = canvas::Canvas::load_shape_packs_from_dir(app::SHAPE_DIR, app::SHAPE_SCALE)?;
+    if shape_packs.is_empty() { return Err("no shape packs found in resources".into()); }
+    tracing::info!("shape packs: {:?}", shape_packs.keys().collect::<Vec<&String>>());
+    shapes_loaded.store(true, std::sync::atomic::Ordering::SeqCst);
+    let shape_packs = Arc::new(RwLock::new(Arc::new(shape_packs)));
 
-    // 各チャンネルごとに独立したステージを管理
-    struct ChannelStage {
-        update_time: DateTime<Local>,
-        channel_id: String,
-        stage: Option<stage::Stage>,
-    }
-    let stages = Arc::new(Mutex::new(HashMap::<String, Arc<tokio::sync::Mutex<ChannelStage>>>::new()));
-
-    // メンションが送られてきたときに呼ばれる関数
-    async fn compute_turn(
-        bot_token: String,
-        shapes: Vec<Vec<(f64, f64)>>,
-        channel_stage: Arc<tokio::sync::Mutex<ChannelStage>>,
-        message: slack::Message
-    ) -> slack::SlackResult {
-        if message.event_type != "app_mention" { return Ok(()); }
-        let re = regex::Regex::new(r"^<@[0-9A-Z]+>").unwrap();
-        let text = re.replace(&message.text, "").to_string();
-
-        // 物理演算の結果を返す前に他の人のターンが重なるのを防ぐ
-        if let Ok(mut channel_stage) = channel_stage.try_lock() {
-            if let Some(stage) = &mut channel_stage.stage {
-                // アイコン画像の登録
-                if !stage.user_icons.contains_key(&message.user_id) {
-                    let user_info = slack::get_user_info(bot_token.clone(), message.user_id.clone()).await?;
-                    if let Some(icon_data) = user_info.icon_data {
-                        stage.user_icons.insert(message.user_id.clone(), icon_data);
-                    }
-                }
+    // 管理者のユーザーID (カンマ区切り)
+    let admin_user_ids: Arc<Vec<String>> = Arc::new(env::var("TOWER_ADMIN_USERS").unwrap_or_default()
+        .split(',').map(|user_id| user_id.trim().to_string()).filter(|user_id| !user_id.is_empty()).collect());
 
-                // メッセージの解析
-                let args: Vec<&str> = text.split_whitespace().collect();
-                if args.len() != 2 {
-                    slack::post_message(bot_token.clone(), message.channel_id,
-                        "無効な入力です。".to_string()
-                    ).await?;
-                    return Ok(());
-                }
-                let args = args[0..2].iter().map(|arg| arg.trim().parse::<f64>()).collect::<Result<Vec<f64>, std::num::ParseFloatError>>();
-                let translation_x;
-                let rotation;
-                if let Ok(args) = args { translation_x = args[0]; rotation = args[1] } else {
-                    slack::post_message(bot_token.clone(), message.channel_id,
-                        "無効な入力です。".to_string()
-                    ).await?;
-                    return Ok(());
-                }
+    let stage_config = load_stage_config();
+    tracing::info!("stage config: {:?}", stage_config);
 
-                // 物理演算
-                if let Ok((turn_result, height, data)) =
-                    stage.next_turn(Some(message.user_id.clone()), translation_x as stage::Real, rotation as stage::Real)
-                {
-                    let result_message = match turn_result {
-                        stage::TurnResult::Success => { format!("{} m", height) },
-                        stage::TurnResult::Failure => { "Game Over :angry:".to_string() },
-                        stage::TurnResult::Timeout => { "物理演算がタイムアウトしました:confounded:".to_string() },
-                    };
-                    let result_message = format!("<@{}> {}", message.user_id.clone(), result_message);
-                    slack::post_image(bot_token.clone(), channel_stage.channel_id.clone(), result_message, &data, "result.png".to_string()).await?;
-
-                    // ゲームオーバーまたはタイムアウトの場合はステージをリセット
-                    if turn_result != stage::TurnResult::Success {
-                        channel_stage.stage = None;
-                    }
-                }
-            }
-            else {
-                // ステージが存在しなかった場合は生成
-                let mut stage = stage::Stage::new(shapes);
-                let (_, _, data) = stage.next_turn(None, 0.0, 0.0)?;
-                channel_stage.stage = Some(stage);
-                slack::post_image(bot_token.clone(), message.channel_id,
-                    ":sparkles: slack tower battleへようこそ :sparkles:\n".to_string() +
-                    "みんなでオブジェクトを積み重ねて高みを目指しましょう:fire: :fire: :fire:\n\n" +
-                    "【遊び方】\n" +
-                    "左右の位置(-1〜1) と回転角度(-180〜180、時計回りが正の回転) を送信してください。\n" +
-                    "コマンド例 :point_right: `@slack_tower_battle -0.25 45`",
-                &data, "result.png".to_string()).await?;
-            }
+    // 再起動してもステージが消えないように、ステージはdata_dir/<channel_id>.json (スレッドごとのステージはdata_dir/<channel_id>_<thread_ts>.json) に保存する
+    let data_dir = env::var("TOWER_DATA_DIR").unwrap_or_else(|_| "data".to_string());
+    let context = app::AppContext::new(Arc::clone(&slack_client), Arc::clone(&bot_user_id), Arc::clone(&shape_packs), Arc::clone(&admin_user_ids), stage_config, data_dir.clone());
 
-            channel_stage.update_time = Local::now();
-        }
-        else {
-            slack::post_message(bot_token.clone(), message.channel_id,
-                format!("<@{}> 現在計算中です。\n結果が投稿された後に再度お試しください。", message.user_id)
-            ).await?;
-        }
-        Ok(())
-    }
+    // 24時間操作がないステージを削除するタスクと、定期的にステージを保存するタスク
+    let channel_deleter = tokio::spawn(app::stage_cleaner(Arc::clone(&context.stages), data_dir.clone()));
+    let channel_saver = tokio::spawn(app::stage_saver(Arc::clone(&context.stages), data_dir.clone()));
 
-    // 24時間以上経過したステージを自動削除するタスク
-    async fn stage_cleaner(stages: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<ChannelStage>>>>>) {
-        loop {
-            let current_time = Local::now();
-            let mut delete_channels = Vec::<String>::new();
-            {
-                if let Ok(stages) = &mut stages.lock() {
-                    for (channel_id, channel_stage) in stages.iter() {
-                        if let Ok(channel_stage) = channel_stage.try_lock() {
-                            let elapsed_time = current_time - channel_stage.update_time;
-                            if elapsed_time.num_hours() >= 24 { delete_channels.push(channel_id.clone()); }
-                        }
-                    }
-                    for channel_id in delete_channels.iter() {
-                        stages.remove(channel_id);
-                        println!("delete: channel {}", channel_id);
-                    }
+    // 終了のシグナル (Ctrl+CまたはSIGTERM) を待つ
+    async fn shutdown_signal() {
+        #[cfg(unix)]
+        {
+            if let Ok(mut terminate) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
                 }
+                return;
             }
-            // 60秒おきに監視
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
         }
+        let _ = tokio::signal::ctrl_c().await;
     }
-    let channel_deleter = tokio::spawn(stage_cleaner(Arc::clone(&stages)));
 
     // slackから取得したwebsocketのURLに接続
-    let receiver = slack::websocket_receiver(slack_app_token.clone(), |message| {
-        let stages = Arc::clone(&stages);
-        let stages = stages.lock();
-        if let Ok(mut stages) = stages {
-            if !stages.contains_key(&message.channel_id) {
-                stages.insert(message.channel_id.clone(), Arc::new(tokio::sync::Mutex::new(ChannelStage{
-                    update_time: Local::now(),
-                    channel_id: message.channel_id.clone(),
-                    stage: None,
-                })));
-            }
+    let message_handler = {
+        let context = context.clone();
+        move |message: slack::Message| app::handle_message(context.clone(), message)
+    };
+    let (receiver, websocket_shutdown) = slack::websocket_receiver(Arc::clone(&slack_client), websocket_health.clone(), message_handler);
+    let shutdown = shutdown_signal();
+    pin_mut!(receiver, channel_deleter, channel_saver, shutdown);
+    future::select(future::select(receiver.as_mut(), shutdown), future::select(channel_deleter, channel_saver)).await;
 
-            if let Some(channel_stage) = stages.get(&message.channel_id) {
-                tokio::spawn(compute_turn(slack_bot_token.clone(), shapes.clone(), Arc::clone(channel_stage), message));
-            }
-        }
-    });
-    pin_mut!(receiver, channel_deleter);
-    future::select(receiver, channel_deleter).await;
+    // 新しいメッセージの受信をやめて、計算中のターンを待ってから全てのステージを保存する
+    // 待っている間にもう一度シグナルを受け取った場合はすぐに終了する
+    let graceful_shutdown = async {
+        tracing::info!("shutting down (websocket connections: {} / {}, dropped responses: {}, dropped messages: {})",
+            websocket_health.get_open_connections(), websocket_health.get_connection_count(),
+            websocket_health.get_dropped_responses(), websocket_health.get_dropped_messages());
+        websocket_shutdown.shutdown().await;
+        receiver.abort();
+        let grace_period = std::time::Duration::from_secs(app::env_or("TOWER_SHUTDOWN_GRACE_SEC", 30));
+        app::finish_turns(&context, grace_period).await;
+    };
+    tokio::select! {
+        _ = graceful_shutdown => {},
+        _ = shutdown_signal() => {
+            tracing::warn!("received a second signal, exiting without waiting for turns");
+            std::process::exit(1);
+        },
+    };
 
     Ok(())
 }
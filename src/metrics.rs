@@ -0,0 +1,108 @@
+// Prometheusのメトリクス
+// 全てのメトリクスはここで登録し、各モジュールはget()で取得して記録する (/metricsでの公開はhealth.rsで行う)
+
+use std::sync::OnceLock;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+// 物理演算と描画にかかった時間のヒストグラムの区切り (秒)
+const PHYSICS_BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 60.0];
+const RENDER_BUCKETS: [f64; 8] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+// Slack APIの応答時間のヒストグラムの区切り (秒、再試行した場合は1回ごとに記録する)
+const SLACK_API_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0];
+
+pub struct Metrics {
+    registry: Registry,
+    // 結果 (TurnResultの名前) ごとのターンの数
+    pub turns: IntCounterVec,
+    pub physics_duration: Histogram,
+    pub render_duration: Histogram,
+    // メソッドごとのSlack APIの応答時間と、失敗した呼び出しの数
+    pub slack_api_duration: HistogramVec,
+    pub slack_api_errors: IntCounterVec,
+    // 保持しているステージの数 (スレッドモードのゲームの別名のキーを含む)
+    pub active_stages: IntGauge,
+    // websocketの再接続の回数
+    pub websocket_reconnects: IntCounter,
+    // websocketで捨てたメッセージの数 (kind: 処理する側に渡せなかった受信メッセージはreceived、送れなかった応答 (受信の確認とPong) はresponse)
+    pub dropped_messages: IntCounterVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+impl Metrics {
+    fn new() -> prometheus::Result<Metrics> {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            turns: IntCounterVec::new(Opts::new("tower_turns_total", "Turns processed by result"), &["result"])?,
+            physics_duration: Histogram::with_opts(HistogramOpts::new("tower_physics_duration_seconds", "Wall clock time of the physics simulation of a turn")
+                .buckets(PHYSICS_BUCKETS.to_vec()))?,
+            render_duration: Histogram::with_opts(HistogramOpts::new("tower_render_duration_seconds", "Time spent rendering the images of a turn")
+                .buckets(RENDER_BUCKETS.to_vec()))?,
+            slack_api_duration: HistogramVec::new(HistogramOpts::new("slack_api_duration_seconds", "Latency of Slack API calls by method")
+                .buckets(SLACK_API_BUCKETS.to_vec()), &["method"])?,
+            slack_api_errors: IntCounterVec::new(Opts::new("slack_api_errors_total", "Failed Slack API calls by method"), &["method"])?,
+            active_stages: IntGauge::new("tower_active_stages", "Stages kept in memory")?,
+            websocket_reconnects: IntCounter::new("slack_websocket_reconnects_total", "Socket mode reconnections")?,
+            dropped_messages: IntCounterVec::new(Opts::new("slack_websocket_dropped_messages_total",
+                "Received messages that could not be passed to the dispatcher and responses (acks and pongs) that could not be sent"), &["kind"])?,
+        };
+        metrics.registry.register(Box::new(metrics.turns.clone()))?;
+        metrics.registry.register(Box::new(metrics.physics_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.render_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.slack_api_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.slack_api_errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.active_stages.clone()))?;
+        metrics.registry.register(Box::new(metrics.websocket_reconnects.clone()))?;
+        metrics.registry.register(Box::new(metrics.dropped_messages.clone()))?;
+        return Ok(metrics);
+    }
+
+    // Prometheusのテキスト形式で書き出す
+    pub fn encode(&self) -> prometheus::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        return Ok(String::from_utf8_lossy(&buffer).into_owned());
+    }
+}
+
+// 最初に呼ばれた時に全てのメトリクスを登録する (名前が重複しない限り失敗しない)
+pub fn get() -> &'static Metrics {
+    return METRICS.get_or_init(|| Metrics::new().expect("failed to register metrics"));
+}
+
+// Prometheusのテキスト形式から、名前とラベルが一致する値を読む (まだ記録されていない場合は0、テスト用)
+#[cfg(test)]
+pub(crate) fn read_value(text: &str, series: &str) -> f64 {
+    return text.lines()
+        .find_map(|line| line.strip_prefix(series).and_then(|value| value.strip_prefix(' ')))
+        .map_or(0.0, |value| value.parse::<f64>().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_encoded_in_the_text_format() {
+        // 他のテストも同じメトリクスに記録するので、増えた量だけを確認する
+        let metrics = get();
+        let before = metrics.encode().unwrap();
+        metrics.turns.with_label_values(&["Success"]).inc();
+        metrics.physics_duration.observe(0.3);
+        metrics.slack_api_errors.with_label_values(&["chat.postMessage"]).inc();
+        metrics.dropped_messages.with_label_values(&["response"]).inc();
+        let after = metrics.encode().unwrap();
+
+        for series in [
+            "tower_turns_total{result=\"Success\"}",
+            "tower_physics_duration_seconds_count",
+            "tower_physics_duration_seconds_bucket{le=\"0.5\"}",
+            "slack_api_errors_total{method=\"chat.postMessage\"}",
+            "slack_websocket_dropped_messages_total{kind=\"response\"}",
+        ] {
+            assert!(read_value(&after, series) >= read_value(&before, series) + 1.0, "{} did not move", series);
+        }
+        assert!(after.contains("tower_physics_duration_seconds_bucket{le=\"0.25\"}"));
+        assert!(after.contains("# TYPE tower_active_stages gauge"));
+    }
+}
@@ -0,0 +1,177 @@
+use rapier2d::prelude::*;
+use serde::{Serialize, Deserialize};
+use super::canvas::RenderBackend;
+
+// 画像の大きさ (px)
+pub const VIEW_WIDTH: f64 = 640.0;
+pub const VIEW_HEIGHT: f64 = 480.0;
+
+// ステージの座標 (m) から画像の座標 (px) への変換
+// ステージの座標は全てm単位で、pxに変換するのは描画の時だけ
+#[derive(Debug, Clone, Copy)]
+pub struct CameraTransform {
+    // 1pxあたりの長さ (m、タワーが高くなると大きくなる)
+    pub meters_per_pixel: Real,
+    // 画像の左右の中央に表示するx座標 (m)
+    pub center_x: Real,
+    // 画像の上端に表示するy座標 (m)
+    pub top: Real,
+}
+
+impl CameraTransform {
+    pub fn new(meters_per_pixel: Real, center_x: Real, top: Real) -> Self {
+        return CameraTransform { meters_per_pixel, center_x, top };
+    }
+
+    // 画像の左右の幅の半分に映る長さ (m)
+    pub fn get_half_width(&self) -> Real {
+        return VIEW_WIDTH as Real * 0.5 * self.meters_per_pixel;
+    }
+
+    pub fn to_pixel_length(&self, length: Real) -> f64 {
+        return (length / self.meters_per_pixel) as f64;
+    }
+
+    pub fn to_pixels(&self, point: Point<Real>) -> (f64, f64) {
+        return (
+            self.to_pixel_length(point.x - self.center_x) + VIEW_WIDTH * 0.5,
+            self.to_pixel_length(point.y - self.top),
+        );
+    }
+
+    // 形状の頂点 (オブジェクトの中心からの相対座標) をpxに変換
+    pub fn to_pixel_shape(&self, vertices: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        let scale = 1.0 / self.meters_per_pixel as f64;
+        return vertices.iter().map(|(x, y)| (x * scale, y * scale)).collect();
+    }
+}
+
+// 描画に使う色 (チャンネルごとのテーマから作る)
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    // 空の色 (タワーが高くなると夕焼けと星空に近づく)
+    pub sky_color: (u8, u8, u8),
+    pub ground_color: (u8, u8, u8),
+    // アイコンのないオブジェクトの塗りと枠の色
+    pub piece_fill_color: (u8, u8, u8),
+    pub piece_stroke_color: (u8, u8, u8),
+    // アイコンで塗ったオブジェクトの枠の色
+    pub icon_stroke_color: (u8, u8, u8),
+    // 描画の方式 (テーマによらず既定はTOWER_RENDER_BACKEND)
+    pub backend: RenderBackend,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        return Theme::Default.get_render_options();
+    }
+}
+
+// 画像のテーマ (settings themeでチャンネルごとに選ぶ)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Theme {
+    #[default]
+    Default,
+    Dark,
+    Sakura,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::Default, Theme::Dark, Theme::Sakura];
+
+    pub fn get_name(&self) -> &'static str {
+        return match self {
+            Theme::Default => "default",
+            Theme::Dark => "dark",
+            Theme::Sakura => "sakura",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Theme> {
+        return Theme::ALL.iter().copied().find(|theme| theme.get_name() == name);
+    }
+
+    pub fn get_render_options(&self) -> RenderOptions {
+        return match self {
+            Theme::Default => RenderOptions {
+                sky_color: (3, 182, 252),
+                ground_color: (20, 222, 106),
+                piece_fill_color: (255, 255, 255),
+                piece_stroke_color: (245, 66, 129),
+                icon_stroke_color: (0, 88, 122),
+                backend: RenderBackend::default(),
+            },
+            Theme::Dark => RenderOptions {
+                sky_color: (28, 32, 56),
+                ground_color: (64, 72, 92),
+                piece_fill_color: (200, 204, 216),
+                piece_stroke_color: (255, 153, 51),
+                icon_stroke_color: (232, 232, 240),
+                backend: RenderBackend::default(),
+            },
+            Theme::Sakura => RenderOptions {
+                sky_color: (255, 214, 228),
+                ground_color: (134, 194, 112),
+                piece_fill_color: (255, 250, 252),
+                piece_stroke_color: (232, 92, 144),
+                icon_stroke_color: (176, 64, 112),
+                backend: RenderBackend::default(),
+            },
+        };
+    }
+}
+
+// 7セグメント表示風の数字 (目盛りのラベル用)
+// 各数字で表示するセグメント (上, 右上, 右下, 下, 左下, 左上, 中央)
+const DIGIT_SEGMENTS: [[bool; 7]; 10] = [
+    [true,  true,  true,  true,  true,  true,  false],
+    [false, true,  true,  false, false, false, false],
+    [true,  true,  false, true,  true,  false, true ],
+    [true,  true,  true,  true,  false, false, true ],
+    [false, true,  true,  false, false, true,  true ],
+    [true,  false, true,  true,  false, true,  true ],
+    [true,  false, true,  true,  true,  true,  true ],
+    [true,  true,  true,  false, false, false, false],
+    [true,  true,  true,  true,  true,  true,  true ],
+    [true,  true,  true,  true,  false, true,  true ],
+];
+
+// 数字の文字列を長方形の集まりに変換する (positionは左上の座標、heightは文字の高さ px)
+// 数字と "." と "-" 以外の文字は空白として扱う
+pub fn get_number_shapes(text: &str, position: (f64, f64), height: f64) -> Vec<Vec<(f64, f64)>> {
+    let rect = |x0: f64, y0: f64, x1: f64, y1: f64| vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1)];
+    let (width, thickness, spacing) = (height * 0.5, height * 0.15, height * 0.25);
+    let (mut x, y) = position;
+    let mut shapes = Vec::new();
+    for c in text.chars() {
+        match c {
+            '0'..='9' => {
+                let middle = y + height * 0.5;
+                let segments = [
+                    rect(x, y, x + width, y + thickness),
+                    rect(x + width - thickness, y, x + width, middle),
+                    rect(x + width - thickness, middle, x + width, y + height),
+                    rect(x, y + height - thickness, x + width, y + height),
+                    rect(x, middle, x + thickness, y + height),
+                    rect(x, y, x + thickness, middle),
+                    rect(x, middle - thickness * 0.5, x + width, middle + thickness * 0.5),
+                ];
+                let digit = c.to_digit(10).unwrap() as usize;
+                for (segment, visible) in segments.into_iter().zip(DIGIT_SEGMENTS[digit]) {
+                    if visible { shapes.push(segment); }
+                }
+                x += width + spacing;
+            },
+            '.' => {
+                shapes.push(rect(x, y + height - thickness, x + thickness, y + height));
+                x += thickness + spacing;
+            },
+            '-' => {
+                shapes.push(rect(x, y + height * 0.5 - thickness * 0.5, x + width, y + height * 0.5 + thickness * 0.5));
+                x += width + spacing;
+            },
+            _ => { x += width + spacing; },
+        }
+    }
+    return shapes;
+}
@@ -1,74 +1,183 @@
 extern crate base64;
-use std::error::Error;
-use std::fmt;
 use std::collections::HashMap;
+use serde::Deserialize;
+use super::metrics;
 
-#[derive(Debug)]
-pub struct SlackError(String);
-impl fmt::Display for SlackError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "There is an error: {}", self.0)
-    }
-}
-impl Error for SlackError {}
-
-pub type SlackResult<T = ()> = Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
-
-pub async fn get_websocket_url(app_token: String) -> SlackResult<String> {
-    // slackからwebsocketのURLを取得
-    // 参考: https://api.slack.com/apis/connections/socket-implement
-    let client = reqwest::Client::new();
-    let response_json = client.post("https://slack.com/api/apps.connections.open")
-        .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", app_token))
-        .body("").send().await?.text().await?;
-    let response: serde_json::Value = serde_json::from_str(&response_json)?;
-    let websocket_url = match response.get("url").and_then(|value| { value.as_str() }) {
-        Some(url) => Ok(url.replace("\"", "")),
-        None => Err(Box::new(SlackError(response_json))),
-    }?;
-    Ok(websocket_url)
-}
-
-pub async fn post_message(bot_token: String, channel: String, text: String) -> SlackResult {
-    // slackにメッセージを送信
-    // 参考: https://api.slack.com/methods/chat.postMessage
-    let mut params = HashMap::new();
-    params.insert("channel", channel);
-    params.insert("text", text);
-    let client = reqwest::Client::new();
-    let response_json = client.post("https://slack.com/api/chat.postMessage")
-        .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", bot_token))
-        .form(&params).send().await?.text().await?;
-    let response: serde_json::Value = serde_json::from_str(&response_json)?;
-    if let Some(ok) = response.get("ok") {
-        if let Some(ok) = ok.as_bool() {
-            if ok { return Ok(()) }
-        }
-    }
-    Err(Box::new(SlackError(response_json)))
-}
-
-pub async fn post_image(bot_token: String, channel: String, text: String, filedata: &Vec<u8>, filename: String) -> SlackResult {
-    // slackに画像を送信
-    // 参考: https://api.slack.com/methods/files.upload
-    let form = reqwest::multipart::Form::new();
-    let form = form.text("channels", channel.to_string());
-    let form = form.text("initial_comment", text.to_string());
-    let form = form.part("file", reqwest::multipart::Part::bytes(filedata.to_vec()).file_name(filename.to_string()));
-    let client = reqwest::Client::new();
-    let response_json = client.post("https://slack.com/api/files.upload")
-        .header(reqwest::header::CONTENT_TYPE, "multipart/form-data")
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", bot_token))
-        .multipart(form).send().await?.text().await?;
-    let response: serde_json::Value = serde_json::from_str(&response_json)?;
-    if let Some(ok_val) = response.get("ok") {
-        if let Some(ok) = ok_val.as_bool() {
-            if ok { return Ok(()) }
-        }
-    }
-    Err(Box::new(SlackError(response_json)))
+// Slack APIの呼び出しとsocket modeの接続のエラー
+// 参考: https://api.slack.com/web#evaluating_responses
+#[derive(Debug, thiserror::Error)]
+pub enum SlackApiError {
+    // 通信エラー、5xx、レート制限 (429) で、再試行しても成功しなかった
+    #[error("{method} failed: {reason}")]
+    Transport { method: String, reason: String },
+    // Slack APIがokをfalseで返した (errorはSlackのエラーコード)
+    #[error("{method} failed: {error}")]
+    Api { method: String, error: String },
+    // レスポンスを読み取れなかったか、必要な値が入っていなかった
+    #[error("{method} returned an invalid response: {reason}")]
+    Parse { method: String, reason: String },
+    // websocketの接続や送受信に失敗した (他のエラーより大きいのでBoxに入れる)
+    #[error("websocket error: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+}
+impl From<tokio_tungstenite::tungstenite::Error> for SlackApiError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> SlackApiError {
+        return SlackApiError::WebSocket(Box::new(err));
+    }
+}
+impl SlackApiError {
+    // Slack APIが返したエラーコード (okがfalseの場合のみ)
+    pub fn get_code(&self) -> Option<&str> {
+        return match self {
+            SlackApiError::Api { error, .. } => Some(error.as_str()),
+            _ => None,
+        };
+    }
+
+    // 再試行すれば成功する可能性があるか
+    // 冪等でない呼び出しは、処理されていないことが確実なレート制限の場合のみ再試行する
+    fn is_retryable(&self, idempotent: bool) -> bool {
+        return match self {
+            SlackApiError::Api { error, .. } if idempotent => RETRYABLE_ERRORS.contains(&error.as_str()),
+            SlackApiError::Api { error, .. } => error == "ratelimited",
+            _ => false,
+        };
+    }
+
+    // レート制限で失敗したか
+    pub fn is_rate_limited(&self) -> bool {
+        return match self {
+            SlackApiError::Api { error, .. } => error == "ratelimited",
+            SlackApiError::Transport { reason, .. } => reason == "HTTP 429",
+            _ => false,
+        };
+    }
+
+    // チャンネルがアーカイブされたか削除されたことを表すエラーか (再試行しても成功しない)
+    pub fn is_channel_unavailable(&self) -> bool {
+        return match self.get_code() {
+            Some(code) => CHANNEL_UNAVAILABLE_ERRORS.contains(&code),
+            None => false,
+        };
+    }
+}
+
+pub type SlackResult<T = ()> = Result<T, SlackApiError>;
+
+// 一時的な失敗 (通信エラー、5xx、レート制限) は指数バックオフで待ってから再試行する
+// 参考: https://api.slack.com/docs/rate-limits
+const MAX_ATTEMPTS: u32 = 4;
+const RETRY_BASE_MILLIS: u64 = 500;
+// 再試行すれば成功する可能性があるエラー (invalid_authやchannel_not_foundなどは再試行せずに失敗する)
+const RETRYABLE_ERRORS: [&str; 5] = ["ratelimited", "internal_error", "fatal_error", "service_unavailable", "request_timeout"];
+
+// attempt回目の失敗の後に待つ時間 (同時に失敗した呼び出しがずれるようにジッターを加える)
+fn get_retry_delay(attempt: u32) -> std::time::Duration {
+    let base = RETRY_BASE_MILLIS * 2u64.pow(attempt - 1);
+    return std::time::Duration::from_millis(base + rand::random::<u64>() % base);
+}
+
+// レート制限の場合にslackが指定する待ち時間
+fn get_retry_after(headers: &reqwest::header::HeaderMap) -> Option<std::time::Duration> {
+    let seconds = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.parse::<u64>().ok()?;
+    return Some(std::time::Duration::from_secs(seconds));
+}
+
+// リクエストを送信し、通信エラー、5xx、429の場合は再試行する
+// リクエストは送信するたびにbuild_requestで作り直す
+// 冪等でない呼び出し (idempotentがfalse) は、タイムアウトや5xxの場合にSlack側で処理済みの可能性があり、
+// 再試行すると二重に投稿されるので、429と接続できなかった場合のみ再試行する
+async fn send_with_retry<F: Fn() -> reqwest::RequestBuilder>(method: &str, idempotent: bool, build_request: F) -> SlackResult<reqwest::Response> {
+    let mut attempt = 0;
+    loop {
+        let (reason, wait, retryable) = match build_request().send().await {
+            Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                ("HTTP 429".to_string(), get_retry_after(response.headers()), true)
+            },
+            Ok(response) if response.status().is_server_error() => (format!("HTTP {}", response.status()), None, idempotent),
+            Ok(response) => { return Ok(response); },
+            Err(err) => (err.to_string(), None, idempotent || err.is_connect()),
+        };
+        if !retryable {
+            return Err(SlackApiError::Transport { method: method.to_string(), reason });
+        }
+        attempt += 1;
+        if attempt >= MAX_ATTEMPTS {
+            return Err(SlackApiError::Transport { method: method.to_string(), reason });
+        }
+        let wait = wait.unwrap_or_else(|| get_retry_delay(attempt));
+        tracing::warn!("{} failed ({}), retrying in {} ms", method, reason, wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// Slack APIを呼び出してレスポンスのjsonを返す
+// 失敗した呼び出しはメソッドごとにメトリクスで数える
+async fn call_api<F: Fn() -> reqwest::RequestBuilder>(method: &str, idempotent: bool, build_request: F) -> SlackResult<serde_json::Value> {
+    let result = call_api_with_retry(method, idempotent, build_request).await;
+    if result.is_err() {
+        metrics::get().slack_api_errors.with_label_values(&[method]).inc();
+    }
+    return result;
+}
+
+// okがfalseの場合、一時的なエラーであれば再試行し、それ以外はSlackApiErrorを返す
+async fn call_api_with_retry<F: Fn() -> reqwest::RequestBuilder>(method: &str, idempotent: bool, build_request: F) -> SlackResult<serde_json::Value> {
+    let mut attempt = 0;
+    loop {
+        let start_time = std::time::Instant::now();
+        let response = send_with_retry(method, idempotent, &build_request).await?;
+        let retry_after = get_retry_after(response.headers());
+        let response_json = response.text().await
+            .map_err(|err| SlackApiError::Transport { method: method.to_string(), reason: err.to_string() })?;
+        let elapsed_ms = start_time.elapsed().as_millis() as u64;
+        metrics::get().slack_api_duration.with_label_values(&[method]).observe(elapsed_ms as f64 / 1000.0);
+        let response: serde_json::Value = serde_json::from_str(&response_json)
+            .map_err(|err| SlackApiError::Parse { method: method.to_string(), reason: err.to_string() })?;
+        if response.get("ok").and_then(|value| value.as_bool()) == Some(true) {
+            tracing::debug!(method, elapsed_ms, "api call succeeded");
+            return Ok(response);
+        }
+        let error = response.get("error").and_then(|value| value.as_str()).unwrap_or(&response_json).to_string();
+        tracing::warn!(method, elapsed_ms, body = %response_json, "api call failed: {}", error);
+        let error = SlackApiError::Api { method: method.to_string(), error };
+        attempt += 1;
+        if !error.is_retryable(idempotent) || attempt >= MAX_ATTEMPTS {
+            return Err(error);
+        }
+        let wait = retry_after.unwrap_or_else(|| get_retry_delay(attempt));
+        tracing::warn!("{}, retrying in {} ms", error, wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// チャンネルがアーカイブされたか削除されたことを表すエラー (再試行しても成功しない)
+const CHANNEL_UNAVAILABLE_ERRORS: [&str; 2] = ["channel_not_found", "is_archived"];
+// ユーザーのプロフィールを取得できないことを表すエラー (再試行しても成功しない)
+const PROFILE_UNAVAILABLE_ERRORS: [&str; 3] = ["user_not_found", "user_not_visible", "missing_scope"];
+
+// 接続とリクエスト全体のタイムアウト (応答がないままターンの処理が止まらないようにする)
+const CONNECT_TIMEOUT_SEC: u64 = 10;
+const REQUEST_TIMEOUT_SEC: u64 = 60;
+
+// TOWER_LEGACY_FILE_UPLOADがtrueの場合は廃止予定のfiles.uploadで画像を送信する (移行期間中の切り戻し用)
+static LEGACY_FILE_UPLOAD: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+fn is_legacy_file_upload() -> bool {
+    return *LEGACY_FILE_UPLOAD.get_or_init(|| {
+        return std::env::var("TOWER_LEGACY_FILE_UPLOAD").map(|value| value == "true").unwrap_or(false);
+    });
+}
+
+// 内部のエラーを報告するチャンネル (ADMIN_CHANNEL_ID、設定しない場合は報告しない)
+fn get_admin_channel_id() -> Option<String> {
+    return std::env::var("ADMIN_CHANNEL_ID").ok().filter(|value| !value.is_empty());
+}
+
+// 報告するエラーの最大の文字数 (Slackのレスポンスの本文がそのまま入っている場合などに長くなりすぎないようにする)
+const ERROR_REPORT_MAX_CHARS: usize = 1000;
+fn truncate_error(error: &str) -> String {
+    if error.chars().count() <= ERROR_REPORT_MAX_CHARS { return error.to_string(); }
+    return error.chars().take(ERROR_REPORT_MAX_CHARS).collect::<String>() + "…";
 }
 
 #[derive(Debug)]
@@ -77,169 +186,850 @@ pub struct UserInfo {
     pub name: Option<String>,
     pub icon_data: Option<Vec<u8>>,
 }
-pub async fn get_user_info(bot_token: String, user_id: String) -> SlackResult<UserInfo> {
-    // slackのuser_idからユーザー名とアイコン画像を取得
-    // 参考: https://api.slack.com/methods/users.profile.get
-    let client = reqwest::Client::new();
-    let response_json = client.get("https://slack.com/api/users.profile.get")
-        .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", bot_token))
-        .query(&[("user", &user_id)]).send().await?.text().await?;
-    let response: serde_json::Value = serde_json::from_str(&response_json)?;
-
-    /*
-    APIで返されるjsonの例
-
-	{
-		"ok": true,
-		"profile": {
-			"avatar_hash": "ge3b51ca72de",
-			"status_text": "Print is dead",
-			"status_emoji": ":books:",
-			"status_expiration": 0,
-			"real_name": "Egon Spengler",
-			"display_name": "spengler",
-			"real_name_normalized": "Egon Spengler",
-			"display_name_normalized": "spengler",
-			"email": "spengler@ghostbusters.example.com",
-			"image_original": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"image_24": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"image_32": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"image_48": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"image_72": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"image_192": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"image_512": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
-			"team": "T012AB3C4"
-		}
-	}
-    */
-
-    let mut user_info = UserInfo{ user_id: user_id.to_string(), name: None, icon_data: None };
-    if let Some(profile) = response.get("profile") {
-        if let Some(name) = profile.get("display_name") {
-            if let Some(name) = name.as_str() { user_info.name = Some(name.to_string()); }
-            if name == "" {
-                if let Some(name) = profile.get("real_name") {
-                    if let Some(name) = name.as_str() { user_info.name = Some(name.to_string()); }
+
+// Slack APIの接続先の既定値
+const SLACK_BASE_URL: &str = "https://slack.com";
+
+// 接続とリクエスト全体にタイムアウトを設定したクライアント (応答しないサーバーでターンが止まらないようにする)
+fn build_http_client(request_timeout: std::time::Duration) -> SlackResult<reqwest::Client> {
+    return reqwest::Client::builder()
+        .connect_timeout(std::time::Duration::from_secs(CONNECT_TIMEOUT_SEC))
+        .timeout(request_timeout)
+        .build()
+        .map_err(|err| SlackApiError::Transport { method: "client".to_string(), reason: err.to_string() });
+}
+
+// Slack APIのクライアント
+// コネクションを使い回すために、1つのreqwest::Clientを全ての呼び出しで共有する
+pub struct SlackClient {
+    client: reqwest::Client,
+    bot_token: String,
+    app_token: String,
+    admin_channel_id: Option<String>,
+    // APIの接続先 (テスト用のサーバーに向ける場合はwith_base_urlで変更する)
+    base_url: String,
+}
+impl SlackClient {
+    pub fn new(bot_token: String, app_token: String) -> SlackResult<SlackClient> {
+        let client = build_http_client(std::time::Duration::from_secs(REQUEST_TIMEOUT_SEC))?;
+        return Ok(SlackClient { client, bot_token, app_token, admin_channel_id: get_admin_channel_id(), base_url: SLACK_BASE_URL.to_string() });
+    }
+
+    // 1回のリクエストのタイムアウトを変更する (既定はREQUEST_TIMEOUT_SEC)
+    pub fn with_request_timeout(mut self, request_timeout: std::time::Duration) -> SlackResult<SlackClient> {
+        self.client = build_http_client(request_timeout)?;
+        return Ok(self);
+    }
+
+    // APIの接続先を変更する (例: http://127.0.0.1:8080)
+    pub fn with_base_url(mut self, base_url: &str) -> SlackClient {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        return self;
+    }
+
+    fn get_api_url(&self, method: &str) -> String {
+        return format!("{}/api/{}", self.base_url, method);
+    }
+
+    pub async fn report_error(&self, context: String, error: String) -> bool {
+        // 内部のエラーを管理用のチャンネルに投稿する (errorは長い場合に切り詰める)
+        // 管理用のチャンネルが設定されていないか、投稿できなかった場合はfalseを返す
+        let admin_channel_id = match &self.admin_channel_id {
+            Some(admin_channel_id) => admin_channel_id.clone(),
+            None => { return false; },
+        };
+        let text = format!(":warning: {}\n```{}```", context, truncate_error(&error));
+        return match self.post_message(admin_channel_id, None, text).await {
+            Ok(_) => true,
+            Err(err) => {
+                tracing::warn!("failed to report an error to the admin channel: {}", err);
+                false
+            },
+        };
+    }
+
+    pub async fn auth_test(&self) -> SlackResult<String> {
+        // bot自身のユーザーIDを取得
+        // 参考: https://api.slack.com/methods/auth.test
+        let client = &self.client;
+        let response = call_api("auth.test", true, || client.post(self.get_api_url("auth.test"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))).await?;
+        match response.get("user_id").and_then(|value| value.as_str()) {
+            Some(user_id) => Ok(user_id.to_string()),
+            None => Err(SlackApiError::Parse { method: "auth.test".to_string(), reason: format!("no user_id in {}", response) }),
+        }
+    }
+
+    pub async fn get_websocket_url(&self) -> SlackResult<String> {
+        // slackからwebsocketのURLを取得
+        // 参考: https://api.slack.com/apis/connections/socket-implement
+        let client = &self.client;
+        let response = call_api("apps.connections.open", true, || client.post(self.get_api_url("apps.connections.open"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.app_token))
+            .body("")).await?;
+        let websocket_url = match response.get("url").and_then(|value| { value.as_str() }) {
+            Some(url) => Ok(url.replace("\"", "")),
+            None => Err(SlackApiError::Parse { method: "apps.connections.open".to_string(), reason: format!("no url in {}", response) }),
+        }?;
+        Ok(websocket_url)
+    }
+
+    pub async fn post_message(&self, channel: String, thread_ts: Option<String>, text: String) -> SlackResult<String> {
+        // slackにメッセージを送信 (thread_tsを指定した場合はスレッドに返信)
+        // 投稿したメッセージのtsを返す (スレッドを開始する時に使う)
+        let mut params = HashMap::new();
+        params.insert("channel", channel);
+        params.insert("text", text);
+        if let Some(thread_ts) = thread_ts { params.insert("thread_ts", thread_ts); }
+        return self.send_message(params).await;
+    }
+
+    pub async fn post_broadcast(&self, channel: String, thread_ts: String, text: String) -> SlackResult<String> {
+        // スレッドに返信し、同じメッセージをチャンネルにも表示する
+        let mut params = HashMap::new();
+        params.insert("channel", channel);
+        params.insert("text", text);
+        params.insert("thread_ts", thread_ts);
+        params.insert("reply_broadcast", "true".to_string());
+        return self.send_message(params).await;
+    }
+
+    pub async fn post_ephemeral(&self, channel: String, thread_ts: Option<String>, user: String, text: String) -> SlackResult {
+        // 指定したユーザーにだけ見えるメッセージを送信する (入力のエラーや順番待ちの通知に使う)
+        // 送信できなかった場合 (ボットがチャンネルにいない場合など) はチャンネルに投稿する
+        // 参考: https://api.slack.com/methods/chat.postEphemeral
+        let client = &self.client;
+        let mut params = vec![("channel", channel.clone()), ("user", user), ("text", text.clone())];
+        if let Some(thread_ts) = &thread_ts { params.push(("thread_ts", thread_ts.clone())); }
+        let result = call_api("chat.postEphemeral", false, || client.post(self.get_api_url("chat.postEphemeral"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&params)).await;
+        if let Err(err) = result {
+            tracing::warn!("failed to post ephemeral message, posting to channel instead: {}", err);
+            self.post_message(channel, thread_ts, text).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn post_blocks(&self, channel: String, thread_ts: Option<String>, text: String, blocks: &serde_json::Value) -> SlackResult<String> {
+        // Block Kitのブロック (ボタンなど) を含むメッセージを送信する
+        // textは通知とブロックを表示できない環境で使われる
+        // 参考: https://api.slack.com/block-kit
+        let mut params = HashMap::new();
+        params.insert("channel", channel);
+        params.insert("text", text);
+        params.insert("blocks", blocks.to_string());
+        if let Some(thread_ts) = thread_ts { params.insert("thread_ts", thread_ts); }
+        return self.send_message(params).await;
+    }
+
+    pub async fn update_blocks(&self, channel: String, ts: String, text: String, blocks: &serde_json::Value) -> SlackResult {
+        // 送信済みのメッセージを書き換える
+        // 参考: https://api.slack.com/methods/chat.update
+        let client = &self.client;
+        let params = [("channel", channel), ("ts", ts), ("text", text), ("blocks", blocks.to_string())];
+        call_api("chat.update", true, || client.post(self.get_api_url("chat.update"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&params)).await?;
+        Ok(())
+    }
+
+    pub async fn update_message(&self, channel: String, ts: String, text: String) -> SlackResult {
+        // 送信済みのメッセージの本文を書き換える (計算中のメッセージを結果に置き換える時に使う)
+        // 参考: https://api.slack.com/methods/chat.update
+        let client = &self.client;
+        let params = [("channel", channel), ("ts", ts), ("text", text)];
+        call_api("chat.update", true, || client.post(self.get_api_url("chat.update"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&params)).await?;
+        Ok(())
+    }
+
+    async fn send_message(&self, params: HashMap<&str, String>) -> SlackResult<String> {
+        // 参考: https://api.slack.com/methods/chat.postMessage
+        let client = &self.client;
+        let response = call_api("chat.postMessage", false, || client.post(self.get_api_url("chat.postMessage"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&params)).await?;
+        match response.get("ts").and_then(|value| value.as_str()) {
+            Some(ts) => Ok(ts.to_string()),
+            None => Err(SlackApiError::Parse { method: "chat.postMessage".to_string(), reason: format!("no ts in {}", response) }),
+        }
+    }
+
+    pub async fn post_image(&self, channel: String, thread_ts: Option<String>, text: String, filedata: &[u8], filename: String) -> SlackResult {
+        // slackに画像を送信 (thread_tsを指定した場合はスレッドに投稿)
+        if is_legacy_file_upload() {
+            return self.post_image_legacy(channel, thread_ts, text, filedata, filename).await;
+        }
+
+        // files.uploadの代わりに、アップロード先のURLを取得 → ファイルを送信 → 投稿の3段階で送信する
+        // 参考: https://api.slack.com/messaging/files#uploading_files
+        let client = &self.client;
+
+        // 1. アップロード先のURLとファイルのIDを取得
+        // 参考: https://api.slack.com/methods/files.getUploadURLExternal
+        let response = call_api("files.getUploadURLExternal", true, || client.post(self.get_api_url("files.getUploadURLExternal"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&[("filename", filename.clone()), ("length", filedata.len().to_string())])).await?;
+        let upload_url = response.get("upload_url").and_then(|value| value.as_str());
+        let file_id = response.get("file_id").and_then(|value| value.as_str());
+        let (upload_url, file_id) = match (upload_url, file_id) {
+            (Some(upload_url), Some(file_id)) => (upload_url.to_string(), file_id.to_string()),
+            _ => { return Err(SlackApiError::Parse { method: "files.getUploadURLExternal".to_string(), reason: format!("no upload_url or file_id in {}", response) }); },
+        };
+
+        // 2. ファイルの中身を送信 (同じURLに送り直しても上書きされるだけなので、冪等として再試行する)
+        let response = send_with_retry("upload", true, || client.post(&upload_url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .body(filedata.to_vec())).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(SlackApiError::Transport { method: "upload".to_string(), reason: format!("{}: HTTP {} {}", filename, status, body) });
+        }
+
+        // 3. アップロードを完了してチャンネルに投稿
+        // 参考: https://api.slack.com/methods/files.completeUploadExternal
+        let mut params = vec![
+            ("files", serde_json::json!([{"id": file_id, "title": filename}]).to_string()),
+            ("channel_id", channel),
+            ("initial_comment", text),
+        ];
+        if let Some(thread_ts) = thread_ts { params.push(("thread_ts", thread_ts)); }
+        call_api("files.completeUploadExternal", false, || client.post(self.get_api_url("files.completeUploadExternal"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&params)).await?;
+        Ok(())
+    }
+
+    async fn post_image_legacy(&self, channel: String, thread_ts: Option<String>, text: String, filedata: &[u8], filename: String) -> SlackResult {
+        // 参考: https://api.slack.com/methods/files.upload
+        // multipartのフォームは使い回せないので、再試行のたびに作り直す
+        let create_form = || {
+            let form = reqwest::multipart::Form::new();
+            let form = form.text("channels", channel.to_string());
+            let form = match &thread_ts {
+                Some(thread_ts) => form.text("thread_ts", thread_ts.to_string()),
+                None => form,
+            };
+            let form = form.text("initial_comment", text.to_string());
+            return form.part("file", reqwest::multipart::Part::bytes(filedata.to_vec()).file_name(filename.to_string()));
+        };
+        let client = &self.client;
+        call_api("files.upload", false, || client.post(self.get_api_url("files.upload"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .multipart(create_form())).await?;
+        Ok(())
+    }
+
+    pub async fn add_reaction(&self, channel: String, ts: String, emoji: String) -> SlackResult {
+        // メッセージにリアクションを付ける
+        // 参考: https://api.slack.com/methods/reactions.add
+        return self.send_reaction("reactions.add", channel, ts, emoji).await;
+    }
+
+    pub async fn remove_reaction(&self, channel: String, ts: String, emoji: String) -> SlackResult {
+        // 自分が付けたリアクションを外す
+        // 参考: https://api.slack.com/methods/reactions.remove
+        return self.send_reaction("reactions.remove", channel, ts, emoji).await;
+    }
+
+    // リアクションは付け直しても外し直しても結果が変わらない (already_reactedなどが返る) ので、冪等として再試行する
+    async fn send_reaction(&self, method: &str, channel: String, ts: String, emoji: String) -> SlackResult {
+        let mut params = HashMap::new();
+        params.insert("channel", channel);
+        params.insert("timestamp", ts);
+        params.insert("name", emoji);
+        let client = &self.client;
+        call_api(method, true, || client.post(self.get_api_url(method))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .form(&params)).await?;
+        Ok(())
+    }
+
+    pub async fn get_user_info(&self, user_id: String) -> SlackResult<UserInfo> {
+        // slackのuser_idからユーザー名とアイコン画像を取得
+        // 参考: https://api.slack.com/methods/users.profile.get
+        let client = &self.client;
+        let response = call_api("users.profile.get", true, || client.get(self.get_api_url("users.profile.get"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .query(&[("user", &user_id)])).await;
+        // プロフィールを取得できない場合 (スコープ不足など) は名前とアイコンなしで続ける
+        // 通信エラーなどの一時的な失敗は、名前とアイコンなしで記憶されないようにエラーを返す
+        let response = match response {
+            Ok(response) => response,
+            Err(err) if err.get_code().is_some_and(|code| PROFILE_UNAVAILABLE_ERRORS.contains(&code)) => {
+                tracing::warn!("failed to get user info {}: {}", user_id, err);
+                serde_json::Value::Null
+            },
+            Err(err) => { return Err(err); },
+        };
+
+        /*
+        APIで返されるjsonの例
+
+    	{
+    		"ok": true,
+    		"profile": {
+    			"avatar_hash": "ge3b51ca72de",
+    			"status_text": "Print is dead",
+    			"status_emoji": ":books:",
+    			"status_expiration": 0,
+    			"real_name": "Egon Spengler",
+    			"display_name": "spengler",
+    			"real_name_normalized": "Egon Spengler",
+    			"display_name_normalized": "spengler",
+    			"email": "spengler@ghostbusters.example.com",
+    			"image_original": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"image_24": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"image_32": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"image_48": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"image_72": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"image_192": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"image_512": "https://.../avatar/e3b51ca72dee4ef87916ae2b9240df50.jpg",
+    			"team": "T012AB3C4"
+    		}
+    	}
+        */
+
+        let mut user_info = UserInfo{ user_id: user_id.to_string(), name: None, icon_data: None };
+        if let Some(profile) = response.get("profile") {
+            if let Some(name) = profile.get("display_name") {
+                if let Some(name) = name.as_str() { user_info.name = Some(name.to_string()); }
+                if name == "" {
+                    if let Some(name) = profile.get("real_name") {
+                        if let Some(name) = name.as_str() { user_info.name = Some(name.to_string()); }
+                    }
                 }
             }
-        }
-        if let Some(image_url) = None.or(
-            profile.get("image_original")).or(
-            profile.get("image_1024")).or(
-            profile.get("image_512")).or(
-            profile.get("image_192")).or(
-            profile.get("image_72")).or(
-            profile.get("image_48")).or(
-            profile.get("image_32")).or(
-            profile.get("image_24"))
-        {
-            if let Some(image_url) = image_url.as_str() {
-                let data = download_data(&image_url.to_string()).await?;
-                user_info.icon_data = Some(data);
+            if let Some(image_url) = None.or(
+                profile.get("image_original")).or(
+                profile.get("image_1024")).or(
+                profile.get("image_512")).or(
+                profile.get("image_192")).or(
+                profile.get("image_72")).or(
+                profile.get("image_48")).or(
+                profile.get("image_32")).or(
+                profile.get("image_24"))
+            {
+                if let Some(image_url) = image_url.as_str() {
+                    let data = self.download_data(&image_url.to_string(), false).await?;
+                    user_info.icon_data = Some(data);
+                }
             }
         }
+        Ok(user_info)
     }
-    Ok(user_info)
-}
 
-pub async fn download_data(url: &String) -> SlackResult<Vec<u8>> {
-    let response = reqwest::get(url).await?;
-    Ok(response.bytes().await?.to_vec())
+    pub async fn download_data(&self, url: &String, authorized: bool) -> SlackResult<Vec<u8>> {
+        // slackにアップロードされたファイルはbot tokenがないとダウンロードできない
+        let client = &self.client;
+        let response = send_with_retry("download", true, || {
+            let request = client.get(url);
+            if authorized { request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.bot_token)) }
+            else { request }
+        }).await?;
+        let data = response.bytes().await
+            .map_err(|err| SlackApiError::Transport { method: "download".to_string(), reason: err.to_string() })?;
+        Ok(data.to_vec())
+    }
 }
 
+
 #[derive(Debug, Clone, Copy)]
 enum Disconnect{ Reconnecting, Exit }
+// メッセージに添付されたファイル
+#[derive(Debug)]
+pub struct File {
+    pub name: String,
+    pub size: u64,
+    pub url: String,
+}
 #[derive(Debug)]
 pub struct Message {
     pub event_type: String,
     pub channel_id: String,
     pub user_id: String,
     pub text: String,
+    pub files: Vec<File>,
+    // メッセージのタイムスタンプと、スレッド内のメッセージの場合はスレッドの親のタイムスタンプ
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    // 再送されたイベントの判別に使う (Events API以外のメッセージの場合はNone)
+    pub event_id: Option<String>,
+    pub event_time: Option<i64>,
+    // ボタンが押された場合の、ボタンのあるメッセージのts
+    pub action_message_ts: Option<String>,
+    // チャンネルの種類 (DMの場合は "im"、分からない場合はNone)
+    pub channel_type: Option<String>,
+    // messageイベントのsubtype (編集されたメッセージの場合は "message_changed")
+    // 参考: https://api.slack.com/events/message#subtypes
+    pub subtype: Option<String>,
+}
+impl Message {
+    // チャンネルのアーカイブとメンバーの退出のイベントを表すメッセージ
+    fn from_channel_event(event_type: &str, event: ChannelEvent, event_id: Option<String>, event_time: Option<i64>) -> Message {
+        return Message {
+            event_type: event_type.to_string(),
+            channel_id: event.channel,
+            user_id: event.user,
+            text: String::new(),
+            files: Vec::new(),
+            ts: String::new(),
+            thread_ts: None,
+            event_id,
+            event_time,
+            action_message_ts: None,
+            channel_type: None,
+            subtype: None,
+        };
+    }
+}
+
+// socket modeで受信するメッセージ
+// 参考: https://api.slack.com/apis/connections/socket-implement
+#[derive(Deserialize)]
+struct EnvelopeHeader {
+    envelope_id: Option<String>,
+}
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Envelope {
+    EventsApi { payload: Box<EventsApiPayload> },
+    SlashCommands { payload: SlashCommandPayload },
+    Interactive { payload: InteractivePayload },
+    Disconnect { reason: Option<String> },
+    // hello など、処理しないメッセージ
+    #[serde(other)]
+    Other,
+}
+// 参考: https://api.slack.com/interactivity/slash-commands#app_command_handling
+#[derive(Deserialize)]
+struct SlashCommandPayload {
+    channel_id: String,
+    user_id: String,
+    #[serde(default)]
+    text: String,
 }
-use futures_util::{pin_mut, StreamExt};
+// 参考: https://api.slack.com/reference/interaction-payloads/block-actions
+#[derive(Deserialize)]
+struct InteractivePayload {
+    #[serde(rename = "type")]
+    payload_type: String,
+    user: InteractiveId,
+    channel: Option<InteractiveId>,
+    container: Option<InteractiveContainer>,
+    #[serde(default)]
+    actions: Vec<InteractiveAction>,
+}
+#[derive(Deserialize)]
+struct InteractiveId {
+    id: String,
+}
+#[derive(Deserialize)]
+struct InteractiveContainer {
+    message_ts: Option<String>,
+    thread_ts: Option<String>,
+}
+#[derive(Deserialize)]
+struct InteractiveAction {
+    value: Option<String>,
+}
+// 参考: https://api.slack.com/apis/connections/events-api#callback-field
+#[derive(Deserialize)]
+struct EventsApiPayload {
+    event_id: Option<String>,
+    event_time: Option<i64>,
+    event: Event,
+}
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    AppMention(AppMentionEvent),
+    Message(MessageEvent),
+    // チャンネルのアーカイブとメンバーの退出 (botが外された場合はチャンネルのステージを削除する)
+    // 参考: https://api.slack.com/events/channel_archive
+    ChannelArchive(ChannelEvent),
+    GroupArchive(ChannelEvent),
+    MemberLeftChannel(ChannelEvent),
+    #[serde(other)]
+    Other,
+}
+// 参考: https://api.slack.com/events/app_mention
+#[derive(Deserialize)]
+struct AppMentionEvent {
+    channel: String,
+    user: String,
+    text: String,
+    ts: String,
+    thread_ts: Option<String>,
+    // botが送信したメッセージの場合に入る (bot同士で反応し続けないように無視する)
+    bot_id: Option<String>,
+    #[serde(default)]
+    files: Vec<EventFile>,
+}
+// DMで送られたメッセージ (message.imを購読すると届く)
+// 参考: https://api.slack.com/events/message.im
+#[derive(Deserialize)]
+struct MessageEvent {
+    channel: String,
+    user: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    thread_ts: Option<String>,
+    channel_type: Option<String>,
+    // 編集や削除などの場合に入る (ファイル付きのメッセージは "file_share")
+    subtype: Option<String>,
+    bot_id: Option<String>,
+    #[serde(default)]
+    files: Vec<EventFile>,
+    // message_changedの場合の編集後のメッセージ
+    message: Option<ChangedMessage>,
+}
+// 参考: https://api.slack.com/events/message/message_changed
+#[derive(Deserialize)]
+struct ChangedMessage {
+    user: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    thread_ts: Option<String>,
+}
+#[derive(Deserialize)]
+struct ChannelEvent {
+    channel: String,
+    #[serde(default)]
+    user: String,
+}
+#[derive(Deserialize)]
+struct EventFile {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    size: u64,
+    url_private: Option<String>,
+}
+use futures_util::{pin_mut, SinkExt, StreamExt};
 use tokio_tungstenite::tungstenite::protocol;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-async fn single_websocket_receiver(id: u64, app_token: String, sender: Sender<Message>) -> SlackResult<Disconnect> {
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+// 何も受信しないまま接続が切れたとみなすまでの時間 (SLACK_SOCKET_TIMEOUT_SECで変更できる)
+const DEFAULT_LIVENESS_TIMEOUT_SEC: u64 = 120;
+const LIVENESS_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+fn get_liveness_timeout() -> std::time::Duration {
+    let seconds = std::env::var("SLACK_SOCKET_TIMEOUT_SEC").ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_LIVENESS_TIMEOUT_SEC);
+    return std::time::Duration::from_secs(seconds.max(1));
+}
+
+// 開いている接続の数を数える (panicした場合も数え間違えないように、dropした時に減らす)
+struct OpenConnection(Arc<AtomicUsize>);
+impl OpenConnection {
+    fn new(open_connections: Arc<AtomicUsize>) -> OpenConnection {
+        open_connections.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        return OpenConnection(open_connections);
+    }
+}
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+async fn single_websocket_receiver(id: u64, slack_client: Arc<SlackClient>, sender: Sender<Message>, health: WebsocketHealth, shutdown: watch::Receiver<bool>) -> SlackResult<Disconnect> {
     // websocketのURLを取得
-    println!("status(id: {}): connecting websocket", id);
-    let url = get_websocket_url(app_token).await;
+    tracing::info!(id, "connecting websocket");
+    let url = slack_client.get_websocket_url().await;
     if let Err(err) = url { return Err(err); }
     let websocket_url = url.unwrap();
+    health.url_obtained.store(true, std::sync::atomic::Ordering::SeqCst);
 
     // websocketに接続
     //let url = url::Url::parse(&format!("{}&debug_reconnects=true", websocket_url)).unwrap();
-    let url = url::Url::parse(&format!("{}", websocket_url)).unwrap();
+    let url = url::Url::parse(&websocket_url).unwrap();
     let (ws_stream, _) = tokio_tungstenite::connect_async(url).await?;
-    println!("status(id: {}): connected websocket", id);
+    tracing::info!(id, "connected websocket");
+    let _open_connection = OpenConnection::new(Arc::clone(&health.open_connections));
 
     // 再接続フラグ
     let reconnect = Arc::new(AtomicBool::new(false));
 
-    let (write, read) = ws_stream.split();
-    let (responder_tx, responder_rx) = futures_channel::mpsc::channel(128);
-    let responder = responder_rx.map(Ok).forward(write);
-    let receiver = read.for_each(|message| async {
-        if let Ok(protocol::Message::Text(json)) = message {
-            let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let (mut write, read) = ws_stream.split();
+    let (responder_tx, mut responder_rx) = channel::<protocol::Message>(128);
+    let responder = async move {
+        while let Some(response) = responder_rx.recv().await {
+            if let Err(err) = write.send(response).await {
+                tracing::warn!(id, "failed to send a frame: {}", err);
+                return;
+            }
+        }
+    };
+    // 応答 (受信の確認とPong) は送信する側に空きができるまで待ってから渡す (大量に受信した場合は受信も待たせる)
+    // 受信の確認を落とすとslackが同じイベントを再送するので、送信する側が終了していて送れなかった場合だけ数える
+    let respond = |response: protocol::Message| async {
+        if let Err(err) = responder_tx.send(response).await {
+            let dropped = health.dropped_responses.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            metrics::get().dropped_messages.with_label_values(&["response"]).inc();
+            tracing::warn!(id, "dropped a response ({} dropped in total): {}", dropped, err);
+        }
+    };
+    // 最後にフレームを受信した時刻 (接続してからのミリ秒)
+    // slackは定期的にPingを送ってくるので、一定時間何も受信しない場合は接続が切れたとみなす
+    let connected_time = std::time::Instant::now();
+    let last_received = AtomicU64::new(0);
+    // 受信したメッセージは処理する側に空きができるまで待ってから渡す (処理が詰まった場合は受信も待たせる)
+    let forward = |message: Message| async {
+        if sender.send(message).await.is_err() {
+            let dropped = health.dropped_messages.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            metrics::get().dropped_messages.with_label_values(&["received"]).inc();
+            tracing::warn!(id, "dropped a message because the dispatcher is closed ({} dropped in total)", dropped);
+        }
+    };
+    let handle_frame = |message: Result<protocol::Message, tokio_tungstenite::tungstenite::Error>| async {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::warn!(id, "failed to receive a frame: {}", err);
+                return;
+            },
+        };
+        last_received.store(connected_time.elapsed().as_millis() as u64, std::sync::atomic::Ordering::SeqCst);
+        health.last_received.store(get_unix_millis(), std::sync::atomic::Ordering::SeqCst);
+        match &message {
+            protocol::Message::Ping(data) => {
+                respond(protocol::Message::Pong(data.clone())).await;
+                return;
+            },
+            // slackから接続を閉じられた場合はすぐに再接続する
+            protocol::Message::Close(frame) => {
+                tracing::info!(id, "received close {:?}", frame);
+                reconnect.store(true, std::sync::atomic::Ordering::SeqCst);
+                return;
+            },
+            _ => {},
+        };
+        if let protocol::Message::Text(json) = message {
+            let envelope = serde_json::from_str::<Envelope>(&json);
+
             // メッセージを受け取ったことをslackにレスポンスする
+            // スラッシュコマンドの場合は、結果が出るまでの間に本人にだけ見えるメッセージを返す
             // 参考: https://api.slack.com/apis/connections/socket-implement#acknowledge
-            if let Some(envelope_id) = json.get("envelope_id") {
-                let _ = responder_tx.clone().try_send(protocol::Message::Text(serde_json::json!({"envelope_id": envelope_id}).to_string()));
-            }
+            match serde_json::from_str::<EnvelopeHeader>(&json) {
+                Ok(EnvelopeHeader { envelope_id: Some(envelope_id) }) => {
+                    let response = match &envelope {
+                        Ok(Envelope::SlashCommands { .. }) => serde_json::json!({
+                            "envelope_id": envelope_id,
+                            "payload": { "response_type": "ephemeral", "text": "計算中..." },
+                        }),
+                        _ => serde_json::json!({"envelope_id": envelope_id}),
+                    };
+                    respond(protocol::Message::Text(response.to_string())).await;
+                },
+                Ok(_) => {},
+                Err(err) => {
+                    tracing::warn!(id, "failed to parse a message: {}", err);
+                    return;
+                },
+            };
 
-            let message_type =
-                if let Some(value) = json.get("type") { value.as_str() }
-                else { None };
-            match message_type {
-                Some("events_api") => {
-                    let event_type =
-                        if let Some(value) = json.pointer("/payload/event/type") { value.as_str() }
-                        else { None };
-                    let channel_id =
-                        if let Some(value) = json.pointer("/payload/event/channel") { value.as_str() }
-                        else { None };
-                    let user_id =
-                        if let Some(value) = json.pointer("/payload/event/user") { value.as_str() }
-                        else { None };
-                    let text =
-                        if let Some(value) = json.pointer("/payload/event/text") { value.as_str() }
-                        else { None };
-                    match (event_type, channel_id, user_id, text) {
-                        (Some(event_type), Some(channel_id), Some(user_id), Some(text)) => {
+            let envelope = match envelope {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    tracing::warn!(id, "failed to parse a message: {}", err);
+                    return;
+                },
+            };
+            match envelope {
+                Envelope::EventsApi { payload } => {
+                    match payload.event {
+                        Event::AppMention(AppMentionEvent { bot_id: Some(bot_id), .. }) => {
+                            tracing::info!(id, "ignored mention from bot {}", bot_id);
+                        },
+                        Event::AppMention(event) => {
+                            // 添付ファイル (ダウンロードにはurl_privateを使う)
+                            // 参考: https://api.slack.com/types/file
+                            let files: Vec<File> = event.files.into_iter().filter_map(|file| {
+                                Some(File { name: file.name, size: file.size, url: file.url_private? })
+                            }).collect();
                             let message = Message {
-                                event_type: event_type.to_string(),
-                                channel_id: channel_id.to_string(),
-                                user_id: user_id.to_string(),
-                                text: text.to_string(),
+                                event_type: "app_mention".to_string(),
+                                channel_id: event.channel,
+                                user_id: event.user,
+                                text: event.text,
+                                files,
+                                ts: event.ts,
+                                thread_ts: event.thread_ts,
+                                event_id: payload.event_id,
+                                event_time: payload.event_time,
+                                action_message_ts: None,
+                                channel_type: None,
+                                subtype: None,
                             };
-                            println!("received(id: {}): message {:?}", id, message);
-                            let _ = sender.clone().try_send(message);
+                            tracing::info!(id, "received message {:?}", message);
+                            forward(message).await;
                         },
-                        _ => {},
+                        // 編集されたメッセージは、順番待ち中のコマンドの修正に使えるように元のメッセージのtsで渡す
+                        Event::Message(MessageEvent { channel, subtype: Some(subtype), channel_type, bot_id: None, message: Some(ChangedMessage { user: Some(user), text, ts, thread_ts }), .. })
+                            if subtype == "message_changed" => {
+                            let message = Message {
+                                event_type: "message".to_string(),
+                                channel_id: channel,
+                                user_id: user,
+                                text,
+                                files: Vec::new(),
+                                ts,
+                                thread_ts,
+                                event_id: payload.event_id,
+                                event_time: payload.event_time,
+                                action_message_ts: None,
+                                channel_type,
+                                subtype: Some(subtype),
+                            };
+                            tracing::info!(id, "received changed message {:?}", message);
+                            forward(message).await;
+                        },
+                        // DMのメッセージはメンションがなくてもコマンドとして扱う
+                        // 削除、botのメッセージ、スレッドの返信のチャンネルへの表示 (thread_broadcast) などは無視する
+                        Event::Message(MessageEvent { channel, user: Some(user), text, ts, thread_ts, channel_type: Some(channel_type), subtype, bot_id: None, files, .. })
+                            if channel_type == "im" && (subtype.is_none() || subtype.as_deref() == Some("file_share")) => {
+                            let files: Vec<File> = files.into_iter().filter_map(|file| {
+                                Some(File { name: file.name, size: file.size, url: file.url_private? })
+                            }).collect();
+                            let message = Message {
+                                event_type: "message".to_string(),
+                                channel_id: channel,
+                                user_id: user,
+                                text,
+                                files,
+                                ts,
+                                thread_ts,
+                                event_id: payload.event_id,
+                                event_time: payload.event_time,
+                                action_message_ts: None,
+                                channel_type: Some(channel_type),
+                                subtype,
+                            };
+                            tracing::info!(id, "received direct message {:?}", message);
+                            forward(message).await;
+                        },
+                        Event::ChannelArchive(event) | Event::GroupArchive(event) => {
+                            let message = Message::from_channel_event("channel_archive", event, payload.event_id, payload.event_time);
+                            tracing::info!(id, "received channel archive {:?}", message);
+                            forward(message).await;
+                        },
+                        Event::MemberLeftChannel(event) => {
+                            let message = Message::from_channel_event("member_left_channel", event, payload.event_id, payload.event_time);
+                            tracing::info!(id, "received member left channel {:?}", message);
+                            forward(message).await;
+                        },
+                        Event::Message(MessageEvent { subtype: Some(subtype), .. }) => {
+                            tracing::info!(id, "ignored message with subtype {}", subtype);
+                        },
+                        Event::Message(_) | Event::Other => {},
+                    }
+                },
+                Envelope::SlashCommands { payload } => {
+                    // スラッシュコマンドにはメッセージのtsがないので、リアクションは付けられない
+                    let message = Message {
+                        event_type: "slash_command".to_string(),
+                        channel_id: payload.channel_id,
+                        user_id: payload.user_id,
+                        text: payload.text,
+                        files: Vec::new(),
+                        ts: String::new(),
+                        thread_ts: None,
+                        event_id: None,
+                        event_time: None,
+                        action_message_ts: None,
+                        channel_type: None,
+                        subtype: None,
                     };
+                    tracing::info!(id, "received slash command {:?}", message);
+                    forward(message).await;
+                },
+                Envelope::Interactive { payload } => {
+                    // ボタンが押された場合は、ボタンのvalueをコマンドとして渡す
+                    let value = payload.actions.into_iter().next().and_then(|action| action.value);
+                    if let ("block_actions", Some(channel), Some(value)) = (payload.payload_type.as_str(), payload.channel, value) {
+                        let (action_message_ts, thread_ts) = match payload.container {
+                            Some(container) => (container.message_ts, container.thread_ts),
+                            None => (None, None),
+                        };
+                        let message = Message {
+                            event_type: "block_action".to_string(),
+                            channel_id: channel.id,
+                            user_id: payload.user.id,
+                            text: value,
+                            files: Vec::new(),
+                            ts: String::new(),
+                            thread_ts,
+                            event_id: None,
+                            event_time: None,
+                            action_message_ts,
+                            channel_type: None,
+                            subtype: None,
+                        };
+                        tracing::info!(id, "received block action {:?}", message);
+                        forward(message).await;
+                    }
                 },
-                Some("disconnect") => {
-                    let message_reason =
-                        if let Some(value) = json.get("reason") { value.as_str() }
-                        else { None };
-                    match message_reason {
+                Envelope::Disconnect { reason } => {
+                    match reason.as_deref() {
                         Some("warning") | Some("refresh_requested") => {
-                            println!("received(id: {}): refresh request", id);
-                            Arc::clone(&reconnect).store(true, std::sync::atomic::Ordering::SeqCst);
-                            //let _ = responder_tx.clone().try_send(protocol::Message::Close(None));
+                            tracing::info!(id, "received refresh request");
+                            reconnect.store(true, std::sync::atomic::Ordering::SeqCst);
                         },
                         _ => {},
                     };
                 },
-                _ => {},
+                Envelope::Other => {},
             };
         }
-    });
-    pin_mut!(receiver, responder);
-    future::select(receiver, responder).await;
-    println!("status(id: {}): disconnected", id);
+    };
+    // 再接続することになった場合 (切断の予告、Close、無応答) は受信をやめて接続を閉じる
+    let mut read = read;
+    let receiver = async {
+        while let Some(message) = read.next().await {
+            handle_frame(message).await;
+            if reconnect.load(std::sync::atomic::Ordering::SeqCst) { return; }
+        }
+    };
+    // 一定時間何も受信しない場合は、接続を閉じて再接続する
+    let liveness_timeout = health.liveness_timeout;
+    let watchdog = async {
+        loop {
+            tokio::time::sleep(LIVENESS_CHECK_INTERVAL.min(liveness_timeout / 2)).await;
+            let silent_millis = connected_time.elapsed().as_millis() as u64 - last_received.load(std::sync::atomic::Ordering::SeqCst);
+            if silent_millis >= liveness_timeout.as_millis() as u64 {
+                tracing::warn!(id, "no frames for {} s, reconnecting", silent_millis / 1000);
+                reconnect.store(true, std::sync::atomic::Ordering::SeqCst);
+                return;
+            }
+        }
+    };
+    // 終了する場合は、Closeを送ってから接続を閉じる (送信されるまで少し待つ)
+    let mut closing_signal = shutdown.clone();
+    let closing = async {
+        if closing_signal.wait_for(|shutdown| *shutdown).await.is_err() { return future::pending::<()>().await; }
+        tracing::info!(id, "closing websocket");
+        let _ = responder_tx.send(protocol::Message::Close(None)).await;
+        tokio::time::sleep(CLOSE_FRAME_WAIT).await;
+    };
+    pin_mut!(receiver, responder, watchdog, closing);
+    future::select(future::select(receiver, responder), future::select(watchdog, closing)).await;
+    tracing::info!(id, "disconnected");
+    if *shutdown.borrow() {
+        return Ok(Disconnect::Exit);
+    }
     if reconnect.load(std::sync::atomic::Ordering::SeqCst) {
         return Ok(Disconnect::Reconnecting);
     }
@@ -247,49 +1037,1161 @@ async fn single_websocket_receiver(id: u64, app_token: String, sender: Sender<Me
 }
 
 use futures::future;
-use futures_channel::mpsc::{ channel, Sender };
-pub async fn websocket_receiver<F: Fn(Message)>(app_token: String, message_handler: F) {
-    async fn auto_reconnecting(id: u64, app_token: String, sender: Sender<Message>) {
-        //while let Ok(Disconnect::Reconnecting) = single_websocket_receiver(id, app_token.clone(), sender.clone()).await {}
-        loop{
-            if let Ok(Disconnect::Reconnecting) = single_websocket_receiver(id, app_token.clone(), sender.clone()).await {
-                continue;
+use tokio::sync::mpsc::{ channel, Sender, Receiver };
+use tokio::sync::watch;
+
+// 最近受信したevent_idを覚えておく
+// slackは応答が遅いと同じイベントを再送するので、同じevent_idのメッセージは1度だけ処理する
+// 参考: https://api.slack.com/apis/connections/events-api#retries
+const RECENT_EVENTS_WINDOW_SEC: u64 = 600;
+const RECENT_EVENTS_CAPACITY: usize = 1000;
+struct RecentEvents {
+    ids: std::collections::HashSet<String>,
+    order: std::collections::VecDeque<(std::time::Instant, String)>,
+}
+impl RecentEvents {
+    fn new() -> RecentEvents {
+        return RecentEvents { ids: std::collections::HashSet::new(), order: std::collections::VecDeque::new() };
+    }
+
+    // 既に受信したevent_idであればtrueを返し、そうでなければ記録してfalseを返す
+    fn is_duplicate(&mut self, event_id: &str) -> bool {
+        // 古いものと上限を超えた分から忘れる
+        let now = std::time::Instant::now();
+        while let Some((received_time, _)) = self.order.front() {
+            let expired = now.duration_since(*received_time).as_secs() >= RECENT_EVENTS_WINDOW_SEC;
+            if !expired && self.order.len() < RECENT_EVENTS_CAPACITY { break; }
+            if let Some((_, id)) = self.order.pop_front() {
+                self.ids.remove(&id);
             }
-            else {
-                // 異常終了した場合は5分経過した後に再接続を試行
-                tokio::time::sleep(tokio::time::Duration::from_secs(360)).await;
+        }
+
+        if self.ids.contains(event_id) {
+            return true;
+        }
+        self.ids.insert(event_id.to_string());
+        self.order.push_back((now, event_id.to_string()));
+        return false;
+    }
+}
+
+// 受信したメッセージを1つずつmessage_handlerに渡し、処理が終わるのを待ってから次のメッセージを渡す
+// 全ての送信側が閉じられると終了する
+async fn dispatch_messages<F, Fut>(mut receiver: Receiver<Message>, message_handler: F)
+where
+    F: Fn(Message) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    // どの接続で受信しても重複を判別できるように、受信側でまとめて記録する
+    let mut recent_events = RecentEvents::new();
+    while let Some(message) = receiver.recv().await {
+        if let Some(event_id) = &message.event_id {
+            if recent_events.is_duplicate(event_id) {
+                tracing::warn!("duplicated event {} (event_time: {:?}) is ignored", event_id, message.event_time);
+                continue;
             }
         }
+        message_handler(message).await;
     }
+}
 
-    // 4つのチャンネルでメッセージの受信を分散
-    // Todo:
-    // 1.  確立的に4つのチャンネル全てが同時に切断される可能性があるので、
-    //     切断の警告を受信したタイミングで他のチャンネルを再起動するようにする
-    // 2.  1つのタスクが終了したら全て終了するようにする
-    //     (JoinHandleはjoinしなくてもいい説も確認)
-    // 3.  エラー処理をちゃんと実装する
-    async fn multi_websocket_receiver(app_token: String, sender: Sender<Message>) {
-        let num_channels = 4;
-        let mut tasks = Vec::new();
-        for id in 0..num_channels {
-            let task = tokio::spawn(auto_reconnecting(id, app_token.clone(), sender.clone()));
-            tasks.push(task);
-            tokio::time::sleep(tokio::time::Duration::from_millis(1000 * 360 / (num_channels + 1) as u64)).await;
+// socket modeの接続数 (SLACK_SOCKET_CONNECTIONSで変更できる、1〜10)
+// 参考: https://api.slack.com/apis/connections/socket-implement#connections
+const DEFAULT_SOCKET_CONNECTIONS: usize = 4;
+const MAX_SOCKET_CONNECTIONS: usize = 10;
+fn get_socket_connections() -> usize {
+    return std::env::var("SLACK_SOCKET_CONNECTIONS").ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_SOCKET_CONNECTIONS)
+        .clamp(1, MAX_SOCKET_CONNECTIONS);
+}
+
+// 現在の時刻 (UNIX時間のミリ秒)
+fn get_unix_millis() -> u64 {
+    return std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_millis() as u64).unwrap_or(0);
+}
+
+// 接続の状態 (ヘルスチェックなどで現在開いている接続の数を確認するために使う)
+// websocket_receiverに渡すと、全ての接続が状態を書き込む
+#[derive(Clone)]
+pub struct WebsocketHealth {
+    open_connections: Arc<AtomicUsize>,
+    connection_count: usize,
+    // 送れなかった応答と、処理する側に渡せなかったメッセージの数
+    dropped_responses: Arc<AtomicUsize>,
+    dropped_messages: Arc<AtomicUsize>,
+    // いずれかの接続で最後にフレームを受信した時刻 (UNIX時間のミリ秒、受信していない場合は0)
+    last_received: Arc<AtomicU64>,
+    // websocketのURLを1度でも取得できたか
+    url_obtained: Arc<AtomicBool>,
+    // 何も受信しないまま接続が切れたとみなすまでの時間
+    liveness_timeout: std::time::Duration,
+}
+impl Default for WebsocketHealth {
+    fn default() -> Self {
+        return WebsocketHealth::new();
+    }
+}
+impl WebsocketHealth {
+    pub fn new() -> WebsocketHealth {
+        return WebsocketHealth {
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            connection_count: get_socket_connections(),
+            dropped_responses: Arc::new(AtomicUsize::new(0)),
+            dropped_messages: Arc::new(AtomicUsize::new(0)),
+            last_received: Arc::new(AtomicU64::new(0)),
+            url_obtained: Arc::new(AtomicBool::new(false)),
+            liveness_timeout: get_liveness_timeout(),
+        };
+    }
+
+    // 接続が1つ以上開いていて、最近 (SLACK_SOCKET_TIMEOUT_SEC以内に) フレームを受信しているか
+    pub fn is_alive(&self) -> bool {
+        if self.get_open_connections() == 0 { return false; }
+        let silent_millis = get_unix_millis().saturating_sub(self.last_received.load(std::sync::atomic::Ordering::SeqCst));
+        return silent_millis < self.liveness_timeout.as_millis() as u64;
+    }
+
+    // websocketのURLを1度でも取得できたか
+    pub fn is_url_obtained(&self) -> bool {
+        return self.url_obtained.load(std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // 現在開いている接続の数
+    pub fn get_open_connections(&self) -> usize {
+        return self.open_connections.load(std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // 設定された接続の数
+    pub fn get_connection_count(&self) -> usize {
+        return self.connection_count;
+    }
+
+    // 送信する側が既に閉じていて送れなかった応答 (受信の確認とPong) の数
+    pub fn get_dropped_responses(&self) -> usize {
+        return self.dropped_responses.load(std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // 処理する側に渡せなかったメッセージの数
+    pub fn get_dropped_messages(&self) -> usize {
+        return self.dropped_messages.load(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl WebsocketHealth {
+    // 開いている接続の数、最後にフレームを受信してからの時間、URLを取得できたかを書き換える (テスト用)
+    pub(crate) fn set_test_state(&self, open_connections: usize, received_ago: Option<std::time::Duration>, url_obtained: bool) {
+        self.open_connections.store(open_connections, std::sync::atomic::Ordering::SeqCst);
+        let last_received = received_ago.map_or(0, |received_ago| get_unix_millis() - received_ago.as_millis() as u64);
+        self.last_received.store(last_received, std::sync::atomic::Ordering::SeqCst);
+        self.url_obtained.store(url_obtained, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+// 終了する時にCloseを送ってから接続を閉じるまでの待ち時間と、全ての接続が閉じるのを待つ時間
+const CLOSE_FRAME_WAIT: std::time::Duration = std::time::Duration::from_secs(1);
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// websocketの接続を閉じるためのハンドル
+pub struct WebsocketShutdown {
+    sender: watch::Sender<bool>,
+    open_connections: Arc<AtomicUsize>,
+}
+impl WebsocketShutdown {
+    // 新しいメッセージの受信をやめて全ての接続を閉じる (閉じ終わるまで最大CLOSE_TIMEOUTだけ待つ)
+    // 閉じた後は再接続しない
+    pub async fn shutdown(&self) {
+        let _ = self.sender.send(true);
+        let wait_for_close = async {
+            while self.open_connections.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, wait_for_close).await.is_err() {
+            tracing::warn!("{} websocket connections did not close in time", self.open_connections.load(std::sync::atomic::Ordering::SeqCst));
         }
-        future::join_all(tasks.into_iter()).await;
     }
+}
 
-    let (sender, mut receiver) = channel::<Message>(128);
-    let _ = tokio::spawn(multi_websocket_receiver(app_token, sender.clone()));
+// 接続に失敗した場合に、もう一度接続するまでの待ち時間 (5分)
+const RECONNECT_WAIT_SEC: u64 = 300;
+// 再接続を繰り返すタスクが終了した場合に、もう一度起動するまでの待ち時間
+const RESTART_BASE_SEC: u64 = 1;
+const MAX_RESTART_SEC: u64 = 60;
+// 起動し直したタスクがこの時間より長く動いていた場合は、続けて異常終了した回数を数え直す (10分)
+const HEALTHY_RUN_SEC: u64 = 600;
+// 接続やタスクが何回続けて失敗したら管理用のチャンネルに報告するか
+const FAILURE_REPORT_COUNT: u32 = 3;
 
+// 複数のチャンネルでメッセージの受信を分散
+// 再接続を繰り返すタスク (connectでidの接続のauto_reconnectingを作る) が終了したりpanicしたりした場合は、
+// ログに残して待ち時間を延ばしながら起動し直す (しばらく正常に動いていた場合は最初の待ち時間に戻す)
+// Todo:
+// 1.  確立的に全てのチャンネルが同時に切断される可能性があるので、
+//     切断の警告を受信したタイミングで他のチャンネルを再起動するようにする
+// 2.  エラー処理をちゃんと実装する
+async fn multi_websocket_receiver<C, Fut>(slack_client: Arc<SlackClient>, num_channels: usize, shutdown: watch::Receiver<bool>, connect: C)
+where
+    C: Fn(u64) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let mut ids = Vec::new();
+    let mut tasks = Vec::new();
+    // 各idのタスクを起動した時刻
+    let mut start_times = HashMap::<u64, tokio::time::Instant>::new();
+    for id in 0..num_channels as u64 {
+        tasks.push(tokio::spawn(connect(id)));
+        ids.push(id);
+        start_times.insert(id, tokio::time::Instant::now());
+        tokio::time::sleep(tokio::time::Duration::from_millis(1000 * 360 / (num_channels + 1) as u64)).await;
+    }
+    let mut restart_counts = HashMap::<u64, u32>::new();
     loop {
-        let receive = receiver.try_next();
-        if let Ok(Some(message)) = receive {
-            message_handler(message);
+        // select_allは終了したタスクをswap_removeで取り除くので、idも同じように取り除く
+        let (result, index, remaining_tasks) = future::select_all(tasks).await;
+        let id = ids.swap_remove(index);
+        tasks = remaining_tasks;
+        // 終了する場合は起動し直さず、全てのタスクが終わるのを待つ
+        if *shutdown.borrow() {
+            if tasks.is_empty() { return; }
+            continue;
+        }
+        let error = match result {
+            Err(err) if err.is_panic() => "websocket task panicked".to_string(),
+            Err(err) => format!("websocket task failed: {}", err),
+            Ok(()) => "websocket task exited".to_string(),
+        };
+        tracing::error!(id, "{}", error);
+        let restart_count = restart_counts.entry(id).or_insert(0);
+        if start_times.get(&id).is_some_and(|start_time| start_time.elapsed() >= tokio::time::Duration::from_secs(HEALTHY_RUN_SEC)) {
+            *restart_count = 0;
+        }
+        let wait = (RESTART_BASE_SEC << (*restart_count).min(6)).min(MAX_RESTART_SEC);
+        *restart_count += 1;
+        if *restart_count == FAILURE_REPORT_COUNT {
+            slack_client.report_error(format!("websocketの受信タスク {} が{}回異常終了しました", id, restart_count), error).await;
+        }
+        tracing::info!(id, "restarting websocket task in {} s", wait);
+        tokio::time::sleep(tokio::time::Duration::from_secs(wait)).await;
+        tasks.push(tokio::spawn(connect(id)));
+        ids.push(id);
+        start_times.insert(id, tokio::time::Instant::now());
+    }
+}
+
+// websocketの受信をバックグラウンドで開始し、受信したメッセージをmessage_handlerで処理するタスクを返す
+// メモ: message_handlerの処理中は次のメッセージを処理しないので、時間のかかる処理はmessage_handlerの中でspawnする
+// 接続の状態はhealthに書き込み、終了する時に接続を閉じるためのハンドルも返す
+pub fn websocket_receiver<F, Fut>(slack_client: Arc<SlackClient>, health: WebsocketHealth, message_handler: F) -> (tokio::task::JoinHandle<()>, WebsocketShutdown)
+where
+    F: Fn(Message) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    async fn auto_reconnecting(id: u64, slack_client: Arc<SlackClient>, sender: Sender<Message>, health: WebsocketHealth, mut shutdown: watch::Receiver<bool>) {
+        //while let Ok(Disconnect::Reconnecting) = single_websocket_receiver(id, Arc::clone(&slack_client), sender.clone()).await {}
+        // 続けて異常終了した回数 (一定の回数に達したら管理用のチャンネルに報告する)
+        let mut failure_count = 0;
+        loop{
+            // 終了する場合は再接続しない
+            if *shutdown.borrow() { return; }
+            match single_websocket_receiver(id, Arc::clone(&slack_client), sender.clone(), health.clone(), shutdown.clone()).await {
+                Ok(Disconnect::Reconnecting) => {
+                    failure_count = 0;
+                    metrics::get().websocket_reconnects.inc();
+                    continue;
+                },
+                result => {
+                    failure_count += 1;
+                    if failure_count == FAILURE_REPORT_COUNT {
+                        let error = match result {
+                            Err(err) => err.to_string(),
+                            Ok(disconnect) => format!("{:?}", disconnect),
+                        };
+                        slack_client.report_error(format!("websocketの接続 {} が{}回続けて異常終了しました", id, failure_count), error).await;
+                    }
+                    // 異常終了した場合はRECONNECT_WAIT_SEC (5分) 経過した後に再接続を試行 (待っている間に終了する場合はすぐに抜ける)
+                    tokio::select! {
+                        _ = tokio::time::sleep(tokio::time::Duration::from_secs(RECONNECT_WAIT_SEC)) => {},
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => {},
+                    };
+                    metrics::get().websocket_reconnects.inc();
+                },
+            };
+        }
+    }
+
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let websocket_shutdown = WebsocketShutdown { sender: shutdown_sender, open_connections: Arc::clone(&health.open_connections) };
+    let (sender, receiver) = channel::<Message>(128);
+    let connection_count = health.connection_count;
+    let connect = {
+        let (slack_client, shutdown) = (Arc::clone(&slack_client), shutdown.clone());
+        move |id| auto_reconnecting(id, Arc::clone(&slack_client), sender.clone(), health.clone(), shutdown.clone())
+    };
+    tokio::spawn(multi_websocket_receiver(slack_client, connection_count, shutdown, connect));
+
+    return (tokio::spawn(dispatch_messages(receiver, message_handler)), websocket_shutdown);
+}
+
+// テスト用のSlack APIのサーバー
+// 受け取ったリクエストを記録し、メソッドごとに登録したレスポンス (登録していない場合は成功) を返す
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct MockRequest {
+    // /api/以下のメソッド名 (アップロード先などのAPI以外のパスは先頭の/を除いたパス)
+    pub method: String,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+#[cfg(test)]
+impl MockRequest {
+    // フォームの値 (multipartのフォームは読めないので空になる)
+    pub fn get_form(&self) -> HashMap<String, String> {
+        return url::form_urlencoded::parse(&self.body).into_owned().collect();
+    }
+}
+
+#[cfg(test)]
+pub(crate) struct MockSlack {
+    pub base_url: String,
+    requests: Arc<std::sync::Mutex<Vec<MockRequest>>>,
+    responses: Arc<std::sync::Mutex<HashMap<String, std::collections::VecDeque<MockResponse>>>>,
+}
+// ステータスコード、ヘッダー、本文
+#[cfg(test)]
+type MockResponse = (u16, Vec<(String, String)>, Vec<u8>);
+#[cfg(test)]
+impl MockSlack {
+    // 127.0.0.1の空いているポートで待ち受ける
+    pub async fn start() -> MockSlack {
+        use hyper::service::{make_service_fn, service_fn};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::<MockRequest>::new()));
+        let responses = Arc::new(std::sync::Mutex::new(HashMap::<String, std::collections::VecDeque<MockResponse>>::new()));
+        // 登録していないメソッドには、どのメソッドの結果としても読めるように全ての値を入れた成功のレスポンスを返す
+        let default_response = serde_json::json!({
+            "ok": true,
+            "ts": "1700000000.000200",
+            "user_id": "U_BOT",
+            "upload_url": format!("{}/upload", base_url),
+            "file_id": "F_TEST",
+        }).to_string();
+        let (service_requests, service_responses) = (Arc::clone(&requests), Arc::clone(&responses));
+        let make_service = make_service_fn(move |_| {
+            let (requests, responses, default_response) = (Arc::clone(&service_requests), Arc::clone(&service_responses), default_response.clone());
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |request: hyper::Request<hyper::Body>| {
+                    let (requests, responses, default_response) = (Arc::clone(&requests), Arc::clone(&responses), default_response.clone());
+                    async move {
+                        let path = request.uri().path();
+                        let method = path.strip_prefix("/api/").unwrap_or(path.trim_start_matches('/')).to_string();
+                        let authorization = request.headers().get(reqwest::header::AUTHORIZATION).and_then(|value| value.to_str().ok()).map(str::to_string);
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap_or_default().to_vec();
+                        let (status, headers, body_bytes) = responses.lock().unwrap().get_mut(&method).and_then(|queue| queue.pop_front())
+                            .unwrap_or((200, Vec::new(), default_response.into_bytes()));
+                        requests.lock().unwrap().push(MockRequest { method, authorization, body });
+                        let mut response = hyper::Response::new(hyper::Body::from(body_bytes));
+                        *response.status_mut() = hyper::StatusCode::from_u16(status).unwrap();
+                        for (name, value) in headers {
+                            response.headers_mut().insert(hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+                        }
+                        Ok::<_, std::convert::Infallible>(response)
+                    }
+                }))
+            }
+        });
+        tokio::spawn(hyper::Server::from_tcp(listener).unwrap().serve(make_service));
+        return MockSlack { base_url, requests, responses };
+    }
+
+    // このサーバーに接続するクライアント
+    pub fn client(&self) -> SlackClient {
+        return SlackClient::new("xoxb-test".to_string(), "xapp-test".to_string()).unwrap().with_base_url(&self.base_url);
+    }
+
+    // methodへの次のリクエストに返すレスポンス (複数登録すると順番に返す)
+    pub fn push_response(&self, method: &str, status: u16, body: &[u8]) {
+        self.push_response_with_headers(method, status, &[], body);
+    }
+
+    // ヘッダー (レート制限のRetry-Afterなど) を付けたレスポンス
+    pub fn push_response_with_headers(&self, method: &str, status: u16, headers: &[(&str, &str)], body: &[u8]) {
+        let headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        self.responses.lock().unwrap().entry(method.to_string()).or_default().push_back((status, headers, body.to_vec()));
+    }
+
+    pub fn push_json(&self, method: &str, body: serde_json::Value) {
+        self.push_response(method, 200, body.to_string().as_bytes());
+    }
+
+    // methodへのリクエスト (受け取った順)
+    pub fn get_requests(&self, method: &str) -> Vec<MockRequest> {
+        return self.requests.lock().unwrap().iter().filter(|request| request.method == method).cloned().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn gets_websocket_url_with_the_app_token() {
+        let slack = MockSlack::start().await;
+        slack.push_json("apps.connections.open", serde_json::json!({"ok": true, "url": "wss://example.com/link/?ticket=1"}));
+        assert_eq!(slack.client().get_websocket_url().await.unwrap(), "wss://example.com/link/?ticket=1");
+        let requests = slack.get_requests("apps.connections.open");
+        assert_eq!(requests[0].authorization.as_deref(), Some("Bearer xapp-test"));
+    }
+
+    #[tokio::test]
+    async fn websocket_url_error_keeps_the_slack_error_code() {
+        let slack = MockSlack::start().await;
+        slack.push_json("apps.connections.open", serde_json::json!({"ok": false, "error": "invalid_auth"}));
+        let err = slack.client().get_websocket_url().await.unwrap_err();
+        assert_eq!(err.get_code(), Some("invalid_auth"));
+        // 再試行しても成功しないエラーは再試行しない
+        assert_eq!(slack.get_requests("apps.connections.open").len(), 1);
+
+        // okでもurlがない場合は読み取りのエラー
+        slack.push_json("apps.connections.open", serde_json::json!({"ok": true}));
+        assert!(matches!(slack.client().get_websocket_url().await, Err(SlackApiError::Parse { .. })));
+    }
+
+    #[tokio::test]
+    async fn post_message_surfaces_the_error_code() {
+        let slack = MockSlack::start().await;
+        let client = slack.client();
+        assert_eq!(client.post_message("C_TEST".to_string(), Some("1.000".to_string()), "hello".to_string()).await.unwrap(), "1700000000.000200");
+        let form = slack.get_requests("chat.postMessage")[0].get_form();
+        assert_eq!((form["channel"].as_str(), form["text"].as_str(), form["thread_ts"].as_str()), ("C_TEST", "hello", "1.000"));
+
+        slack.push_json("chat.postMessage", serde_json::json!({"ok": false, "error": "channel_not_found"}));
+        let err = client.post_message("C_GONE".to_string(), None, "hello".to_string()).await.unwrap_err();
+        assert_eq!(err.get_code(), Some("channel_not_found"));
+        assert!(err.is_channel_unavailable());
+        // 投稿は冪等でないので再試行しない
+        assert_eq!(slack.get_requests("chat.postMessage").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_after_rate_limits_and_server_errors() {
+        // 429の場合はRetry-Afterの秒数だけ待ってから再試行する (投稿のように冪等でない呼び出しも再試行する)
+        let slack = MockSlack::start().await;
+        let client = slack.client();
+        slack.push_response_with_headers("chat.postMessage", 429, &[("Retry-After", "1")], b"");
+        let start_time = std::time::Instant::now();
+        assert_eq!(client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap(), "1700000000.000200");
+        assert!(start_time.elapsed() >= std::time::Duration::from_secs(1));
+        assert_eq!(slack.get_requests("chat.postMessage").len(), 2);
+
+        // okがfalseでもratelimitedの場合は再試行する
+        slack.push_json("chat.postMessage", serde_json::json!({"ok": false, "error": "ratelimited"}));
+        client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap();
+        assert_eq!(slack.get_requests("chat.postMessage").len(), 4);
+
+        // 5xxの場合、冪等な呼び出しは再試行し、投稿は二重に投稿しないように再試行しない
+        slack.push_response("apps.connections.open", 503, b"");
+        slack.push_json("apps.connections.open", serde_json::json!({"ok": true, "url": "wss://example.com/link/?ticket=1"}));
+        assert_eq!(client.get_websocket_url().await.unwrap(), "wss://example.com/link/?ticket=1");
+        assert_eq!(slack.get_requests("apps.connections.open").len(), 2);
+        slack.push_response("chat.postMessage", 503, b"");
+        let err = client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Transport { reason, .. } if reason.contains("503")), "{:?}", err);
+        assert_eq!(slack.get_requests("chat.postMessage").len(), 5);
+
+        // 再試行しても成功しない回数 (MAX_ATTEMPTS回) 続けてレート制限された場合は諦める
+        for _ in 0..MAX_ATTEMPTS {
+            slack.push_response_with_headers("reactions.add", 429, &[("Retry-After", "0")], b"");
         }
-        else {
-            tokio::time::sleep(tokio::time::Duration::from_millis(1000 / 60 as u64)).await;
+        let err = client.add_reaction("C_TEST".to_string(), "1.000".to_string(), "tada".to_string()).await.unwrap_err();
+        assert!(err.is_rate_limited());
+        assert_eq!(slack.get_requests("reactions.add").len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn stalled_request_times_out() {
+        // 接続を受け付けたまま応答しないサーバーへのリクエストは、タイムアウトで失敗する
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let client = SlackClient::new("xoxb-test".to_string(), "xapp-test".to_string()).unwrap()
+            .with_base_url(&base_url)
+            .with_request_timeout(std::time::Duration::from_millis(300)).unwrap();
+        let start_time = std::time::Instant::now();
+        let err = client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap_err();
+        let elapsed = start_time.elapsed();
+        assert!(matches!(&err, SlackApiError::Transport { method, .. } if method == "chat.postMessage"), "{:?}", err);
+        assert!(elapsed >= std::time::Duration::from_millis(300));
+        // 処理済みの可能性がある投稿は再試行しない
+        assert!(elapsed < std::time::Duration::from_secs(5), "the request took {} ms", elapsed.as_millis());
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn ephemeral_message_falls_back_to_the_channel() {
+        // 本人にだけ見えるメッセージを送れた場合は、チャンネルには投稿しない
+        let slack = MockSlack::start().await;
+        let client = slack.client();
+        client.post_ephemeral("C_TEST".to_string(), Some("1.000".to_string()), "U_TEST".to_string(), "無効な入力です。".to_string()).await.unwrap();
+        let form = slack.get_requests("chat.postEphemeral")[0].get_form();
+        assert_eq!((form["channel"].as_str(), form["user"].as_str(), form["text"].as_str(), form["thread_ts"].as_str()), ("C_TEST", "U_TEST", "無効な入力です。", "1.000"));
+        assert!(slack.get_requests("chat.postMessage").is_empty());
+
+        // 送れなかった場合 (ボットがチャンネルにいない場合など) は、同じスレッドに同じ本文を投稿する
+        slack.push_json("chat.postEphemeral", serde_json::json!({"ok": false, "error": "user_not_in_channel"}));
+        client.post_ephemeral("C_TEST".to_string(), Some("1.000".to_string()), "U_TEST".to_string(), "現在計算中です。".to_string()).await.unwrap();
+        let requests = slack.get_requests("chat.postMessage");
+        assert_eq!(requests.len(), 1);
+        let form = requests[0].get_form();
+        assert_eq!((form["channel"].as_str(), form["text"].as_str(), form["thread_ts"].as_str()), ("C_TEST", "現在計算中です。", "1.000"));
+
+        // チャンネルにも投稿できなかった場合はエラーを返す
+        slack.push_json("chat.postEphemeral", serde_json::json!({"ok": false, "error": "user_not_in_channel"}));
+        slack.push_json("chat.postMessage", serde_json::json!({"ok": false, "error": "not_in_channel"}));
+        let err = client.post_ephemeral("C_TEST".to_string(), None, "U_TEST".to_string(), "hello".to_string()).await.unwrap_err();
+        assert_eq!(err.get_code(), Some("not_in_channel"));
+    }
+
+    #[tokio::test]
+    async fn responses_map_to_error_variants() {
+        // okがfalseの場合はSlackのエラーコードを持つApi (再試行しても成功しないエラーはすぐに返す)
+        let slack = MockSlack::start().await;
+        let client = slack.client();
+        slack.push_json("auth.test", serde_json::json!({"ok": false, "error": "invalid_auth"}));
+        let err = client.auth_test().await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Api { method, error } if method == "auth.test" && error == "invalid_auth"), "{:?}", err);
+        assert!(!err.is_rate_limited() && !err.is_channel_unavailable());
+        slack.push_json("chat.update", serde_json::json!({"ok": false, "error": "is_archived"}));
+        let err = client.update_message("C_TEST".to_string(), "1.000".to_string(), "hello".to_string()).await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Api { error, .. } if error == "is_archived"), "{:?}", err);
+        assert!(err.is_channel_unavailable());
+        assert_eq!(slack.get_requests("chat.update").len(), 1);
+
+        // jsonでないレスポンスと、必要な値がないレスポンスはParse
+        slack.push_response("auth.test", 200, b"<html>maintenance</html>");
+        assert!(matches!(client.auth_test().await, Err(SlackApiError::Parse { method, .. }) if method == "auth.test"));
+        slack.push_json("chat.postMessage", serde_json::json!({"ok": true}));
+        let err = client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Parse { method, reason } if method == "chat.postMessage" && reason.contains("no ts")), "{:?}", err);
+        slack.push_json("files.getUploadURLExternal", serde_json::json!({"ok": true, "file_id": "F_TEST"}));
+        let err = client.post_image("C_TEST".to_string(), None, "result".to_string(), &[1, 2, 3], "result.png".to_string()).await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Parse { method, .. } if method == "files.getUploadURLExternal"), "{:?}", err);
+
+        // 5xxと、再試行しても続いたレート制限はTransport
+        slack.push_response("chat.postMessage", 502, b"bad gateway");
+        let err = client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Transport { method, reason } if method == "chat.postMessage" && reason.contains("502")), "{:?}", err);
+        assert!(!err.is_rate_limited());
+        for _ in 0..MAX_ATTEMPTS {
+            slack.push_response_with_headers("chat.postMessage", 429, &[("Retry-After", "0")], b"");
         }
+        let err = client.post_message("C_TEST".to_string(), None, "hello".to_string()).await.unwrap_err();
+        assert!(matches!(&err, SlackApiError::Transport { reason, .. } if reason == "HTTP 429"), "{:?}", err);
+        assert!(err.is_rate_limited());
+
+        // websocketに接続できなかった場合はWebSocket
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        slack.push_json("apps.connections.open", serde_json::json!({"ok": true, "url": url}));
+        let (sender, _receiver) = channel(1);
+        let (_shutdown_sender, shutdown) = watch::channel(false);
+        let result = single_websocket_receiver(0, Arc::new(client), sender, test_health(std::time::Duration::from_secs(60)), shutdown).await;
+        assert!(matches!(result, Err(SlackApiError::WebSocket(_))));
+    }
+
+    #[tokio::test]
+    async fn post_image_sends_the_upload_fields() {
+        let slack = MockSlack::start().await;
+        let data = vec![0x89, b'P', b'N', b'G', 1, 2, 3];
+        slack.client().post_image("C_TEST".to_string(), Some("1.000".to_string()), "result".to_string(), &data, "result.png".to_string()).await.unwrap();
+
+        let form = slack.get_requests("files.getUploadURLExternal")[0].get_form();
+        assert_eq!((form["filename"].as_str(), form["length"].as_str()), ("result.png", "7"));
+        assert_eq!(slack.get_requests("upload")[0].body, data);
+        let form = slack.get_requests("files.completeUploadExternal")[0].get_form();
+        let files: serde_json::Value = serde_json::from_str(&form["files"]).unwrap();
+        assert_eq!(files, serde_json::json!([{"id": "F_TEST", "title": "result.png"}]));
+        assert_eq!((form["channel_id"].as_str(), form["initial_comment"].as_str(), form["thread_ts"].as_str()), ("C_TEST", "result", "1.000"));
+    }
+
+    #[tokio::test]
+    async fn failed_upload_does_not_complete_the_upload() {
+        // ファイルの送信 (2段階目) に失敗した場合は、エラーを返してチャンネルに投稿しない
+        let slack = MockSlack::start().await;
+        slack.push_response("upload", 403, b"forbidden");
+        let data = vec![0x89, b'P', b'N', b'G'];
+        let err = slack.client().post_image("C_TEST".to_string(), None, "result".to_string(), &data, "result.png".to_string()).await.unwrap_err();
+        match &err {
+            SlackApiError::Transport { method, reason } => {
+                assert_eq!(method, "upload");
+                assert!(reason.contains("403") && reason.contains("forbidden"), "{}", reason);
+            },
+            _ => panic!("unexpected error {:?}", err),
+        };
+        assert_eq!(slack.get_requests("files.getUploadURLExternal").len(), 1);
+        assert_eq!(slack.get_requests("upload").len(), 1);
+        assert!(slack.get_requests("files.completeUploadExternal").is_empty());
+    }
+
+    #[tokio::test]
+    async fn legacy_image_upload_sends_the_multipart_fields() {
+        let slack = MockSlack::start().await;
+        let data = b"png data".to_vec();
+        slack.client().post_image_legacy("C_TEST".to_string(), Some("1.000".to_string()), "result".to_string(), &data, "result.png".to_string()).await.unwrap();
+        let body = String::from_utf8(slack.get_requests("files.upload")[0].body.clone()).unwrap();
+        for field in ["name=\"channels\"\r\n\r\nC_TEST", "name=\"thread_ts\"\r\n\r\n1.000", "name=\"initial_comment\"\r\n\r\nresult", "name=\"file\"; filename=\"result.png\""] {
+            assert!(body.contains(field), "{} is not in {}", field, body);
+        }
+        assert!(body.contains("png data"));
+    }
+
+    #[tokio::test]
+    async fn user_info_falls_back_to_real_name_and_prefers_the_largest_icon() {
+        let slack = MockSlack::start().await;
+        slack.push_json("users.profile.get", serde_json::json!({"ok": true, "profile": {
+            "display_name": "",
+            "real_name": "Egon Spengler",
+            "image_72": format!("{}/icons/72.png", slack.base_url),
+            "image_512": format!("{}/icons/512.png", slack.base_url),
+            "image_original": format!("{}/icons/original.png", slack.base_url),
+        }}));
+        slack.push_response("icons/original.png", 200, b"original");
+        let user_info = slack.client().get_user_info("U_TEST".to_string()).await.unwrap();
+        assert_eq!(user_info.name.as_deref(), Some("Egon Spengler"));
+        assert_eq!(user_info.icon_data.as_deref(), Some(b"original".as_slice()));
+        assert!(slack.get_requests("icons/512.png").is_empty());
+
+        // display_nameがある場合はdisplay_name、image_originalがない場合は次に大きいアイコン
+        slack.push_json("users.profile.get", serde_json::json!({"ok": true, "profile": {
+            "display_name": "spengler",
+            "real_name": "Egon Spengler",
+            "image_72": format!("{}/icons/72.png", slack.base_url),
+            "image_512": format!("{}/icons/512.png", slack.base_url),
+        }}));
+        slack.push_response("icons/512.png", 200, b"512");
+        let user_info = slack.client().get_user_info("U_TEST".to_string()).await.unwrap();
+        assert_eq!(user_info.name.as_deref(), Some("spengler"));
+        assert_eq!(user_info.icon_data.as_deref(), Some(b"512".as_slice()));
+        assert!(slack.get_requests("icons/72.png").is_empty());
+
+        // プロフィールを取得できないユーザーは名前とアイコンなし
+        slack.push_json("users.profile.get", serde_json::json!({"ok": false, "error": "user_not_found"}));
+        let user_info = slack.client().get_user_info("U_GONE".to_string()).await.unwrap();
+        assert_eq!((user_info.name, user_info.icon_data), (None, None));
+    }
+
+    #[tokio::test]
+    async fn download_data_sends_the_bot_token_only_when_authorized() {
+        let slack = MockSlack::start().await;
+        let url = format!("{}/files/shape.svg", slack.base_url);
+        slack.push_response("files/shape.svg", 200, b"<svg/>");
+        slack.push_response("files/shape.svg", 200, b"<svg/>");
+        let client = slack.client();
+        assert_eq!(client.download_data(&url, true).await.unwrap(), b"<svg/>");
+        assert_eq!(client.download_data(&url, false).await.unwrap(), b"<svg/>");
+        let requests = slack.get_requests("files/shape.svg");
+        assert_eq!(requests[0].authorization.as_deref(), Some("Bearer xoxb-test"));
+        assert_eq!(requests[1].authorization, None);
+    }
+
+    fn test_message(channel_id: &str, event_id: Option<&str>) -> Message {
+        return Message {
+            event_type: "app_mention".to_string(),
+            channel_id: channel_id.to_string(),
+            user_id: "U_TEST".to_string(),
+            text: "<@U_BOT> 0 0".to_string(),
+            files: Vec::new(),
+            ts: "1700000000.000100".to_string(),
+            thread_ts: None,
+            event_id: event_id.map(str::to_string),
+            event_time: None,
+            action_message_ts: None,
+            channel_type: None,
+            subtype: None,
+        };
+    }
+
+    #[tokio::test]
+    async fn dispatches_injected_messages_in_order() {
+        // 受信したメッセージは1つずつ順番に渡され、同じevent_idのメッセージ (slackの再送) は1度だけ渡される
+        let (sender, receiver) = channel::<Message>(4);
+        let handled = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let in_handler = Arc::new(AtomicBool::new(false));
+        let dispatcher = {
+            let (handled, in_handler) = (Arc::clone(&handled), Arc::clone(&in_handler));
+            tokio::spawn(dispatch_messages(receiver, move |message: Message| {
+                let (handled, in_handler) = (Arc::clone(&handled), Arc::clone(&in_handler));
+                async move {
+                    assert!(!in_handler.swap(true, std::sync::atomic::Ordering::SeqCst), "two messages were handled at the same time");
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                    handled.lock().unwrap().push(message.channel_id);
+                    in_handler.store(false, std::sync::atomic::Ordering::SeqCst);
+                }
+            }))
+        };
+        for (channel_id, event_id) in [("C1", Some("Ev1")), ("C2", Some("Ev2")), ("C1", Some("Ev1")), ("C3", None), ("C3", None)] {
+            sender.send(test_message(channel_id, event_id)).await.unwrap();
+        }
+        // 全ての送信側を閉じると終了する
+        drop(sender);
+        tokio::time::timeout(std::time::Duration::from_secs(5), dispatcher).await.unwrap().unwrap();
+        assert_eq!(*handled.lock().unwrap(), vec!["C1", "C2", "C3", "C3"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicked_connection_task_is_restarted() {
+        // 最初に起動した接続のタスクだけpanicさせると、待ち時間の後に同じidのタスクが起動し直される
+        let slack_client = Arc::new(SlackClient::new("xoxb-test".to_string(), "xapp-test".to_string()).unwrap());
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let started = Arc::new(std::sync::Mutex::new(Vec::<u64>::new()));
+        let connect = {
+            let (started, shutdown) = (Arc::clone(&started), shutdown.clone());
+            move |id: u64| {
+                let first = {
+                    let mut started = started.lock().unwrap();
+                    started.push(id);
+                    started.len() == 1
+                };
+                let mut shutdown = shutdown.clone();
+                async move {
+                    if first { panic!("stub connection {} panicked", id); }
+                    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+                }
+            }
+        };
+        let supervisor = tokio::spawn(multi_websocket_receiver(slack_client, 2, shutdown, connect));
+        let restarted = async {
+            while started.lock().unwrap().len() < 3 { tokio::time::sleep(std::time::Duration::from_secs(1)).await; }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(600), restarted).await.expect("the panicked task was not restarted");
+        assert_eq!(*started.lock().unwrap(), vec![0, 1, 0]);
+
+        // 終了する場合は起動し直さず、全てのタスクが終わったら終了する
+        shutdown_sender.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(10), supervisor).await.unwrap().unwrap();
+        assert_eq!(started.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_backoff_resets_after_a_healthy_run() {
+        // 続けて異常終了すると待ち時間が延び、HEALTHY_RUN_SECより長く動いた後の異常終了では最初の待ち時間に戻る
+        let slack_client = Arc::new(SlackClient::new("xoxb-test".to_string(), "xapp-test".to_string()).unwrap());
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        // 起動した順の動く時間 (最初のタスクは全てのタスクの起動が終わるまで動かす)
+        let run_secs = [200, 0, HEALTHY_RUN_SEC + 100, 0];
+        let started = Arc::new(std::sync::Mutex::new(Vec::<tokio::time::Instant>::new()));
+        let connect = {
+            let (started, shutdown) = (Arc::clone(&started), shutdown.clone());
+            move |_id: u64| {
+                let run_sec = {
+                    let mut started = started.lock().unwrap();
+                    started.push(tokio::time::Instant::now());
+                    run_secs.get(started.len() - 1).copied()
+                };
+                let mut shutdown = shutdown.clone();
+                async move {
+                    match run_sec {
+                        Some(run_sec) => { tokio::time::sleep(std::time::Duration::from_secs(run_sec)).await; },
+                        None => { let _ = shutdown.wait_for(|shutdown| *shutdown).await; },
+                    };
+                }
+            }
+        };
+        let supervisor = tokio::spawn(multi_websocket_receiver(slack_client, 1, shutdown, connect));
+        let restarted = async {
+            while started.lock().unwrap().len() <= run_secs.len() { tokio::time::sleep(std::time::Duration::from_secs(1)).await; }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(2000), restarted).await.expect("the task was not restarted");
+        let started = started.lock().unwrap().clone();
+        let waits: Vec<u64> = run_secs.iter().enumerate()
+            .map(|(index, run_sec)| (started[index + 1] - started[index]).as_secs() - run_sec)
+            .collect();
+        assert_eq!(waits, vec![1, 2, 1, 2]);
+
+        shutdown_sender.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(10), supervisor).await.unwrap().unwrap();
+    }
+
+    type ServerWebsocket = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+    // websocketの接続を受け付けるテスト用のサーバー (受け付けた接続のサーバー側を順番に渡す)
+    // apps.connections.openがこのサーバーのURLを返すように登録する (count回分)
+    async fn start_websocket_server(slack: &MockSlack, count: usize) -> Receiver<ServerWebsocket> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..count {
+            slack.push_json("apps.connections.open", serde_json::json!({"ok": true, "url": format!("ws://{}/", listener.local_addr().unwrap())}));
+        }
+        let (sender, receiver) = channel(count.max(1));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(websocket) = tokio_tungstenite::accept_async(stream).await {
+                    if sender.send(websocket).await.is_err() { return; }
+                }
+            }
+        });
+        return receiver;
+    }
+
+    // liveness_timeoutの間何も受信しなければ切断とみなす接続の状態
+    fn test_health(liveness_timeout: std::time::Duration) -> WebsocketHealth {
+        return WebsocketHealth { liveness_timeout, ..WebsocketHealth::new() };
+    }
+
+    // single_websocket_receiverでテスト用のサーバーに接続する (受信したメッセージはreceiverに渡される)
+    async fn connect_single(slack: &MockSlack, health: &WebsocketHealth) -> (tokio::task::JoinHandle<SlackResult<Disconnect>>, ServerWebsocket, Receiver<Message>, watch::Sender<bool>) {
+        let mut server = start_websocket_server(slack, 1).await;
+        let (sender, receiver) = channel(16);
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let connection = tokio::spawn(single_websocket_receiver(0, Arc::new(slack.client()), sender, health.clone(), shutdown));
+        let websocket = server.recv().await.unwrap();
+        return (connection, websocket, receiver, shutdown_sender);
+    }
+
+    // サーバー側で次のテキストのフレームを受信する
+    async fn next_text(websocket: &mut ServerWebsocket) -> String {
+        loop {
+            match websocket.next().await {
+                Some(Ok(protocol::Message::Text(text))) => { return text; },
+                Some(Ok(_)) => {},
+                frame => panic!("unexpected frame {:?}", frame),
+            };
+        }
+    }
+
+    // 1つの接続で受信するwebsocket_receiverをテスト用のサーバーに接続する (処理したメッセージはhandledに記録される)
+    async fn start_receiver(slack: &MockSlack, health: &WebsocketHealth) -> (ServerWebsocket, Arc<std::sync::Mutex<Vec<Message>>>, tokio::task::JoinHandle<()>, WebsocketShutdown) {
+        let mut server = start_websocket_server(slack, 1).await;
+        let health = WebsocketHealth { connection_count: 1, ..health.clone() };
+        let handled = Arc::new(std::sync::Mutex::new(Vec::<Message>::new()));
+        let message_handler = {
+            let handled = Arc::clone(&handled);
+            move |message: Message| {
+                let handled = Arc::clone(&handled);
+                async move { handled.lock().unwrap().push(message); }
+            }
+        };
+        let (receiver, websocket_shutdown) = websocket_receiver(Arc::new(slack.client()), health, message_handler);
+        let websocket = server.recv().await.unwrap();
+        return (websocket, handled, receiver, websocket_shutdown);
+    }
+
+    // handledにcount件のメッセージが記録されるまで待つ
+    async fn wait_for_handled(handled: &std::sync::Mutex<Vec<Message>>, count: usize) {
+        let handled_all = async {
+            while handled.lock().unwrap().len() < count { tokio::time::sleep(std::time::Duration::from_millis(10)).await; }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), handled_all).await
+            .unwrap_or_else(|_| panic!("{} of {} messages were handled", handled.lock().unwrap().len(), count));
+    }
+
+    // app_mentionのイベントを送るenvelope
+    fn app_mention_envelope(envelope_id: &str, event_id: &str, text: &str) -> serde_json::Value {
+        return serde_json::json!({
+            "type": "events_api",
+            "envelope_id": envelope_id,
+            "payload": {
+                "type": "event_callback",
+                "event_id": event_id,
+                "event_time": 1700000000,
+                "event": {"type": "app_mention", "channel": "C_TEST", "user": "U_TEST", "text": text, "ts": "1700000000.000100"},
+            },
+            "accepts_response_payload": false,
+        });
+    }
+
+    #[tokio::test]
+    async fn quiet_connection_reconnects() {
+        // Pingを含めて何も届かなくなった接続は、liveness_timeoutの後に閉じて再接続する
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_millis(400));
+        let (connection, _websocket, _receiver, _shutdown) = connect_single(&slack, &health).await;
+        let disconnect = tokio::time::timeout(std::time::Duration::from_secs(5), connection).await
+            .expect("the quiet connection was not closed").unwrap().unwrap();
+        assert!(matches!(disconnect, Disconnect::Reconnecting));
+        assert_eq!(health.get_open_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_pings_and_acknowledges_envelopes() {
+        // Pingを送り続けている間は接続を保ち、PingにはPong、メッセージには受信の確認を返す
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_millis(400));
+        let (connection, mut websocket, mut receiver, _shutdown) = connect_single(&slack, &health).await;
+        for _ in 0..4 {
+            websocket.send(protocol::Message::Ping(b"ping".to_vec())).await.unwrap();
+            assert_eq!(websocket.next().await.unwrap().unwrap(), protocol::Message::Pong(b"ping".to_vec()));
+            tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        }
+        assert!(!connection.is_finished());
+        assert!(health.is_alive());
+
+        let envelope = serde_json::json!({
+            "type": "slash_commands",
+            "envelope_id": "E_TEST",
+            "payload": {"channel_id": "C_TEST", "user_id": "U_TEST", "text": "0 0"},
+        });
+        websocket.send(protocol::Message::Text(envelope.to_string())).await.unwrap();
+        let ack: serde_json::Value = serde_json::from_str(&next_text(&mut websocket).await).unwrap();
+        assert_eq!(ack["envelope_id"], "E_TEST");
+        let message = receiver.recv().await.unwrap();
+        assert_eq!((message.event_type.as_str(), message.channel_id.as_str(), message.text.as_str()), ("slash_command", "C_TEST", "0 0"));
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn disconnect_warning_ends_the_connection() {
+        // 切断の予告を受け取ったら、slackが接続を閉じるのを待たずに受信をやめて再接続する
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (connection, mut websocket, _receiver, _shutdown) = connect_single(&slack, &health).await;
+        let envelope = serde_json::json!({"type": "disconnect", "reason": "refresh_requested", "debug_info": {"host": "test"}});
+        websocket.send(protocol::Message::Text(envelope.to_string())).await.unwrap();
+        let disconnect = tokio::time::timeout(std::time::Duration::from_secs(2), connection).await
+            .expect("the connection kept receiving after the disconnect warning").unwrap().unwrap();
+        assert!(matches!(disconnect, Disconnect::Reconnecting));
+    }
+
+    #[tokio::test]
+    async fn close_frame_reconnects_and_shutdown_exits() {
+        // slackから閉じられた場合は再接続する
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (connection, mut websocket, _receiver, _shutdown) = connect_single(&slack, &health).await;
+        websocket.close(None).await.unwrap();
+        let disconnect = tokio::time::timeout(std::time::Duration::from_secs(2), connection).await.unwrap().unwrap().unwrap();
+        assert!(matches!(disconnect, Disconnect::Reconnecting));
+
+        // 終了する場合はCloseを送ってから閉じ、再接続しない
+        let (connection, mut websocket, _receiver, shutdown) = connect_single(&slack, &health).await;
+        shutdown.send(true).unwrap();
+        assert!(matches!(websocket.next().await, Some(Ok(protocol::Message::Close(_)))));
+        let disconnect = tokio::time::timeout(std::time::Duration::from_secs(5), connection).await.unwrap().unwrap().unwrap();
+        assert!(matches!(disconnect, Disconnect::Exit));
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_the_connections_without_reconnecting() {
+        // 受信中のwebsocket_receiverを終了すると、Closeを送って接続を閉じ、再接続しない
+        let slack = MockSlack::start().await;
+        let mut server = start_websocket_server(&slack, 2).await;
+        let health = WebsocketHealth { connection_count: 1, ..test_health(std::time::Duration::from_secs(60)) };
+        let handled = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let message_handler = {
+            let handled = Arc::clone(&handled);
+            move |message: Message| {
+                let handled = Arc::clone(&handled);
+                async move { handled.lock().unwrap().push(message.text); }
+            }
+        };
+        let (receiver, websocket_shutdown) = websocket_receiver(Arc::new(slack.client()), health.clone(), message_handler);
+        let mut websocket = server.recv().await.unwrap();
+        let envelope = serde_json::json!({
+            "type": "slash_commands",
+            "envelope_id": "E_TEST",
+            "payload": {"channel_id": "C_TEST", "user_id": "U_TEST", "text": "status"},
+        });
+        websocket.send(protocol::Message::Text(envelope.to_string())).await.unwrap();
+        next_text(&mut websocket).await;
+        let handled_all = async {
+            while handled.lock().unwrap().is_empty() { tokio::time::sleep(std::time::Duration::from_millis(10)).await; }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), handled_all).await.unwrap();
+
+        websocket_shutdown.shutdown().await;
+        assert!(matches!(websocket.next().await, Some(Ok(protocol::Message::Close(_)))));
+        assert_eq!(health.get_open_connections(), 0);
+        receiver.abort();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(slack.get_requests("apps.connections.open").len(), 1);
+        assert_eq!(*handled.lock().unwrap(), vec!["status"]);
+    }
+
+    #[tokio::test]
+    async fn redelivered_event_is_handled_once() {
+        // 受信の確認が遅れてslackが同じイベントを再送した場合、どちらにも受信の確認を返すが、処理は1度だけ
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (mut websocket, handled, receiver, websocket_shutdown) = start_receiver(&slack, &health).await;
+        for (envelope_id, event_id, text) in [("E1", "Ev1", "<@U_BOT> 0 0"), ("E2", "Ev1", "<@U_BOT> 0 0"), ("E3", "Ev2", "<@U_BOT> 0.1 0")] {
+            let mut envelope = app_mention_envelope(envelope_id, event_id, text);
+            if envelope_id == "E2" {
+                envelope["retry_attempt"] = serde_json::json!(1);
+                envelope["retry_reason"] = serde_json::json!("timeout");
+            }
+            websocket.send(protocol::Message::Text(envelope.to_string())).await.unwrap();
+            let ack: serde_json::Value = serde_json::from_str(&next_text(&mut websocket).await).unwrap();
+            assert_eq!(ack["envelope_id"], envelope_id);
+        }
+        wait_for_handled(&handled, 2).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let handled: Vec<_> = handled.lock().unwrap().iter().map(|message| (message.event_id.clone().unwrap(), message.text.clone())).collect();
+        assert_eq!(handled, vec![("Ev1".to_string(), "<@U_BOT> 0 0".to_string()), ("Ev2".to_string(), "<@U_BOT> 0.1 0".to_string())]);
+        websocket_shutdown.shutdown().await;
+        receiver.abort();
+    }
+
+    #[test]
+    fn parses_captured_envelopes() {
+        // slackから受信したフレームの例 (tests/fixtures/slack/) を読み取れる
+        let json = include_str!("../tests/fixtures/slack/app_mention.json");
+        assert_eq!(serde_json::from_str::<EnvelopeHeader>(json).unwrap().envelope_id.as_deref(), Some("5f1c3a2e-8b4d-4c6a-9e7f-2d1b0a9c8e7f"));
+        let payload = match serde_json::from_str::<Envelope>(json).unwrap() {
+            Envelope::EventsApi { payload } => payload,
+            _ => panic!("app_mention.json is not an events_api envelope"),
+        };
+        assert_eq!((payload.event_id.as_deref(), payload.event_time), (Some("Ev06TOWER001"), Some(1700000123)));
+        match payload.event {
+            Event::AppMention(event) => {
+                assert_eq!((event.channel.as_str(), event.user.as_str(), event.text.as_str()), ("C0TOWERCH", "U0PLAYER01", "<@U05TOWERBOT> 0.2 30"));
+                assert_eq!((event.ts.as_str(), event.thread_ts.as_deref()), ("1700000123.004500", Some("1700000000.000100")));
+                assert!(event.bot_id.is_none());
+                assert!(event.files.is_empty());
+            },
+            _ => panic!("app_mention.json is not an app_mention event"),
+        };
+
+        // 添付ファイル (削除されたファイルにはurl_privateがない)
+        let event = match serde_json::from_str::<Envelope>(include_str!("../tests/fixtures/slack/app_mention_with_file.json")).unwrap() {
+            Envelope::EventsApi { payload } => payload.event,
+            _ => panic!("app_mention_with_file.json is not an events_api envelope"),
+        };
+        match event {
+            Event::AppMention(event) => {
+                let files: Vec<_> = event.files.iter().map(|file| (file.name.as_str(), file.size, file.url_private.as_deref())).collect();
+                assert_eq!(files, vec![
+                    ("star.svg", 1234, Some("https://files.slack.com/files-pri/T0TESTTEAM-F06SHAPE01/star.svg")),
+                    ("gone.svg", 0, None),
+                ]);
+            },
+            _ => panic!("app_mention_with_file.json is not an app_mention event"),
+        };
+
+        // 知らない種類のイベントも読み取れる (受信の確認を返すためにenvelope_idも読む)
+        let json = include_str!("../tests/fixtures/slack/reaction_added.json");
+        assert!(matches!(serde_json::from_str::<Envelope>(json).unwrap(), Envelope::EventsApi { payload } if matches!(payload.event, Event::Other)));
+        assert!(serde_json::from_str::<EnvelopeHeader>(json).unwrap().envelope_id.is_some());
+
+        // helloと切断の予告にはenvelope_idがない
+        let json = include_str!("../tests/fixtures/slack/hello.json");
+        assert!(matches!(serde_json::from_str::<Envelope>(json).unwrap(), Envelope::Other));
+        assert!(serde_json::from_str::<EnvelopeHeader>(json).unwrap().envelope_id.is_none());
+        match serde_json::from_str::<Envelope>(include_str!("../tests/fixtures/slack/disconnect.json")).unwrap() {
+            Envelope::Disconnect { reason } => assert_eq!(reason.as_deref(), Some("warning")),
+            _ => panic!("disconnect.json is not a disconnect envelope"),
+        };
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_do_not_end_the_connection() {
+        // 読み取れないフレームや知らない種類のイベントはログに残して無視し、同じ接続で受信を続ける
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (connection, mut websocket, mut receiver, _shutdown) = connect_single(&slack, &health).await;
+        let frames = [
+            include_str!("../tests/fixtures/slack/hello.json"),
+            "{\"envelope_id\": \"E_BROKEN\", \"type\": \"events_api\", \"payload\": ",
+            "[1, 2, 3]",
+            "{\"envelope_id\": \"E_MISSING\", \"type\": \"events_api\", \"payload\": {\"event\": {\"type\": \"app_mention\"}}}",
+            include_str!("../tests/fixtures/slack/reaction_added.json"),
+        ];
+        for frame in frames {
+            websocket.send(protocol::Message::Text(frame.to_string())).await.unwrap();
+        }
+        websocket.send(protocol::Message::Binary(vec![0xff, 0x00])).await.unwrap();
+        websocket.send(protocol::Message::Text(include_str!("../tests/fixtures/slack/app_mention.json").to_string())).await.unwrap();
+
+        // envelope_idを読み取れたものには受信の確認を返す (中身を読み取れなかった場合も再送されないように返す)
+        let mut acks = Vec::new();
+        for _ in 0..3 {
+            let ack: serde_json::Value = serde_json::from_str(&next_text(&mut websocket).await).unwrap();
+            acks.push(ack["envelope_id"].as_str().unwrap().to_string());
+        }
+        assert_eq!(acks, vec!["E_MISSING", "7d6c5b4a-3e2f-4d1c-8b0a-9f8e7d6c5b4a", "5f1c3a2e-8b4d-4c6a-9e7f-2d1b0a9c8e7f"]);
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!((message.event_type.as_str(), message.channel_id.as_str(), message.text.as_str()), ("app_mention", "C0TOWERCH", "<@U05TOWERBOT> 0.2 30"));
+        assert_eq!((message.thread_ts.as_deref(), message.event_id.as_deref()), (Some("1700000000.000100"), Some("Ev06TOWER001")));
+        assert!(!connection.is_finished());
+        assert!(receiver.try_recv().is_err());
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn ignores_mentions_from_bots() {
+        // 他のbotからのメンションには受信の確認だけ返し、処理する側に渡さない (bot同士で反応し続けないようにする)
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (connection, mut websocket, mut receiver, _shutdown) = connect_single(&slack, &health).await;
+        let mut from_bot = app_mention_envelope("E1", "Ev1", "<@U_BOT> 0 0");
+        from_bot["payload"]["event"]["bot_id"] = serde_json::json!("B_OTHER");
+        for envelope in [from_bot, app_mention_envelope("E2", "Ev2", "<@U_BOT> 0.1 0")] {
+            websocket.send(protocol::Message::Text(envelope.to_string())).await.unwrap();
+            next_text(&mut websocket).await;
+        }
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(message.event_id.as_deref(), Some("Ev2"));
+        assert!(receiver.try_recv().is_err());
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn slash_command_is_acknowledged_with_an_ephemeral_response() {
+        // スラッシュコマンドには本人にだけ見える「計算中...」で受信の確認を返し、slash_commandとして処理する側に渡す
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (connection, mut websocket, mut receiver, _shutdown) = connect_single(&slack, &health).await;
+        websocket.send(protocol::Message::Text(include_str!("../tests/fixtures/slack/slash_command.json").to_string())).await.unwrap();
+        let ack: serde_json::Value = serde_json::from_str(&next_text(&mut websocket).await).unwrap();
+        assert_eq!(ack, serde_json::json!({
+            "envelope_id": "1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5d",
+            "payload": {"response_type": "ephemeral", "text": "計算中..."},
+        }));
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!((message.event_type.as_str(), message.channel_id.as_str(), message.user_id.as_str()), ("slash_command", "C0TOWERCH", "U0PLAYER01"));
+        assert_eq!(message.text, "0.2 30");
+        // スラッシュコマンドにはメッセージのtsとevent_idがない
+        assert_eq!((message.ts.as_str(), message.thread_ts, message.event_id), ("", None, None));
+        connection.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn thousands_of_messages_are_received_without_losses() {
+        // 処理が遅れて受信が待たされても、受信の確認とメッセージを1つも落とさない
+        let slack = MockSlack::start().await;
+        let health = test_health(std::time::Duration::from_secs(60));
+        let (websocket, handled, receiver, websocket_shutdown) = start_receiver(&slack, &health).await;
+        let (mut write, mut read) = websocket.split();
+        let message_count = 3000;
+        let send_all = async {
+            for index in 0..message_count {
+                let envelope = app_mention_envelope(&format!("E{}", index), &format!("Ev{}", index), &format!("<@U_BOT> {}", index));
+                write.send(protocol::Message::Text(envelope.to_string())).await.unwrap();
+            }
+        };
+        let read_acks = async {
+            let mut acks = Vec::new();
+            while acks.len() < message_count {
+                match read.next().await {
+                    Some(Ok(protocol::Message::Text(text))) => {
+                        let ack: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        acks.push(ack["envelope_id"].as_str().unwrap().to_string());
+                    },
+                    Some(Ok(_)) => {},
+                    frame => panic!("unexpected frame {:?}", frame),
+                };
+            }
+            acks
+        };
+        let (_, acks) = tokio::time::timeout(std::time::Duration::from_secs(60), future::join(send_all, read_acks)).await
+            .expect("the acknowledgements were not received in time");
+        assert_eq!(acks, (0..message_count).map(|index| format!("E{}", index)).collect::<Vec<_>>());
+        wait_for_handled(&handled, message_count).await;
+
+        let texts: Vec<String> = handled.lock().unwrap().iter().map(|message| message.text.clone()).collect();
+        assert_eq!(texts, (0..message_count).map(|index| format!("<@U_BOT> {}", index)).collect::<Vec<_>>());
+        assert_eq!((health.get_dropped_responses(), health.get_dropped_messages()), (0, 0));
+        websocket_shutdown.shutdown().await;
+        receiver.abort();
     }
 }
@@ -1,39 +1,417 @@
-// Todo: 座標の直打ちやめろ
-
 extern crate rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand_chacha::ChaCha12Rng;
 use rapier2d::prelude::*;
-use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use std::collections::{ HashMap, VecDeque };
+use std::sync::Arc;
 use super::canvas;
+use super::canvas::ShapeDef;
+use super::render;
+use super::render::{ CameraTransform, RenderOptions, VIEW_WIDTH, VIEW_HEIGHT };
 
 pub use rapier2d::prelude::Real;
 
-#[derive(Debug, Clone)]
+// ステージの座標と長さは全てm単位 (y軸は下向きが正)
+
+// ターンを進められなかった場合のエラー
+#[derive(Debug, thiserror::Error)]
+pub enum StageError {
+    // 順番が決まっている場合に、順番ではないユーザーがオブジェクトを落とそうとした
+    #[error("it is not the turn of {0:?}")]
+    NotYourTurn(Option<String>),
+    // 結果の画像を作れなかった
+    #[error("failed to render the stage: {0}")]
+    Render(#[from] canvas::CanvasError),
+}
+
+// 地面の中心のx座標 (画面の中央に表示する)
+const GROUND_CENTER_X: Real = 3.2;
+
+// 傾いた地面の傾き (度、時計回りが正の回転)
+const GROUND_TILT_ANGLE: Real = 3.0;
+// 狭い地面の幅の倍率
+const GROUND_NARROW_RATIO: Real = 0.6;
+// 段差のある地面の右側の段の高さ
+const GROUND_STEP_HEIGHT: Real = 0.3;
+
+// 画像の上端と左右に空ける余白 (px)
+const CAMERA_MARGIN: Real = 10.0;
+
+// 高さの目盛りの間隔の候補 (m、目盛りの間隔がRULER_MIN_SPACING px以上になる最小のものを使う)
+// 2目盛りごとに長い目盛りと数字を表示する
+const RULER_INTERVALS: [Real; 5] = [0.5, 1.0, 2.5, 5.0, 10.0];
+const RULER_MIN_SPACING: f64 = 12.0;
+
+// 落とすオブジェクトが既存のオブジェクトと重なる場合に持ち上げる間隔と上限
+const SPAWN_LIFT_STEP: Real = 0.1;
+const MAX_SPAWN_LIFT: Real = 3.0;
+
+// 高さを測る時に下向きのレイを飛ばす間隔
+const HEIGHT_RAY_SPACING: Real = 0.04;
+// この速さ (m/s) 未満のオブジェクトだけを高さに含める (宙を舞っている破片を除く)
+const SUPPORTED_SPEED: Real = 0.05;
+
+// 壁の厚さの半分と高さ
+const WALL_HALF_THICKNESS: Real = 0.06;
+const WALL_HEIGHT: Real = 50.0;
+
+// オブジェクトの密度 (kg/m^2)
+const PIECE_DENSITY: Real = 1.0;
+
+// 静止しないターンが何回続いたらゲームを終了するか
+const MAX_UNSETTLED_COUNT: u32 = 3;
+
+// 何ターン続けて静止し、タワーの頂点から何m下にあるオブジェクトを固定するか
+// (埋もれたオブジェクトを物理演算から外して計算量を抑える)
+const BURY_SLEEP_TURNS: u32 = 3;
+const BURY_DEPTH: Real = 2.0;
+
+// 次に落とすオブジェクトの候補の数
+const CANDIDATE_COUNT: usize = 3;
+
+// 落とす高さが1の時のタワーの頂点からの距離
+const MAX_DROP_OFFSET: Real = 1.5;
+
+// 回転速度の上限 (rad/s)
+pub const MAX_SPIN: Real = 5.0;
+
+// ゴールデンピースが出現する確率
+const GOLDEN_PROBABILITY: f64 = 0.1;
+// ゴールデンピースを積んだ時の高さの伸びの倍率 (リーダーボード用)
+pub const GOLDEN_BONUS: Real = 2.0;
+
+// 風の強さが1.0の時に地面の高さで受ける力 (高い位置ほど強くなる)
+const WIND_FORCE: Real = 1.0;
+// 風が吹き続ける時間 (秒)
+const WIND_DURATION: Real = 3.0;
+
+// アニメーションGIFのフレームを記録する間隔 (ステップ数) と最大フレーム数
+// 最大フレーム数を超えた場合はフレームを間引いて間隔を倍にする
+const ANIMATION_FRAME_INTERVAL: u64 = 6;
+const MAX_ANIMATION_FRAMES: usize = 80;
+// アニメーションGIFの縮小率 (640x480 -> 320x240)
+const ANIMATION_ZOOM: f64 = 0.5;
+
+// 空の色が変わり始める高さと、夜空 (星空) になる高さ (m)
+// SKY_DUSK_HEIGHTからSKY_NIGHT_HEIGHTまでは昼の空から夕焼けのグラデーションに近づける
+const SKY_DUSK_HEIGHT: Real = 2.0;
+const SKY_NIGHT_HEIGHT: Real = 5.0;
+const DUSK_COLORS: ((u8, u8, u8), (u8, u8, u8)) = ((72, 52, 140), (252, 146, 84));
+const NIGHT_COLORS: ((u8, u8, u8), (u8, u8, u8)) = ((8, 12, 38), (36, 40, 102));
+// 星の数 (位置は高さの1 mごとの区間から決まる)
+const STAR_COUNT: usize = 40;
+
+// 崩れる様子のアニメーションGIFのフレームを記録する間隔 (ステップ数)
+// 失敗と判定される直前のCOLLAPSE_FRAMES_BEFOREフレームを常に記録しておき (姿勢のみ)、
+// 失敗した場合は判定後も物理演算を続けてCOLLAPSE_FRAMES_AFTERフレームを追加する
+const COLLAPSE_FRAME_INTERVAL: u64 = 2;
+const COLLAPSE_FRAMES_BEFORE: usize = 30;
+const COLLAPSE_FRAMES_AFTER: usize = 30;
+
+// 地震が発生する周期 (ターン数)
+const EARTHQUAKE_PERIOD: u32 = 10;
+// 地震の揺れが続く時間 (秒)
+const EARTHQUAKE_DURATION: Real = 0.5;
+// 地震の揺れで1フレームごとに加わる速度の最大値 (m/s)
+const EARTHQUAKE_STRENGTH: Real = 0.1;
+
+// 風の強さの段階 (1: 弱, 2: 中, 3: 強)
+pub fn get_wind_level(wind: Real) -> usize {
+    return ((wind.abs() * 3.0).ceil() as usize).clamp(1, 3);
+}
+
+// オブジェクトの形状の一覧画像 (アップロードされた形状の確認用)
+pub fn render_shapes_preview(shapes: &[ShapeDef]) -> canvas::CanvasResult<Vec<u8>> {
+    let (columns, cell_size) = (4, 160.0);
+    let rows = shapes.len().div_ceil(columns).max(1);
+    let mut canvas = canvas::Canvas::new(columns as f64 * cell_size, rows as f64 * cell_size);
+    canvas.set_no_stroke();
+    canvas.set_color_fill(3, 182, 252);
+    canvas.add_shape(&[
+        (0.0,                        0.0),
+        (columns as f64 * cell_size, 0.0),
+        (columns as f64 * cell_size, rows as f64 * cell_size),
+        (0.0,                        rows as f64 * cell_size),
+    ], (0.0, 0.0), 0.0);
+    canvas.set_color_fill(255, 255, 255);
+    canvas.set_color_stroke(245, 66, 129, 4.0);
+    for (index, shape) in shapes.iter().enumerate() {
+        let radius = shape.vertices.iter().map(|(x, y)| (x * x + y * y).sqrt()).fold(0.0, f64::max);
+        let scale = (cell_size * 0.4 / radius).min(1.0);
+        let preview_shape: Vec<(f64, f64)> = shape.vertices.iter().map(|(x, y)| (x * scale, y * scale)).collect();
+        let cell_x = (index % columns) as f64 * cell_size;
+        let cell_y = (index / columns) as f64 * cell_size;
+        canvas.add_shape(&preview_shape, (cell_x + cell_size * 0.5, cell_y + cell_size * 0.5), 0.0);
+    }
+    return canvas.encode_png();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+impl Team {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Team::Red => "赤",
+            Team::Blue => "青",
+        }
+    }
+
+    pub fn get_color(&self) -> (u8, u8, u8) {
+        match self {
+            Team::Red => (235, 64, 52),
+            Team::Blue => (52, 110, 235),
+        }
+    }
+
+    pub fn get_opponent(&self) -> Team {
+        match self {
+            Team::Red => Team::Blue,
+            Team::Blue => Team::Red,
+        }
+    }
+}
+
+// 難易度 (ステージ生成時に決まり、ゲーム中は変わらない)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Normal,
+    // 氷のように滑りやすく、弾みやすい
+    Hard,
+}
+
+impl Difficulty {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Difficulty::Normal => "ノーマル",
+            Difficulty::Hard => "ハード",
+        }
+    }
+
+    // オブジェクトと地面の摩擦係数 (defaultはStageConfigの摩擦係数)
+    pub fn get_friction(&self, default: Real) -> Real {
+        match self {
+            Difficulty::Normal => default,
+            Difficulty::Hard => 0.2,
+        }
+    }
+
+    // オブジェクトの反発係数
+    pub fn get_restitution(&self) -> Real {
+        match self {
+            Difficulty::Normal => 0.0,
+            Difficulty::Hard => 0.3,
+        }
+    }
+}
+
+// 地面の種類 (ステージ生成時に決まり、ゲーム中は変わらない)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroundVariant {
+    Flat,
+    // 少し傾いている
+    Tilted,
+    // 幅が狭い
+    Narrow,
+    // 右半分が一段高い
+    Steps,
+}
+
+impl GroundVariant {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            GroundVariant::Flat => "平ら",
+            GroundVariant::Tilted => "傾き",
+            GroundVariant::Narrow => "狭い",
+            GroundVariant::Steps => "段差",
+        }
+    }
+
+    // 地面を構成する直方体 (当たり判定と描画の両方に使う)
+    fn get_blocks(&self, config: &StageConfig) -> Vec<GroundBlock> {
+        let half_height = config.fail_y - config.ground_y;
+        let flat = GroundBlock {
+            center: vector![GROUND_CENTER_X, config.ground_y],
+            half_extents: vector![config.ground_half_width, half_height],
+            rotation: 0.0,
+        };
+        match self {
+            GroundVariant::Flat => vec![flat],
+            GroundVariant::Tilted => vec![GroundBlock { rotation: GROUND_TILT_ANGLE.to_radians(), ..flat }],
+            GroundVariant::Narrow => vec![GroundBlock { half_extents: vector![config.ground_half_width * GROUND_NARROW_RATIO, half_height], ..flat }],
+            GroundVariant::Steps => {
+                // 下端を揃えて、右側の段だけ上に伸ばす
+                let quarter_width = config.ground_half_width * 0.5;
+                vec![
+                    GroundBlock {
+                        center: vector![GROUND_CENTER_X - quarter_width, config.ground_y],
+                        half_extents: vector![quarter_width, half_height],
+                        rotation: 0.0,
+                    },
+                    GroundBlock {
+                        center: vector![GROUND_CENTER_X + quarter_width, config.ground_y - GROUND_STEP_HEIGHT * 0.5],
+                        half_extents: vector![quarter_width, half_height + GROUND_STEP_HEIGHT * 0.5],
+                        rotation: 0.0,
+                    },
+                ]
+            },
+        }
+    }
+}
+
+// 地面を構成する直方体 (回転はラジアン)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct GroundBlock {
+    center: Vector<Real>,
+    half_extents: Vector<Real>,
+    rotation: Real,
+}
+
+impl GroundBlock {
+    // 回転を適用した4つの角の座標
+    fn get_corners(&self) -> Vec<Point<Real>> {
+        let (sin, cos) = self.rotation.sin_cos();
+        return [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter().map(|(sx, sy)| {
+            let x = sx * self.half_extents.x;
+            let y = sy * self.half_extents.y;
+            Point::new(x * cos - y * sin + self.center.x, x * sin + y * cos + self.center.y)
+        }).collect();
+    }
+}
+
+// 物理演算とステージの配置のパラメーター (長さの単位はm)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StageConfig {
+    // 重力加速度 (m/s^2、下向きが正)
+    pub gravity: Real,
+    // 1ターンの物理演算を打ち切る時間 (秒)
+    pub timeout_sec: Real,
+    // 1ターンの物理演算にかけられる実際の時間 (秒、タワーが大きいとtimeout_secより先に達する)
+    pub wall_clock_budget_sec: Real,
+    // 画像の1pxあたりの長さ (m、px単位の形状の変換と描画にのみ使う)
+    pub world_scale: Real,
+    // 地面の中心の高さ
+    pub ground_y: Real,
+    // 失敗判定の高さ (地面の下端)
+    pub fail_y: Real,
+    // 地面の幅の半分
+    pub ground_half_width: Real,
+    // 落とす高さを指定しなかった時のタワーの頂点からの距離
+    pub spawn_margin: Real,
+    // オブジェクトと地面の摩擦係数 (難易度によっては上書きされる)
+    pub friction: Real,
+    // 全てのオブジェクトの運動エネルギーの合計 (J) がrest_energy未満の状態が
+    // rest_framesフレーム続いた場合は静止したとみなす (わずかに揺れ続けるタワー対策)
+    pub rest_energy: Real,
+    pub rest_frames: u32,
+    // オブジェクトの密度の倍率 (ピースセットごとに変えられる)
+    pub density_multiplier: Real,
+    // 画像の左上にターン数、高さ、直前にプレイしたユーザーを表示するか
+    #[serde(default = "default_overlay")]
+    pub overlay: bool,
+    // 左右の位置と回転角度 (度) の入力の範囲 (それぞれ-max〜max)
+    #[serde(default = "default_max_translation_x")]
+    pub max_translation_x: Real,
+    #[serde(default = "default_max_rotation")]
+    pub max_rotation: Real,
+}
+
+// 保存されたステージに設定がない場合は表示する
+fn default_overlay() -> bool {
+    return true;
+}
+
+fn default_max_translation_x() -> Real {
+    return 1.0;
+}
+
+fn default_max_rotation() -> Real {
+    return 180.0;
+}
+
+impl Default for StageConfig {
+    fn default() -> Self {
+        StageConfig {
+            gravity: 9.81,
+            timeout_sec: 60.0,
+            wall_clock_budget_sec: 20.0,
+            world_scale: 0.01,
+            ground_y: 4.1,
+            fail_y: 4.2,
+            ground_half_width: 2.2,
+            spawn_margin: 0.5,
+            friction: 1.0,
+            rest_energy: 0.0001,
+            rest_frames: 120,
+            density_multiplier: 1.0,
+            overlay: true,
+            max_translation_x: default_max_translation_x(),
+            max_rotation: default_max_rotation(),
+        }
+    }
+}
+
+impl StageConfig {
+    // 地面の上端の高さ
+    pub fn get_ground_top(&self) -> Real {
+        return self.ground_y * 2.0 - self.fail_y;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Object {
     pub user_id: Option<String>,
-    pub shape: Vec<(f64, f64)>,
+    pub team: Option<Team>,
+    pub golden: bool,
+    pub shape: ShapeDef,
     pub translation: Vector<Real>,
     pub rotation: Real,
     rigid_body_handle: RigidBodyHandle,
+    // 連続で静止していたターンの数
+    sleep_streak: u32,
+    // 埋もれて固定された (物理演算の対象外)
+    buried: bool,
 }
 
 impl Object {
+    // 回転と移動を適用した頂点の座標
+    pub fn get_world_vertices(&self) -> Vec<Point<Real>> {
+        return self.shape.vertices.iter().map(|vertex| {
+            let point = Point::new(vertex.0 as Real, vertex.1 as Real);
+            Point::new(
+                point.x * self.rotation.cos() - point.y * self.rotation.sin() + self.translation.x,
+                point.x * self.rotation.sin() + point.y * self.rotation.cos() + self.translation.y,
+            )
+        }).collect();
+    }
+
     pub fn get_top(&self) -> Real {
         let mut top = Real::MAX;
-        for vertex in &self.shape {
-            let point1 = Point::new(vertex.0 as Real, vertex.1 as Real);
-            let point2 = Point::new(
-                point1.x * self.rotation.cos() - point1.y * self.rotation.sin() + self.translation.x,
-                point1.x * self.rotation.sin() + point1.y * self.rotation.cos() + self.translation.y,
-            );
-            if top > point2.y { top = point2.y; }
+        for point in self.get_world_vertices() {
+            if top > point.y { top = point.y; }
         }
         return top;
     }
 
+    // 多角形の面積 (m^2、靴紐公式)
+    pub fn get_area(&self) -> Real {
+        let mut area = 0.0;
+        let vertices = &self.shape.vertices;
+        for (index, vertex) in vertices.iter().enumerate() {
+            let next = vertices[(index + 1) % vertices.len()];
+            area += vertex.0 * next.1 - next.0 * vertex.1;
+        }
+        return (area * 0.5).abs() as Real;
+    }
+
     pub fn get_radius(&self) -> Real {
         let mut radius: Real = 0.0;
-        for vertex in &self.shape {
+        for vertex in &self.shape.vertices {
             let radius_ = (vertex.0 * vertex.0 + vertex.1 * vertex.1).sqrt();
             if radius_ as Real > radius { radius = radius_ as Real; }
         }
@@ -41,14 +419,87 @@ impl Object {
     }
 }
 
+// ターン開始前のステージの状態 (undo用)
+#[derive(Clone, Serialize, Deserialize)]
+struct Snapshot {
+    objects: Vec<Object>,
+    height: Real,
+    turn_index: usize,
+    turns_remaining: Option<u32>,
+    turn_count: u32,
+    candidates: Vec<ShapeDef>,
+}
+
+// ユーザーのアイコン
+pub enum UserIcon {
+    // アイコン画像 (縮小済みのPNG、描画のたびに複製しないようにArcで持つ)
+    Image(Arc<Vec<u8>>),
+    // アイコン画像がないユーザーはuser_idから模様を生成する
+    Identicon,
+}
+
 pub struct Stage {
-    pub user_icons: HashMap<String, Vec<u8>>,
+    // ユーザーのアイコン (登録済みのユーザーはSlackに問い合わせ直さない)
+    pub user_icons: HashMap<String, UserIcon>,
+    // ユーザーの表示名 (user_iconsと同様に保存せず、復元後に取得し直す)
+    pub user_names: HashMap<String, String>,
+    height: Real,
+    snapshot: Option<Snapshot>,
+    config: StageConfig,
+    difficulty: Difficulty,
+    ground: GroundVariant,
+    ground_blocks: Vec<GroundBlock>,
+    // ステージ内の乱数は全てseedから初期化したrngを使う (再現用)
+    seed: u64,
+    rng: ChaCha12Rng,
+
+    // ターン順 (参加者がいない場合は誰でもプレイ可能)
+    players: Vec<String>,
+    turn_index: usize,
+
+    // チーム戦の場合のみSome (user_id -> チーム)
+    teams: Option<HashMap<String, Team>>,
+
+    // 手数制限がある場合のみSome
+    turns_remaining: Option<u32>,
+
+    // 目標の高さ (m、目標モードの場合のみSome)
+    target_height: Option<Real>,
+
+    // 脱落モードの場合のみSome (脱落したuser_id)
+    eliminated_players: Option<Vec<String>>,
+
+    // 地面の両端の壁 (壁がない場合は空)
+    wall_collider_handles: Vec<ColliderHandle>,
+
+    // 風 (-1〜1、正の値が右向き)
+    wind_enabled: bool,
+    wind: Real,
+
+    // 結果の画像をアニメーションGIFにするか
+    animation_enabled: bool,
+    // 物理演算の前に落とす位置のガイドの画像を作るか
+    drop_guide_enabled: bool,
+
+    // 連続で静止しなかったターンの数 (1以上の場合は次のターンで続きから物理演算する)
+    unsettled_count: u32,
+
+    // 地震 (EARTHQUAKE_PERIODターンごとに発生)
+    turn_count: u32,
+    earthquake: bool,
+
+    // 描画の色 (チャンネルのテーマ、保存せずにチャンネルの設定から設定し直す)
+    render_options: RenderOptions,
+
+    // 直前のターンで積むのに成功したオブジェクトのインデックス (結果の画像で1回だけ強調する)
+    // 脱落やundoでオブジェクトの並びが変わるので、最後から2番目とはみなさずに記録する
+    last_placed_index: Option<usize>,
 
     // Rapier 2D
-    world_scale: Real,
     gravity: Vector<Real>,
     integration_parameters: IntegrationParameters,
     physics_pipeline: PhysicsPipeline,
+    query_pipeline: QueryPipeline,
     island_manager: IslandManager,
     broad_phase: BroadPhase,
     narrow_phase: NarrowPhase,
@@ -62,26 +513,220 @@ pub struct Stage {
 
     // Game Objects
     objects: Vec<Object>,
-    shapes: Vec<Vec<(f64, f64)>>,
+    fallen_object_indices: Vec<usize>,
+    shapes: Vec<ShapeDef>,
+    candidates: Vec<ShapeDef>,
+}
+
+// 保存・復元用のステージの状態 (ユーザーのアイコンは含まないので復元後に取得し直す)
+// 形式を変えた場合はSTAGE_SNAPSHOT_VERSIONを上げる
+#[derive(Serialize, Deserialize)]
+pub struct StageSnapshot {
+    version: u32,
+    height: Real,
+    snapshot: Option<Snapshot>,
+    config: StageConfig,
+    difficulty: Difficulty,
+    ground: GroundVariant,
+    seed: u64,
+    // rngの内部状態 (バージョン1の形式にはないので、代わりにrng_seedから初期化する)
+    #[serde(default, with = "binary")]
+    rng: Option<ChaCha12Rng>,
+    #[serde(default, skip_serializing)]
+    rng_seed: Option<u64>,
+
+    players: Vec<String>,
+    turn_index: usize,
+    teams: Option<HashMap<String, Team>>,
+    turns_remaining: Option<u32>,
+    target_height: Option<Real>,
+    eliminated_players: Option<Vec<String>>,
+    wall_collider_handles: Vec<ColliderHandle>,
+    wind_enabled: bool,
+    wind: Real,
+    #[serde(default)]
+    animation_enabled: bool,
+    #[serde(default)]
+    drop_guide_enabled: bool,
+    unsettled_count: u32,
+    turn_count: u32,
+    earthquake: bool,
+
+    // Rapier 2D
+    #[serde(with = "binary")]
+    physics: PhysicsSnapshot,
+
+    // Game Objects
+    objects: Vec<Object>,
+    fallen_object_indices: Vec<usize>,
+    shapes: Vec<ShapeDef>,
+    candidates: Vec<ShapeDef>,
+}
+
+// 保存するRapier 2Dの状態
+// 接触の情報 (broad_phase, narrow_phase) も保存するので、復元後も同じ物理演算の結果になる
+#[derive(Serialize, Deserialize)]
+struct PhysicsSnapshot {
+    integration_parameters: IntegrationParameters,
+    island_manager: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    rigid_body_set: RigidBodySet,
+    collider_set: ColliderSet,
+    impulse_joint_set: ImpulseJointSet,
+    multibody_joint_set: MultibodyJointSet,
+}
+
+// JSONで表せない値 (文字列以外のキーのマップやu128など) をbincodeでエンコードし、base64の文字列として保存する
+mod binary {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use serde::de::DeserializeOwned;
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = bincode::serialize(value).map_err(serde::ser::Error::custom)?;
+        return serializer.serialize_str(&base64::encode(bytes));
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes = base64::decode(text).map_err(serde::de::Error::custom)?;
+        return bincode::deserialize(&bytes).map_err(serde::de::Error::custom);
+    }
 }
 
-#[derive(PartialEq, Debug)]
+// 1: rngの代わりに、保存時にrngから引いたrng_seedを保存していた (読み込みは引き続き可能)
+const STAGE_SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum TurnResult {
     Success,
     Failure,
+    // 時間内にタワーが静止しなかった (次のターンで続きから物理演算する)
+    Unsettled,
+    // 物理演算が破綻した、または静止しないターンが続いた
     Timeout,
+    // 物理演算の計算に実時間でwall_clock_budget_sec秒以上かかった (次のターンで続きから物理演算する)
+    Aborted,
+    Finished,
+}
+
+// プレイヤーが指定するターンの操作
+#[derive(Debug, Clone)]
+pub struct TurnInput {
+    // 候補の番号 (0始まり)
+    pub selection: usize,
+    // 左右の位置 (-1〜1)
+    pub translation_x: Real,
+    // 回転角度 (-180〜180、時計回りが正の回転)
+    pub rotation: Real,
+    // 落とす高さ (0: タワーの真上、1: MAX_DROP_OFFSETだけ上、None: StageConfig.spawn_marginだけ上)
+    pub drop_height: Option<Real>,
+    // 落とす瞬間の回転速度 (rad/s、時計回りが正の回転)
+    pub spin: Real,
+    // 左右反転して落とすか
+    pub flip: bool,
+}
+
+impl Default for TurnInput {
+    fn default() -> Self {
+        TurnInput {
+            selection: 0,
+            translation_x: 0.0,
+            rotation: 0.0,
+            drop_height: None,
+            spin: 0.0,
+            flip: false,
+        }
+    }
+}
+
+// next_turnの結果 (今後も項目を増やすので、Stageの外では構築しない)
+#[non_exhaustive]
+pub struct TurnReport {
+    pub result: TurnResult,
+    pub height: Real,
+    // ターン開始前からの高さの変化 (m)
+    pub height_delta: Real,
+    // 風が無効の場合はNone
+    pub wind: Option<Real>,
+    // 地面から落下したオブジェクトのインデックス (get_objects()の添字)
+    pub fallen_object_indices: Vec<usize>,
+    // ゴールデンピースを積むのに成功したか
+    pub golden: bool,
+    // 前のターンで静止しなかったタワーの続きを物理演算したか (この場合は入力を無視する)
+    pub resumed: bool,
+    // 今回落としたオブジェクトの質量 (kg、落としていない場合はNone)
+    pub mass: Option<Real>,
+    // 既存のオブジェクトと重なっていたため落とす位置を持ち上げた距離 (m、持ち上げていない場合はNone)
+    pub spawn_lift: Option<Real>,
+    // 実行した物理演算のステップ数と、かかった実際の時間 (ミリ秒)
+    pub steps_executed: u64,
+    pub elapsed_ms: u64,
+    // 結果の画像とアニメーションの描画にかかった時間 (ミリ秒)
+    pub render_ms: u64,
+    // 地面の端からはみ出している長さの最大値 (m)
+    pub overhang: Real,
+    // タワーの重心が地面の中心にどれだけ近いか (0〜1、1が中心)
+    pub stability: Real,
+    // 次に落とすオブジェクトの形状の番号 (ステージの形状リストの添字、落下待ちのオブジェクトがない場合はNone)
+    pub pending_shape_index: Option<usize>,
+    pub image_png: Vec<u8>,
+    // 物理演算の様子のアニメーション (アニメーションが無効の場合はNone)
+    pub animation_gif: Option<Vec<u8>>,
+    // 落とす直前のオブジェクトと落下地点までのガイドの画像 (ガイドが無効の場合やオブジェクトを落としていない場合はNone)
+    pub drop_guide_png: Option<Vec<u8>>,
+    // 失敗した場合に、失敗と判定される前後のタワーが崩れる様子のアニメーション (失敗以外はNone)
+    pub collapse_gif: Option<Vec<u8>>,
 }
 
 impl Stage {
-    pub fn new(shapes: Vec<Vec<(f64, f64)>>) -> Self {
+    // 同じseedと同じ操作からは同じ結果が得られる
+    pub fn new(shapes: Vec<ShapeDef>, difficulty: Difficulty, ground: GroundVariant, seed: u64, config: StageConfig) -> Self {
         let mut stage = Stage {
             user_icons: HashMap::new(),
+            user_names: HashMap::new(),
+            height: 0.0,
+            snapshot: None,
+            config,
+            difficulty,
+            ground,
+            ground_blocks: ground.get_blocks(&config),
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+
+            players: Vec::new(),
+            turn_index: 0,
+
+            teams: None,
+
+            turns_remaining: None,
+
+            target_height: None,
+
+            eliminated_players: None,
+
+            wall_collider_handles: Vec::new(),
+
+            wind_enabled: false,
+            wind: 0.0,
+
+            animation_enabled: false,
+            drop_guide_enabled: false,
+
+            unsettled_count: 0,
+
+            turn_count: 0,
+            earthquake: false,
+
+            render_options: RenderOptions::default(),
+
+            last_placed_index: None,
 
             // Rapier 2D
-            world_scale: 0.01,
-            gravity: vector![0.0, 9.81],
+            gravity: vector![0.0, config.gravity],
             integration_parameters: IntegrationParameters::default(),
             physics_pipeline: PhysicsPipeline::new(),
+            query_pipeline: QueryPipeline::new(),
             island_manager: IslandManager::new(),
             broad_phase: BroadPhase::new(),
             narrow_phase: NarrowPhase::new(),
@@ -95,15 +740,22 @@ impl Stage {
 
             // Game Object Handles
             objects: Vec::new(),
-            shapes,
+            fallen_object_indices: Vec::new(),
+            shapes: Vec::new(),
+            candidates: Vec::new(),
         };
 
         // 地面の生成
-        let collider =
-            ColliderBuilder::cuboid(220.0 * stage.world_scale, 10.0 * stage.world_scale)
-                .translation(vector![320.0 * stage.world_scale, 410.0 * stage.world_scale])
-                .build();
-        stage.collider_set.insert(collider);
+        for block in stage.ground_blocks.clone() {
+            let collider =
+                ColliderBuilder::cuboid(block.half_extents.x, block.half_extents.y)
+                    .position(Isometry::new(block.center, block.rotation))
+                    .friction(difficulty.get_friction(config.friction))
+                    .build();
+            stage.collider_set.insert(collider);
+        }
+
+        stage.add_shapes(shapes);
 
         return stage;
     }
@@ -111,151 +763,1835 @@ impl Stage {
     pub fn next_turn(
         &mut self,
         user_id: Option<String>,
-        translation_x: Real, rotation: Real,
-    ) -> Result<(TurnResult, Real, Vec<u8>), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        self.reset_last_object(user_id, translation_x, rotation);
-        let turn_result = self.continue_until_convergence(60.0);
-        let height = self.get_stage_height();
-        if TurnResult::Success == turn_result { self.add_object(); }
-        let data = self.render_frame()?;
-        Ok((turn_result, height, data))
-    }
-
-    fn add_object(&mut self) {
-        let shape = &self.shapes[rand::thread_rng().gen_range(0..self.shapes.len())];
-        let mut vertices = Vec::<Point<Real>>::new();
-        let mut indices = Vec::<[u32; DIM]>::new();
-        for (index, vertex) in shape.iter().enumerate() {
-            vertices.push(Point::new(vertex.0 as Real * self.world_scale, vertex.1 as Real * self.world_scale));
-            if index == shape.len() - 1 {
-                indices.push([index as u32, 0]);
+        input: TurnInput,
+    ) -> Result<TurnReport, StageError> {
+        if let Some(current_player) = self.get_current_player() {
+            if user_id.as_ref() != Some(&current_player) {
+                return Err(StageError::NotYourTurn(user_id));
             }
-            else {
-                indices.push([index as u32, index as u32 + 1]);
+        }
+        // 前のターンでタワーが静止しなかった場合は、オブジェクトを落とさずに続きから物理演算する
+        let resumed = self.unsettled_count > 0;
+        if !resumed { self.save_snapshot(); }
+        let dropped = !resumed && !self.objects.is_empty();
+        // ターンごとに風向きと強さを決める
+        self.wind = if self.wind_enabled && dropped { self.rng.gen_range(-1.0..1.0) } else { 0.0 };
+        if dropped { self.turn_count += 1; }
+        self.earthquake = dropped && self.turn_count.is_multiple_of(EARTHQUAKE_PERIOD);
+        let mut spawn_lift = 0.0;
+        if !resumed {
+            self.select_candidate(input.selection);
+            if input.flip { self.flip_pending_object(); }
+            spawn_lift = self.reset_last_object(user_id, &input);
+        }
+        // 直前に落としたオブジェクト (続きから物理演算する場合も最後のオブジェクト)
+        let placed = dropped || resumed;
+        let golden = placed && self.objects.last().is_some_and(|object| object.golden);
+        let mass = if dropped { self.objects.last().map(|object| self.get_mass(object)) } else { None };
+        let drop_guide_png = if self.drop_guide_enabled && dropped { Some(self.render_drop_guide()?) } else { None };
+        let start_time = std::time::Instant::now();
+        // アニメーションが有効な場合は一定のステップごとにオブジェクトの姿勢を記録する (描画は物理演算の後)
+        let animation_enabled = self.animation_enabled;
+        let mut frames: Vec<Vec<(Vector<Real>, Real)>> = Vec::new();
+        let mut frame_interval = ANIMATION_FRAME_INTERVAL;
+        // 崩れる様子を描画するために直前の姿勢だけを記録する (古いフレームのVecは使い回す)
+        let mut collapse_frames: VecDeque<Vec<(Vector<Real>, Real)>> = VecDeque::with_capacity(COLLAPSE_FRAMES_BEFORE);
+        let (mut turn_result, fallen_object_indices, steps_executed) = self.continue_until_convergence(self.config.timeout_sec, &mut |step, objects| {
+            if step % COLLAPSE_FRAME_INTERVAL == 0 {
+                let mut poses = if collapse_frames.len() >= COLLAPSE_FRAMES_BEFORE { collapse_frames.pop_front().unwrap_or_default() } else { Vec::new() };
+                poses.clear();
+                poses.extend(objects.iter().map(|object| (object.translation, object.rotation)));
+                collapse_frames.push_back(poses);
+            }
+            if !animation_enabled || step % frame_interval != 0 { return; }
+            if frames.len() >= MAX_ANIMATION_FRAMES {
+                frames = frames.drain(..).step_by(2).collect();
+                frame_interval *= 2;
+                if step % frame_interval != 0 { return; }
             }
+            frames.push(objects.iter().map(|object| (object.translation, object.rotation)).collect());
+        });
+        let elapsed_ms = start_time.elapsed().as_millis() as u64;
+        tracing::debug!(steps_executed, elapsed_ms, result = ?turn_result, fallen_objects = fallen_object_indices.len(), "physics finished");
+        self.fallen_object_indices = fallen_object_indices;
+        // 失敗した場合は、判定後にオブジェクトが落ちていく様子も複製したワールドで物理演算して追加する
+        if turn_result == TurnResult::Failure {
+            collapse_frames.extend(self.simulate_aftermath(COLLAPSE_FRAMES_AFTER, COLLAPSE_FRAME_INTERVAL));
+        }
+        let collapse_frames: Vec<Vec<(Vector<Real>, Real)>> = if turn_result == TurnResult::Failure { collapse_frames.into() } else { Vec::new() };
+        // 静止しないターンがMAX_UNSETTLED_COUNT回続いた場合はタイムアウトとしてゲーム終了
+        // (計算を打ち切った場合も静止しなかったものとして数える)
+        let unsettled = turn_result == TurnResult::Unsettled || turn_result == TurnResult::Aborted;
+        self.unsettled_count = if unsettled { self.unsettled_count + 1 } else { 0 };
+        if self.unsettled_count >= MAX_UNSETTLED_COUNT { turn_result = TurnResult::Timeout; }
+        // 手数制限に達した場合はゲーム終了
+        if let (TurnResult::Success, true, Some(turns_remaining)) = (&turn_result, placed, &mut self.turns_remaining) {
+            *turns_remaining = turns_remaining.saturating_sub(1);
+            if *turns_remaining == 0 { turn_result = TurnResult::Finished; }
+        }
+        // 結果に関わらず次の参加者の番にする (続きから物理演算しただけの場合は番を消費しない)
+        if !resumed { self.advance_turn(); }
+        let height_before = self.height;
+        let height = self.get_supported_height();
+        self.height = height;
+        let overhang = self.get_overhang();
+        let stability = self.get_stability();
+        let success = turn_result == TurnResult::Success || turn_result == TurnResult::Finished;
+        self.last_placed_index = if placed && success { self.objects.len().checked_sub(1) } else { None };
+        if TurnResult::Success == turn_result {
+            self.bury_sleeping_objects();
+            self.add_object();
         }
+        let pending_shape_index = self.get_pending_object()
+            .and_then(|object| self.shapes.iter().position(|shape| *shape == object.shape));
+        let render_start_time = std::time::Instant::now();
+        let image_png = self.render_frame()?;
+        let animation_gif = if animation_enabled { Some(self.render_animation(&frames, frame_interval)?) } else { None };
+        let collapse_gif = if collapse_frames.is_empty() { None } else { Some(self.render_collapse(&collapse_frames)?) };
+        let render_ms = render_start_time.elapsed().as_millis() as u64;
+        // 強調するのはこのターンの結果の画像だけ (statusなどで描画し直した場合は通常の枠にする)
+        self.last_placed_index = None;
+        Ok(TurnReport {
+            result: turn_result,
+            height,
+            height_delta: height - height_before,
+            wind: if self.wind_enabled { Some(self.wind) } else { None },
+            fallen_object_indices: self.fallen_object_indices.clone(),
+            golden: golden && (turn_result == TurnResult::Success || turn_result == TurnResult::Finished),
+            resumed,
+            mass,
+            spawn_lift: if spawn_lift > 0.0 { Some(spawn_lift) } else { None },
+            steps_executed,
+            elapsed_ms,
+            render_ms,
+            overhang,
+            stability,
+            pending_shape_index,
+            image_png,
+            collapse_gif,
+            animation_gif,
+            drop_guide_png,
+        })
+    }
 
-        let rigid_body = RigidBodyBuilder::dynamic()
-            .build();
-        let collider = ColliderBuilder::convex_decomposition(&vertices, &indices).friction(1.0).build();
-        let shape_body_handle = self.rigid_body_set.insert(rigid_body);
-        self.collider_set.insert_with_parent(collider, shape_body_handle, &mut self.rigid_body_set);
-        let mut object = Object{
-            user_id: None,
-            shape: shape.clone(),
-            translation: vector![0.0, 0.0],
-            rotation: 0.0,
-            rigid_body_handle: shape_body_handle
-        };
-        let translation = vector![0.0, self.get_stage_top() - object.get_radius() - 50.0];
-        object.translation = translation;
-        self.objects.push(object);
-        self.reset_last_object(None, 0.0, 0.0);
+    pub fn get_difficulty(&self) -> Difficulty {
+        return self.difficulty;
     }
 
-    fn reset_last_object(&mut self, user_id: Option<String>, translation_x: Real, rotation: Real) {
-        if let Some(object) = self.objects.last_mut() {
-            object.user_id = user_id;
-            object.translation.x = ((translation_x + 1.0) * 0.5 * 640.0) as Real;
-            object.rotation = rotation.to_radians() as Real;
-            self.rigid_body_set[object.rigid_body_handle].set_position(Isometry::new(object.translation * self.world_scale, object.rotation), true);
-        }
+    pub fn get_ground(&self) -> GroundVariant {
+        return self.ground;
     }
 
-    fn continue_until_convergence(&mut self, timeout_sec: Real) -> TurnResult {
-        // timeout_sec秒まで物理演算を実行
-        let timeout_frame = (timeout_sec / self.integration_parameters.dt).floor() as u64;
-        for _ in 0..timeout_frame {
-            self.physics_pipeline.step(
-                &self.gravity,
-                &self.integration_parameters,
-                &mut self.island_manager,
-                &mut self.broad_phase,
-                &mut self.narrow_phase,
-                &mut self.rigid_body_set,
-                &mut self.collider_set,
-                &mut self.impulse_joint_set,
-                &mut self.multibody_joint_set,
-                &mut self.ccd_solver,
-                &self.physics_hooks,
-                &self.event_handler,
-            );
+    // 地面の左端と右端のx座標
+    fn get_ground_span(&self) -> (Real, Real) {
+        let corners: Vec<Point<Real>> = self.ground_blocks.iter().flat_map(|block| block.get_corners()).collect();
+        let left = corners.iter().fold(Real::MAX, |left, corner| left.min(corner.x));
+        let right = corners.iter().fold(Real::MIN, |right, corner| right.max(corner.x));
+        return (left, right);
+    }
 
-            for object in &mut self.objects {
-                let body = &self.rigid_body_set[object.rigid_body_handle];
-                let rotation = body.rotation();
-                object.translation = body.translation() / self.world_scale;
-                object.rotation = rotation.im.atan2(rotation.re);
-            }
+    // 高さを測る基準のy座標
+    // 平らな地面のfail_yを基準に、地面の最も高い点が平らな地面より高い分だけ基準を上げる
+    fn get_height_baseline(&self) -> Real {
+        let ground_highest = self.ground_blocks.iter()
+            .flat_map(|block| block.get_corners())
+            .fold(Real::MAX, |highest, corner| highest.min(corner.y));
+        return self.config.fail_y - (self.config.get_ground_top() - ground_highest).max(0.0);
+    }
 
-            // オブジェクトが地面から1つでも落下した場合は失敗判定
-            for object in &self.objects {
-                let obj_top = object.get_top() * self.world_scale;
-                if obj_top > 420.0 * self.world_scale { return TurnResult::Failure; }
-            }
+    // オブジェクトの質量 (kg)
+    // コライダーの密度と同じ値を使うので物理演算上の質量と一致する
+    pub fn get_mass(&self, object: &Object) -> Real {
+        return object.get_area() * self.get_density(&object.shape);
+    }
 
-            // オブジェクトが全て静止した場合は成功判定
-            // メモ: 要素数0のall()はtrueを返す
-            let all_sleep = self.objects.iter().all(|object| self.rigid_body_set[object.rigid_body_handle].is_sleeping());
-            if all_sleep { return TurnResult::Success; }
-        }
+    fn get_density(&self, shape: &ShapeDef) -> Real {
+        return shape.density.map_or(PIECE_DENSITY, |density| density as Real) * self.config.density_multiplier;
+    }
 
-        // オブジェクトが全て静止しなかった場合はタイムアウト判定
-        return TurnResult::Timeout;
+    pub fn get_seed(&self) -> u64 {
+        return self.seed;
     }
 
-    fn render_frame(&self) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut canvas = canvas::Canvas::new(640.0, 480.0);
+    pub fn get_config(&self) -> StageConfig {
+        return self.config;
+    }
 
-        let top: f64 = 0.0f64.min(self.get_stage_top() as f64 - 10.0);
+    // 直近のターン終了時点でのタワーの高さ
+    pub fn get_height(&self) -> Real {
+        return self.height;
+    }
 
-        for (user_id, user_icon) in self.user_icons.iter() {
-            canvas.add_image(user_id.clone(), &user_icon);
-        }
+    // 積み上げられたオブジェクトの数 (落下待ちのオブジェクトは除く)
+    pub fn get_object_count(&self) -> usize {
+        return self.objects.len().saturating_sub(1);
+    }
 
-        canvas.set_no_stroke();
-        canvas.set_color_fill(3, 182, 252);
-        canvas.add_shape(&vec![
-            (  0.0,   0.0),
-            (640.0,   0.0),
-            (640.0, 480.0),
-            (  0.0, 480.0),
-        ], (0.0, 0.0), 0.0);
-        canvas.set_color_fill(20, 222, 106);
-        canvas.add_shape(&vec![
-            (100.0, 400.0 - top),
-            (540.0, 400.0 - top),
-            (540.0, 420.0 - top),
-            (100.0, 420.0 - top),
-        ], (0.0, 0.0), 0.0);
+    // ターン順に参加 (既に参加している場合はfalseを返す)
+    pub fn join(&mut self, user_id: String) -> bool {
+        if self.players.contains(&user_id) { return false; }
+        self.players.push(user_id);
+        return true;
+    }
 
-        for object in &self.objects {
-            canvas.set_color_fill(255, 255, 255);
-            canvas.set_color_stroke(245, 66, 129, 4.0);
-            if let Some(user_id) = &object.user_id {
-                if self.user_icons.contains_key(user_id) {
-                    canvas.set_image_fill(user_id.clone());
-                    canvas.set_color_stroke(0, 88, 122, 2.0);
-                }
+    // ターン順から抜ける (参加していない場合はfalseを返す)
+    pub fn leave(&mut self, user_id: &str) -> bool {
+        let index = match self.players.iter().position(|player| player == user_id) {
+            Some(index) => index,
+            None => { return false; }
+        };
+        self.players.remove(index);
+        // 現在の番の人より前の人が抜けた場合は番号を詰める
+        if index < self.turn_index { self.turn_index -= 1; }
+        if self.turn_index >= self.players.len() { self.turn_index = 0; }
+        return true;
+    }
+
+    pub fn get_players(&self) -> &Vec<String> {
+        return &self.players;
+    }
+
+    // 現在の番の参加者 (参加者がいない場合はNone)
+    pub fn get_current_player(&self) -> Option<String> {
+        return self.players.get(self.turn_index).cloned();
+    }
+
+    fn advance_turn(&mut self) {
+        if self.players.is_empty() { return; }
+        self.turn_index = (self.turn_index + 1) % self.players.len();
+    }
+
+    // 風の有効化/無効化 (次のターンから反映)
+    pub fn set_wind_enabled(&mut self, enabled: bool) {
+        self.wind_enabled = enabled;
+    }
+
+    // 結果の画像をアニメーションGIFにするか (次のターンから反映)
+    pub fn set_animation_enabled(&mut self, enabled: bool) {
+        self.animation_enabled = enabled;
+    }
+
+    // 画像の配色と描画の方法 (次に描画する画像から反映)
+    pub fn set_render_options(&mut self, render_options: RenderOptions) {
+        self.render_options = render_options;
+    }
+
+    // 物理演算の前に落とす位置のガイドの画像を作るか (次のターンから反映)
+    pub fn set_drop_guide_enabled(&mut self, enabled: bool) {
+        self.drop_guide_enabled = enabled;
+    }
+
+    // 地面の両端の壁の追加/削除 (すぐに反映)
+    pub fn set_walls_enabled(&mut self, enabled: bool) {
+        if enabled == self.is_walls_enabled() { return; }
+        if enabled {
+            // 地面が傾いていても隙間ができないように、壁は地面の下端から立てる
+            let (ground_left, ground_right) = self.get_ground_span();
+            for wall_x in [ground_left + WALL_HALF_THICKNESS, ground_right - WALL_HALF_THICKNESS] {
+                let wall_y = self.config.fail_y - WALL_HEIGHT * 0.5;
+                let collider = ColliderBuilder::cuboid(WALL_HALF_THICKNESS, WALL_HEIGHT * 0.5)
+                    .translation(vector![wall_x, wall_y])
+                    .friction(self.difficulty.get_friction(self.config.friction))
+                    .build();
+                self.wall_collider_handles.push(self.collider_set.insert(collider));
             }
-            canvas.add_shape(&object.shape, (object.translation.x as f64, object.translation.y as f64 - top), object.rotation.to_degrees() as f64);
         }
+        else {
+            for handle in std::mem::take(&mut self.wall_collider_handles) {
+                self.collider_set.remove(handle, &mut self.island_manager, &mut self.rigid_body_set, true);
+            }
+        }
+    }
 
-        let data = canvas.encode_png()?;
+    pub fn is_walls_enabled(&self) -> bool {
+        return !self.wall_collider_handles.is_empty();
+    }
 
-        Ok(data)
+    // 直前のターンで地震が発生したか
+    pub fn is_earthquake(&self) -> bool {
+        return self.earthquake;
     }
 
-    fn get_stage_top(&self) -> Real {
-        let mut top = 420.0;
-        for object in &self.objects {
-            let obj_top = object.get_top();
-            if top > obj_top { top = obj_top; }
-        }
-        return top;
+    // 手数制限を設定
+    pub fn set_turn_limit(&mut self, turns: u32) {
+        self.turns_remaining = Some(turns);
+    }
+
+    pub fn get_turns_remaining(&self) -> Option<u32> {
+        return self.turns_remaining;
+    }
+
+    // ユーザーごとの積み上げたオブジェクトの数 (多い順)
+    pub fn get_user_piece_counts(&self) -> Vec<(String, usize)> {
+        let mut counts = Vec::<(String, usize)>::new();
+        for user_id in self.objects.iter().filter_map(|object| object.user_id.as_ref()) {
+            match counts.iter_mut().find(|(counted_user_id, _)| counted_user_id == user_id) {
+                Some((_, count)) => { *count += 1; },
+                None => { counts.push((user_id.clone(), 1)); },
+            }
+        }
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        return counts;
+    }
+
+    // 目標の高さを設定 (m)
+    pub fn set_target_height(&mut self, target_height: Real) {
+        self.target_height = Some(target_height);
+    }
+
+    pub fn get_target_height(&self) -> Option<Real> {
+        return self.target_height;
+    }
+
+    // 脱落モードを有効化
+    pub fn enable_elimination(&mut self) {
+        if self.eliminated_players.is_none() { self.eliminated_players = Some(Vec::new()); }
+    }
+
+    pub fn is_elimination_mode(&self) -> bool {
+        return self.eliminated_players.is_some();
+    }
+
+    pub fn is_eliminated(&self, user_id: &str) -> bool {
+        return self.eliminated_players.as_ref().is_some_and(|eliminated_players| eliminated_players.iter().any(|player| player == user_id));
+    }
+
+    pub fn get_eliminated_players(&self) -> Vec<String> {
+        return self.eliminated_players.clone().unwrap_or_default();
+    }
+
+    // 直前のターンで落下したオブジェクトを取り除き、その持ち主を脱落させる (脱落モードのみ)
+    // 脱落したuser_idを返す
+    pub fn eliminate_fallen_objects(&mut self) -> Vec<String> {
+        if self.eliminated_players.is_none() { return Vec::new(); }
+        let mut fallen_object_indices = std::mem::take(&mut self.fallen_object_indices);
+        fallen_object_indices.sort_unstable_by(|a, b| b.cmp(a));
+        let mut eliminated_user_ids = Vec::<String>::new();
+        for index in fallen_object_indices {
+            if let Some(user_id) = self.remove_object(index).user_id {
+                if !eliminated_user_ids.contains(&user_id) { eliminated_user_ids.push(user_id); }
+            }
+        }
+        for user_id in &eliminated_user_ids {
+            self.leave(user_id);
+        }
+        if let Some(eliminated_players) = self.eliminated_players.as_mut() {
+            eliminated_players.extend(eliminated_user_ids.iter().cloned());
+        }
+        // 取り除いたオブジェクトは元に戻せないのでundoできなくする
+        self.snapshot = None;
+        self.height = self.get_supported_height();
+        if self.get_pending_object().is_none() { self.add_object(); }
+        return eliminated_user_ids;
+    }
+
+    // チーム戦を有効化
+    pub fn enable_teams(&mut self) {
+        if self.teams.is_none() { self.teams = Some(HashMap::new()); }
+    }
+
+    pub fn is_team_mode(&self) -> bool {
+        return self.teams.is_some();
+    }
+
+    pub fn get_team(&self, user_id: &str) -> Option<Team> {
+        return self.teams.as_ref().and_then(|teams| teams.get(user_id).copied());
+    }
+
+    // チームに所属させる (teamがNoneの場合は人数の少ないチームに割り当てる)
+    // チーム戦でない場合や既に所属している場合は何もせず現在のチームを返す
+    pub fn assign_team(&mut self, user_id: &str, team: Option<Team>) -> Option<Team> {
+        let teams = self.teams.as_mut()?;
+        if let Some(current_team) = teams.get(user_id) { return Some(*current_team); }
+        let team = team.unwrap_or_else(|| {
+            let red_count = teams.values().filter(|team| **team == Team::Red).count();
+            let blue_count = teams.len() - red_count;
+            if red_count <= blue_count { Team::Red } else { Team::Blue }
+        });
+        teams.insert(user_id.to_string(), team);
+        return Some(team);
+    }
+
+    // チームごとの積み上げたオブジェクトの数 (落下待ちのオブジェクトはチームが未設定)
+    pub fn get_team_piece_counts(&self) -> (usize, usize) {
+        let red_count = self.objects.iter().filter(|object| object.team == Some(Team::Red)).count();
+        let blue_count = self.objects.iter().filter(|object| object.team == Some(Team::Blue)).count();
+        return (red_count, blue_count);
+    }
+
+    pub fn get_objects(&self) -> &Vec<Object> {
+        return &self.objects;
+    }
+
+    // 出現するオブジェクトの形状を追加 (次の候補から反映、使えない形状は追加しない)
+    // 形状はpx単位で受け取り、ここでm単位に変換する (使えない形状は変換前に取り除く)
+    pub fn add_shapes(&mut self, shapes: Vec<ShapeDef>) {
+        let world_scale = self.config.world_scale as f64;
+        self.shapes.extend(shapes.into_iter()
+            .map(|shape| shape.normalized())
+            .filter(|shape| shape.validate().is_ok())
+            .map(|shape| shape.scaled(world_scale)));
+    }
+
+    // 次に落とすオブジェクトの候補の数
+    pub fn get_candidate_count(&self) -> usize {
+        return self.candidates.len();
+    }
+
+    // 落下待ちのオブジェクト (ゲームオーバー直後などは存在しない)
+    fn get_pending_object(&self) -> Option<&Object> {
+        return self.objects.last().filter(|object| object.user_id.is_none());
+    }
+
+    // 最後にオブジェクトを落としたユーザー (最後のオブジェクトは落下待ちのため1つ前を参照)
+    pub fn get_last_user_id(&self) -> Option<String> {
+        if self.objects.len() < 2 { return None; }
+        return self.objects[self.objects.len() - 2].user_id.clone();
+    }
+
+    // 現在の状態を保存 (保持するのは直前の1ターン分のみ)
+    pub fn save_snapshot(&mut self) {
+        self.snapshot = Some(Snapshot {
+            objects: self.objects.clone(),
+            height: self.height,
+            turn_index: self.turn_index,
+            turns_remaining: self.turns_remaining,
+            turn_count: self.turn_count,
+            candidates: self.candidates.clone(),
+        });
+    }
+
+    // 保存した状態に戻す (保存された状態がない場合はfalseを返す)
+    pub fn restore_snapshot(&mut self) -> bool {
+        let snapshot = match self.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None => { return false; }
+        };
+
+        // 現在のオブジェクトを全て削除
+        for object in std::mem::take(&mut self.objects) {
+            self.remove_rigid_body(object.rigid_body_handle);
+        }
+
+        // 保存した姿勢でオブジェクトを作り直す
+        let mut objects = snapshot.objects;
+        for object in &mut objects {
+            object.rigid_body_handle = self.create_rigid_body(&object.shape, Isometry::new(object.translation, object.rotation));
+            let body = &mut self.rigid_body_set[object.rigid_body_handle];
+            if object.buried { body.set_body_type(RigidBodyType::Fixed); }
+        }
+        self.objects = objects;
+        self.height = snapshot.height;
+        self.turns_remaining = snapshot.turns_remaining;
+        self.turn_count = snapshot.turn_count;
+        self.candidates = snapshot.candidates;
+        self.fallen_object_indices.clear();
+        self.last_placed_index = None;
+        self.earthquake = false;
+        self.unsettled_count = 0;
+        // 保存後に参加者が抜けている場合があるので範囲内に収める
+        self.turn_index = if self.players.is_empty() { 0 } else { snapshot.turn_index % self.players.len() };
+        return true;
+    }
+
+    // ステージ全体を保存用の状態にする
+    // 保存した状態から復元したステージと元のステージは、同じ操作から同じ結果になる
+    // rngの内部状態もそのまま保存するので、保存してもステージの乱数には影響しない
+    pub fn to_snapshot(&self) -> StageSnapshot {
+        return StageSnapshot {
+            version: STAGE_SNAPSHOT_VERSION,
+            height: self.height,
+            snapshot: self.snapshot.clone(),
+            config: self.config,
+            difficulty: self.difficulty,
+            ground: self.ground,
+            seed: self.seed,
+            rng: Some(self.rng.clone()),
+            rng_seed: None,
+
+            players: self.players.clone(),
+            turn_index: self.turn_index,
+            teams: self.teams.clone(),
+            turns_remaining: self.turns_remaining,
+            target_height: self.target_height,
+            eliminated_players: self.eliminated_players.clone(),
+            wall_collider_handles: self.wall_collider_handles.clone(),
+            wind_enabled: self.wind_enabled,
+            wind: self.wind,
+            animation_enabled: self.animation_enabled,
+            drop_guide_enabled: self.drop_guide_enabled,
+            unsettled_count: self.unsettled_count,
+            turn_count: self.turn_count,
+            earthquake: self.earthquake,
+
+            physics: PhysicsSnapshot {
+                integration_parameters: self.integration_parameters,
+                island_manager: self.island_manager.clone(),
+                broad_phase: self.broad_phase.clone(),
+                narrow_phase: self.narrow_phase.clone(),
+                rigid_body_set: self.rigid_body_set.clone(),
+                collider_set: self.collider_set.clone(),
+                impulse_joint_set: self.impulse_joint_set.clone(),
+                multibody_joint_set: self.multibody_joint_set.clone(),
+            },
+
+            objects: self.objects.clone(),
+            fallen_object_indices: self.fallen_object_indices.clone(),
+            shapes: self.shapes.clone(),
+            candidates: self.candidates.clone(),
+        };
+    }
+
+    // 保存した状態からステージを作り直す (形式のバージョンが違う場合はエラー)
+    pub fn from_snapshot(snapshot: StageSnapshot) -> Result<Self, String> {
+        if snapshot.version == 0 || snapshot.version > STAGE_SNAPSHOT_VERSION {
+            return Err(format!("unsupported stage snapshot version: {}", snapshot.version));
+        }
+        let mut stage = Stage {
+            user_icons: HashMap::new(),
+            user_names: HashMap::new(),
+            height: snapshot.height,
+            snapshot: snapshot.snapshot,
+            config: snapshot.config,
+            difficulty: snapshot.difficulty,
+            ground: snapshot.ground,
+            ground_blocks: snapshot.ground.get_blocks(&snapshot.config),
+            seed: snapshot.seed,
+            rng: match (snapshot.rng, snapshot.rng_seed) {
+                (Some(rng), _) => rng,
+                (None, rng_seed) => ChaCha12Rng::seed_from_u64(rng_seed.unwrap_or(snapshot.seed)),
+            },
+
+            players: snapshot.players,
+            turn_index: snapshot.turn_index,
+
+            teams: snapshot.teams,
+
+            turns_remaining: snapshot.turns_remaining,
+
+            target_height: snapshot.target_height,
+
+            eliminated_players: snapshot.eliminated_players,
+
+            wall_collider_handles: snapshot.wall_collider_handles,
+
+            wind_enabled: snapshot.wind_enabled,
+            wind: snapshot.wind,
+
+            animation_enabled: snapshot.animation_enabled,
+            drop_guide_enabled: snapshot.drop_guide_enabled,
+
+            unsettled_count: snapshot.unsettled_count,
+
+            turn_count: snapshot.turn_count,
+            earthquake: snapshot.earthquake,
+
+            render_options: RenderOptions::default(),
+
+            last_placed_index: None,
+
+            // Rapier 2D
+            gravity: vector![0.0, snapshot.config.gravity],
+            integration_parameters: snapshot.physics.integration_parameters,
+            physics_pipeline: PhysicsPipeline::new(),
+            query_pipeline: QueryPipeline::new(),
+            island_manager: snapshot.physics.island_manager,
+            broad_phase: snapshot.physics.broad_phase,
+            narrow_phase: snapshot.physics.narrow_phase,
+            rigid_body_set: snapshot.physics.rigid_body_set,
+            collider_set: snapshot.physics.collider_set,
+            impulse_joint_set: snapshot.physics.impulse_joint_set,
+            multibody_joint_set: snapshot.physics.multibody_joint_set,
+            ccd_solver: CCDSolver::new(),
+            physics_hooks: (),
+            event_handler: (),
+
+            // Game Object Handles
+            objects: snapshot.objects,
+            fallen_object_indices: snapshot.fallen_object_indices,
+            shapes: snapshot.shapes,
+            candidates: snapshot.candidates,
+        };
+        // クエリ用の構造は保存しないので作り直す
+        stage.query_pipeline.update(&stage.island_manager, &stage.rigid_body_set, &stage.collider_set);
+        return Ok(stage);
+    }
+
+    fn add_object(&mut self) {
+        // 候補をランダムに選び、1番目の候補を落下待ちのオブジェクトにする
+        if self.shapes.is_empty() { return; }
+        let shape_count = self.shapes.len();
+        let rng = &mut self.rng;
+        self.candidates = (0..CANDIDATE_COUNT)
+            .map(|_| self.shapes[rng.gen_range(0..shape_count)].clone())
+            .collect();
+        // 一定の確率でゴールデンピースにする (候補を選び直しても変わらない)
+        let golden = self.rng.gen_bool(GOLDEN_PROBABILITY);
+        self.push_pending_object(self.candidates[0].clone(), golden);
+    }
+
+    // 落下待ちのオブジェクトを候補の中から選び直す (indexは0始まり)
+    fn select_candidate(&mut self, index: usize) {
+        if index == 0 || index >= self.candidates.len() || self.get_pending_object().is_none() { return; }
+        let mut golden = false;
+        if let Some(object) = self.objects.pop() {
+            golden = object.golden;
+            self.remove_rigid_body(object.rigid_body_handle);
+        }
+        self.push_pending_object(self.candidates[index].clone(), golden);
+    }
+
+    // 落下待ちのオブジェクトを左右反転する (コライダーも作り直す)
+    fn flip_pending_object(&mut self) {
+        if self.get_pending_object().is_none() { return; }
+        if let Some(object) = self.objects.pop() {
+            self.remove_rigid_body(object.rigid_body_handle);
+            self.push_pending_object(object.shape.mirrored(), object.golden);
+        }
+    }
+
+    fn push_pending_object(&mut self, shape: ShapeDef, golden: bool) {
+        let shape_body_handle = self.create_rigid_body(&shape, Isometry::identity());
+        let object = Object{
+            user_id: None,
+            team: None,
+            golden,
+            shape,
+            translation: vector![0.0, 0.0],
+            rotation: 0.0,
+            rigid_body_handle: shape_body_handle,
+            sleep_streak: 0,
+            buried: false,
+        };
+        self.objects.push(object);
+        self.reset_last_object(None, &TurnInput::default());
+    }
+
+    // 形状に材質の指定がない場合はステージの既定値を使う
+    // コライダーの位置も揃うように、剛体は最初からpositionに置く
+    fn create_rigid_body(&mut self, shape: &ShapeDef, position: Isometry<Real>) -> RigidBodyHandle {
+        let mut vertices = Vec::<Point<Real>>::new();
+        let mut indices = Vec::<[u32; DIM]>::new();
+        for (index, vertex) in shape.vertices.iter().enumerate() {
+            vertices.push(Point::new(vertex.0 as Real, vertex.1 as Real));
+            if index == shape.vertices.len() - 1 {
+                indices.push([index as u32, 0]);
+            }
+            else {
+                indices.push([index as u32, index as u32 + 1]);
+            }
+        }
+
+        // 高い位置から落とした細いオブジェクトが地面をすり抜けないようにCCDを有効にする
+        let rigid_body = RigidBodyBuilder::dynamic()
+            .position(position)
+            .ccd_enabled(true)
+            .build();
+        // 辺が交差している形状は凸分解が失敗するので凸包で代用する
+        let collider = if shape.is_self_intersecting() {
+            ColliderBuilder::convex_hull(&vertices).unwrap_or_else(|| ColliderBuilder::ball(0.01))
+        } else {
+            ColliderBuilder::convex_decomposition(&vertices, &indices)
+        };
+        let collider = collider
+            .friction(shape.friction.map_or(self.difficulty.get_friction(self.config.friction), |friction| friction as Real))
+            .restitution(shape.restitution.map_or(self.difficulty.get_restitution(), |restitution| restitution as Real))
+            .density(self.get_density(shape))
+            .build();
+        let shape_body_handle = self.rigid_body_set.insert(rigid_body);
+        self.collider_set.insert_with_parent(collider, shape_body_handle, &mut self.rigid_body_set);
+        return shape_body_handle;
+    }
+
+    // オブジェクトをワールドから取り除く
+    fn remove_object(&mut self, index: usize) -> Object {
+        let object = self.objects.remove(index);
+        self.remove_rigid_body(object.rigid_body_handle);
+        // 後ろのオブジェクトのインデックスがずれる
+        self.last_placed_index = match self.last_placed_index {
+            Some(last_placed_index) if last_placed_index > index => Some(last_placed_index - 1),
+            Some(last_placed_index) if last_placed_index == index => None,
+            last_placed_index => last_placed_index,
+        };
+        return object;
+    }
+
+    // 静止が続いていて、タワーの頂点からBURY_DEPTH m以上下にあるオブジェクトを固定する
+    // 固定したオブジェクトは描画されるが、物理演算では地面と同じ扱いになる
+    fn bury_sleeping_objects(&mut self) {
+        let top = self.get_stage_top();
+        for object in &mut self.objects {
+            if object.buried { continue; }
+            let body = &mut self.rigid_body_set[object.rigid_body_handle];
+            object.sleep_streak = if body.is_sleeping() { object.sleep_streak + 1 } else { 0 };
+            if object.sleep_streak >= BURY_SLEEP_TURNS && object.get_top() - top > BURY_DEPTH {
+                body.set_linvel(vector![0.0, 0.0], false);
+                body.set_angvel(0.0, false);
+                body.set_body_type(RigidBodyType::Fixed);
+                object.buried = true;
+            }
+        }
+    }
+
+    // 物理演算の対象になっているオブジェクトの数 (埋もれて固定されたものを除く)
+    pub fn get_active_object_count(&self) -> usize {
+        return self.objects.iter().filter(|object| !object.buried).count();
+    }
+
+    fn remove_rigid_body(&mut self, handle: RigidBodyHandle) {
+        self.rigid_body_set.remove(
+            handle,
+            &mut self.island_manager,
+            &mut self.collider_set,
+            &mut self.impulse_joint_set,
+            &mut self.multibody_joint_set,
+            true,
+        );
+    }
+
+    // 既存のオブジェクトと重なる位置に置いた場合は持ち上げて、持ち上げた距離を返す
+    fn reset_last_object(&mut self, user_id: Option<String>, input: &TurnInput) -> Real {
+        let team = user_id.as_ref().and_then(|user_id| self.get_team(user_id));
+        // 最後のオブジェクトを除いたタワーの頂点
+        let top = match self.objects.split_last() {
+            Some((_, placed_objects)) => placed_objects.iter().fold(self.get_height_baseline(), |top, object| top.min(object.get_top())),
+            None => self.get_height_baseline(),
+        };
+        let drop_offset = match input.drop_height {
+            Some(drop_height) => drop_height * MAX_DROP_OFFSET,
+            None => self.config.spawn_margin,
+        };
+        let handle = match self.objects.last() {
+            Some(object) => object.rigid_body_handle,
+            None => { return 0.0; }
+        };
+        // 左右の位置は画像の左端 (-1) から右端 (1) まで
+        let translation_x = GROUND_CENTER_X + input.translation_x * self.get_camera().get_half_width();
+        let rotation = input.rotation.to_radians() as Real;
+        let radius = self.objects[self.objects.len() - 1].get_radius();
+        let mut translation_y = top - radius - drop_offset;
+
+        // 既存のオブジェクトや地面と重なる場合は、重ならなくなるまで少しずつ持ち上げる (最大MAX_SPAWN_LIFT m)
+        // 重なったまま物理演算を始めると、押し出す力でタワーが吹き飛ぶことがある
+        self.query_pipeline.update(&self.island_manager, &self.rigid_body_set, &self.collider_set);
+        let mut lift = 0.0;
+        while lift < MAX_SPAWN_LIFT && self.is_overlapping(handle, vector![translation_x, translation_y], rotation) {
+            translation_y -= SPAWN_LIFT_STEP;
+            lift += SPAWN_LIFT_STEP;
+        }
+
+        let object = self.objects.last_mut().unwrap();
+        object.user_id = user_id;
+        object.team = team;
+        object.translation.x = translation_x;
+        object.translation.y = translation_y;
+        object.rotation = rotation;
+        let body = &mut self.rigid_body_set[handle];
+        body.set_position(Isometry::new(object.translation, object.rotation), true);
+        // 落下待ちの間に残った速度や力を持ち越さないようにする (同じ操作からは同じ結果になる)
+        body.set_linvel(vector![0.0, 0.0], true);
+        body.set_angvel(input.spin.clamp(-MAX_SPIN, MAX_SPIN), true);
+        body.reset_forces(true);
+        body.reset_torques(true);
+        body.wake_up(true);
+        return lift;
+    }
+
+    // 剛体をtranslation、rotationの姿勢に置いた時に、他のコライダーと重なるか
+    // (query_pipelineは事前に更新しておくこと)
+    fn is_overlapping(&self, handle: RigidBodyHandle, translation: Vector<Real>, rotation: Real) -> bool {
+        let position = Isometry::new(translation, rotation);
+        let collider_set = &self.collider_set;
+        let is_other = |collider_handle: ColliderHandle| collider_set[collider_handle].parent() != Some(handle);
+        return self.rigid_body_set[handle].colliders().iter().any(|collider_handle| {
+            self.query_pipeline.intersection_with_shape(
+                collider_set,
+                &position,
+                collider_set[*collider_handle].shape(),
+                InteractionGroups::all(),
+                Some(&is_other),
+            ).is_some()
+        });
+    }
+
+    // 失敗判定の場合は地面から落下したオブジェクトのインデックスも返す
+    // 最後の値は実行したステップ数
+    // on_frameは各ステップの後に呼ばれる (引数は0始まりのステップ数と全てのオブジェクト)
+    fn continue_until_convergence(&mut self, timeout_sec: Real, on_frame: &mut dyn FnMut(u64, &[Object])) -> (TurnResult, Vec<usize>, u64) {
+        // timeout_sec秒まで物理演算を実行 (実時間でwall_clock_budget_sec秒を超えた場合はその時点で打ち切る)
+        let timeout_frame = (timeout_sec / self.integration_parameters.dt).floor() as u64;
+        let wall_clock_budget = std::time::Duration::from_secs_f64(self.config.wall_clock_budget_sec.max(0.0) as f64);
+        let start_time = std::time::Instant::now();
+        let wind_frame = (WIND_DURATION / self.integration_parameters.dt).floor() as u64;
+        let earthquake_frame = (EARTHQUAKE_DURATION / self.integration_parameters.dt).floor() as u64;
+        let mut rest_frame_count = 0;
+        for frame in 0..timeout_frame {
+            if start_time.elapsed() >= wall_clock_budget { return (TurnResult::Aborted, Vec::new(), frame); }
+
+            // 地震の場合は最初のEARTHQUAKE_DURATION秒間だけ全てのオブジェクトを揺らす
+            if self.earthquake && frame < earthquake_frame {
+                for object in self.objects.iter().filter(|object| !object.buried) {
+                    let body = &mut self.rigid_body_set[object.rigid_body_handle];
+                    let velocity = vector![
+                        self.rng.gen_range(-EARTHQUAKE_STRENGTH..EARTHQUAKE_STRENGTH),
+                        self.rng.gen_range(-EARTHQUAKE_STRENGTH..EARTHQUAKE_STRENGTH) * 0.5
+                    ];
+                    let impulse = velocity * body.mass();
+                    body.apply_impulse(impulse, true);
+                }
+            }
+
+            // 風は最初のWIND_DURATION秒間だけ吹かせる (吹き続けるとオブジェクトが静止しない)
+            // 同じ力でも軽いオブジェクトほど、また高い位置ほど大きく動く
+            if self.wind != 0.0 && frame < wind_frame {
+                let ground_y = self.config.get_ground_top();
+                for object in self.objects.iter().filter(|object| !object.buried) {
+                    let body = &mut self.rigid_body_set[object.rigid_body_handle];
+                    let altitude = (ground_y - body.translation().y).max(0.0);
+                    let force = self.wind * WIND_FORCE * (1.0 + altitude);
+                    body.apply_impulse(vector![force * self.integration_parameters.dt, 0.0], true);
+                }
+            }
+
+            self.physics_pipeline.step(
+                &self.gravity,
+                &self.integration_parameters,
+                &mut self.island_manager,
+                &mut self.broad_phase,
+                &mut self.narrow_phase,
+                &mut self.rigid_body_set,
+                &mut self.collider_set,
+                &mut self.impulse_joint_set,
+                &mut self.multibody_joint_set,
+                &mut self.ccd_solver,
+                &self.physics_hooks,
+                &self.event_handler,
+            );
+
+            for object in &mut self.objects {
+                let body = &self.rigid_body_set[object.rigid_body_handle];
+                let rotation = body.rotation();
+                object.translation = *body.translation();
+                object.rotation = rotation.im.atan2(rotation.re);
+            }
+            on_frame(frame, &self.objects);
+
+            // 位置が数値でなくなった場合は物理演算が破綻しているので続けられない
+            let diverged = self.objects.iter().any(|object| !object.translation.x.is_finite() || !object.translation.y.is_finite() || !object.rotation.is_finite());
+            if diverged { return (TurnResult::Timeout, Vec::new(), frame + 1); }
+
+            // オブジェクトが地面から1つでも落下した場合は失敗判定
+            // 地面の横にはみ出して下向きに動いているオブジェクトも、地面の下に落ちるのを待たずに落下とみなす
+            let (ground_left, ground_right) = self.get_ground_span();
+            let fallen_object_indices: Vec<usize> = self.objects.iter().enumerate()
+                .filter(|(_, object)| {
+                    let outside = object.translation.x < ground_left - object.get_radius() || object.translation.x > ground_right + object.get_radius();
+                    let falling = self.rigid_body_set[object.rigid_body_handle].linvel().y > 0.0;
+                    object.get_top() > self.config.fail_y || (outside && falling)
+                })
+                .map(|(index, _)| index)
+                .collect();
+            if !fallen_object_indices.is_empty() { return (TurnResult::Failure, fallen_object_indices, frame + 1); }
+
+            // オブジェクトが全て静止した場合は成功判定
+            // メモ: 要素数0のall()はtrueを返す
+            // 固定されたオブジェクトは静止しているものとして扱う
+            let all_sleep = self.objects.iter().all(|object| object.buried || self.rigid_body_set[object.rigid_body_handle].is_sleeping());
+            if all_sleep { return (TurnResult::Success, Vec::new(), frame + 1); }
+
+            // 運動エネルギーが十分小さい状態が続いた場合もほぼ静止しているとみなして成功判定
+            let kinetic_energy: Real = self.objects.iter()
+                .filter(|object| !object.buried)
+                .map(|object| {
+                    let body = &self.rigid_body_set[object.rigid_body_handle];
+                    let inertia = body.mass_properties().principal_inertia();
+                    0.5 * (body.linvel().norm_squared() * body.mass() + body.angvel() * body.angvel() * inertia)
+                })
+                .sum();
+            rest_frame_count = if kinetic_energy < self.config.rest_energy { rest_frame_count + 1 } else { 0 };
+            if rest_frame_count >= self.config.rest_frames { return (TurnResult::Success, Vec::new(), frame + 1); }
+        }
+
+        // オブジェクトが全て静止しなかった場合は静止待ち (現在の状態のまま次のターンに続ける)
+        return (TurnResult::Unsettled, Vec::new(), timeout_frame);
+    }
+
+    // 描画用のカメラ
+    // 地面と全てのオブジェクトが上端と左右にCAMERA_MARGIN px以上の余白を空けて収まるように縮小する (world_scaleより拡大はしない)
+    // 画像の下端の高さは固定なので、タワーがどれだけ高くなっても地面は画像に映る
+    // メモ: 座標をpxに変換してから描画するので、縮小しても線の太さは変わらない
+    fn get_camera(&self) -> CameraTransform {
+        let bottom = VIEW_HEIGHT as Real * self.config.world_scale;
+        let mut top = self.get_height_baseline();
+        let mut half_width: Real = 0.0;
+        let object_vertices = self.objects.iter().flat_map(|object| object.get_world_vertices());
+        let ground_vertices = self.ground_blocks.iter().flat_map(|block| block.get_corners());
+        // 画像の下端より下に落ちたオブジェクトは収めなくてよい
+        for vertex in object_vertices.chain(ground_vertices).filter(|vertex| vertex.y < bottom) {
+            top = top.min(vertex.y);
+            half_width = half_width.max((vertex.x - GROUND_CENTER_X).abs());
+        }
+        let meters_per_pixel = self.config.world_scale
+            .max(half_width / (VIEW_WIDTH as Real * 0.5 - CAMERA_MARGIN))
+            .max((bottom - top) / (VIEW_HEIGHT as Real - CAMERA_MARGIN));
+        return CameraTransform::new(meters_per_pixel, GROUND_CENTER_X, bottom - VIEW_HEIGHT as Real * meters_per_pixel);
+    }
+
+    pub fn render_frame(&self) -> canvas::CanvasResult<Vec<u8>> {
+        let data = self.draw(None).encode_png()?;
+
+        Ok(data)
+    }
+
+    // 落とす直前のオブジェクトから、真下のタワーか地面までを破線でつないだ画像
+    fn render_drop_guide(&self) -> canvas::CanvasResult<Vec<u8>> {
+        let mut canvas = self.draw(None);
+        if let Some(object) = self.get_pending_object() {
+            let camera = self.get_camera();
+            let start = point![object.translation.x, object.translation.y + object.get_radius()];
+            let handle = object.rigid_body_handle;
+            let collider_set = &self.collider_set;
+            let is_other = |collider_handle: ColliderHandle| collider_set[collider_handle].parent() != Some(handle);
+            let ray = Ray::new(start, vector![0.0, 1.0]);
+            let max_toi = (self.config.fail_y - start.y).max(0.0);
+            let toi = self.query_pipeline.cast_ray(collider_set, &ray, max_toi, true, InteractionGroups::all(), Some(&is_other))
+                .map_or(max_toi, |(_, toi)| toi);
+            let (guide_x, start_y) = camera.to_pixels(start);
+            let (_, end_y) = camera.to_pixels(point![start.x, start.y + toi]);
+            canvas.set_color_fill(255, 255, 255);
+            canvas.set_color_stroke(0, 88, 122, 1.0);
+            let mut y = start_y;
+            while y < end_y {
+                let dash_end = (y + 8.0).min(end_y);
+                canvas.add_shape(&[
+                    (guide_x - 1.5, y),
+                    (guide_x + 1.5, y),
+                    (guide_x + 1.5, dash_end),
+                    (guide_x - 1.5, dash_end),
+                ], (0.0, 0.0), 0.0);
+                y += 14.0;
+            }
+        }
+        return canvas.encode_png();
+    }
+
+    // 記録した姿勢を順に描画したアニメーションGIF (最後のフレームは現在の状態)
+    fn render_animation(&self, frames: &[Vec<(Vector<Real>, Real)>], frame_interval: u64) -> canvas::CanvasResult<Vec<u8>> {
+        let mut encoder = canvas::GifEncoder::new(VIEW_WIDTH, VIEW_HEIGHT, ANIMATION_ZOOM)?;
+        let delay = (frame_interval as Real * self.integration_parameters.dt * 100.0).round() as u16;
+        for poses in frames {
+            encoder.add_frame(&self.draw(Some(poses)), delay)?;
+        }
+        // 最後のフレームは結果が分かるように長めに表示
+        encoder.add_frame(&self.draw(None), 300)?;
+        return encoder.finish();
+    }
+
+    // タワーの高さに応じた空を描画する (低い: 昼の空、中間: 夕焼け、高い: 星空)
+    fn draw_sky(&self, canvas: &mut canvas::Canvas) {
+        let lerp = |from: (u8, u8, u8), to: (u8, u8, u8), t: Real| {
+            let mix = |from: u8, to: u8| (from as Real + (to as Real - from as Real) * t).round() as u8;
+            (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+        };
+        let sky_color = self.render_options.sky_color;
+        canvas.set_no_stroke();
+        if self.height < SKY_DUSK_HEIGHT {
+            canvas.set_color_fill(sky_color.0, sky_color.1, sky_color.2);
+        } else if self.height < SKY_NIGHT_HEIGHT {
+            let t = (self.height - SKY_DUSK_HEIGHT) / (SKY_NIGHT_HEIGHT - SKY_DUSK_HEIGHT);
+            canvas.set_gradient_fill("sky".to_string(), lerp(sky_color, DUSK_COLORS.0, t), lerp(sky_color, DUSK_COLORS.1, t));
+        } else {
+            canvas.set_gradient_fill("sky".to_string(), NIGHT_COLORS.0, NIGHT_COLORS.1);
+        }
+        canvas.add_shape(&[
+            (       0.0,         0.0),
+            (VIEW_WIDTH,         0.0),
+            (VIEW_WIDTH, VIEW_HEIGHT),
+            (       0.0, VIEW_HEIGHT),
+        ], (0.0, 0.0), 0.0);
+        if self.height < SKY_NIGHT_HEIGHT { return; }
+
+        // 星の位置は高さの区間ごとに固定 (同じ区間の間は描画し直しても変わらない)
+        let mut rng = StdRng::seed_from_u64(self.height.floor() as u64);
+        canvas.set_color_fill(255, 255, 255);
+        for _ in 0..STAR_COUNT {
+            let (x, y) = (rng.gen_range(0.0..VIEW_WIDTH), rng.gen_range(0.0..VIEW_HEIGHT * 0.6));
+            let radius = rng.gen_range(0.8..2.0);
+            let star: Vec<(f64, f64)> = (0..8).map(|i| {
+                let angle = i as f64 * std::f64::consts::PI / 4.0;
+                (radius * angle.cos(), radius * angle.sin())
+            }).collect();
+            canvas.add_shape(&star, (x, y), 0.0);
+        }
+    }
+
+    // 失敗と判定される前後の姿勢を順に描画したアニメーションGIF (落下したオブジェクトは全てのフレームで赤枠)
+    fn render_collapse(&self, frames: &[Vec<(Vector<Real>, Real)>]) -> canvas::CanvasResult<Vec<u8>> {
+        let mut encoder = canvas::GifEncoder::new(VIEW_WIDTH, VIEW_HEIGHT, ANIMATION_ZOOM)?;
+        // 実際の2倍の時間をかけてスローモーションで表示する
+        let delay = (COLLAPSE_FRAME_INTERVAL as Real * self.integration_parameters.dt * 200.0).round() as u16;
+        for (index, poses) in frames.iter().enumerate() {
+            // 最後のフレームは長めに表示
+            encoder.add_frame(&self.draw(Some(poses)), if index + 1 == frames.len() { 300 } else { delay })?;
+        }
+        return encoder.finish();
+    }
+
+    // 現在のワールドを複製して物理演算を続け、frame_interval ステップごとの姿勢をframe_count回分返す
+    // (ステージの状態は変えないので、脱落モードで続きをプレイする場合にも影響しない)
+    fn simulate_aftermath(&self, frame_count: usize, frame_interval: u64) -> Vec<Vec<(Vector<Real>, Real)>> {
+        let mut physics_pipeline = PhysicsPipeline::new();
+        let mut island_manager = self.island_manager.clone();
+        let mut broad_phase = self.broad_phase.clone();
+        let mut narrow_phase = self.narrow_phase.clone();
+        let mut rigid_body_set = self.rigid_body_set.clone();
+        let mut collider_set = self.collider_set.clone();
+        let mut impulse_joint_set = self.impulse_joint_set.clone();
+        let mut multibody_joint_set = self.multibody_joint_set.clone();
+        let mut ccd_solver = CCDSolver::new();
+        let mut frames = Vec::with_capacity(frame_count);
+        for step in 1..=(frame_count as u64 * frame_interval) {
+            physics_pipeline.step(
+                &self.gravity,
+                &self.integration_parameters,
+                &mut island_manager,
+                &mut broad_phase,
+                &mut narrow_phase,
+                &mut rigid_body_set,
+                &mut collider_set,
+                &mut impulse_joint_set,
+                &mut multibody_joint_set,
+                &mut ccd_solver,
+                &self.physics_hooks,
+                &self.event_handler,
+            );
+            if step % frame_interval != 0 { continue; }
+            frames.push(self.objects.iter().map(|object| {
+                let body = &rigid_body_set[object.rigid_body_handle];
+                let rotation = body.rotation();
+                (*body.translation(), rotation.im.atan2(rotation.re))
+            }).collect());
+        }
+        return frames;
+    }
+
+    // posesがSomeの場合は物理演算の途中の姿勢でオブジェクトを描画する
+    // (途中のフレームには重心と候補のプレビューを表示しない)
+    fn draw(&self, poses: Option<&Vec<(Vector<Real>, Real)>>) -> canvas::Canvas {
+        let mut canvas = canvas::Canvas::with_backend(VIEW_WIDTH, VIEW_HEIGHT, self.render_options.backend);
+        let camera = self.get_camera();
+
+        for (user_id, user_icon) in self.user_icons.iter() {
+            match user_icon {
+                UserIcon::Image(data) => canvas.add_image(user_id.clone(), data),
+                UserIcon::Identicon => canvas.add_identicon(user_id.clone(), user_id),
+            }
+        }
+
+        self.draw_sky(&mut canvas);
+        let options = self.render_options;
+        canvas.set_color_fill(options.ground_color.0, options.ground_color.1, options.ground_color.2);
+        for block in &self.ground_blocks {
+            let corners: Vec<(f64, f64)> = block.get_corners().iter().map(|corner| camera.to_pixels(*corner)).collect();
+            canvas.add_shape(&corners, (0.0, 0.0), 0.0);
+        }
+
+        for (index, object) in self.objects.iter().enumerate() {
+            // 途中のフレームには物理演算の後に追加されたオブジェクトはまだない
+            let (translation, rotation) = match poses {
+                Some(poses) => match poses.get(index) {
+                    Some(pose) => *pose,
+                    None => { break; }
+                },
+                None => (object.translation, object.rotation),
+            };
+            canvas.set_color_fill(options.piece_fill_color.0, options.piece_fill_color.1, options.piece_fill_color.2);
+            canvas.set_color_stroke(options.piece_stroke_color.0, options.piece_stroke_color.1, options.piece_stroke_color.2, 4.0);
+            if let Some(user_id) = &object.user_id {
+                if self.user_icons.contains_key(user_id) {
+                    canvas.set_image_fill(user_id.clone());
+                    canvas.set_color_stroke(options.icon_stroke_color.0, options.icon_stroke_color.1, options.icon_stroke_color.2, 2.0);
+                }
+            }
+            if let Some(team) = &object.team {
+                let (red, green, blue) = team.get_color();
+                canvas.set_color_stroke(red, green, blue, 4.0);
+            }
+            // ゴールデンピースはアイコンより優先して金色で塗る
+            if object.golden {
+                canvas.set_color_fill(255, 215, 0);
+            }
+            // 落下したオブジェクトは赤枠で強調 (途中のフレームでも強調して、どれが落ちたか分かるようにする)
+            if self.fallen_object_indices.contains(&index) {
+                canvas.set_color_stroke(255, 0, 0, 6.0);
+            }
+            // 直前に積んだオブジェクトは明るい太枠で強調
+            if poses.is_none() && self.last_placed_index == Some(index) {
+                canvas.set_color_stroke(255, 241, 118, 6.0);
+            }
+            canvas.add_shape(&camera.to_pixel_shape(&object.shape.vertices), camera.to_pixels(Point::from(translation)), rotation.to_degrees() as f64);
+        }
+
+        // 直前に積んだオブジェクトの上に下向きの矢印を表示
+        if let (None, Some(object)) = (poses, self.last_placed_index.and_then(|index| self.objects.get(index))) {
+            let (arrow_x, _) = camera.to_pixels(Point::from(object.translation));
+            let (_, top_y) = camera.to_pixels(point![object.translation.x, object.get_top()]);
+            canvas.set_color_fill(255, 241, 118);
+            canvas.set_color_stroke(0, 88, 122, 1.5);
+            canvas.add_shape(&[
+                (-4.0, -22.0),
+                ( 4.0, -22.0),
+                ( 4.0, -12.0),
+                ( 9.0, -12.0),
+                ( 0.0,  -4.0),
+                (-9.0, -12.0),
+                (-4.0, -12.0),
+            ], (arrow_x, top_y), 0.0);
+        }
+
+        // タワーの重心を赤い菱形で表示
+        if let (None, Some(center_of_mass)) = (poses, self.get_center_of_mass()) {
+            canvas.set_color_fill(255, 0, 0);
+            canvas.set_color_stroke(255, 255, 255, 1.5);
+            canvas.add_shape(&[
+                ( 0.0, -6.0),
+                ( 6.0,  0.0),
+                ( 0.0,  6.0),
+                (-6.0,  0.0),
+            ], camera.to_pixels(center_of_mass), 0.0);
+        }
+
+        // 地面の両端の壁
+        if self.is_walls_enabled() {
+            canvas.set_color_fill(120, 120, 120);
+            canvas.set_color_stroke(60, 60, 60, 2.0);
+            let (ground_left, ground_right) = self.get_ground_span();
+            for wall_x in [ground_left + WALL_HALF_THICKNESS, ground_right - WALL_HALF_THICKNESS] {
+                let (wall_x, ground_bottom) = camera.to_pixels(point![wall_x, self.config.fail_y]);
+                let wall_half_thickness = camera.to_pixel_length(WALL_HALF_THICKNESS);
+                canvas.add_shape(&[
+                    (wall_x - wall_half_thickness, -10.0),
+                    (wall_x + wall_half_thickness, -10.0),
+                    (wall_x + wall_half_thickness, ground_bottom),
+                    (wall_x - wall_half_thickness, ground_bottom),
+                ], (0.0, 0.0), 0.0);
+            }
+        }
+
+        // 目標の高さを破線で表示
+        if let Some(target_height) = self.target_height {
+            let (_, target_y) = camera.to_pixels(point![GROUND_CENTER_X, self.get_height_baseline() - target_height]);
+            canvas.set_no_stroke();
+            canvas.set_color_fill(255, 255, 255);
+            for i in 0..27 {
+                let x = i as f64 * 24.0;
+                canvas.add_shape(&[
+                    (x,        target_y - 1.5),
+                    (x + 14.0, target_y - 1.5),
+                    (x + 14.0, target_y + 1.5),
+                    (x,        target_y + 1.5),
+                ], (0.0, 0.0), 0.0);
+            }
+        }
+
+        // 左端に地面からの高さの目盛りを表示
+        let baseline = self.get_height_baseline();
+        let (_, baseline_y) = camera.to_pixels(point![GROUND_CENTER_X, baseline]);
+        let interval = RULER_INTERVALS.iter().copied()
+            .find(|interval| camera.to_pixel_length(*interval) >= RULER_MIN_SPACING)
+            .unwrap_or(RULER_INTERVALS[RULER_INTERVALS.len() - 1]);
+        canvas.set_color_fill(255, 255, 255);
+        canvas.set_color_stroke(0, 88, 122, 1.0);
+        canvas.add_shape(&[
+            (4.0, 0.0),
+            (6.0, 0.0),
+            (6.0, baseline_y),
+            (4.0, baseline_y),
+        ], (0.0, 0.0), 0.0);
+        for i in 0.. {
+            let (_, tick_y) = camera.to_pixels(point![GROUND_CENTER_X, baseline - interval * i as Real]);
+            if tick_y < 0.0 { break; }
+            let labeled = i % 2 == 0;
+            let tick_length = if labeled { 16.0 } else { 8.0 };
+            canvas.add_shape(&[
+                (6.0,               tick_y - 1.0),
+                (6.0 + tick_length, tick_y - 1.0),
+                (6.0 + tick_length, tick_y + 1.0),
+                (6.0,               tick_y + 1.0),
+            ], (0.0, 0.0), 0.0);
+            if labeled && i > 0 {
+                for shape in render::get_number_shapes(&format!("{}", interval * i as Real), (26.0, tick_y - 5.0), 10.0) {
+                    canvas.add_shape(&shape, (0.0, 0.0), 0.0);
+                }
+            }
+        }
+        // 現在の高さを目盛りの上に黄色の線で表示
+        if self.height > 0.0 {
+            let (_, height_y) = camera.to_pixels(point![GROUND_CENTER_X, baseline - self.height]);
+            canvas.set_color_fill(252, 211, 3);
+            canvas.set_color_stroke(0, 88, 122, 1.0);
+            canvas.add_shape(&[
+                ( 0.0, height_y - 1.5),
+                (48.0, height_y - 1.5),
+                (48.0, height_y + 1.5),
+                ( 0.0, height_y + 1.5),
+            ], (0.0, 0.0), 0.0);
+        }
+
+        // 次に落とすオブジェクトの候補を右上のプレビュー枠に回転0で表示
+        // ラベル部分の点の数が候補の番号を表す
+        if poses.is_none() && self.get_pending_object().is_some() {
+            let (box_x, box_size, label_height, margin) = (550.0, 80.0, 14.0, 6.0);
+            for (index, shape) in self.candidates.iter().enumerate() {
+                let box_y = 10.0 + index as f64 * (label_height + box_size + margin);
+                canvas.set_color_stroke(0, 88, 122, 2.0);
+                canvas.set_color_fill(255, 255, 255);
+                canvas.add_shape(&[
+                    (box_x,            box_y),
+                    (box_x + box_size, box_y),
+                    (box_x + box_size, box_y + label_height + box_size),
+                    (box_x,            box_y + label_height + box_size),
+                ], (0.0, 0.0), 0.0);
+                canvas.set_color_fill(0, 88, 122);
+                canvas.add_shape(&[
+                    (box_x,            box_y),
+                    (box_x + box_size, box_y),
+                    (box_x + box_size, box_y + label_height),
+                    (box_x,            box_y + label_height),
+                ], (0.0, 0.0), 0.0);
+                canvas.set_no_stroke();
+                canvas.set_color_fill(255, 255, 255);
+                for pip in 0..=index {
+                    let pip_x = box_x + 6.0 + pip as f64 * 10.0;
+                    canvas.add_shape(&[
+                        (pip_x,       box_y + 4.0),
+                        (pip_x + 6.0, box_y + 4.0),
+                        (pip_x + 6.0, box_y + 10.0),
+                        (pip_x,       box_y + 10.0),
+                    ], (0.0, 0.0), 0.0);
+                }
+                // プレビューはカメラの縮小に関わらず同じ縮尺で表示する
+                let pixel_vertices = CameraTransform::new(self.config.world_scale, GROUND_CENTER_X, 0.0).to_pixel_shape(&shape.vertices);
+                let radius = pixel_vertices.iter().map(|(x, y)| (x * x + y * y).sqrt()).fold(0.0, f64::max);
+                let scale = (box_size * 0.4 / radius).min(1.0);
+                let preview_shape: Vec<(f64, f64)> = pixel_vertices.iter().map(|(x, y)| (x * scale, y * scale)).collect();
+                canvas.set_color_stroke(options.piece_stroke_color.0, options.piece_stroke_color.1, options.piece_stroke_color.2, 2.0);
+                canvas.add_shape(&preview_shape, (box_x + box_size * 0.5, box_y + label_height + box_size * 0.5), 0.0);
+            }
+        }
+
+        // 地震が発生したターンは画面下部に警告の帯を表示
+        if self.earthquake {
+            canvas.set_no_stroke();
+            canvas.set_color_fill(252, 211, 3);
+            canvas.add_shape(&[
+                (  0.0, 456.0),
+                (640.0, 456.0),
+                (640.0, 480.0),
+                (  0.0, 480.0),
+            ], (0.0, 0.0), 0.0);
+            canvas.set_color_fill(30, 30, 30);
+            for i in 0..17 {
+                let x = i as f64 * 40.0;
+                canvas.add_shape(&[
+                    (x,        456.0),
+                    (x + 20.0, 456.0),
+                    (x + 4.0,  480.0),
+                    (x - 16.0, 480.0),
+                ], (0.0, 0.0), 0.0);
+            }
+        }
+
+        // 風向きと強さを矢印で表示
+        if self.wind != 0.0 {
+            let arrow = vec![
+                (-12.0, -4.0),
+                (  2.0, -4.0),
+                (  2.0, -10.0),
+                ( 14.0,  0.0),
+                (  2.0,  10.0),
+                (  2.0,  4.0),
+                (-12.0,  4.0),
+            ];
+            let rotation = if self.wind > 0.0 { 0.0 } else { 180.0 };
+            // 左上の表示と重ならないようにずらす
+            let wind_y = if self.config.overlay { 48.0 } else { 30.0 };
+            canvas.set_color_fill(255, 255, 255);
+            canvas.set_color_stroke(0, 88, 122, 2.0);
+            for i in 0..get_wind_level(self.wind) {
+                canvas.add_shape(&arrow, (30.0 + i as f64 * 32.0, wind_y), rotation);
+            }
+        }
+
+        // 左上にターン数、高さ、直前にプレイしたユーザーを半透明の背景付きで表示
+        if self.config.overlay {
+            let mut text = format!("TURN {} / {:.2} m", self.turn_count, self.height);
+            if let Some(user_id) = self.get_placed_objects().last().and_then(|object| object.user_id.clone()) {
+                let name = self.user_names.get(&user_id).cloned().unwrap_or(user_id);
+                text += &format!(" / last: @{}", name);
+            }
+            let (text_x, text_y, text_size, padding) = (56.0, 6.0, 16.0, 4.0);
+            let text_width = canvas.measure_text(&text, text_size);
+            canvas.set_no_stroke();
+            canvas.set_translucent_color_fill(0, 0, 0, 0.5);
+            canvas.add_shape(&[
+                (text_x - padding,              text_y - padding),
+                (text_x + text_width + padding, text_y - padding),
+                (text_x + text_width + padding, text_y + text_size + padding),
+                (text_x - padding,              text_y + text_size + padding),
+            ], (0.0, 0.0), 0.0);
+            canvas.add_text(&text, text_x, text_y + text_size * 0.85, text_size, (255, 255, 255), canvas::TextAnchor::Start);
+        }
+
+        return canvas;
+    }
+
+    // 積まれているオブジェクト (落下待ちのオブジェクトを除く)
+    fn get_placed_objects(&self) -> &[Object] {
+        return match self.get_pending_object() {
+            Some(_) => &self.objects[..self.objects.len() - 1],
+            None => &self.objects,
+        };
+    }
+
+    // 地面の端から最も遠くにはみ出している頂点までの距離 (m、はみ出していない場合は0)
+    fn get_overhang(&self) -> Real {
+        let (ground_left, ground_right) = self.get_ground_span();
+        let mut overhang: Real = 0.0;
+        for object in self.get_placed_objects() {
+            for point in object.get_world_vertices() {
+                overhang = overhang.max(ground_left - point.x).max(point.x - ground_right);
+            }
+        }
+        return overhang;
+    }
+
+    // 積まれているオブジェクト全体の重心 (オブジェクトがない場合はNone)
+    fn get_center_of_mass(&self) -> Option<Point<Real>> {
+        let mut total_mass = 0.0;
+        let mut moment = vector![0.0, 0.0];
+        for object in self.get_placed_objects() {
+            let body = &self.rigid_body_set[object.rigid_body_handle];
+            total_mass += body.mass();
+            moment += (body.position() * body.mass_properties().local_com).coords * body.mass();
+        }
+        if total_mass <= 0.0 { return None; }
+        return Some(Point::from(moment / total_mass));
+    }
+
+    // 重心と地面の中心の水平距離を地面の幅の半分で割って1から引いたもの (0〜1)
+    fn get_stability(&self) -> Real {
+        let (ground_left, ground_right) = self.get_ground_span();
+        let ground_center = (ground_left + ground_right) * 0.5;
+        let ground_half_width = (ground_right - ground_left) * 0.5;
+        return match self.get_center_of_mass() {
+            Some(center_of_mass) => (1.0 - (center_of_mass.x - ground_center).abs() / ground_half_width).clamp(0.0, 1.0),
+            None => 1.0,
+        };
+    }
+
+    fn get_stage_top(&self) -> Real {
+        let mut top = self.get_height_baseline();
+        for object in &self.objects {
+            let obj_top = object.get_top();
+            if top > obj_top { top = obj_top; }
+        }
+        return top;
+    }
+
+    // 記録する高さ (m)
+    // 地面の幅の範囲で上から下向きにレイを飛ばし、ほぼ静止している積まれたオブジェクトに当たった最も高い点を頂点とする
+    // (get_stage_topと違い、宙に浮いている破片や地面の外にはみ出したオブジェクトは含まない)
+    fn get_supported_height(&mut self) -> Real {
+        self.query_pipeline.update(&self.island_manager, &self.rigid_body_set, &self.collider_set);
+        let baseline = self.get_height_baseline();
+        let (ground_left, ground_right) = self.get_ground_span();
+        // 地面の幅の範囲に頂点があり、ほぼ静止しているオブジェクトだけを対象にする
+        let supported_objects: Vec<&Object> = self.get_placed_objects().iter()
+            .filter(|object| {
+                let body = &self.rigid_body_set[object.rigid_body_handle];
+                !body.is_dynamic() || body.is_sleeping() || body.linvel().norm() < SUPPORTED_SPEED
+            })
+            .filter(|object| object.get_world_vertices().iter().any(|point| ground_left <= point.x && point.x <= ground_right))
+            .collect();
+        // レイの始点も対象のオブジェクトだけで決める (対象外のオブジェクトで丸め誤差が変わらないようにする)
+        let start_y = supported_objects.iter().fold(baseline, |top, object| top.min(object.get_top())) - 0.1;
+        let supported_handles: Vec<RigidBodyHandle> = supported_objects.iter().map(|object| object.rigid_body_handle).collect();
+        let collider_set = &self.collider_set;
+        let is_supported = |collider_handle: ColliderHandle| {
+            collider_set[collider_handle].parent().is_some_and(|handle| supported_handles.contains(&handle))
+        };
+        let mut top = baseline;
+        let mut x = ground_left;
+        while x <= ground_right {
+            let ray = Ray::new(point![x, start_y], vector![0.0, 1.0]);
+            let max_toi = baseline - start_y;
+            if let Some((_, toi)) = self.query_pipeline.cast_ray(collider_set, &ray, max_toi, true, InteractionGroups::all(), Some(&is_supported)) {
+                top = top.min(start_y + toi);
+            }
+            x += HEIGHT_RAY_SPACING;
+        }
+        return baseline - top;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 正方形、横長の長方形、三角形 (px単位)
+    fn test_shapes() -> Vec<ShapeDef> {
+        return vec![
+            ShapeDef::new(vec![(-30.0, -30.0), (30.0, -30.0), (30.0, 30.0), (-30.0, 30.0)]),
+            ShapeDef::new(vec![(-60.0, -15.0), (60.0, -15.0), (60.0, 15.0), (-60.0, 15.0)]),
+            ShapeDef::new(vec![(0.0, -35.0), (40.0, 30.0), (-40.0, 30.0)]),
+        ];
+    }
+
+    // テストを実行する速さで結果が変わらないように、実時間での打ち切りは無効にする
+    fn test_config() -> StageConfig {
+        return StageConfig { wall_clock_budget_sec: 1.0e6, ..StageConfig::default() };
+    }
+
+    // ゲーム開始時のターン (オブジェクトを落とさない) まで進めたステージ
+    fn new_stage(seed: u64) -> Stage {
+        let mut stage = Stage::new(test_shapes(), Difficulty::Normal, GroundVariant::Flat, seed, test_config());
+        stage.next_turn(None, TurnInput::default()).unwrap();
+        return stage;
+    }
+
+    fn input(translation_x: Real, rotation: Real) -> TurnInput {
+        return TurnInput { translation_x, rotation, ..TurnInput::default() };
+    }
+
+    // 全てのオブジェクトの姿勢 (ビット単位で比較する)
+    fn poses(stage: &Stage) -> Vec<[u32; 3]> {
+        return stage.get_objects().iter()
+            .map(|object| [object.translation.x.to_bits(), object.translation.y.to_bits(), object.rotation.to_bits()])
+            .collect();
+    }
+
+    // 物理演算を使わずに、積まれたオブジェクトを指定した姿勢で追加する (落下待ちのオブジェクトより前に入れる)
+    fn place_object(stage: &mut Stage, shape_index: usize, translation: Vector<Real>, rotation: Real) -> RigidBodyHandle {
+        let shape = stage.shapes[shape_index].clone();
+        let handle = stage.create_rigid_body(&shape, Isometry::new(translation, rotation));
+        let object = Object {
+            user_id: Some("U_TEST".to_string()),
+            team: None,
+            golden: false,
+            shape,
+            translation,
+            rotation,
+            rigid_body_handle: handle,
+            sleep_streak: 0,
+            buried: false,
+        };
+        let index = if stage.get_pending_object().is_some() { stage.objects.len() - 1 } else { stage.objects.len() };
+        stage.objects.insert(index, object);
+        return handle;
+    }
+
+    // 物理演算を使わずに、地面の中央に0.6 mの正方形をcount個積んだステージ
+    // (描画の比較が環境のフォントで変わらないように、左上の文字は表示しない)
+    fn posed_tower(count: usize) -> Stage {
+        let config = StageConfig { overlay: false, ..test_config() };
+        let mut stage = Stage::new(test_shapes(), Difficulty::Normal, GroundVariant::Flat, 1, config);
+        // 比較する画像はusvgで描画したもの (TOWER_RENDER_BACKENDによらない)
+        stage.render_options.backend = canvas::RenderBackend::Usvg;
+        let ground_top = stage.config.get_ground_top();
+        for index in 0..count {
+            place_object(&mut stage, 0, vector![GROUND_CENTER_X, ground_top - 0.3 - 0.6 * index as Real], 0.0);
+        }
+        stage.height = stage.get_supported_height();
+        return stage;
+    }
+
+    fn inputs() -> Vec<TurnInput> {
+        return vec![input(0.0, 0.0), input(-0.1, 45.0), input(0.1, 90.0), input(0.0, -30.0), input(0.05, 0.0), input(-0.05, 15.0)];
+    }
+
+    #[test]
+    fn snapshot_round_trip_continues_identically() {
+        let inputs = inputs();
+        let mut original = new_stage(42);
+        original.set_wind_enabled(true);
+        for turn_input in &inputs[..3] { original.next_turn(None, turn_input.clone()).unwrap(); }
+
+        let json = serde_json::to_string(&original.to_snapshot()).unwrap();
+        let mut restored = Stage::from_snapshot(serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(poses(&restored), poses(&original));
+        for turn_input in &inputs[3..] {
+            let expected = original.next_turn(None, turn_input.clone()).unwrap();
+            let actual = restored.next_turn(None, turn_input.clone()).unwrap();
+            assert_eq!(actual.result, expected.result);
+            assert_eq!(actual.height.to_bits(), expected.height.to_bits());
+            assert_eq!(actual.wind.map(Real::to_bits), expected.wind.map(Real::to_bits));
+            assert_eq!(actual.pending_shape_index, expected.pending_shape_index);
+        }
+        assert_eq!(poses(&restored), poses(&original));
+    }
+
+    #[test]
+    fn saving_does_not_change_the_game() {
+        // 保存の回数によって乱数 (風や次のピース) が変わらない
+        let mut saved = new_stage(7);
+        let mut unsaved = new_stage(7);
+        saved.set_wind_enabled(true);
+        unsaved.set_wind_enabled(true);
+        for turn_input in inputs() {
+            for _ in 0..3 { saved.to_snapshot(); }
+            let expected = unsaved.next_turn(None, turn_input.clone()).unwrap();
+            let actual = saved.next_turn(None, turn_input).unwrap();
+            assert_eq!(actual.wind.map(Real::to_bits), expected.wind.map(Real::to_bits));
+            assert_eq!(actual.pending_shape_index, expected.pending_shape_index);
+        }
+        assert_eq!(poses(&saved), poses(&unsaved));
+    }
+
+    #[test]
+    fn loads_version_1_snapshot() {
+        // バージョン1の形式 (rngの代わりにrng_seed) も読み込める
+        let stage = new_stage(3);
+        let mut json = serde_json::to_value(stage.to_snapshot()).unwrap();
+        json["version"] = serde_json::json!(1);
+        json.as_object_mut().unwrap().remove("rng");
+        json["rng_seed"] = serde_json::json!(12345);
+        let restored = Stage::from_snapshot(serde_json::from_value(json).unwrap()).unwrap();
+        assert_eq!(poses(&restored), poses(&stage));
+
+        let mut json = serde_json::to_value(stage.to_snapshot()).unwrap();
+        json["version"] = serde_json::json!(STAGE_SNAPSHOT_VERSION + 1);
+        assert!(Stage::from_snapshot(serde_json::from_value(json).unwrap()).is_err());
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_identical_games() {
+        // 同じseedと同じ形状のステージに同じ操作をすると、高さと全てのオブジェクトの姿勢がビット単位で一致する
+        let mut first = new_stage(2024);
+        let mut second = new_stage(2024);
+        first.set_wind_enabled(true);
+        second.set_wind_enabled(true);
+        for turn_input in inputs() {
+            let first_report = first.next_turn(None, turn_input.clone()).unwrap();
+            let second_report = second.next_turn(None, turn_input).unwrap();
+            assert_eq!(first_report.result, second_report.result);
+            assert_eq!(first_report.height.to_bits(), second_report.height.to_bits());
+            assert_eq!(first_report.steps_executed, second_report.steps_executed);
+            assert_eq!(poses(&first), poses(&second));
+        }
+        assert_eq!(first.get_height().to_bits(), second.get_height().to_bits());
+    }
+
+    #[test]
+    fn reset_last_object_clears_leftover_velocity() {
+        // 落下待ちのオブジェクトに速度が残っていても、同じ操作からは同じ結果になる
+        let mut clean = new_stage(11);
+        let mut dirty = new_stage(11);
+        let handle = dirty.objects.last().unwrap().rigid_body_handle;
+        let body = &mut dirty.rigid_body_set[handle];
+        body.set_linvel(vector![3.0, -2.0], true);
+        body.set_angvel(4.0, true);
+
+        let turn_input = TurnInput { spin: 1.5, ..input(0.1, 30.0) };
+        dirty.reset_last_object(None, &turn_input);
+        let body = &dirty.rigid_body_set[handle];
+        assert_eq!(*body.linvel(), vector![0.0, 0.0]);
+        assert_eq!(body.angvel(), 1.5);
+
+        let clean_report = clean.next_turn(None, turn_input.clone()).unwrap();
+        let dirty_report = dirty.next_turn(None, turn_input).unwrap();
+        assert_eq!(dirty_report.result, clean_report.result);
+        assert_eq!(dirty_report.height.to_bits(), clean_report.height.to_bits());
+        assert_eq!(poses(&dirty), poses(&clean));
+    }
+
+    #[test]
+    fn jittery_stack_converges_by_kinetic_energy() {
+        // 眠らない (is_sleepingにならない) オブジェクトを揺らしても、運動エネルギーで静止を判定してタイムアウトより十分前に成功する
+        let mut stage = new_stage(5);
+        let timeout_frame = (stage.config.timeout_sec / stage.integration_parameters.dt).floor() as u64;
+        for turn in 0..5 {
+            let jitter = if turn % 2 == 0 { 0.05 } else { -0.05 };
+            for object in &stage.objects {
+                let body = &mut stage.rigid_body_set[object.rigid_body_handle];
+                *body.activation_mut() = RigidBodyActivation::cannot_sleep();
+                body.set_linvel(vector![jitter, 0.0], true);
+                body.set_angvel(jitter, true);
+            }
+            let report = stage.next_turn(None, input(0.0, 0.0)).unwrap();
+            assert_eq!(report.result, TurnResult::Success);
+            assert!(report.steps_executed < timeout_frame / 4, "took {} of {} steps", report.steps_executed, timeout_frame);
+            assert!(report.steps_executed >= stage.config.rest_frames as u64);
+        }
+    }
+
+    #[test]
+    fn buried_objects_leave_the_simulation() {
+        // 高いタワーでは、下の方で静止し続けているオブジェクトが固定されて物理演算の対象が減る
+        let plank = ShapeDef::new(vec![(-60.0, -15.0), (60.0, -15.0), (60.0, 15.0), (-60.0, 15.0)]);
+        let mut stage = Stage::new(vec![plank], Difficulty::Normal, GroundVariant::Flat, 1, test_config());
+        stage.next_turn(None, TurnInput::default()).unwrap();
+        for _ in 0..100 {
+            if stage.next_turn(None, input(0.0, 0.0)).unwrap().result != TurnResult::Success { break; }
+        }
+        let object_count = stage.get_object_count();
+        assert!(object_count >= 30, "the tower fell after {} pieces", object_count);
+        assert!(stage.get_active_object_count() < object_count / 2,
+            "{} of {} objects are still simulated", stage.get_active_object_count(), object_count);
+        for object in stage.objects.iter().filter(|object| object.buried) {
+            assert_eq!(stage.rigid_body_set[object.rigid_body_handle].body_type(), RigidBodyType::Fixed);
+        }
+    }
+
+    #[test]
+    fn new_piece_never_starts_inside_the_tower() {
+        // タワーの真上 (落とす高さ0) に落としても、物理演算を始める時点では既存のオブジェクトや地面と重なっていない
+        let mut stage = new_stage(9);
+        for turn in 0..30 {
+            let turn_input = TurnInput { drop_height: Some(0.0), ..input((turn % 5 - 2) as Real * 0.03, (turn * 37 % 180) as Real) };
+            // next_turnと同じ位置に置いて、物理演算を始める前の姿勢を確かめる
+            let lift = stage.reset_last_object(None, &turn_input);
+            stage.query_pipeline.update(&stage.island_manager, &stage.rigid_body_set, &stage.collider_set);
+            let object = stage.objects.last().unwrap();
+            assert!(!stage.is_overlapping(object.rigid_body_handle, object.translation, object.rotation), "turn {} starts overlapping", turn);
+
+            let report = stage.next_turn(None, turn_input).unwrap();
+            assert_eq!(report.spawn_lift.unwrap_or(0.0), lift);
+            if report.result != TurnResult::Success { break; }
+        }
+    }
+
+    #[test]
+    fn far_away_piece_does_not_count_toward_height() {
+        let mut stage = new_stage(13);
+        for _ in 0..3 { stage.next_turn(None, input(0.0, 0.0)).unwrap(); }
+        let height = stage.get_supported_height();
+        assert!(height > 0.0);
+
+        // 地面の外の高い位置にあるオブジェクトは、get_stage_topには含まれるが高さには数えない
+        let (_, ground_right) = stage.get_ground_span();
+        let top = stage.get_stage_top();
+        place_object(&mut stage, 0, vector![ground_right + 3.0, top - 5.0], 0.0);
+        assert!(stage.get_height_baseline() - stage.get_stage_top() > height + 4.0);
+        assert_eq!(stage.get_supported_height().to_bits(), height.to_bits());
+
+        // タワーの真上を飛んでいる破片も数えない
+        let handle = place_object(&mut stage, 0, vector![GROUND_CENTER_X, top - 3.0], 0.0);
+        stage.rigid_body_set[handle].set_linvel(vector![0.0, -5.0], true);
+        assert_eq!(stage.get_supported_height().to_bits(), height.to_bits());
+    }
+
+    #[test]
+    fn piece_pushed_off_the_side_fails_before_reaching_the_ground() {
+        // 地面の右端より外で下向きに動いているオブジェクトは、地面の高さまで落ちるのを待たずに落下と判定される
+        let mut stage = new_stage(17);
+        stage.reset_last_object(None, &input(1.0, 0.0));
+        let (_, ground_right) = stage.get_ground_span();
+        let object = stage.objects.last().unwrap();
+        assert!(object.translation.x > ground_right + object.get_radius());
+        let handle = object.rigid_body_handle;
+        stage.rigid_body_set[handle].set_linvel(vector![1.0, 0.5], true);
+
+        let timeout_sec = stage.config.timeout_sec;
+        let (result, fallen_object_indices, steps_executed) = stage.continue_until_convergence(timeout_sec, &mut |_, _| {});
+        assert_eq!(result, TurnResult::Failure);
+        assert_eq!(fallen_object_indices, vec![stage.objects.len() - 1]);
+        assert!(steps_executed <= 30, "took {} steps", steps_executed);
+        assert!(stage.objects.last().unwrap().get_top() < stage.config.fail_y);
+    }
+
+    #[test]
+    fn thin_sliver_dropped_from_max_height_stays_above_the_ground() {
+        // 細いオブジェクトを最大の高さから何度落としても、地面をすり抜けない
+        let sliver = ShapeDef::new(vec![(-60.0, -2.0), (60.0, -2.0), (60.0, 2.0), (-60.0, 2.0)]);
+        let mut rng = ChaCha12Rng::seed_from_u64(32);
+        for attempt in 0..100 {
+            let mut stage = Stage::new(vec![sliver.clone()], Difficulty::Normal, GroundVariant::Flat, rng.gen(), test_config());
+            stage.next_turn(None, TurnInput::default()).unwrap();
+            let turn_input = TurnInput {
+                drop_height: Some(1.0),
+                ..input(rng.gen_range(-0.3..0.3), rng.gen_range(-180.0..180.0))
+            };
+            stage.next_turn(None, turn_input).unwrap();
+            let object = &stage.objects[0];
+            assert!(object.translation.y < stage.config.get_ground_top(), "attempt {} went through the ground: {:?}", attempt, object.translation);
+        }
+    }
+
+    #[test]
+    fn height_delta_matches_the_change_in_height() {
+        let mut stage = new_stage(21);
+        for turn_input in inputs() {
+            let height_before = stage.get_height();
+            let report = stage.next_turn(None, turn_input).unwrap();
+            assert_eq!(report.height.to_bits(), stage.get_height().to_bits());
+            assert_eq!(report.height_delta.to_bits(), (stage.get_height() - height_before).to_bits());
+        }
+        assert!(stage.get_height() > 0.0);
+    }
+
+    #[test]
+    fn posed_tower_keeps_its_height_and_image() {
+        // 座標をmで持つようにしても、記録する高さ (地面の下端から) と描画結果は変わらない
+        let stage = posed_tower(3);
+        let expected_height = stage.config.fail_y - (stage.config.get_ground_top() - 0.6 * 3.0);
+        assert!((stage.get_height() - expected_height).abs() < 1.0e-3, "height {} != {}", stage.get_height(), expected_height);
+        canvas::assert_golden_png("posed_tower", &stage.render_frame().unwrap());
+    }
+
+    #[test]
+    fn camera_zooms_out_only_for_tall_towers() {
+        // 画像に収まる低いタワーはworld_scaleのまま描画する
+        let short = posed_tower(2);
+        assert_eq!(short.get_camera().meters_per_pixel, short.config.world_scale);
+        canvas::assert_golden_png("camera_short_tower", &short.render_frame().unwrap());
+
+        // 画像に収まらない高いタワーは縮小して、頂点と地面 (目盛りの基準) の両方を余白を空けて映す
+        let tall = posed_tower(12);
+        let camera = tall.get_camera();
+        assert!(camera.meters_per_pixel > tall.config.world_scale);
+        let (_, top_y) = camera.to_pixels(point![GROUND_CENTER_X, tall.get_stage_top()]);
+        let (_, ground_y) = camera.to_pixels(point![GROUND_CENTER_X, tall.config.fail_y]);
+        assert!(top_y >= CAMERA_MARGIN as f64 - 1.0e-3, "the top is drawn at {} px", top_y);
+        assert!(ground_y <= VIEW_HEIGHT, "the ground is drawn at {} px", ground_y);
+        canvas::assert_golden_png("camera_tall_tower", &tall.render_frame().unwrap());
+    }
+
+    #[test]
+    fn sky_changes_with_the_height_band() {
+        // 昼の空 (2 m未満)、夕焼け (2〜5 m)、星空 (5 m以上) の区間ごとに描画を比較する
+        for (height, name) in [(1.0, "sky_day"), (3.5, "sky_dusk"), (6.0, "sky_night")] {
+            let mut stage = posed_tower(1);
+            stage.height = height;
+            canvas::assert_golden_png(name, &stage.render_frame().unwrap());
+        }
+
+        // 星の位置は1 mの区間ごとに決まり、同じ区間の間は変わらない (このカメラでは高さの線は画像の外)
+        let mut stage = posed_tower(1);
+        let mut render_at = |height: Real| {
+            stage.height = height;
+            return stage.render_frame().unwrap();
+        };
+        let first = render_at(6.2);
+        assert!(render_at(6.8) == first, "the stars moved within the same band");
+        assert!(render_at(7.2) != first, "the stars did not change in the next band");
+    }
+
+    // tiny-skiaで直接描画した画像とusvgの画像で、色が異なってよい画素の割合
+    // (アンチエイリアスとパターンの補間が少し異なるので、図形の縁の画素は一致しない)
+    const SKIA_PIXEL_TOLERANCE: f64 = 0.01;
+
+    fn render_with_backend(stage: &mut Stage, backend: canvas::RenderBackend) -> Vec<u8> {
+        stage.render_options.backend = backend;
+        return stage.render_frame().unwrap();
+    }
+
+    #[test]
+    fn skia_backend_matches_the_usvg_images() {
+        // tiny-skiaで描画しても、usvgで描画した画像と見た目は変わらない (空のグラデーションとアイコンを含む)
+        let mut stages = vec![("posed_tower", posed_tower(3)), ("camera_tall_tower", posed_tower(12))];
+        let mut dusk = posed_tower(1);
+        dusk.height = 3.5;
+        stages.push(("sky_dusk", dusk));
+        for (name, mut stage) in stages {
+            canvas::assert_similar_to_golden_png(name, &render_with_backend(&mut stage, canvas::RenderBackend::Skia), SKIA_PIXEL_TOLERANCE);
+        }
+        let mut stage = stage_with_icons(10, &gradient_icon(96));
+        let usvg = render_with_backend(&mut stage, canvas::RenderBackend::Usvg);
+        let skia = render_with_backend(&mut stage, canvas::RenderBackend::Skia);
+        assert!(usvg != skia, "the backend option was ignored");
+        canvas::assert_golden_png("tower_with_icons", &usvg);
+        canvas::assert_similar_to_golden_png("tower_with_icons", &skia, SKIA_PIXEL_TOLERANCE);
+    }
+
+    #[test]
+    fn themes_change_the_image() {
+        // 同じステージでもテーマごとに異なる画像になる
+        let mut stage = posed_tower(3);
+        let images: Vec<Vec<u8>> = render::Theme::ALL.iter().map(|theme| {
+            stage.set_render_options(theme.get_render_options());
+            return stage.render_frame().unwrap();
+        }).collect();
+        for (index, image) in images.iter().enumerate() {
+            for other in &images[index + 1..] {
+                assert!(image != other, "{} gives the same image as another theme", render::Theme::ALL[index].get_name());
+            }
+        }
+    }
+
+    #[test]
+    fn self_intersecting_shape_can_be_played() {
+        // 辺が交差している形状も凸包で代用して落とせる (ターンの途中でpanicしない)
+        let bowtie = ShapeDef::new(vec![(-20.0, -20.0), (20.0, 20.0), (20.0, -10.0), (-20.0, 20.0)]);
+        assert!(bowtie.is_self_intersecting());
+        let mut stage = Stage::new(vec![bowtie], Difficulty::Normal, GroundVariant::Flat, 37, test_config());
+        stage.next_turn(None, TurnInput::default()).unwrap();
+        for turn_input in inputs() {
+            stage.next_turn(None, turn_input).unwrap();
+        }
+        assert!(stage.get_object_count() > 0);
+    }
+
+    // size x size pxのグラデーションのアイコン画像 (PNG)
+    fn gradient_icon(size: u32) -> Vec<u8> {
+        let icon = image::RgbaImage::from_fn(size, size, |x, y| image::Rgba([(x * 255 / size) as u8, (y * 255 / size) as u8, 128, 255]));
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgba8(icon).write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png).unwrap();
+        return data;
+    }
+
+    // user_count人のユーザーが1つずつ積んだステージ (全員がアイコン画像を持つ)
+    fn stage_with_icons(user_count: usize, icon: &[u8]) -> Stage {
+        let mut stage = posed_tower(0);
+        let ground_top = stage.config.get_ground_top();
+        let columns = 5;
+        for index in 0..user_count {
+            let (column, row) = (index % columns, index / columns);
+            let x = GROUND_CENTER_X + (column as Real - (columns - 1) as Real * 0.5) * 0.7;
+            place_object(&mut stage, 0, vector![x, ground_top - 0.3 - 0.6 * row as Real], 0.0);
+            let user_id = format!("U{:03}", index);
+            stage.user_icons.insert(user_id.clone(), UserIcon::Image(Arc::new(icon.to_vec())));
+            stage.objects.last_mut().unwrap().user_id = Some(user_id);
+        }
+        return stage;
+    }
+
+    // 描画を repeat 回繰り返した平均の時間 (ms)
+    fn measure_render_ms(stage: &Stage, repeat: u32) -> f64 {
+        let start = std::time::Instant::now();
+        for _ in 0..repeat { stage.render_frame().unwrap(); }
+        return start.elapsed().as_secs_f64() * 1000.0 / repeat as f64;
+    }
+
+    #[test]
+    #[ignore = "benchmark: cargo test --release -- --ignored --nocapture render_benchmark"]
+    fn render_benchmark_with_resized_icons() {
+        // 20人分の512pxのアイコンをそのまま埋め込んだ場合と、96pxに縮小した場合の比較
+        let original = gradient_icon(512);
+        let resized = canvas::Canvas::resize_icon(&original, 96).unwrap();
+        let decode_start = std::time::Instant::now();
+        for _ in 0..20 { canvas::Canvas::resize_icon(&original, 96).unwrap(); }
+        let resize_ms = decode_start.elapsed().as_secs_f64() * 1000.0 / 20.0;
+        let original_ms = measure_render_ms(&stage_with_icons(20, &original), 5);
+        let resized_ms = measure_render_ms(&stage_with_icons(20, &resized), 5);
+        let png_size = |icon: &[u8]| stage_with_icons(20, icon).render_frame().unwrap().len();
+        println!("decode and resize: {:.2} ms per icon", resize_ms);
+        println!("render with 512px icons: {:.1} ms, {} bytes", original_ms, png_size(&original));
+        println!("render with 96px icons: {:.1} ms, {} bytes", resized_ms, png_size(&resized));
+        assert!(resized_ms < original_ms);
     }
 
-    fn get_stage_height(&self) -> Real {
-        return (420.0 - self.get_stage_top()) * self.world_scale;
+    #[test]
+    #[ignore = "benchmark: cargo test --release -- --ignored --nocapture render_benchmark"]
+    fn render_benchmark_with_skia_backend() {
+        // 50人分のオブジェクト (96pxのアイコン付き) を、usvgとtiny-skiaのそれぞれで描画した場合の比較
+        let icon = canvas::Canvas::resize_icon(&gradient_icon(512), 96).unwrap();
+        let mut stage = stage_with_icons(50, &icon);
+        stage.render_options.backend = canvas::RenderBackend::Usvg;
+        let usvg_ms = measure_render_ms(&stage, 20);
+        stage.render_options.backend = canvas::RenderBackend::Skia;
+        let skia_ms = measure_render_ms(&stage, 20);
+        println!("render 50 pieces with usvg: {:.1} ms", usvg_ms);
+        println!("render 50 pieces with tiny-skia: {:.1} ms ({:.2}x)", skia_ms, usvg_ms / skia_ms);
     }
 }
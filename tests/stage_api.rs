@@ -0,0 +1,54 @@
+// ライブラリの公開APIだけでステージを遊ぶテスト
+#![allow(clippy::needless_return)]
+
+use slack_tower_battle::canvas::ShapeDef;
+use slack_tower_battle::stage::{ Difficulty, GroundVariant, Stage, StageConfig, TurnInput, TurnResult };
+
+// 正方形と横長の長方形 (px単位)
+fn test_shapes() -> Vec<ShapeDef> {
+    return vec![
+        ShapeDef::new(vec![(-30.0, -30.0), (30.0, -30.0), (30.0, 30.0), (-30.0, 30.0)]),
+        ShapeDef::new(vec![(-60.0, -15.0), (60.0, -15.0), (60.0, 15.0), (-60.0, 15.0)]),
+    ];
+}
+
+fn new_stage() -> Stage {
+    // テストを実行する速さで結果が変わらないように、実時間での打ち切りは無効にする
+    let config = StageConfig { wall_clock_budget_sec: 1.0e6, ..StageConfig::default() };
+    return Stage::new(test_shapes(), Difficulty::Normal, GroundVariant::Flat, 5, config);
+}
+
+#[test]
+fn stacking_in_the_center_raises_the_tower() {
+    let mut stage = new_stage();
+    // ゲーム開始時のターンはオブジェクトを落とさない
+    let report = stage.next_turn(None, TurnInput::default()).unwrap();
+    assert_eq!(report.result, TurnResult::Success);
+    assert_eq!(stage.get_height(), 0.0);
+
+    let mut height = 0.0;
+    for turn in 1..=3 {
+        let report = stage.next_turn(Some("U_TEST".to_string()), TurnInput::default()).unwrap();
+        assert_eq!(report.result, TurnResult::Success, "turn {}", turn);
+        assert!(report.height > height, "turn {}: {} m is not higher than {} m", turn, report.height, height);
+        assert_eq!(report.height, stage.get_height());
+        assert!(report.mass.is_some());
+        assert!(!report.image_png.is_empty());
+        height = report.height;
+    }
+    assert_eq!(stage.get_last_user_id().as_deref(), Some("U_TEST"));
+    assert_eq!(stage.get_user_piece_counts(), vec![("U_TEST".to_string(), 3)]);
+}
+
+#[test]
+fn dropping_off_the_edge_ends_the_game() {
+    let mut stage = new_stage();
+    stage.next_turn(None, TurnInput::default()).unwrap();
+    stage.next_turn(Some("U_TEST".to_string()), TurnInput::default()).unwrap();
+    // 地面の外に落とすと失敗して、落ちたオブジェクトが報告される
+    let input = TurnInput { translation_x: 1.0, ..TurnInput::default() };
+    let report = stage.next_turn(Some("U_TEST".to_string()), input).unwrap();
+    assert_eq!(report.result, TurnResult::Failure);
+    assert!(!report.fallen_object_indices.is_empty());
+    assert!(report.collapse_gif.is_some());
+}